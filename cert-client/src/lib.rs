@@ -1,5 +1,5 @@
-use anyhow::{Context, Result};
-use dstack_kms_rpc::{kms_client::KmsClient, SignCertRequest};
+use anyhow::{bail, Context, Result};
+use dstack_kms_rpc::{kms_client::KmsClient, CheckAppUpgradeRequest, SignCertRequest};
 use dstack_types::{AppKeys, KeyProvider};
 use ra_rpc::client::{RaClient, RaClientConfig};
 use ra_tls::{
@@ -53,6 +53,22 @@ impl CertRequestClient {
         }
     }
 
+    /// Ask the KMS whether the app may switch to the compose with the given hash.
+    pub async fn check_app_upgrade(&self, compose_hash: &[u8]) -> Result<()> {
+        match self {
+            CertRequestClient::Local { .. } => {
+                bail!("Upgrades can only be authorized by a KMS")
+            }
+            CertRequestClient::Kms { client, vm_config } => client
+                .check_app_upgrade(CheckAppUpgradeRequest {
+                    vm_config: vm_config.clone(),
+                    compose_hash: compose_hash.to_vec(),
+                })
+                .await
+                .context("KMS rejected the upgrade"),
+        }
+    }
+
     pub async fn create(
         keys: &AppKeys,
         pccs_url: Option<&str>,
@@ -1,7 +1,8 @@
 use anyhow::{bail, Context, Result};
 use clap::Parser;
 use dstack_gateway_rpc::gateway_client::GatewayClient;
-use ra_rpc::client::RaClient;
+use ra_rpc::client::RaClientConfig;
+use ra_tls::verifier::Expectation;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeSet;
@@ -13,6 +14,8 @@ const BASE_URL: &str = "https://crt.sh";
 
 struct Monitor {
     gateway_uri: String,
+    gateway_app_id: Option<Vec<u8>>,
+    pccs_url: Option<String>,
     domain: String,
     known_keys: BTreeSet<Vec<u8>>,
     last_checked: Option<u64>,
@@ -33,10 +36,22 @@ struct CTLog {
 }
 
 impl Monitor {
-    fn new(gateway_uri: String, domain: String) -> Result<Self> {
+    fn new(args: Args) -> Result<Self> {
+        let Args {
+            gateway_uri,
+            domain,
+            gateway_app_id,
+            pccs_url,
+        } = args;
         validate_domain(&domain)?;
+        let gateway_app_id = gateway_app_id
+            .map(hex::decode)
+            .transpose()
+            .context("Invalid gateway app id")?;
         Ok(Self {
             gateway_uri,
+            gateway_app_id,
+            pccs_url,
             domain,
             known_keys: BTreeSet::new(),
             last_checked: None,
@@ -45,9 +60,16 @@ impl Monitor {
 
     async fn refresh_known_keys(&mut self) -> Result<()> {
         info!("fetching known public keys from {}", self.gateway_uri);
-        let todo = "Use RA-TLS";
-        let tls_no_check = true;
-        let rpc = GatewayClient::new(RaClient::new(self.gateway_uri.clone(), tls_no_check)?);
+        let client = RaClientConfig::builder()
+            .remote_uri(self.gateway_uri.clone())
+            .maybe_pccs_url(self.pccs_url.clone())
+            .ra_tls_expectation(Expectation {
+                app_id: self.gateway_app_id.clone(),
+                ..Default::default()
+            })
+            .build()
+            .into_client()?;
+        let rpc = GatewayClient::new(client);
         let info = rpc.acme_info().await?;
         self.known_keys = info.hist_keys.into_iter().collect();
         info!("got {} known public keys", self.known_keys.len());
@@ -152,6 +174,12 @@ struct Args {
     /// Domain name to monitor
     #[arg(short, long)]
     domain: String,
+    /// App id the gateway must attest to, in hex. Any attested gateway is accepted if unset
+    #[arg(long)]
+    gateway_app_id: Option<String>,
+    /// PCCS to fetch quote collateral from
+    #[arg(long)]
+    pccs_url: Option<String>,
 }
 
 #[tokio::main]
@@ -162,7 +190,7 @@ async fn main() -> anyhow::Result<()> {
         fmt().with_env_filter(filter).init();
     }
     let args = Args::parse();
-    let mut monitor = Monitor::new(args.gateway_uri, args.domain)?;
+    let mut monitor = Monitor::new(args)?;
     monitor.run().await;
     Ok(())
 }
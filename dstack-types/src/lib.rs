@@ -1,3 +1,5 @@
+use std::collections::BTreeMap;
+
 use serde::{Deserialize, Serialize};
 use serde_human_bytes as hex_bytes;
 
@@ -19,6 +21,9 @@ pub struct AppCompose {
     pub public_sysinfo: bool,
     #[serde(default = "default_true")]
     pub public_tcbinfo: bool,
+    /// Lets whoever reaches the guest API, such as the VMM operator, restart and stop services.
+    #[serde(default)]
+    pub public_service_control: bool,
     #[serde(default)]
     pub kms_enabled: bool,
     #[serde(deserialize_with = "deserialize_gateway_enabled", flatten)]
@@ -35,6 +40,89 @@ pub struct AppCompose {
     pub no_instance_id: bool,
     #[serde(default = "default_true")]
     pub secure_time: bool,
+    /// Key paths the app may use with the in-enclave signing API. A trailing `*` matches any
+    /// path with the given prefix. The signing API is disabled if empty.
+    #[serde(default)]
+    pub allowed_sign_paths: Vec<String>,
+    /// TLS certificates issued and renewed by the guest agent on behalf of the app.
+    #[serde(default)]
+    pub managed_certs: Vec<ManagedCert>,
+    /// Bind the app keys to these measurements, so that they change whenever any of them does.
+    ///
+    /// With the local key provider all app keys are derived from the bound seed. With a KMS only
+    /// the disk encryption key is rebound, as the other keys are certified by the KMS. Without a
+    /// key provider the keys are random on every boot, and binding them changes nothing.
+    #[serde(default)]
+    pub mr_binding: Option<MrBindingPolicy>,
+}
+
+/// The measurements app keys are bound to, in addition to the seed from the key provider.
+///
+/// RTMR3 is taken at `boot-mr-done`, so it covers the app id, the compose hash and the instance
+/// id, but not the key provider.
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct MrBindingPolicy {
+    pub mrtd: bool,
+    pub rtmr0: bool,
+    pub rtmr1: bool,
+    pub rtmr2: bool,
+    pub rtmr3: bool,
+    pub compose_hash: bool,
+}
+
+impl MrBindingPolicy {
+    /// Binds to all the measurements.
+    pub fn all() -> Self {
+        Self {
+            mrtd: true,
+            rtmr0: true,
+            rtmr1: true,
+            rtmr2: true,
+            rtmr3: true,
+            compose_hash: true,
+        }
+    }
+
+    /// Whether the policy selects no measurement.
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// A long-lived certificate managed by the guest agent.
+///
+/// The key and certificate chain are written to `<certs dir>/<name>/{key.pem,cert.pem}`. On
+/// renewal the directory is replaced atomically, so apps may also watch the files.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ManagedCert {
+    /// Name of the certificate, used as its directory name
+    pub name: String,
+    /// Subject of the certificate
+    pub subject: String,
+    /// DNS alternative names
+    #[serde(default)]
+    pub alt_names: Vec<String>,
+    /// Includes the quote in the certificate
+    #[serde(default)]
+    pub usage_ra_tls: bool,
+    #[serde(default = "default_true")]
+    pub usage_server_auth: bool,
+    #[serde(default)]
+    pub usage_client_auth: bool,
+    /// Renew this many seconds before expiry. Defaults to a third of the certificate lifetime.
+    #[serde(default)]
+    pub renew_before_secs: Option<u64>,
+    /// Compose services to signal after the certificate is renewed
+    #[serde(default)]
+    pub reload_services: Vec<String>,
+    /// The signal sent to `reload_services`
+    #[serde(default = "default_reload_signal")]
+    pub reload_signal: String,
+}
+
+fn default_reload_signal() -> String {
+    "SIGHUP".into()
 }
 
 fn default_true() -> bool {
@@ -56,7 +144,7 @@ where
     Ok(value.gateway_enabled || value.tproxy_enabled)
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
 pub enum KeyProviderKind {
     None,
@@ -74,7 +162,7 @@ impl KeyProviderKind {
     }
 }
 
-#[derive(Deserialize, Serialize, Debug, Default, Clone)]
+#[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq, Eq)]
 pub struct DockerConfig {
     /// The URL of the Docker registry.
     pub registry: Option<String>,
@@ -204,11 +292,23 @@ impl KeyProvider {
 pub struct KeyProviderInfo {
     pub name: String,
     pub id: String,
+    /// The measurements the app keys are bound to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mr_binding: Option<MrBindingPolicy>,
 }
 
 impl KeyProviderInfo {
     pub fn new(name: String, id: String) -> Self {
-        Self { name, id }
+        Self {
+            name,
+            id,
+            mr_binding: None,
+        }
+    }
+
+    pub fn with_mr_binding(mut self, mr_binding: Option<MrBindingPolicy>) -> Self {
+        self.mr_binding = mr_binding;
+        self
     }
 }
 
@@ -218,6 +318,9 @@ pub struct ImageInfo {
     pub kernel: String,
     pub initrd: String,
     pub bios: String,
+    /// Hex encoded SEV-SNP launch measurements of the image, by vCPU count
+    #[serde(default)]
+    pub sev_snp_measurements: BTreeMap<u8, String>,
 }
 
 pub mod mr_config;
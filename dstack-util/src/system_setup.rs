@@ -2,6 +2,7 @@ use std::{
     collections::{BTreeMap, BTreeSet},
     ops::Deref,
     path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
 };
 
 use anyhow::{anyhow, bail, Context, Result};
@@ -11,15 +12,15 @@ use dstack_types::{
         APP_COMPOSE, APP_KEYS, DECRYPTED_ENV, DECRYPTED_ENV_JSON, ENCRYPTED_ENV,
         HOST_SHARED_DIR_NAME, INSTANCE_INFO, SYS_CONFIG, USER_CONFIG,
     },
-    KeyProvider, KeyProviderInfo,
+    KeyProvider, KeyProviderInfo, MrBindingPolicy,
 };
 use fs_err as fs;
-use ra_rpc::client::{CertInfo, RaClient, RaClientConfig};
-use ra_tls::cert::generate_ra_cert;
+use ra_rpc::client::{CertInfo, RaClient, RaClientConfig, RaEndpoint, RetryPolicy};
+use ra_tls::{attestation::Attestation, cert::generate_ra_cert, kdf::derive_mr_bound_key};
 use rand::Rng as _;
 use serde::{Deserialize, Serialize};
 use tdx_attest::extend_rtmr3;
-use tracing::{info, warn};
+use tracing::info;
 
 use crate::{
     cmd_show_mrs,
@@ -202,6 +203,16 @@ fn truncate(s: &[u8], len: usize) -> &[u8] {
     }
 }
 
+/// Derive a key from `seed` bound to the boot-time measurements selected by `policy`.
+fn bind_to_boot_mrs(seed: &[u8], policy: &MrBindingPolicy) -> Result<[u8; 32]> {
+    info!("Binding app keys to measurements: {policy:?}");
+    let attestation = Attestation::local().context("Failed to get local attestation")?;
+    let app_info = attestation
+        .decode_app_info(true)
+        .context("Failed to decode boot-time measurements")?;
+    derive_mr_bound_key(seed, policy, &app_info).context("Failed to derive MR-bound key")
+}
+
 fn emit_key_provider_info(provider_info: &KeyProviderInfo) -> Result<()> {
     info!("Key provider info: {provider_info:?}");
     let provider_info_json = serde_json::to_vec(&provider_info)?;
@@ -288,11 +299,32 @@ impl<'a> Stage0<'a> {
         self.shared.dir.join(APP_KEYS)
     }
 
-    async fn request_app_keys_from_kms_url(&self, kms_url: String) -> Result<AppKeys> {
-        info!("Requesting app keys from KMS: {kms_url}");
+    async fn request_app_keys_from_kms(&self) -> Result<AppKeys> {
+        if self.shared.sys_config.kms_urls.is_empty() {
+            bail!("No KMS URLs are set");
+        }
+        // Any KMS of the cluster will do, they share the CAs
+        let endpoints: Vec<RaEndpoint> = self
+            .shared
+            .sys_config
+            .kms_urls
+            .iter()
+            .map(|url| RaEndpoint::new(format!("{url}/prpc")))
+            .collect();
+        let retry = RetryPolicy::default().with_idempotent_methods(&["GetTempCaCert", "GetAppKey"]);
+        info!(
+            "Requesting app keys from KMS: {:?}",
+            self.shared.sys_config.kms_urls
+        );
         let tmp_ca = {
             info!("Getting temp ca cert");
-            let client = RaClient::new(kms_url.clone(), true)?;
+            let client = RaClientConfig::builder()
+                .tls_no_check(true)
+                .endpoints(endpoints.clone())
+                .retry(retry.clone())
+                .build()
+                .into_client()
+                .context("Failed to create client")?;
             let kms_client = dstack_kms_rpc::kms_client::KmsClient::new(client);
             kms_client
                 .get_temp_ca_cert()
@@ -300,32 +332,40 @@ impl<'a> Stage0<'a> {
                 .context("Failed to get temp ca cert")?
         };
         let cert_pair = generate_ra_cert(tmp_ca.temp_ca_cert, tmp_ca.temp_ca_key)?;
+        // Only the KMS that answers is measured, not the ones tried before it
+        let mr_kms = Arc::new(Mutex::new(None));
         let ra_client = RaClientConfig::builder()
             .tls_no_check(false)
             .tls_built_in_root_certs(false)
-            .remote_uri(kms_url.clone())
+            .endpoints(endpoints)
+            .retry(retry)
             .tls_client_cert(cert_pair.cert_pem)
             .tls_client_key(cert_pair.key_pem)
             .tls_ca_cert(tmp_ca.ca_cert.clone())
             .maybe_pccs_url(self.shared.sys_config.pccs_url.clone())
-            .cert_validator(Box::new(|cert| {
-                let Some(cert) = cert else {
-                    bail!("Missing server cert");
-                };
-                let Some(usage) = cert.special_usage else {
-                    bail!("Missing server cert usage");
-                };
-                if usage != "kms:rpc" {
-                    bail!("Invalid server cert usage: {usage}");
-                }
-                if let Some(att) = &cert.attestation {
-                    let kms_info = att
-                        .decode_app_info(false)
-                        .context("Failed to decode app_info")?;
-                    extend_rtmr3("mr-kms", &kms_info.mr_aggregated)
-                        .context("Failed to extend mr-kms to RTMR3")?;
+            .cert_validator(Box::new({
+                let mr_kms = mr_kms.clone();
+                move |cert| {
+                    let Some(cert) = cert else {
+                        bail!("Missing server cert");
+                    };
+                    let Some(usage) = cert.special_usage else {
+                        bail!("Missing server cert usage");
+                    };
+                    if usage != "kms:rpc" {
+                        bail!("Invalid server cert usage: {usage}");
+                    }
+                    let mr_aggregated = match &cert.attestation {
+                        Some(att) => Some(
+                            att.decode_app_info(false)
+                                .context("Failed to decode app_info")?
+                                .mr_aggregated,
+                        ),
+                        None => None,
+                    };
+                    *mr_kms.lock().unwrap_or_else(PoisonError::into_inner) = mr_aggregated;
+                    Ok(())
                 }
-                Ok(())
             }))
             .build()
             .into_client()
@@ -338,7 +378,17 @@ impl<'a> Stage0<'a> {
             })
             .await
             .context("Failed to get app key")?;
-
+        let kms_url = kms_client
+            .client
+            .last_endpoint()
+            .context("No KMS answered")?
+            .to_string();
+        info!("Got app keys from KMS: {kms_url}");
+
+        let mr_kms = mr_kms.lock().unwrap_or_else(PoisonError::into_inner).take();
+        if let Some(mr_kms) = mr_kms {
+            extend_rtmr3("mr-kms", &mr_kms).context("Failed to extend mr-kms to RTMR3")?;
+        }
         extend_rtmr3("os-image-hash", &response.os_image_hash)
             .context("Failed to extend os-image-hash to RTMR3")?;
 
@@ -362,28 +412,6 @@ impl<'a> Stage0<'a> {
         Ok(keys)
     }
 
-    async fn request_app_keys_from_kms(&self) -> Result<AppKeys> {
-        if self.shared.sys_config.kms_urls.is_empty() {
-            bail!("No KMS URLs are set");
-        }
-        let keys = 'out: {
-            for kms_url in self.shared.sys_config.kms_urls.iter() {
-                let kms_url = format!("{kms_url}/prpc");
-                let response = self.request_app_keys_from_kms_url(kms_url.clone()).await;
-                match response {
-                    Ok(response) => {
-                        break 'out response;
-                    }
-                    Err(err) => {
-                        warn!("Failed to get app keys from KMS {kms_url}: {err:?}");
-                    }
-                }
-            }
-            bail!("Failed to get app keys from KMS");
-        };
-        Ok(keys)
-    }
-
     fn verify_key_provider_id(&self, provider_id: &[u8]) -> Result<()> {
         let expected_key_provider_id = &self.shared.app_compose.key_provider_id;
         if expected_key_provider_id.is_empty() {
@@ -405,20 +433,38 @@ impl<'a> Stage0<'a> {
             .get_sealing_key()
             .await
             .context("Failed to get sealing key")?;
+        let seed = match &self.shared.app_compose.mr_binding {
+            Some(policy) => bind_to_boot_mrs(&provision.sk, policy)?.to_vec(),
+            None => provision.sk.to_vec(),
+        };
         // write to fs
-        let app_keys = gen_app_keys_from_seed(&provision.sk, Some(provision.mr.to_vec()))
+        let app_keys = gen_app_keys_from_seed(&seed, Some(provision.mr.to_vec()))
             .context("Failed to generate app keys")?;
         Ok(app_keys)
     }
 
     async fn request_app_keys(&self) -> Result<AppKeys> {
         let key_provider = self.shared.app_compose.key_provider();
+        let mr_binding = self.shared.app_compose.mr_binding.as_ref();
         match key_provider {
-            KeyProviderKind::Kms => self.request_app_keys_from_kms().await,
+            KeyProviderKind::Kms => {
+                let mut keys = self.request_app_keys_from_kms().await?;
+                if let Some(policy) = mr_binding {
+                    // Only the disk key is rebound, the other keys are certified by the KMS
+                    keys.disk_crypt_key = bind_to_boot_mrs(&keys.disk_crypt_key, policy)?.to_vec();
+                }
+                Ok(keys)
+            }
+            // Binds the seed of all the keys to the measurements
             KeyProviderKind::Local => self.get_keys_from_local_key_provider().await,
             KeyProviderKind::None => {
                 info!("No key provider is enabled, generating temporary app keys");
                 let seed: [u8; 32] = rand::thread_rng().gen();
+                // The keys are temporary anyway, binding them keeps the policy uniform
+                let seed = match mr_binding {
+                    Some(policy) => bind_to_boot_mrs(&seed, policy)?,
+                    None => seed,
+                };
                 gen_app_keys_from_seed(&seed, None).context("Failed to generate app keys")
             }
         }
@@ -541,7 +587,7 @@ impl<'a> Stage0<'a> {
                 KeyProviderInfo::new("kms".into(), hex::encode(pubkey))
             }
         };
-        emit_key_provider_info(&kp_info)?;
+        emit_key_provider_info(&kp_info.with_mr_binding(self.shared.app_compose.mr_binding))?;
         Ok(())
     }
 
@@ -666,18 +712,24 @@ impl Stage1<'_> {
 
     async fn register_cvm(
         &self,
-        gateway_url: &str,
         client_key: String,
         client_cert: String,
         wg_pk: String,
     ) -> Result<RegisterCvmResponse> {
-        let url = format!("{}/prpc", gateway_url);
+        // Registering with any of the gateways will do
+        let endpoints = self
+            .shared
+            .sys_config
+            .gateway_urls
+            .iter()
+            .map(|url| RaEndpoint::new(format!("{url}/prpc")))
+            .collect();
         let ca_cert = self.keys.ca_cert.clone();
         let cert_validator = AppIdValidator {
             allowed_app_id: self.keys.gateway_app_id.clone(),
         };
         let client = RaClientConfig::builder()
-            .remote_uri(url)
+            .endpoints(endpoints)
             .maybe_pccs_url(self.shared.sys_config.pccs_url.clone())
             .tls_client_cert(client_cert)
             .tls_client_key(client_key)
@@ -740,22 +792,10 @@ impl Stage1<'_> {
             bail!("Missing gateway urls");
         }
         // Read config and make API call
-        let response = 'out: {
-            for url in self.shared.sys_config.gateway_urls.iter() {
-                let response = self
-                    .register_cvm(url, client_key.clone(), client_cert.clone(), pk.clone())
-                    .await;
-                match response {
-                    Ok(response) => {
-                        break 'out response;
-                    }
-                    Err(err) => {
-                        warn!("Failed to register CVM: {err:?}, retrying with next dstack-gateway");
-                    }
-                }
-            }
-            bail!("Failed to register CVM, all dstack-gateway urls are down");
-        };
+        let response = self
+            .register_cvm(client_key, client_cert, pk)
+            .await
+            .context("Failed to register CVM with dstack-gateway")?;
         let wg_info = response.wg.context("Missing wg info")?;
 
         let client_ip = &wg_info.client_ip;
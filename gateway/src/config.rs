@@ -2,6 +2,8 @@ use anyhow::{bail, Context, Result};
 use cmd_lib::run_cmd as cmd;
 use ipnet::Ipv4Net;
 use load_config::load_config;
+use ra_rpc::signed_request::SignedRequestPolicy;
+use ra_tls::{collateral::CollateralConfig, sev_snp::SnpConfig, tcb_policy::TcbPolicy};
 use rocket::figment::Figment;
 use serde::{Deserialize, Serialize};
 use std::net::Ipv4Addr;
@@ -133,6 +135,14 @@ pub struct Config {
     pub proxy: ProxyConfig,
     pub certbot: CertbotConfig,
     pub pccs_url: Option<String>,
+    #[serde(default)]
+    pub collateral: CollateralConfig,
+    #[serde(default)]
+    pub tcb_policy: TcbPolicy,
+    #[serde(default)]
+    pub sev_snp: SnpConfig,
+    #[serde(default)]
+    pub signed_requests: Option<SignedRequestPolicy>,
     pub recycle: RecycleConfig,
     pub state_path: String,
     pub set_ulimit: bool,
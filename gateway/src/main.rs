@@ -157,7 +157,11 @@ async fn main() -> Result<()> {
         None
     };
     let proxy_config = config.proxy.clone();
+    ra_tls::collateral::init(&config.collateral).context("Failed to set up collateral cache")?;
+    ra_tls::sev_snp::init(&config.sev_snp).context("Failed to set up SEV-SNP verifier")?;
     let pccs_url = config.pccs_url.clone();
+    let tcb_policy = config.tcb_policy.clone();
+    let signed_requests = config.signed_requests.clone();
     let admin_enabled = config.admin.enabled;
     let state = main_service::Proxy::new(config, my_app_id).await?;
     info!("Starting background tasks");
@@ -185,7 +189,19 @@ async fn main() -> Result<()> {
             })
         }))
         .manage(state.clone());
-    let verifier = QuoteVerifier::new(pccs_url);
+    let verifier = QuoteVerifier::new(pccs_url).with_tcb_policy(tcb_policy);
+    let verifier = match signed_requests {
+        Some(policy) => verifier
+            .with_signed_requests(policy)
+            .context("Invalid signed request policy")?,
+        None => verifier,
+    };
+    #[cfg(feature = "simulator")]
+    let verifier = {
+        use ra_tls::simulator::{SimVerifier, DEFAULT_PCK_SEED};
+        tracing::warn!("Trusting quotes of the simulator, never use this build in production");
+        verifier.with_sim_verifier(SimVerifier::new(DEFAULT_PCK_SEED)?)
+    };
     rocket = rocket.manage(verifier);
     let main_srv = rocket.launch();
     let admin_srv = async move {
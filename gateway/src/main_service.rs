@@ -13,8 +13,8 @@ use certbot::{CertBot, WorkDir};
 use cmd_lib::run_cmd as cmd;
 use dstack_gateway_rpc::{
     gateway_server::{GatewayRpc, GatewayServer},
-    AcmeInfoResponse, GatewayState, GuestAgentConfig, InfoResponse, QuotedPublicKey,
-    RegisterCvmRequest, RegisterCvmResponse, WireGuardConfig, WireGuardPeer,
+    AcmeInfoResponse, GatewayState, GuestAgentConfig, InfoResponse, ListPeersResponse,
+    QuotedPublicKey, RegisterCvmRequest, RegisterCvmResponse, WireGuardConfig, WireGuardPeer,
 };
 use dstack_guest_agent_rpc::{dstack_guest_client::DstackGuestClient, RawQuoteArgs};
 use fs_err as fs;
@@ -345,6 +345,24 @@ impl ProxyState {
         self.state.instances.insert(info.id.clone(), info);
     }
 
+    /// Instances of `app_id` other than `instance_id` seen within `live_within`.
+    fn app_peers(
+        &self,
+        app_id: &str,
+        instance_id: &str,
+        live_within: Duration,
+    ) -> Vec<InstanceInfo> {
+        let Some(ids) = self.state.apps.get(app_id) else {
+            return vec![];
+        };
+        ids.iter()
+            .filter(|id| *id != instance_id)
+            .filter_map(|id| self.state.instances.get(id))
+            .filter(|info| info.last_seen.elapsed().unwrap_or_default() <= live_within)
+            .cloned()
+            .collect()
+    }
+
     fn generate_wg_config(&self) -> Result<String> {
         let model = WgConf {
             private_key: &self.config.wg.private_key,
@@ -781,6 +799,34 @@ impl GatewayRpc for RpcHandler {
         Ok(())
     }
 
+    async fn list_peers(self) -> Result<ListPeersResponse> {
+        let Some(ra) = &self.attestation else {
+            bail!("no attestation provided");
+        };
+        let app_info = ra
+            .decode_app_info(false)
+            .context("failed to decode app-info from attestation")?;
+        let app_id = hex::encode(&app_info.app_id);
+        let instance_id = hex::encode(&app_info.instance_id);
+
+        let mut state = self.state.lock();
+        // Only instances that passed the authorization in RegisterCvm may look up their peers
+        match state.state.instances.get(&instance_id) {
+            Some(info) if info.app_id == app_id => {}
+            _ => bail!("[{instance_id}] instance is not registered"),
+        }
+        if let Err(err) = state.refresh_state() {
+            warn!("failed to refresh state: {err:?}");
+        }
+        let live_within = state.config.recycle.timeout;
+        let peers = state
+            .app_peers(&app_id, &instance_id, live_within)
+            .into_iter()
+            .map(Into::into)
+            .collect();
+        Ok(ListPeersResponse { peers })
+    }
+
     async fn info(self) -> Result<InfoResponse> {
         let state = self.state.lock();
         Ok(InfoResponse {
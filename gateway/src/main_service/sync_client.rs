@@ -3,7 +3,7 @@ use std::time::{Duration, Instant};
 use anyhow::{Context, Result};
 use dstack_gateway_rpc::{gateway_client::GatewayClient, GatewayState};
 use dstack_guest_agent_rpc::GetTlsKeyArgs;
-use ra_rpc::client::{RaClient, RaClientConfig};
+use ra_rpc::client::{RaClient, RaClientConfig, RaEndpoint, RetryPolicy};
 use tracing::{error, info};
 
 use crate::{dstack_agent, main_service::Proxy};
@@ -19,12 +19,26 @@ struct SyncClient {
 }
 
 impl SyncClient {
+    /// A client syncing with the peer at `url`.
+    ///
+    /// Each peer gets a client of its own, so that a peer rejecting the state does not keep it
+    /// from the others.
     fn create_rpc_client(&self, url: &str) -> Result<GatewayClient<RaClient>> {
         let app_id = self.app_id.clone();
-        let url = format!("{}/prpc", url.trim_end_matches('/'));
+        let endpoints = vec![RaEndpoint::new(format!(
+            "{}/prpc",
+            url.trim_end_matches('/')
+        ))];
+        // Merging the same state twice is harmless
+        let retry = RetryPolicy {
+            attempt_timeout: Some(self.timeout),
+            ..Default::default()
+        }
+        .with_idempotent_methods(&["UpdateState"]);
         let client = if self.in_dstack {
             RaClientConfig::builder()
-                .remote_uri(url)
+                .endpoints(endpoints)
+                .retry(retry)
                 // Don't verify server RA because we use the CA cert from KMS to verify
                 // the server cert.
                 .verify_server_attestation(false)
@@ -47,7 +61,13 @@ impl SyncClient {
                 .into_client()
                 .context("failed to create client")?
         } else {
-            RaClient::new(url, true)?
+            RaClientConfig::builder()
+                .endpoints(endpoints)
+                .retry(retry)
+                .tls_no_check(true)
+                .build()
+                .into_client()
+                .context("failed to create client")?
         };
         Ok(GatewayClient::new(client))
     }
@@ -55,10 +75,8 @@ impl SyncClient {
     async fn sync_state(&self, url: &str, state: &GatewayState) -> Result<()> {
         info!("Trying to sync state to {url}");
         let rpc = self.create_rpc_client(url)?;
-        tokio::time::timeout(self.timeout, rpc.update_state(state.clone()))
+        rpc.update_state(state.clone())
             .await
-            .ok()
-            .context("Timeout while syncing state")?
             .context("Failed to sync state")?;
         info!("Synced state to {url}");
         Ok(())
@@ -136,34 +154,29 @@ pub(crate) async fn sync_task(proxy: Proxy) -> Result<()> {
             apps: apps.into_iter().map(|a| a.into()).collect(),
         };
 
+        let bootnode = &config.sync.bootnode;
         if state.nodes.is_empty() {
             // If no nodes exist yet, sync with bootnode
-            sync_client
-                .sync_state_ignore_error(&config.sync.bootnode, &state)
-                .await;
+            sync_client.sync_state_ignore_error(bootnode, &state).await;
         } else {
             let nodes = &state.nodes;
             // Try nodes after self, wrapping around to beginning
+            let peers: Vec<String> = (1..nodes.len())
+                .map(|i| nodes[(self_idx + i) % nodes.len()].url.clone())
+                .collect();
             let mut success = false;
-            for i in 1..nodes.len() {
-                let idx = (self_idx + i) % nodes.len();
-                if sync_client
-                    .sync_state_ignore_error(&nodes[idx].url, &state)
-                    .await
-                {
-                    success = true;
-                    if !broadcast {
-                        break;
-                    }
+            for peer in &peers {
+                success |= sync_client.sync_state_ignore_error(peer, &state).await;
+                // Without a broadcast, one peer having the state is enough
+                if success && !broadcast {
+                    break;
                 }
             }
 
             // If no node succeeded, try bootnode as fallback
             if !success {
                 info!("Fallback to sync with bootnode");
-                sync_client
-                    .sync_state_ignore_error(&config.sync.bootnode, &state)
-                    .await;
+                sync_client.sync_state_ignore_error(bootnode, &state).await;
             }
         }
 
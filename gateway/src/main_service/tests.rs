@@ -40,3 +40,77 @@ async fn test_config() {
     let wg_config = state.lock().generate_wg_config().unwrap();
     insta::assert_snapshot!(wg_config);
 }
+
+#[tokio::test]
+async fn test_app_peers() {
+    let state = create_test_state().await;
+    let mut state = state.lock();
+    for (id, app_id) in [
+        ("a-0", "app-a"),
+        ("a-1", "app-a"),
+        ("a-2", "app-a"),
+        ("b-0", "app-b"),
+    ] {
+        state
+            .new_client_by_id(id, app_id, &format!("pubkey-{id}"))
+            .unwrap();
+    }
+    let live_within = Duration::from_secs(60);
+    state.state.instances.get_mut("a-2").unwrap().last_seen = SystemTime::UNIX_EPOCH;
+    let peers: Vec<_> = state
+        .app_peers("app-a", "a-0", live_within)
+        .into_iter()
+        .map(|info| info.id)
+        .collect();
+    assert_eq!(peers, ["a-1"]);
+    assert!(state.app_peers("app-b", "b-0", live_within).is_empty());
+    assert!(state.app_peers("app-c", "c-0", live_within).is_empty());
+}
+
+#[tokio::test]
+async fn test_register_simulated_cvm() {
+    use ra_rpc::{rocket_helper::QuoteVerifier, Attestation};
+    use ra_tls::{
+        attestation::QuoteContentType,
+        simulator::{QuoteSimulator, SimProfile, SimVerifier, DEFAULT_PCK_SEED},
+    };
+
+    let state = create_test_state().await;
+    let simulator = QuoteSimulator::new(SimProfile {
+        app_id: vec![0xaa; 20],
+        instance_id: vec![0xbb; 20],
+        ..Default::default()
+    })
+    .unwrap();
+    let ra_pubkey = b"client cert public key";
+    let report_data = QuoteContentType::RaTlsCert.to_report_data(ra_pubkey);
+    let event_log = simulator.event_log();
+    let quote = simulator.quote(&report_data, &event_log).unwrap();
+    let attestation = || Attestation::new(quote.clone(), serde_json::to_vec(&event_log).unwrap());
+    let verifier =
+        QuoteVerifier::new(None).with_sim_verifier(SimVerifier::new(DEFAULT_PCK_SEED).unwrap());
+    assert!(verifier
+        .verify(attestation().unwrap(), b"another public key")
+        .await
+        .is_err());
+    let verified = verifier
+        .verify(attestation().unwrap(), ra_pubkey)
+        .await
+        .unwrap();
+
+    let handler = RpcHandler {
+        remote_app_id: None,
+        attestation: Some(verified),
+        state: state.clone(),
+    };
+    let response = handler
+        .register_cvm(RegisterCvmRequest {
+            client_public_key: "test-pubkey".into(),
+        })
+        .await
+        .unwrap();
+    assert!(response.wg.is_some());
+    let state = state.lock();
+    let info = &state.state.instances[&hex::encode([0xbb; 20])];
+    assert_eq!(info.app_id, hex::encode([0xaa; 20]));
+}
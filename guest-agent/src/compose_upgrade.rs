@@ -0,0 +1,231 @@
+//! In-place upgrade of the app compose, without rebooting the CVM.
+//!
+//! The new compose is authorized by the KMS under the same policy as a boot, applied with
+//! `docker compose up` and, once running, recorded in RTMR3 as a `compose-hash-upgrade` event.
+//! A failed upgrade is rolled back to the previous compose. Only the compose file itself may
+//! change; everything consumed during boot must stay as it is.
+//!
+//! Once upgraded, data sealed to the compose hash is bound to the new compose, and data sealed
+//! before the upgrade can be unsealed only until the next reboot. Apps must reseal it in between.
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use dstack_types::shared_filenames::{APP_COMPOSE, DECRYPTED_ENV_JSON, HOST_SHARED_DIR_NAME};
+use dstack_types::AppCompose;
+use fs_err as fs;
+use sha2::{Digest, Sha256};
+use tracing::{error, info, warn};
+
+use crate::config::AppComposeWrapper;
+use crate::containers;
+use crate::rpc_service::AppState;
+
+/// The RTMR3 event recording the hash of the compose upgraded to.
+pub use ra_tls::attestation::COMPOSE_UPGRADE_EVENT as UPGRADE_EVENT;
+const COMPOSE_YAML: &str = "docker-compose.yaml";
+
+/// Fields of `new` that differ from `current` but can only take effect on boot.
+fn boot_fields_changed(current: &AppCompose, new: &AppCompose) -> Vec<&'static str> {
+    [
+        ("runner", current.runner != new.runner),
+        ("kms_enabled", current.kms_enabled() != new.kms_enabled()),
+        (
+            "gateway_enabled",
+            current.gateway_enabled() != new.gateway_enabled(),
+        ),
+        ("key_provider", current.key_provider() != new.key_provider()),
+        (
+            "key_provider_id",
+            current.key_provider_id != new.key_provider_id,
+        ),
+        ("allowed_envs", current.allowed_envs != new.allowed_envs),
+        (
+            "no_instance_id",
+            current.no_instance_id != new.no_instance_id,
+        ),
+        ("secure_time", current.secure_time != new.secure_time),
+        ("docker_config", current.docker_config != new.docker_config),
+        // Read by the agent at startup
+        ("public_logs", current.public_logs != new.public_logs),
+        (
+            "public_sysinfo",
+            current.public_sysinfo != new.public_sysinfo,
+        ),
+        (
+            "public_tcbinfo",
+            current.public_tcbinfo != new.public_tcbinfo,
+        ),
+        (
+            "allowed_sign_paths",
+            current.allowed_sign_paths != new.allowed_sign_paths,
+        ),
+        ("managed_certs", current.managed_certs != new.managed_certs),
+        ("mr_binding", current.mr_binding != new.mr_binding),
+    ]
+    .into_iter()
+    .filter_map(|(name, changed)| changed.then_some(name))
+    .collect()
+}
+
+fn ensure_upgradable(current: &AppCompose, new: &AppCompose) -> Result<()> {
+    // Without a KMS the app id is derived from the compose hash, so it can not be upgraded
+    if !current.key_provider().is_kms() {
+        bail!("Only apps using the KMS key provider can be upgraded in place");
+    }
+    // The keys would change on the next boot, locking the app out of its data
+    if let Some(binding) = &current.mr_binding {
+        if binding.compose_hash || binding.rtmr3 {
+            bail!("Apps with keys bound to the compose hash can not be upgraded");
+        }
+    }
+    if new.runner != "docker-compose" || new.docker_compose_file.is_none() {
+        bail!("Only docker-compose apps can be upgraded in place");
+    }
+    let changed = boot_fields_changed(current, new);
+    if !changed.is_empty() {
+        bail!(
+            "Can not change {} without a reboot, use UpgradeApp instead",
+            changed.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Replace `path` with `content`, so that readers never see a partial file.
+fn write_atomic(path: &Path, content: &str) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, content)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+fn read_env(workdir: &Path) -> Result<BTreeMap<String, String>> {
+    let path = workdir.join(HOST_SHARED_DIR_NAME).join(DECRYPTED_ENV_JSON);
+    if !path.exists() {
+        return Ok(Default::default());
+    }
+    serde_json::from_str(&fs::read_to_string(path)?).context("Failed to parse decrypted env")
+}
+
+/// Write the compose files and bring the app up with them.
+async fn apply(workdir: &Path, compose_file: &str, yaml: &str) -> Result<()> {
+    // Keep the shared copy in sync for the agent and for app-compose.sh on restart
+    write_atomic(
+        &workdir.join(HOST_SHARED_DIR_NAME).join(APP_COMPOSE),
+        compose_file,
+    )
+    .context("Failed to write app compose")?;
+    write_atomic(&workdir.join(COMPOSE_YAML), yaml).context("Failed to write compose file")?;
+    containers::compose_up(workdir, &read_env(workdir)?).await
+}
+
+/// Upgrade the app to `compose_file`, returning its compose hash.
+pub async fn upgrade(state: &AppState, compose_file: &str) -> Result<Vec<u8>> {
+    let new: AppCompose = serde_json::from_str(compose_file).context("Invalid compose file")?;
+    let config = state.config();
+    let compose_hash = Sha256::digest(compose_file.as_bytes()).to_vec();
+
+    let _guard = state.lock_upgrade().await;
+    ensure_upgradable(&state.app_compose(), &new)?;
+    state.check_app_upgrade(&compose_hash).await?;
+    info!("Upgrading app compose to {}", hex::encode(&compose_hash));
+
+    let workdir = &config.compose_workdir;
+    let previous_compose = fs::read_to_string(workdir.join(HOST_SHARED_DIR_NAME).join(APP_COMPOSE))
+        .context("Failed to read current app compose")?;
+    let previous_yaml = fs::read_to_string(workdir.join(COMPOSE_YAML))
+        .context("Failed to read current compose file")?;
+    let yaml = new.docker_compose_file.as_deref().unwrap_or_default();
+    let result = match apply(workdir, compose_file, yaml).await {
+        // Only a compose that is up gets measured
+        Ok(()) => state
+            .extend_rtmr3(UPGRADE_EVENT, &compose_hash)
+            .context("Failed to extend RTMR3"),
+        Err(err) => Err(err),
+    };
+    if let Err(err) = result {
+        warn!("Upgrade failed, rolling back: {err:?}");
+        if let Err(rollback_err) = apply(workdir, &previous_compose, &previous_yaml).await {
+            error!("Failed to roll back the upgrade: {rollback_err:?}");
+        }
+        return Err(err);
+    }
+    state.set_upgraded_compose(
+        AppComposeWrapper {
+            app_compose: new,
+            raw: compose_file.to_string(),
+        },
+        compose_hash.clone(),
+    );
+    Ok(compose_hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use dstack_types::MrBindingPolicy;
+
+    use super::*;
+
+    fn compose() -> AppCompose {
+        serde_json::from_str(
+            r#"{
+                "manifest_version": 2,
+                "name": "app",
+                "runner": "docker-compose",
+                "docker_compose_file": "services: {}",
+                "kms_enabled": true
+            }"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_ensure_upgradable() {
+        let current = compose();
+        let renamed = AppCompose {
+            name: "renamed".into(),
+            docker_compose_file: Some("services: {web: {image: nginx}}".into()),
+            ..compose()
+        };
+        assert!(ensure_upgradable(&current, &renamed).is_ok());
+
+        let envs = AppCompose {
+            allowed_envs: vec!["A".into()],
+            ..compose()
+        };
+        let err = ensure_upgradable(&current, &envs).unwrap_err().to_string();
+        assert!(err.contains("allowed_envs"), "{err}");
+        let exposed = AppCompose {
+            gateway_enabled: true,
+            public_logs: true,
+            ..compose()
+        };
+        assert_eq!(
+            boot_fields_changed(&current, &exposed),
+            ["gateway_enabled", "public_logs"]
+        );
+        let bash = AppCompose {
+            runner: "bash".into(),
+            ..compose()
+        };
+        assert!(ensure_upgradable(&current, &bash).is_err());
+
+        let local = AppCompose {
+            kms_enabled: false,
+            local_key_provider_enabled: true,
+            ..compose()
+        };
+        assert!(ensure_upgradable(&local, &local).is_err());
+
+        let bound = AppCompose {
+            mr_binding: Some(MrBindingPolicy {
+                mrtd: true,
+                compose_hash: true,
+                ..Default::default()
+            }),
+            ..compose()
+        };
+        assert!(ensure_upgradable(&bound, &bound).is_err());
+    }
+}
@@ -4,6 +4,7 @@ use dstack_types::AppCompose;
 use figment::Figment;
 use fs_err as fs;
 use load_config::load_config;
+use ra_tls::simulator::SimProfile;
 use serde::{de::Error, Deserialize};
 
 pub const DEFAULT_CONFIG: &str = include_str!("../dstack.toml");
@@ -42,6 +43,10 @@ pub struct Config {
     pub simulator: Simulator,
     // List of disks to be shown in the dashboard
     pub data_disks: HashSet<PathBuf>,
+    /// Directory the managed certificates are written to, to be mounted into containers
+    pub managed_certs_dir: PathBuf,
+    /// Working directory of docker compose, holding docker-compose.yaml
+    pub compose_workdir: PathBuf,
 }
 
 fn deserialize_app_compose<'de, D>(deserializer: D) -> Result<AppComposeWrapper, D::Error>
@@ -69,4 +74,8 @@ pub struct Simulator {
     pub enabled: bool,
     pub quote_file: String,
     pub event_log_file: String,
+    /// Synthesize quotes and the event log from this measurement profile instead of replaying
+    /// `quote_file` and `event_log_file`
+    #[serde(default)]
+    pub profile: Option<SimProfile>,
 }
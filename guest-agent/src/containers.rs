@@ -0,0 +1,335 @@
+//! Lifecycle management of the compose services of the app.
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use bollard::container::{
+    InspectContainerOptions, ListContainersOptions, LogOutput, LogsOptions,
+    RestartContainerOptions, StopContainerOptions,
+};
+use bollard::models::ContainerSummary;
+use bollard::Docker;
+use chrono::DateTime;
+use guest_api::{ContainerHealth, LogLine, ServiceLogsRequest, ServiceLogsResponse};
+use rocket::futures::{stream, Stream, StreamExt};
+
+pub(crate) const SERVICE_LABEL: &str = "com.docker.compose.service";
+const DEFAULT_STOP_TIMEOUT: u32 = 10;
+/// Once a waiting log read got a line, stop waiting after this much silence.
+const LOG_BATCH_GAP: Duration = Duration::from_millis(200);
+/// Upper bound of `wait_secs`, to keep requests through the VMM proxy short.
+const MAX_LOG_WAIT_SECS: u32 = 60;
+
+fn connect() -> Result<Docker> {
+    Docker::connect_with_defaults().context("Failed to connect to Docker")
+}
+
+/// The containers of a compose service, or of all services if `service` is empty.
+pub async fn service_containers(docker: &Docker, service: &str) -> Result<Vec<ContainerSummary>> {
+    let label = if service.is_empty() {
+        SERVICE_LABEL.to_string()
+    } else {
+        format!("{SERVICE_LABEL}={service}")
+    };
+    let filters = [("label".to_string(), vec![label])];
+    docker
+        .list_containers(Some(ListContainersOptions {
+            all: true,
+            filters: filters.into_iter().collect(),
+            ..Default::default()
+        }))
+        .await
+        .context("Failed to list containers")
+}
+
+async fn service_container_ids(docker: &Docker, service: &str) -> Result<Vec<String>> {
+    if service.is_empty() {
+        bail!("Service name is required");
+    }
+    let ids: Vec<String> = service_containers(docker, service)
+        .await?
+        .into_iter()
+        .filter_map(|c| c.id)
+        .collect();
+    if ids.is_empty() {
+        bail!("No containers found for service {service}");
+    }
+    Ok(ids)
+}
+
+fn stop_timeout(timeout_secs: u32) -> u32 {
+    match timeout_secs {
+        0 => DEFAULT_STOP_TIMEOUT,
+        t => t,
+    }
+}
+
+pub async fn restart_service(service: &str, timeout_secs: u32) -> Result<()> {
+    let docker = connect()?;
+    let t = stop_timeout(timeout_secs) as isize;
+    for id in service_container_ids(&docker, service).await? {
+        docker
+            .restart_container(&id, Some(RestartContainerOptions { t }))
+            .await
+            .with_context(|| format!("Failed to restart container {id}"))?;
+    }
+    Ok(())
+}
+
+pub async fn stop_service(service: &str, timeout_secs: u32) -> Result<()> {
+    let docker = connect()?;
+    let t = stop_timeout(timeout_secs) as i64;
+    for id in service_container_ids(&docker, service).await? {
+        docker
+            .stop_container(&id, Some(StopContainerOptions { t }))
+            .await
+            .with_context(|| format!("Failed to stop container {id}"))?;
+    }
+    Ok(())
+}
+
+/// Send a signal to the containers of a compose service.
+pub async fn signal_service(service: &str, signal: &str) -> Result<()> {
+    let docker = connect()?;
+    for id in service_container_ids(&docker, service).await? {
+        docker
+            .kill_container(
+                &id,
+                Some(bollard::container::KillContainerOptions { signal }),
+            )
+            .await
+            .with_context(|| format!("Failed to signal container {id}"))?;
+    }
+    Ok(())
+}
+
+pub async fn service_health(service: &str) -> Result<Vec<ContainerHealth>> {
+    let docker = connect()?;
+    let mut containers = vec![];
+    for summary in service_containers(&docker, service).await? {
+        let Some(id) = summary.id else {
+            continue;
+        };
+        let info = docker
+            .inspect_container(&id, None::<InspectContainerOptions>)
+            .await
+            .with_context(|| format!("Failed to inspect container {id}"))?;
+        let state = info.state.unwrap_or_default();
+        let health = state.health.unwrap_or_default();
+        containers.push(ContainerHealth {
+            name: info
+                .name
+                .unwrap_or_default()
+                .trim_start_matches('/')
+                .to_string(),
+            service: summary
+                .labels
+                .and_then(|labels| labels.get(SERVICE_LABEL).cloned())
+                .unwrap_or_default(),
+            state: state.status.map(|s| s.to_string()).unwrap_or_default(),
+            health: health
+                .status
+                .map(|s| s.to_string())
+                .unwrap_or_else(|| "none".into()),
+            failing_streak: health.failing_streak.unwrap_or_default() as u32,
+            restart_count: info.restart_count.unwrap_or_default() as u32,
+            id,
+        });
+    }
+    containers.sort_by(|a, b| (&a.service, &a.name).cmp(&(&b.service, &b.name)));
+    Ok(containers)
+}
+
+/// Split a log line read with timestamps into its time in nanoseconds and the message.
+fn split_timestamp(line: &str) -> Option<(u64, &str)> {
+    let (timestamp, message) = line.split_once(' ')?;
+    let time = DateTime::parse_from_rfc3339(timestamp).ok()?;
+    let nanos = time.timestamp_nanos_opt()?;
+    Some((u64::try_from(nanos).ok()?, message))
+}
+
+fn channel_of(output: &LogOutput) -> &'static str {
+    match output {
+        LogOutput::StdErr { .. } => "stderr",
+        LogOutput::StdOut { .. } => "stdout",
+        LogOutput::StdIn { .. } => "stdin",
+        LogOutput::Console { .. } => "console",
+    }
+}
+
+fn to_log_line(container: &str, channel: &str, raw: &[u8]) -> Option<LogLine> {
+    let text = String::from_utf8_lossy(raw);
+    let (timestamp_ns, message) = split_timestamp(&text)?;
+    Some(LogLine {
+        container: container.to_string(),
+        channel: channel.to_string(),
+        timestamp_ns,
+        message: message.to_string(),
+    })
+}
+
+/// The ids and names of the containers of a compose service.
+async fn log_containers(docker: &Docker, service: &str) -> Result<Vec<(String, String)>> {
+    let mut containers = vec![];
+    for summary in service_containers(docker, service).await? {
+        let Some(id) = summary.id else {
+            continue;
+        };
+        let name = summary
+            .names
+            .and_then(|names| names.into_iter().next())
+            .unwrap_or_else(|| id.clone());
+        containers.push((id, name.trim_start_matches('/').to_string()));
+    }
+    if service.is_empty() || containers.is_empty() {
+        bail!("No containers found for service {service}");
+    }
+    Ok(containers)
+}
+
+/// The log lines of `containers` logged after `since_ns`, merged in the order they are read.
+fn read_logs(
+    docker: Docker,
+    containers: Vec<(String, String)>,
+    since_ns: u64,
+    tail: u32,
+    follow: bool,
+) -> impl Stream<Item = LogLine> + Send + 'static {
+    let streams = containers.into_iter().map(|(id, name)| {
+        let options = LogsOptions {
+            stdout: true,
+            stderr: true,
+            follow,
+            timestamps: true,
+            // Docker only accepts whole seconds, the cursor is applied to the lines below
+            since: (since_ns / 1_000_000_000) as i64,
+            tail: match tail {
+                0 => "all".to_string(),
+                n => n.to_string(),
+            },
+            ..Default::default()
+        };
+        docker.logs(&id, Some(options)).filter_map(move |output| {
+            let line = output
+                .ok()
+                .and_then(|output| to_log_line(&name, channel_of(&output), output.as_ref()));
+            async move { line.filter(|line| line.timestamp_ns > since_ns) }
+        })
+    });
+    stream::select_all(streams.map(Box::pin))
+}
+
+/// Read the logs of a compose service after the cursor in the request.
+///
+/// With `wait_secs`, the call returns as soon as new lines arrive, which lets clients follow the
+/// logs with repeated calls. This is long polling, kept for clients that can not read streams:
+/// each call is a separate request, and lines logged within the same nanosecond as the cursor
+/// are skipped. See [`follow_service_logs`] for a stream.
+pub async fn service_logs(request: &ServiceLogsRequest) -> Result<ServiceLogsResponse> {
+    let docker = connect()?;
+    let containers = log_containers(&docker, &request.service).await?;
+    let since_ns = request.since_ns;
+    let wait_secs = request.wait_secs.min(MAX_LOG_WAIT_SECS);
+    let read = |follow: bool| {
+        read_logs(
+            docker.clone(),
+            containers.clone(),
+            since_ns,
+            request.tail,
+            follow,
+        )
+    };
+
+    let mut lines: Vec<LogLine> = read(false).collect().await;
+    if lines.is_empty() && wait_secs > 0 {
+        let mut follow = read(true);
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(wait_secs as u64);
+        loop {
+            let timeout = if lines.is_empty() {
+                deadline.saturating_duration_since(tokio::time::Instant::now())
+            } else {
+                LOG_BATCH_GAP
+            };
+            match tokio::time::timeout(timeout, follow.next()).await {
+                Ok(Some(line)) => lines.push(line),
+                Ok(None) | Err(_) => break,
+            }
+        }
+    }
+    lines.sort_by_key(|line| line.timestamp_ns);
+    let next_since_ns = lines.last().map_or(since_ns, |line| line.timestamp_ns);
+    Ok(ServiceLogsResponse {
+        lines,
+        next_since_ns,
+    })
+}
+
+/// Stream the logs of a compose service after the cursor in the request, then follow them until
+/// the containers stop or the client goes away. `wait_secs` is ignored.
+pub async fn follow_service_logs(
+    request: &ServiceLogsRequest,
+) -> Result<impl Stream<Item = Result<LogLine>> + Send + 'static> {
+    let docker = connect()?;
+    let containers = log_containers(&docker, &request.service).await?;
+    Ok(read_logs(docker, containers, request.since_ns, request.tail, true).map(Ok))
+}
+
+/// The compose file with defaults resolved, without interpolating variables.
+pub async fn compose_config(workdir: &Path) -> Result<String> {
+    let output = tokio::process::Command::new("docker")
+        .args(["compose", "config", "--no-interpolate"])
+        .current_dir(workdir)
+        .output()
+        .await
+        .context("Failed to run docker compose")?;
+    if !output.status.success() {
+        bail!(
+            "docker compose config failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    String::from_utf8(output.stdout).context("Invalid compose config")
+}
+
+/// Bring the compose services up to date with docker-compose.yaml in `workdir`.
+pub async fn compose_up(workdir: &Path, envs: &BTreeMap<String, String>) -> Result<()> {
+    let output = tokio::process::Command::new("docker")
+        .args(["compose", "up", "--remove-orphans", "-d", "--build"])
+        .current_dir(workdir)
+        .envs(envs)
+        .output()
+        .await
+        .context("Failed to run docker compose")?;
+    if !output.status.success() {
+        bail!(
+            "docker compose up failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_timestamp() {
+        let (ns, message) = split_timestamp("2024-05-01T10:00:00.000000123Z hello world").unwrap();
+        assert_eq!(ns, 1_714_557_600_000_000_123);
+        assert_eq!(message, "hello world");
+        assert!(split_timestamp("no-timestamp here").is_none());
+    }
+
+    #[test]
+    fn test_to_log_line() {
+        let line = to_log_line("app-web-1", "stderr", b"2024-05-01T10:00:01Z failed\n").unwrap();
+        assert_eq!(line.channel, "stderr");
+        assert_eq!(line.container, "app-web-1");
+        assert_eq!(line.timestamp_ns, 1_714_557_601_000_000_000);
+        assert_eq!(line.message, "failed\n");
+        assert_eq!(stop_timeout(0), DEFAULT_STOP_TIMEOUT);
+        assert_eq!(stop_timeout(3), 3);
+    }
+}
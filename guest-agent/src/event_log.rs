@@ -0,0 +1,180 @@
+//! Filtering and replay of the event log for `GetEventLog`.
+use anyhow::{Context, Result};
+use dstack_guest_agent_rpc::{EventLogEntry, EventLogQuery};
+use ra_tls::attestation::{replay_event_logs, Attestation};
+use sha2::{Digest, Sha384};
+use tdx_attest::eventlog::TdxEventLog;
+
+/// Events selected by a query.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct EventFilter {
+    pub imr: Option<u32>,
+    pub event: Option<String>,
+    /// First index to include
+    pub start: u32,
+    /// Index to stop at, exclusive
+    pub end: Option<u32>,
+}
+
+impl From<&EventLogQuery> for EventFilter {
+    fn from(query: &EventLogQuery) -> Self {
+        Self {
+            imr: query.imr,
+            event: query.event.clone(),
+            start: query.start.unwrap_or_default(),
+            end: query.end,
+        }
+    }
+}
+
+impl EventFilter {
+    fn matches(&self, index: u32, event: &TdxEventLog) -> bool {
+        if index < self.start || self.end.is_some_and(|end| index >= end) {
+            return false;
+        }
+        if self.imr.is_some_and(|imr| event.imr != imr) {
+            return false;
+        }
+        match &self.event {
+            Some(name) => &event.event == name,
+            None => true,
+        }
+    }
+
+    /// The matching events, with their indexes in the full event log and the value of their IMR
+    /// after each of them.
+    pub fn apply(&self, event_log: &[TdxEventLog]) -> Vec<EventLogEntry> {
+        event_log
+            .iter()
+            .zip(running_imrs(event_log))
+            .enumerate()
+            .map(|(index, (event, imr_after))| (index as u32, event, imr_after))
+            .filter(|(index, event, _)| self.matches(*index, event))
+            .map(|(index, event, imr_after)| EventLogEntry {
+                index,
+                imr: event.imr,
+                event_type: event.event_type,
+                digest: event.digest.to_vec(),
+                event: event.event.clone(),
+                event_payload: event.event_payload.clone(),
+                imr_after,
+            })
+            .collect()
+    }
+}
+
+/// The value of the IMR of each event right after the event is extended to it.
+fn running_imrs(event_log: &[TdxEventLog]) -> Vec<Vec<u8>> {
+    let mut imrs = [[0u8; 48]; 4];
+    event_log
+        .iter()
+        .map(|event| {
+            let Some(imr) = imrs.get_mut(event.imr as usize) else {
+                return vec![];
+            };
+            *imr = Sha384::new()
+                .chain_update(*imr)
+                .chain_update(event.digest)
+                .finalize()
+                .into();
+            imr.to_vec()
+        })
+        .collect()
+}
+
+/// Replay the full event log into RTMR0-3.
+pub fn replay(event_log: &[TdxEventLog]) -> Result<Vec<Vec<u8>>> {
+    let rtmrs = replay_event_logs(event_log, None).context("Failed to replay event log")?;
+    Ok(rtmrs.iter().map(|mr| mr.to_vec()).collect())
+}
+
+/// The RTMR0-3 values reported in a quote.
+pub fn quote_rtmrs(quote: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let quote = Attestation::new(quote.to_vec(), vec![])?.decode_quote()?;
+    let report = quote.report.as_td10().context("TDX report not found")?;
+    Ok([report.rt_mr0, report.rt_mr1, report.rt_mr2, report.rt_mr3]
+        .iter()
+        .map(|mr| mr.to_vec())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event_log() -> Vec<TdxEventLog> {
+        vec![
+            TdxEventLog::new(0, 1, "".into(), vec![]),
+            TdxEventLog::new(3, 0x08000001, "app-id".into(), vec![1; 20]),
+            TdxEventLog::new(3, 0x08000001, "compose-hash".into(), vec![2; 32]),
+            TdxEventLog::new(3, 0x08000001, "custom".into(), b"a".to_vec()),
+            TdxEventLog::new(3, 0x08000001, "custom".into(), b"b".to_vec()),
+        ]
+    }
+
+    #[test]
+    fn test_filter() {
+        let log = event_log();
+        let indexes = |filter: EventFilter| -> Vec<u32> {
+            filter.apply(&log).iter().map(|e| e.index).collect()
+        };
+        assert_eq!(indexes(EventFilter::default()), [0, 1, 2, 3, 4]);
+        let by_imr = EventFilter {
+            imr: Some(3),
+            ..Default::default()
+        };
+        assert_eq!(indexes(by_imr), [1, 2, 3, 4]);
+        let by_name = EventFilter {
+            event: Some("custom".into()),
+            ..Default::default()
+        };
+        assert_eq!(indexes(by_name.clone()), [3, 4]);
+        let range = EventFilter {
+            start: 2,
+            end: Some(4),
+            ..by_name
+        };
+        assert_eq!(indexes(range), [3]);
+
+        let entry = &EventFilter::default().apply(&log)[4];
+        assert_eq!(entry.event_payload, b"b");
+        assert_eq!(entry.digest, log[4].digest.to_vec());
+    }
+
+    #[test]
+    fn test_running_imrs() {
+        let log = event_log();
+        let entries = EventFilter::default().apply(&log);
+        let rtmrs = replay(&log).unwrap();
+        // The last event of each IMR leaves it at its final value
+        assert_eq!(entries[0].imr_after, rtmrs[0]);
+        assert_eq!(entries[4].imr_after, rtmrs[3]);
+        for i in 1..log.len() {
+            assert_eq!(entries[i].imr_after, replay(&log[..=i]).unwrap()[3]);
+        }
+        // Each step can be checked from the previous value and the digest
+        let step: [u8; 48] = Sha384::new()
+            .chain_update(&entries[2].imr_after)
+            .chain_update(&entries[3].digest)
+            .finalize()
+            .into();
+        assert_eq!(entries[3].imr_after, step);
+        // Filtering keeps the values computed over the full event log
+        let custom = EventFilter {
+            event: Some("custom".into()),
+            ..Default::default()
+        };
+        assert_eq!(custom.apply(&log)[0].imr_after, entries[3].imr_after);
+    }
+
+    #[test]
+    fn test_replay() {
+        let log = event_log();
+        let rtmrs = replay(&log).unwrap();
+        assert_eq!(rtmrs.len(), 4);
+        assert_eq!(rtmrs[1], vec![0u8; 48]);
+        assert_ne!(rtmrs[3], vec![0u8; 48]);
+        // Each event changes the replayed value
+        assert_ne!(replay(&log[..4]).unwrap()[3], rtmrs[3]);
+    }
+}
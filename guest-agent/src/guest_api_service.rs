@@ -1,6 +1,6 @@
 use std::fmt::Debug;
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use bollard::{container::ListContainersOptions, Docker};
 use cmd_lib::{run_cmd as cmd, run_fun};
 use dstack_guest_agent_rpc::worker_server::WorkerRpc as _;
@@ -9,14 +9,22 @@ use dstack_types::SysConfig;
 use fs_err as fs;
 use guest_api::{
     guest_api_server::{GuestApiRpc, GuestApiServer},
-    Container, DiskInfo, Gateway, GuestInfo, Interface, IpAddress, ListContainersResponse,
-    NetworkInformation, SystemInfo,
+    ComposeConfigResponse, Container, DiskInfo, Gateway, GuestInfo, Interface, IpAddress,
+    ListContainersResponse, NetworkInformation, ServiceHealthRequest, ServiceHealthResponse,
+    ServiceLogsRequest, ServiceLogsResponse, ServiceRequest, SystemInfo, UpgradeComposeRequest,
+    UpgradeComposeResponse,
 };
 use host_api::Notification;
-use ra_rpc::{CallContext, RpcCall};
+use ra_rpc::{
+    streaming::{decode_message, encode_messages, RpcStream},
+    CallContext, RpcCall,
+};
 use tracing::error;
 
-use crate::{rpc_service::ExternalRpcHandler, AppState};
+use crate::{compose_upgrade, containers, rpc_service::ExternalRpcHandler, AppState};
+
+/// Streams the lines of `ServiceLogs` as they are logged, taking a `ServiceLogsRequest`.
+const FOLLOW_SERVICE_LOGS: &str = "GuestApi.FollowServiceLogs";
 
 pub struct GuestApiHandler {
     state: AppState,
@@ -30,6 +38,36 @@ impl RpcCall<AppState> for GuestApiHandler {
             state: context.state.clone(),
         })
     }
+
+    fn streaming_methods() -> &'static [&'static str] {
+        &[FOLLOW_SERVICE_LOGS]
+    }
+
+    async fn call_streaming(
+        self,
+        method: String,
+        payload: Vec<u8>,
+        is_json: bool,
+    ) -> Result<RpcStream> {
+        if method != FOLLOW_SERVICE_LOGS {
+            bail!("method {method} is not a streaming method");
+        }
+        if !self.state.config().app_compose.public_logs {
+            bail!("Logs API is disabled");
+        }
+        let request: ServiceLogsRequest = decode_message(&payload, is_json)?;
+        let lines = containers::follow_service_logs(&request).await?;
+        Ok(encode_messages(lines, is_json))
+    }
+}
+
+impl GuestApiHandler {
+    fn ensure_service_control(&self) -> Result<()> {
+        if !self.state.config().app_compose.public_service_control {
+            bail!("Service control API is disabled");
+        }
+        Ok(())
+    }
 }
 
 impl GuestApiRpc for GuestApiHandler {
@@ -112,6 +150,48 @@ impl GuestApiRpc for GuestApiHandler {
     async fn list_containers(self) -> Result<ListContainersResponse> {
         list_containers().await
     }
+
+    async fn restart_service(self, request: ServiceRequest) -> Result<()> {
+        self.ensure_service_control()?;
+        containers::restart_service(&request.service, request.timeout_secs).await
+    }
+
+    async fn stop_service(self, request: ServiceRequest) -> Result<()> {
+        self.ensure_service_control()?;
+        containers::stop_service(&request.service, request.timeout_secs).await
+    }
+
+    async fn service_logs(self, request: ServiceLogsRequest) -> Result<ServiceLogsResponse> {
+        if !self.state.config().app_compose.public_logs {
+            bail!("Logs API is disabled");
+        }
+        containers::service_logs(&request).await
+    }
+
+    async fn service_health(self, request: ServiceHealthRequest) -> Result<ServiceHealthResponse> {
+        if !self.state.config().app_compose.public_sysinfo {
+            bail!("Sysinfo API is disabled");
+        }
+        Ok(ServiceHealthResponse {
+            containers: containers::service_health(&request.service).await?,
+        })
+    }
+
+    async fn compose_config(self) -> Result<ComposeConfigResponse> {
+        if !self.state.config().app_compose.public_sysinfo {
+            bail!("Sysinfo API is disabled");
+        }
+        let config = containers::compose_config(&self.state.config().compose_workdir).await?;
+        Ok(ComposeConfigResponse { config })
+    }
+
+    async fn upgrade_compose(
+        self,
+        request: UpgradeComposeRequest,
+    ) -> Result<UpgradeComposeResponse> {
+        let compose_hash = compose_upgrade::upgrade(&self.state, &request.compose_file).await?;
+        Ok(UpgradeComposeResponse { compose_hash })
+    }
 }
 
 pub(crate) async fn list_containers() -> Result<ListContainersResponse> {
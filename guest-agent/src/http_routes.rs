@@ -10,6 +10,7 @@ use guest_api::guest_api_server::GuestApiRpc;
 use ra_rpc::{CallContext, RpcCall};
 use rinja::Template;
 use rocket::futures::StreamExt;
+use rocket::http::ContentType;
 use rocket::response::stream::TextStream;
 use rocket::{get, response::content::RawHtml, routes, Route, State};
 
@@ -44,6 +45,7 @@ async fn index(state: &State<AppState>) -> Result<RawHtml<String>, String> {
         tcb_info,
         app_cert: _,
         vm_config: _,
+        managed_certs: _,
     } = handler
         .info()
         .await
@@ -73,9 +75,9 @@ async fn index(state: &State<AppState>) -> Result<RawHtml<String>, String> {
     }
 }
 
-// Returns metrics about the guest in prometheus format if public_sysinfo is enabled
+// Returns metrics about the guest in OpenMetrics format if public_sysinfo is enabled
 #[get("/metrics")]
-async fn metrics(state: &State<AppState>) -> Result<String, String> {
+async fn metrics(state: &State<AppState>) -> Result<(ContentType, String), String> {
     let public_sysinfo = state.config().app_compose.public_sysinfo;
     if !public_sysinfo {
         return Err("Sysinfo API is disabled".to_string());
@@ -85,10 +87,9 @@ async fn metrics(state: &State<AppState>) -> Result<String, String> {
         .map_err(|e| format!("Failed to construct RPC handler: {}", e))?;
 
     let system_info = handler.sys_info().await.unwrap_or_default();
-    let model = crate::models::Metrics { system_info };
-    match model.render() {
-        Ok(body) => Ok(body),
-        Err(err) => Err(format!("Failed to render template: {err}")),
+    match crate::metrics::render(state, &system_info).await {
+        Ok(body) => Ok((crate::metrics::content_type(), body)),
+        Err(err) => Err(format!("Failed to render metrics: {err:?}")),
     }
 }
 
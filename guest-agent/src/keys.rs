@@ -0,0 +1,512 @@
+//! Deterministic derivation of app keys for the supported key algorithms.
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Context, Result};
+use k256::ecdsa::SigningKey;
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use ra_tls::kdf::derive_ecdsa_key;
+use ring::signature::{Ed25519KeyPair, KeyPair as _};
+use sha3::{Digest, Keccak256};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeyAlgorithm {
+    #[default]
+    Secp256k1,
+    Ed25519,
+    Secp256r1,
+    X25519,
+}
+
+impl KeyAlgorithm {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KeyAlgorithm::Secp256k1 => "secp256k1",
+            KeyAlgorithm::Ed25519 => "ed25519",
+            KeyAlgorithm::Secp256r1 => "secp256r1",
+            KeyAlgorithm::X25519 => "x25519",
+        }
+    }
+}
+
+impl fmt::Display for KeyAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for KeyAlgorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "" | "secp256k1" | "k256" => KeyAlgorithm::Secp256k1,
+            "ed25519" => KeyAlgorithm::Ed25519,
+            "secp256r1" | "p256" | "p-256" | "prime256v1" => KeyAlgorithm::Secp256r1,
+            "x25519" => KeyAlgorithm::X25519,
+            _ => bail!("Unsupported key algorithm: {s}"),
+        })
+    }
+}
+
+/// A key derived from the app key.
+pub struct DerivedKey {
+    pub algorithm: KeyAlgorithm,
+    /// The 32 bytes secret: the scalar for the ECDSA curves, the seed for Ed25519 and the
+    /// unclamped secret for X25519.
+    pub private_key: Vec<u8>,
+    /// Compressed SEC1 point for the ECDSA curves, the raw 32 bytes otherwise.
+    pub public_key: Vec<u8>,
+}
+
+/// Derive the key of `algorithm` at `path` from the app key.
+///
+/// secp256k1 keys keep the historical derivation from the path alone, for any path. The other
+/// algorithms derive from a [`kdf_context`] of the algorithm name and the path, so that the same
+/// path yields unrelated keys for different algorithms.
+pub fn derive_key(app_key: &[u8], path: &str, algorithm: KeyAlgorithm) -> Result<DerivedKey> {
+    let tag = algorithm.as_str().as_bytes();
+    match algorithm {
+        KeyAlgorithm::Secp256k1 => derive_with_context(app_key, &[legacy_context(path)], algorithm),
+        _ => {
+            validate_path(path)?;
+            let context = kdf_context(KEY_DOMAIN, &[tag, path.as_bytes()]);
+            derive_with_context(app_key, &[&context], algorithm)
+        }
+    }
+}
+
+/// Derive a key used by the in-enclave signing API.
+///
+/// Signing keys are derived in their own [`kdf_context`] domain, which neither `GetKey` nor the
+/// legacy Tappd `DeriveKey` can produce, so they can never be exported.
+pub fn derive_signing_key(
+    app_key: &[u8],
+    path: &str,
+    algorithm: KeyAlgorithm,
+) -> Result<DerivedKey> {
+    validate_path(path)?;
+    let tag = algorithm.as_str().as_bytes();
+    let context = kdf_context(SIGNING_DOMAIN, &[tag, path.as_bytes()]);
+    derive_with_context(app_key, &[&context], algorithm)
+}
+
+const KEY_DOMAIN: &[u8] = b"key";
+const SIGNING_DOMAIN: &[u8] = b"sign";
+
+fn validate_path(path: &str) -> Result<()> {
+    if path.contains('\0') {
+        bail!("Key path must not contain NUL characters");
+    }
+    Ok(())
+}
+
+/// The derivation context of keys derived from a path alone, which is the path itself.
+pub fn legacy_context(path: &str) -> &[u8] {
+    path.as_bytes()
+}
+
+/// The first byte of every [`kdf_context`]. It never occurs in UTF-8, so no [`legacy_context`]
+/// can spell out a context.
+const CONTEXT_MARKER: u8 = 0xff;
+
+/// The derivation context of a key in `domain`.
+///
+/// Each part is prefixed with its length as a big-endian u32, so different parts never encode to
+/// the same context.
+pub fn kdf_context(domain: &[u8], parts: &[&[u8]]) -> Vec<u8> {
+    let mut context = vec![CONTEXT_MARKER];
+    for part in std::iter::once(&domain).chain(parts) {
+        context.extend_from_slice(&(part.len() as u32).to_be_bytes());
+        context.extend_from_slice(part);
+    }
+    context
+}
+
+fn derive_with_context(
+    app_key: &[u8],
+    context: &[&[u8]],
+    algorithm: KeyAlgorithm,
+) -> Result<DerivedKey> {
+    let private_key =
+        derive_ecdsa_key(app_key, context, 32).map_err(|_| anyhow!("Failed to derive key"))?;
+    let public_key = match algorithm {
+        KeyAlgorithm::Secp256k1 => SigningKey::from_slice(&private_key)
+            .context("Failed to parse k256 key")?
+            .verifying_key()
+            .to_sec1_bytes()
+            .to_vec(),
+        KeyAlgorithm::Ed25519 => Ed25519KeyPair::from_seed_unchecked(&private_key)
+            .map_err(|_| anyhow!("Failed to parse ed25519 key"))?
+            .public_key()
+            .as_ref()
+            .to_vec(),
+        KeyAlgorithm::Secp256r1 => p256::SecretKey::from_slice(&private_key)
+            .context("Failed to parse p256 key")?
+            .public_key()
+            .to_encoded_point(true)
+            .as_bytes()
+            .to_vec(),
+        KeyAlgorithm::X25519 => {
+            let secret: [u8; 32] = private_key[..].try_into().context("Invalid key length")?;
+            let secret = x25519_dalek::StaticSecret::from(secret);
+            x25519_dalek::PublicKey::from(&secret).as_bytes().to_vec()
+        }
+    };
+    Ok(DerivedKey {
+        algorithm,
+        private_key,
+        public_key,
+    })
+}
+
+/// Whether `path` matches one of the patterns. A pattern is either an exact path or a prefix
+/// followed by `*`.
+pub fn path_allowed(patterns: &[String], path: &str) -> bool {
+    patterns
+        .iter()
+        .any(|pattern| match pattern.strip_suffix('*') {
+            Some(prefix) => path.starts_with(prefix),
+            None => pattern == path,
+        })
+}
+
+/// How the data passed to `Sign` and `Verify` is turned into the signed message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SignMode {
+    /// The data is the message. It is hashed with keccak256 for secp256k1 and sha256 for
+    /// secp256r1, and signed as is for ed25519.
+    #[default]
+    Raw,
+    /// The data is a 32 bytes digest. Not supported by ed25519.
+    Prehashed,
+    /// Ethereum `personal_sign`: keccak256 of `"\x19Ethereum Signed Message:\n" || len || data`.
+    /// secp256k1 only. The recovery id is encoded as 27 or 28.
+    EthPersonal,
+}
+
+impl FromStr for SignMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "" | "raw" => SignMode::Raw,
+            "prehashed" => SignMode::Prehashed,
+            "eth_personal" => SignMode::EthPersonal,
+            _ => bail!("Unsupported sign mode: {s}"),
+        })
+    }
+}
+
+fn eth_personal_hash(data: &[u8]) -> [u8; 32] {
+    let prefix = format!("\x19Ethereum Signed Message:\n{}", data.len());
+    Keccak256::new_with_prefix(prefix)
+        .chain_update(data)
+        .finalize()
+        .into()
+}
+
+/// The 32 bytes digest signed by the ECDSA curves.
+fn ecdsa_prehash(algorithm: KeyAlgorithm, data: &[u8], mode: SignMode) -> Result<[u8; 32]> {
+    Ok(match (algorithm, mode) {
+        (_, SignMode::Prehashed) => data.try_into().context("Prehashed data must be 32 bytes")?,
+        (KeyAlgorithm::Secp256k1, SignMode::Raw) => Keccak256::digest(data).into(),
+        (KeyAlgorithm::Secp256k1, SignMode::EthPersonal) => eth_personal_hash(data),
+        (KeyAlgorithm::Secp256r1, SignMode::Raw) => sha2::Sha256::digest(data).into(),
+        (algorithm, mode) => bail!("Sign mode {mode:?} is not supported by {algorithm}"),
+    })
+}
+
+/// Verify a signature made by [`DerivedKey::sign`].
+pub fn verify(
+    algorithm: KeyAlgorithm,
+    public_key: &[u8],
+    data: &[u8],
+    signature: &[u8],
+    mode: SignMode,
+) -> Result<bool> {
+    use k256::ecdsa::signature::hazmat::PrehashVerifier;
+
+    match algorithm {
+        KeyAlgorithm::Secp256k1 => {
+            let hash = ecdsa_prehash(algorithm, data, mode)?;
+            let key = k256::ecdsa::VerifyingKey::from_sec1_bytes(public_key)
+                .context("Invalid secp256k1 public key")?;
+            let Some(signature) = signature.get(..64) else {
+                return Ok(false);
+            };
+            let signature =
+                k256::ecdsa::Signature::from_slice(signature).context("Invalid signature")?;
+            Ok(key.verify_prehash(&hash, &signature).is_ok())
+        }
+        KeyAlgorithm::Secp256r1 => {
+            let hash = ecdsa_prehash(algorithm, data, mode)?;
+            let key = p256::ecdsa::VerifyingKey::from_sec1_bytes(public_key)
+                .context("Invalid secp256r1 public key")?;
+            let signature =
+                p256::ecdsa::Signature::from_slice(signature).context("Invalid signature")?;
+            Ok(key.verify_prehash(&hash, &signature).is_ok())
+        }
+        KeyAlgorithm::Ed25519 => {
+            if mode != SignMode::Raw {
+                bail!("Sign mode {mode:?} is not supported by {algorithm}");
+            }
+            let key =
+                ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, public_key);
+            Ok(key.verify(data, signature).is_ok())
+        }
+        KeyAlgorithm::X25519 => bail!("x25519 keys can not sign"),
+    }
+}
+
+impl DerivedKey {
+    /// The message signed by the app key to prove that the key belongs to the app.
+    ///
+    /// It is `<purpose>:<hex public key>` for secp256k1, as it always was, and
+    /// `<algorithm>:<purpose>:<hex public key>` for the other algorithms.
+    pub fn provenance_message(&self, purpose: &str) -> String {
+        let public_key = hex::encode(&self.public_key);
+        match self.algorithm {
+            KeyAlgorithm::Secp256k1 => format!("{purpose}:{public_key}"),
+            _ => format!("{}:{purpose}:{public_key}", self.algorithm),
+        }
+    }
+
+    /// Sign `data` with the key. secp256k1 signatures are 65 bytes `r || s || v`, secp256r1
+    /// signatures are 64 bytes `r || s` and ed25519 signatures are 64 bytes.
+    pub fn sign(&self, data: &[u8], mode: SignMode) -> Result<Vec<u8>> {
+        use k256::ecdsa::signature::hazmat::PrehashSigner;
+
+        match self.algorithm {
+            KeyAlgorithm::Secp256k1 => {
+                let hash = ecdsa_prehash(self.algorithm, data, mode)?;
+                let key = SigningKey::from_slice(&self.private_key).context("Invalid k256 key")?;
+                let (signature, recid) = key.sign_prehash_recoverable(&hash)?;
+                let mut signature = signature.to_vec();
+                let v = recid.to_byte();
+                signature.push(if mode == SignMode::EthPersonal {
+                    v + 27
+                } else {
+                    v
+                });
+                Ok(signature)
+            }
+            KeyAlgorithm::Secp256r1 => {
+                let hash = ecdsa_prehash(self.algorithm, data, mode)?;
+                let key = p256::ecdsa::SigningKey::from_slice(&self.private_key)
+                    .context("Invalid p256 key")?;
+                let signature: p256::ecdsa::Signature = key.sign_prehash(&hash)?;
+                Ok(signature.to_vec())
+            }
+            KeyAlgorithm::Ed25519 => {
+                if mode != SignMode::Raw {
+                    bail!("Sign mode {mode:?} is not supported by {}", self.algorithm);
+                }
+                let key = Ed25519KeyPair::from_seed_unchecked(&self.private_key)
+                    .map_err(|_| anyhow!("Invalid ed25519 key"))?;
+                Ok(key.sign(data).as_ref().to_vec())
+            }
+            KeyAlgorithm::X25519 => bail!("x25519 keys can not sign"),
+        }
+    }
+
+    /// Sign the provenance message with the app key. Returns a 65 bytes recoverable signature
+    /// over the keccak256 of the message.
+    pub fn sign_provenance(&self, app_key: &[u8], purpose: &str) -> Result<Vec<u8>> {
+        let app_signing_key =
+            SigningKey::from_slice(app_key).context("Failed to parse app k256 key")?;
+        let digest = Keccak256::new_with_prefix(self.provenance_message(purpose));
+        let (signature, recid) = app_signing_key.sign_digest_recoverable(digest)?;
+        let mut signature = signature.to_vec();
+        signature.push(recid.to_byte());
+        Ok(signature)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k256::ecdsa::{RecoveryId, Signature, VerifyingKey};
+
+    const APP_KEY: [u8; 32] = [0x11; 32];
+    const ALGORITHMS: [KeyAlgorithm; 4] = [
+        KeyAlgorithm::Secp256k1,
+        KeyAlgorithm::Ed25519,
+        KeyAlgorithm::Secp256r1,
+        KeyAlgorithm::X25519,
+    ];
+
+    #[test]
+    fn test_parse_algorithm() {
+        assert_eq!("".parse::<KeyAlgorithm>().unwrap(), KeyAlgorithm::Secp256k1);
+        assert_eq!(
+            "P256".parse::<KeyAlgorithm>().unwrap(),
+            KeyAlgorithm::Secp256r1
+        );
+        for algo in ALGORITHMS {
+            assert_eq!(algo.as_str().parse::<KeyAlgorithm>().unwrap(), algo);
+        }
+        assert!("rsa".parse::<KeyAlgorithm>().is_err());
+    }
+
+    #[test]
+    fn test_derive_key() {
+        // secp256k1 keys must stay compatible with the previous derivation
+        let k256 = derive_key(&APP_KEY, "wallet", KeyAlgorithm::Secp256k1).unwrap();
+        assert_eq!(
+            k256.private_key,
+            derive_ecdsa_key(&APP_KEY, &[b"wallet"], 32).unwrap()
+        );
+        assert_eq!(k256.public_key.len(), 33);
+
+        let mut private_keys = std::collections::HashSet::new();
+        for algo in ALGORITHMS {
+            let key = derive_key(&APP_KEY, "wallet", algo).unwrap();
+            let again = derive_key(&APP_KEY, "wallet", algo).unwrap();
+            assert_eq!(key.private_key, again.private_key);
+            assert_eq!(key.public_key, again.public_key);
+            assert_ne!(
+                key.private_key,
+                derive_key(&APP_KEY, "other", algo).unwrap().private_key
+            );
+            private_keys.insert(key.private_key);
+        }
+        assert_eq!(private_keys.len(), ALGORITHMS.len());
+        assert!(derive_key(&APP_KEY, "a\0b", KeyAlgorithm::Ed25519).is_err());
+        // Legacy paths are taken as they are
+        let nul = derive_key(&APP_KEY, "a\0b", KeyAlgorithm::Secp256k1).unwrap();
+        assert_eq!(
+            nul.private_key,
+            derive_ecdsa_key(&APP_KEY, &[b"a\0b"], 32).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_kdf_context() {
+        assert_ne!(
+            kdf_context(b"key", &[b"ed25519", b"path"]),
+            kdf_context(b"key", &[b"ed25519path"])
+        );
+        assert_ne!(
+            kdf_context(b"key", &[b"a", b"bc"]),
+            kdf_context(b"key", &[b"ab", b"c"])
+        );
+    }
+
+    #[test]
+    fn test_domain_separation() {
+        // A context of each domain, with the legacy paths that come closest to it. The NUL
+        // separated ones are the contexts signing and sealing keys used to have.
+        let cases: [(Vec<u8>, &[&str]); 3] = [
+            (
+                kdf_context(KEY_DOMAIN, &[b"ed25519", b"wallet"]),
+                &["ed25519\0wallet", "keyed25519wallet", "wallet"],
+            ),
+            (
+                kdf_context(SIGNING_DOMAIN, &[b"secp256k1", b"signer"]),
+                &["sign\0secp256k1\0signer", "signsecp256k1signer", "signer"],
+            ),
+            (
+                kdf_context(crate::seal::SEAL_DOMAIN, &[&[1, 0], b"db"]),
+                &["seal\0\x01\x00db\0", "seal\x01\x00db", "db"],
+            ),
+        ];
+        for (context, paths) in &cases {
+            assert!(std::str::from_utf8(context).is_err());
+            let key = derive_ecdsa_key(&APP_KEY, &[context], 32).unwrap();
+            // The path of GetKey and Tappd DeriveKey is the derivation context as is
+            let crafted = String::from_utf8_lossy(context).into_owned();
+            for path in paths.iter().copied().chain([crafted.as_str()]) {
+                let exported = derive_key(&APP_KEY, path, KeyAlgorithm::Secp256k1).unwrap();
+                assert_ne!(exported.private_key, key, "{path:?}");
+            }
+        }
+        let signing_key = derive_signing_key(&APP_KEY, "signer", KeyAlgorithm::Secp256k1).unwrap();
+        assert_eq!(
+            signing_key.private_key,
+            derive_ecdsa_key(&APP_KEY, &[&cases[1].0], 32).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_provenance_signature() {
+        let app_pubkey = SigningKey::from_slice(&APP_KEY)
+            .unwrap()
+            .verifying_key()
+            .to_sec1_bytes();
+        for algo in ALGORITHMS {
+            let key = derive_key(&APP_KEY, "path", algo).unwrap();
+            let signature = key.sign_provenance(&APP_KEY, "purpose").unwrap();
+            let digest = Keccak256::new_with_prefix(key.provenance_message("purpose"));
+            let recovered = VerifyingKey::recover_from_digest(
+                digest,
+                &Signature::from_slice(&signature[..64]).unwrap(),
+                RecoveryId::from_byte(signature[64]).unwrap(),
+            )
+            .unwrap();
+            assert_eq!(recovered.to_sec1_bytes(), app_pubkey);
+        }
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let modes = [
+            (KeyAlgorithm::Secp256k1, SignMode::Raw, 65),
+            (KeyAlgorithm::Secp256k1, SignMode::EthPersonal, 65),
+            (KeyAlgorithm::Ed25519, SignMode::Raw, 64),
+            (KeyAlgorithm::Secp256r1, SignMode::Raw, 64),
+        ];
+        for (algo, mode, len) in modes {
+            let key = derive_signing_key(&APP_KEY, "signer", algo).unwrap();
+            assert_ne!(
+                key.private_key,
+                derive_key(&APP_KEY, "signer", algo).unwrap().private_key
+            );
+            let signature = key.sign(b"hello", mode).unwrap();
+            assert_eq!(signature.len(), len, "{algo} {mode:?}");
+            assert!(verify(algo, &key.public_key, b"hello", &signature, mode).unwrap());
+            assert!(!verify(algo, &key.public_key, b"world", &signature, mode).unwrap());
+        }
+
+        let key = derive_signing_key(&APP_KEY, "signer", KeyAlgorithm::Secp256r1).unwrap();
+        let digest = [7u8; 32];
+        let signature = key.sign(&digest, SignMode::Prehashed).unwrap();
+        assert!(verify(
+            KeyAlgorithm::Secp256r1,
+            &key.public_key,
+            &digest,
+            &signature,
+            SignMode::Prehashed
+        )
+        .unwrap());
+        assert!(key.sign(b"short", SignMode::Prehashed).is_err());
+        assert!(key.sign(b"hello", SignMode::EthPersonal).is_err());
+        let x25519 = derive_signing_key(&APP_KEY, "signer", KeyAlgorithm::X25519).unwrap();
+        assert!(x25519.sign(b"hello", SignMode::Raw).is_err());
+    }
+
+    #[test]
+    fn test_eth_personal_sign() {
+        let key = derive_signing_key(&APP_KEY, "eth", KeyAlgorithm::Secp256k1).unwrap();
+        let signature = key.sign(b"hello", SignMode::EthPersonal).unwrap();
+        assert!(matches!(signature[64], 27 | 28));
+        let recovered = VerifyingKey::recover_from_prehash(
+            &eth_personal_hash(b"hello"),
+            &Signature::from_slice(&signature[..64]).unwrap(),
+            RecoveryId::from_byte(signature[64] - 27).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(recovered.to_sec1_bytes().to_vec(), key.public_key);
+    }
+
+    #[test]
+    fn test_path_allowed() {
+        let patterns = vec!["wallet/*".to_string(), "signer".to_string()];
+        assert!(path_allowed(&patterns, "wallet/0"));
+        assert!(path_allowed(&patterns, "signer"));
+        assert!(!path_allowed(&patterns, "signer/1"));
+        assert!(!path_allowed(&patterns, "wallets"));
+        assert!(!path_allowed(&[], "signer"));
+        assert!(path_allowed(&["*".to_string()], "anything"));
+    }
+}
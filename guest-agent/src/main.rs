@@ -3,7 +3,9 @@ use std::{fs::Permissions, future::pending, os::unix::fs::PermissionsExt};
 use anyhow::{anyhow, Context, Result};
 use clap::Parser;
 use config::BindAddr;
+use dstack_guest_agent_rpc::{dstack_guest_server::DstackGuestServer, tappd_server::TappdServer};
 use guest_api_service::GuestApiHandler;
+use metrics::RpcMetricsFairing;
 use rocket::{
     fairing::AdHoc,
     figment::Figment,
@@ -16,11 +18,20 @@ use std::time::Duration;
 use tokio::sync::oneshot;
 use tracing::{error, info};
 
+mod compose_upgrade;
 mod config;
+mod containers;
+mod event_log;
 mod guest_api_service;
 mod http_routes;
+mod keys;
+mod managed_certs;
+mod metrics;
 mod models;
+mod peers;
 mod rpc_service;
+mod seal;
+mod simulator;
 
 const CARGO_PKG_VERSION: &str = env!("CARGO_PKG_VERSION");
 const GIT_REV: &str = git_version::git_version!(
@@ -55,6 +66,10 @@ async fn run_internal_v0(
             "/prpc/",
             ra_rpc::prpc_routes!(AppState, InternalRpcHandlerV0, trim: "Tappd."),
         )
+        .attach(RpcMetricsFairing {
+            service: "Tappd",
+            methods: <TappdServer<InternalRpcHandlerV0>>::supported_methods(),
+        })
         .manage(state);
     let ignite = rocket
         .ignite()
@@ -84,6 +99,10 @@ async fn run_internal(
 ) -> Result<()> {
     let rocket = rocket::custom(figment)
         .mount("/", ra_rpc::prpc_routes!(AppState, InternalRpcHandler))
+        .attach(RpcMetricsFairing {
+            service: "DstackGuest",
+            methods: <DstackGuestServer<InternalRpcHandler>>::supported_methods(),
+        })
         .manage(state);
     let ignite = rocket
         .ignite()
@@ -208,6 +227,7 @@ async fn main() -> Result<()> {
     let state = AppState::new(figment.focus("core").extract()?)
         .await
         .context("Failed to create app state")?;
+    managed_certs::spawn(&state);
     let internal_v0_figment = figment.clone().select("internal-v0");
     let internal_figment = figment.clone().select("internal");
     let external_figment = figment.clone().select("external");
@@ -0,0 +1,318 @@
+//! Long-lived TLS certificates issued and renewed by the agent on behalf of the app.
+//!
+//! Each certificate lives in `<certs dir>/<name>`, a symlink to a versioned directory holding
+//! `key.pem` and `cert.pem`. Renewal writes a new versioned directory and swaps the symlink, so
+//! readers never observe a key that does not match the certificate.
+use std::collections::BTreeMap;
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use dstack_guest_agent_rpc::ManagedCertStatus;
+use dstack_types::ManagedCert;
+use fs_err as fs;
+use ra_tls::cert::CertConfig;
+use rcgen::KeyPair;
+use tracing::{error, info, warn};
+
+use crate::containers;
+use crate::rpc_service::AppState;
+
+const KEY_FILE: &str = "key.pem";
+const CERT_FILE: &str = "cert.pem";
+/// Delay before retrying a failed issuance.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+/// Upper bound of the sleep between renewal checks.
+const MAX_CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// The status of the managed certificates, shown in `Info`.
+#[derive(Clone, Default)]
+pub struct CertStatusMap {
+    inner: Arc<Mutex<BTreeMap<String, ManagedCertStatus>>>,
+}
+
+impl CertStatusMap {
+    pub fn list(&self) -> Vec<ManagedCertStatus> {
+        let map = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        map.values().cloned().collect()
+    }
+
+    fn update(&self, name: &str, f: impl FnOnce(&mut ManagedCertStatus)) {
+        let mut map = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let status = map
+            .entry(name.to_string())
+            .or_insert_with(|| ManagedCertStatus {
+                name: name.to_string(),
+                ..Default::default()
+            });
+        f(status);
+    }
+}
+
+/// Validity period of a certificate in seconds since the epoch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Validity {
+    not_before: u64,
+    not_after: u64,
+}
+
+impl Validity {
+    fn of_pem(pem: &str) -> Result<Self> {
+        let (_, pem) =
+            x509_parser::pem::parse_x509_pem(pem.as_bytes()).context("Invalid certificate PEM")?;
+        let cert = pem.parse_x509().context("Invalid certificate")?;
+        let validity = cert.validity();
+        Ok(Self {
+            not_before: validity.not_before.timestamp().max(0) as u64,
+            not_after: validity.not_after.timestamp().max(0) as u64,
+        })
+    }
+
+    /// When the certificate should be renewed.
+    fn renew_at(&self, renew_before_secs: Option<u64>) -> u64 {
+        let lifetime = self.not_after.saturating_sub(self.not_before);
+        let renew_before = renew_before_secs.unwrap_or(lifetime / 3).min(lifetime);
+        self.not_after - renew_before
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn validate_name(name: &str) -> Result<()> {
+    if name.is_empty()
+        || name.starts_with('.')
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        bail!("Invalid certificate name: {name}");
+    }
+    Ok(())
+}
+
+/// Files of the managed certificates in the certs directory.
+struct CertStore<'a> {
+    dir: &'a Path,
+}
+
+impl CertStore<'_> {
+    fn cert_dir(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    fn load(&self, name: &str) -> Option<Validity> {
+        let pem = fs::read_to_string(self.cert_dir(name).join(CERT_FILE)).ok()?;
+        Validity::of_pem(&pem).ok()
+    }
+
+    /// Write a new version of the certificate and switch the symlink to it.
+    fn store(&self, name: &str, key_pem: &str, chain: &[String], version: u64) -> Result<()> {
+        fs::create_dir_all(self.dir).context("Failed to create certs directory")?;
+        let versioned = format!(".{name}-{version}");
+        let versioned_dir = self.dir.join(&versioned);
+        if versioned_dir.exists() {
+            fs::remove_dir_all(&versioned_dir)?;
+        }
+        fs::create_dir(&versioned_dir)?;
+        fs::set_permissions(&versioned_dir, std::fs::Permissions::from_mode(0o755))?;
+        let key_path = versioned_dir.join(KEY_FILE);
+        fs::write(&key_path, key_pem)?;
+        fs::set_permissions(&key_path, std::fs::Permissions::from_mode(0o600))?;
+        fs::write(versioned_dir.join(CERT_FILE), chain.join("\n"))?;
+
+        let link = self.cert_dir(name);
+        let tmp_link = self.dir.join(format!(".{name}.link"));
+        if tmp_link.symlink_metadata().is_ok() {
+            fs::remove_file(&tmp_link)?;
+        }
+        symlink(&versioned, &tmp_link).context("Failed to create symlink")?;
+        fs::rename(&tmp_link, &link).context("Failed to switch certificate")?;
+        self.remove_old_versions(name, &versioned);
+        Ok(())
+    }
+
+    fn remove_old_versions(&self, name: &str, current: &str) {
+        let prefix = format!(".{name}-");
+        let Ok(entries) = fs::read_dir(self.dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let file_name = entry.file_name();
+            let Some(file_name) = file_name.to_str() else {
+                continue;
+            };
+            let Some(version) = file_name.strip_prefix(&prefix) else {
+                continue;
+            };
+            // Names may share a prefix with other certificates, e.g. `web` and `web-2`
+            if file_name == current || !version.chars().all(|c| c.is_ascii_digit()) {
+                continue;
+            }
+            if let Err(err) = fs::remove_dir_all(entry.path()) {
+                warn!("Failed to remove old certificate {file_name}: {err}");
+            }
+        }
+    }
+}
+
+async fn issue(state: &AppState, cert: &ManagedCert, store: &CertStore<'_>) -> Result<Validity> {
+    let key = KeyPair::generate().context("Failed to generate key")?;
+    let config = CertConfig {
+        org_name: None,
+        subject: cert.subject.clone(),
+        subject_alt_names: cert.alt_names.clone(),
+        usage_server_auth: cert.usage_server_auth,
+        usage_client_auth: cert.usage_client_auth,
+        ext_quote: cert.usage_ra_tls,
+    };
+    let chain = state.request_cert(&key, config).await?;
+    let leaf = chain.first().context("Empty certificate chain")?;
+    let validity = Validity::of_pem(leaf)?;
+    let version = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    store.store(&cert.name, &key.serialize_pem(), &chain, version)?;
+    Ok(validity)
+}
+
+/// Send the reload signal to the containers of the given compose services.
+async fn signal_services(services: &[String], signal: &str) -> Result<()> {
+    for service in services {
+        containers::signal_service(service, signal).await?;
+    }
+    Ok(())
+}
+
+async fn manage(state: AppState, cert: ManagedCert) {
+    let dir = state.config().managed_certs_dir.clone();
+    let store = CertStore { dir: &dir };
+    let statuses = state.cert_status().clone();
+    statuses.update(&cert.name, |status| {
+        status.path = store.cert_dir(&cert.name).display().to_string();
+    });
+    // Reuse the certificate left by a previous run of the agent
+    let mut validity = store.load(&cert.name);
+    if let Some(validity) = validity {
+        statuses.update(&cert.name, |status| {
+            status.not_before = validity.not_before;
+            status.not_after = validity.not_after;
+        });
+    }
+    loop {
+        let now = now_secs();
+        let due = match validity {
+            Some(validity) => validity.renew_at(cert.renew_before_secs),
+            None => now,
+        };
+        if due > now {
+            let wait = Duration::from_secs(due - now).min(MAX_CHECK_INTERVAL);
+            tokio::time::sleep(wait).await;
+            continue;
+        }
+        match issue(&state, &cert, &store).await {
+            Ok(issued) => {
+                info!(
+                    "Issued certificate {}, valid until {}",
+                    cert.name, issued.not_after
+                );
+                let renewed = validity.is_some();
+                validity = Some(issued);
+                statuses.update(&cert.name, |status| {
+                    status.not_before = issued.not_before;
+                    status.not_after = issued.not_after;
+                    status.renewed_at = now_secs();
+                    status.last_error.clear();
+                });
+                if renewed {
+                    if let Err(err) =
+                        signal_services(&cert.reload_services, &cert.reload_signal).await
+                    {
+                        error!("Failed to signal services of {}: {err:?}", cert.name);
+                    }
+                }
+            }
+            Err(err) => {
+                error!("Failed to issue certificate {}: {err:?}", cert.name);
+                statuses.update(&cert.name, |status| {
+                    status.last_error = format!("{err:#}");
+                });
+                tokio::time::sleep(RETRY_INTERVAL).await;
+            }
+        }
+    }
+}
+
+/// Start managing the certificates declared in the app compose.
+pub fn spawn(state: &AppState) {
+    for cert in state.config().app_compose.managed_certs.iter() {
+        if let Err(err) = validate_name(&cert.name) {
+            error!("Skipping managed certificate: {err}");
+            continue;
+        }
+        tokio::spawn(manage(state.clone(), cert.clone()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn self_signed() -> (String, String) {
+        let key = KeyPair::generate().unwrap();
+        let cert = rcgen::CertificateParams::new(vec!["app.local".into()])
+            .unwrap()
+            .self_signed(&key)
+            .unwrap();
+        (key.serialize_pem(), cert.pem())
+    }
+
+    #[test]
+    fn test_renew_at() {
+        let validity = Validity {
+            not_before: 1000,
+            not_after: 4000,
+        };
+        assert_eq!(validity.renew_at(None), 3000);
+        assert_eq!(validity.renew_at(Some(500)), 3500);
+        assert_eq!(validity.renew_at(Some(10_000)), 1000);
+    }
+
+    #[test]
+    fn test_store_and_rotate() {
+        let tmp = tempfile::tempdir().unwrap();
+        let store = CertStore { dir: tmp.path() };
+        assert!(store.load("web").is_none());
+
+        let (key1, cert1) = self_signed();
+        store.store("web", &key1, &[cert1.clone()], 1).unwrap();
+        store.store("web-2", &key1, &[cert1], 1).unwrap();
+        let validity = store.load("web").unwrap();
+        assert!(validity.not_after > validity.not_before);
+
+        let (key2, cert2) = self_signed();
+        store.store("web", &key2, &[cert2.clone()], 2).unwrap();
+        let dir = store.cert_dir("web");
+        assert_eq!(fs::read_to_string(dir.join(KEY_FILE)).unwrap(), key2);
+        assert_eq!(fs::read_to_string(dir.join(CERT_FILE)).unwrap(), cert2);
+        assert!(!tmp.path().join(".web-1").exists());
+        // Versions of other certificates are kept
+        assert!(tmp.path().join(".web-2-1").exists());
+        assert!(store.load("web-2").is_some());
+    }
+
+    #[test]
+    fn test_validate_name() {
+        assert!(validate_name("web.tls_1").is_ok());
+        for name in ["", ".web", "a/b", "a b"] {
+            assert!(validate_name(name).is_err(), "{name}");
+        }
+    }
+}
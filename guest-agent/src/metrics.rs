@@ -0,0 +1,616 @@
+//! OpenMetrics export of the guest system, the app containers and the agent RPCs.
+//!
+//! System and container metrics are collected on each scrape, the container stats concurrently.
+//! RPC latencies are recorded by [`RpcMetricsFairing`] on the RPC servers and live in
+//! [`RpcMetrics`] for the whole run. The series exported before OpenMetrics are still emitted
+//! under their old names.
+use std::sync::atomic::AtomicU64;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use bollard::container::{Stats, StatsOptions};
+use bollard::Docker;
+use guest_api::SystemInfo;
+use prometheus_client::encoding::text::encode;
+use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::metrics::info::Info;
+use prometheus_client::registry::Registry;
+use rocket::fairing::{self, Fairing, Kind};
+use rocket::futures::{stream, StreamExt};
+use rocket::http::ContentType;
+use rocket::{Data, Request, Response};
+use tracing::warn;
+
+use crate::containers;
+use crate::rpc_service::AppState;
+
+type Labels = Vec<(String, String)>;
+type FloatGauge = Gauge<f64, AtomicU64>;
+type FloatCounter = Counter<f64, AtomicU64>;
+
+/// Methods not in the service are counted under this name, to bound the label cardinality.
+const UNKNOWN_METHOD: &str = "unknown";
+
+pub fn content_type() -> ContentType {
+    ContentType::new("application", "openmetrics-text")
+        .with_params([("version", "1.0.0"), ("charset", "utf-8")])
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct RpcLabels {
+    service: String,
+    method: String,
+    status: String,
+}
+
+fn latency_histogram() -> Histogram {
+    // 0.5ms to ~16s
+    Histogram::new(exponential_buckets(0.0005, 2.0, 16))
+}
+
+/// Latencies of the RPCs served by the agent.
+#[derive(Clone)]
+pub struct RpcMetrics {
+    latency: Family<RpcLabels, Histogram, fn() -> Histogram>,
+}
+
+impl Default for RpcMetrics {
+    fn default() -> Self {
+        Self {
+            latency: Family::new_with_constructor(latency_histogram),
+        }
+    }
+}
+
+impl RpcMetrics {
+    fn observe(&self, service: &str, method: &str, ok: bool, elapsed: Duration) {
+        let labels = RpcLabels {
+            service: service.to_string(),
+            method: method.to_string(),
+            status: if ok { "ok" } else { "error" }.to_string(),
+        };
+        self.latency
+            .get_or_create(&labels)
+            .observe(elapsed.as_secs_f64());
+    }
+
+    fn register(&self, registry: &mut Registry) {
+        registry.register(
+            "dstack_rpc_duration_seconds",
+            "Latency of the RPCs served by the guest agent",
+            self.latency.clone(),
+        );
+    }
+}
+
+/// The method called by a prpc request path, if it is one of `methods`.
+fn method_of(path: &str, methods: &[&'static str]) -> &'static str {
+    let called = path.rsplit('/').next().unwrap_or_default();
+    methods
+        .iter()
+        .find_map(|full| {
+            let short = full.split_once('.').map_or(*full, |(_, method)| method);
+            (called == *full || called == short).then_some(short)
+        })
+        .unwrap_or(UNKNOWN_METHOD)
+}
+
+struct RequestStart(Instant);
+
+/// Records the latency of each prpc request into [`RpcMetrics`].
+pub struct RpcMetricsFairing {
+    pub service: &'static str,
+    pub methods: &'static [&'static str],
+}
+
+#[rocket::async_trait]
+impl Fairing for RpcMetricsFairing {
+    fn info(&self) -> fairing::Info {
+        fairing::Info {
+            name: "RPC metrics",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _data: &mut Data<'_>) {
+        req.local_cache(|| RequestStart(Instant::now()));
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let Some(state) = req.rocket().state::<AppState>() else {
+            return;
+        };
+        let start = req.local_cache(|| RequestStart(Instant::now()));
+        let method = method_of(req.uri().path().as_str(), self.methods);
+        let ok = res.status().code < 400;
+        state
+            .rpc_metrics()
+            .observe(self.service, method, ok, start.0.elapsed());
+    }
+}
+
+fn labels<const N: usize>(pairs: [(&str, &str); N]) -> Labels {
+    pairs
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+fn register_gauge(registry: &mut Registry, name: &str, help: &str, value: f64) {
+    let gauge = FloatGauge::default();
+    gauge.set(value);
+    registry.register(name, help, gauge);
+}
+
+fn register_system(registry: &mut Registry, info: &SystemInfo) {
+    registry.register(
+        "system",
+        "Operating system and hardware of the guest",
+        Info::new(labels([
+            ("os_name", info.os_name.as_str()),
+            ("os_version", info.os_version.as_str()),
+            ("kernel_version", info.kernel_version.as_str()),
+            ("cpu_model", info.cpu_model.as_str()),
+        ])),
+    );
+    let gauges = [
+        (
+            "system_num_cpus",
+            "Number of logical CPUs",
+            info.num_cpus as f64,
+        ),
+        (
+            "system_memory_total_bytes",
+            "Total memory",
+            info.total_memory as f64,
+        ),
+        (
+            "system_memory_available_bytes",
+            "Available memory",
+            info.available_memory as f64,
+        ),
+        (
+            "system_memory_used_bytes",
+            "Used memory",
+            info.used_memory as f64,
+        ),
+        (
+            "system_memory_free_bytes",
+            "Free memory",
+            info.free_memory as f64,
+        ),
+        (
+            "system_swap_total_bytes",
+            "Total swap",
+            info.total_swap as f64,
+        ),
+        ("system_swap_used_bytes", "Used swap", info.used_swap as f64),
+        ("system_swap_free_bytes", "Free swap", info.free_swap as f64),
+        ("system_uptime_seconds", "System uptime", info.uptime as f64),
+        (
+            "system_load_1m",
+            "System load average over 1 minute",
+            info.loadavg_one as f64 / 100.0,
+        ),
+        (
+            "system_load_5m",
+            "System load average over 5 minutes",
+            info.loadavg_five as f64 / 100.0,
+        ),
+        (
+            "system_load_15m",
+            "System load average over 15 minutes",
+            info.loadavg_fifteen as f64 / 100.0,
+        ),
+    ];
+    for (name, help, value) in gauges {
+        register_gauge(registry, name, help, value);
+    }
+
+    let total = Family::<Labels, FloatGauge>::default();
+    let free = Family::<Labels, FloatGauge>::default();
+    for disk in &info.disks {
+        let disk_labels = labels([
+            ("name", disk.name.as_str()),
+            ("mount_point", disk.mount_point.as_str()),
+        ]);
+        total
+            .get_or_create(&disk_labels)
+            .set(disk.total_size as f64);
+        free.get_or_create(&disk_labels).set(disk.free_size as f64);
+    }
+    registry.register("disk_total_bytes", "Disk total size", total);
+    registry.register("disk_free_bytes", "Disk free size", free);
+    register_legacy_system(registry, info);
+}
+
+/// The series exported before OpenMetrics, with their original names and values, so that
+/// existing dashboards keep working. They will be removed in a future release.
+fn register_legacy_system(registry: &mut Registry, info: &SystemInfo) {
+    let infos = [
+        ("system_os_name", "os_name", info.os_name.as_str()),
+        ("system_os_version", "os_version", info.os_version.as_str()),
+        (
+            "system_kernel_version",
+            "kernel_version",
+            info.kernel_version.as_str(),
+        ),
+        ("system_cpu_model", "cpu_model", info.cpu_model.as_str()),
+    ];
+    for (name, label, value) in infos {
+        let family = Family::<Labels, FloatGauge>::default();
+        family.get_or_create(&labels([(label, value)])).set(1.0);
+        registry.register(name, "Deprecated, use system_info", family);
+    }
+    let gauges = [
+        (
+            "system_memory_total",
+            "Deprecated, use system_memory_total_bytes",
+            info.total_memory as f64,
+        ),
+        (
+            "system_memory_available",
+            "Deprecated, use system_memory_available_bytes",
+            info.available_memory as f64,
+        ),
+        (
+            "system_memory_used",
+            "Deprecated, use system_memory_used_bytes",
+            info.used_memory as f64,
+        ),
+        (
+            "system_memory_free",
+            "Deprecated, use system_memory_free_bytes",
+            info.free_memory as f64,
+        ),
+        (
+            "system_swap_total",
+            "Deprecated, use system_swap_total_bytes",
+            info.total_swap as f64,
+        ),
+        (
+            "system_swap_used",
+            "Deprecated, use system_swap_used_bytes",
+            info.used_swap as f64,
+        ),
+        (
+            "system_swap_free",
+            "Deprecated, use system_swap_free_bytes",
+            info.free_swap as f64,
+        ),
+        (
+            "system_uptime",
+            "Deprecated, use system_uptime_seconds",
+            info.uptime as f64,
+        ),
+        // These were exported scaled by 100
+        (
+            "system_load_average_1m",
+            "Deprecated, use system_load_1m",
+            info.loadavg_one as f64,
+        ),
+        (
+            "system_load_average_5m",
+            "Deprecated, use system_load_5m",
+            info.loadavg_five as f64,
+        ),
+        (
+            "system_load_average_15m",
+            "Deprecated, use system_load_15m",
+            info.loadavg_fifteen as f64,
+        ),
+    ];
+    for (name, help, value) in gauges {
+        register_gauge(registry, name, help, value);
+    }
+
+    let total = Family::<Labels, FloatGauge>::default();
+    let free = Family::<Labels, FloatGauge>::default();
+    let used = Family::<Labels, FloatGauge>::default();
+    let usage = Family::<Labels, FloatGauge>::default();
+    for disk in &info.disks {
+        let disk_labels = labels([
+            ("name", disk.name.as_str()),
+            ("mount_point", disk.mount_point.as_str()),
+        ]);
+        let used_size = disk.total_size.saturating_sub(disk.free_size) as f64;
+        total
+            .get_or_create(&disk_labels)
+            .set(disk.total_size as f64);
+        free.get_or_create(&disk_labels).set(disk.free_size as f64);
+        used.get_or_create(&disk_labels).set(used_size);
+        let percentage = if disk.total_size > 0 {
+            used_size / disk.total_size as f64 * 100.0
+        } else {
+            0.0
+        };
+        usage.get_or_create(&disk_labels).set(percentage);
+    }
+    registry.register("disk_total_size", "Deprecated, use disk_total_bytes", total);
+    registry.register("disk_free_size", "Deprecated, use disk_free_bytes", free);
+    registry.register(
+        "disk_used_size",
+        "Deprecated, use disk_total_bytes - disk_free_bytes",
+        used,
+    );
+    registry.register(
+        "disk_usage_percentage",
+        "Deprecated, use disk_free_bytes / disk_total_bytes",
+        usage,
+    );
+}
+
+fn register_io(registry: &mut Registry) {
+    let read = Family::<Labels, FloatCounter>::default();
+    let written = Family::<Labels, FloatCounter>::default();
+    for disk in sysinfo::Disks::new_with_refreshed_list().list() {
+        let disk_labels = labels([("name", disk.name().to_string_lossy().as_ref())]);
+        let usage = disk.usage();
+        read.get_or_create(&disk_labels)
+            .inc_by(usage.total_read_bytes as f64);
+        written
+            .get_or_create(&disk_labels)
+            .inc_by(usage.total_written_bytes as f64);
+    }
+    registry.register("disk_read_bytes", "Bytes read from the disk", read);
+    registry.register("disk_written_bytes", "Bytes written to the disk", written);
+
+    let received = Family::<Labels, FloatCounter>::default();
+    let transmitted = Family::<Labels, FloatCounter>::default();
+    for (name, data) in sysinfo::Networks::new_with_refreshed_list().iter() {
+        let interface = labels([("interface", name.as_str())]);
+        received
+            .get_or_create(&interface)
+            .inc_by(data.total_received() as f64);
+        transmitted
+            .get_or_create(&interface)
+            .inc_by(data.total_transmitted() as f64);
+    }
+    registry.register(
+        "network_received_bytes",
+        "Bytes received on the interface",
+        received,
+    );
+    registry.register(
+        "network_transmitted_bytes",
+        "Bytes transmitted on the interface",
+        transmitted,
+    );
+}
+
+#[derive(Default)]
+struct ContainerMetrics {
+    cpu: Family<Labels, FloatCounter>,
+    memory: Family<Labels, FloatGauge>,
+    memory_limit: Family<Labels, FloatGauge>,
+    received: Family<Labels, FloatCounter>,
+    transmitted: Family<Labels, FloatCounter>,
+    block_read: Family<Labels, FloatCounter>,
+    block_written: Family<Labels, FloatCounter>,
+}
+
+impl ContainerMetrics {
+    fn add(&self, container_labels: &Labels, stats: &Stats) {
+        self.cpu
+            .get_or_create(container_labels)
+            .inc_by(stats.cpu_stats.cpu_usage.total_usage as f64 / 1e9);
+        let memory = &stats.memory_stats;
+        self.memory
+            .get_or_create(container_labels)
+            .set(memory.usage.unwrap_or_default() as f64);
+        self.memory_limit
+            .get_or_create(container_labels)
+            .set(memory.limit.unwrap_or_default() as f64);
+        let (rx, tx) = stats
+            .networks
+            .iter()
+            .flat_map(|networks| networks.values())
+            .fold((0, 0), |(rx, tx), net| {
+                (rx + net.rx_bytes, tx + net.tx_bytes)
+            });
+        self.received
+            .get_or_create(container_labels)
+            .inc_by(rx as f64);
+        self.transmitted
+            .get_or_create(container_labels)
+            .inc_by(tx as f64);
+        let (read, written) = stats
+            .blkio_stats
+            .io_service_bytes_recursive
+            .iter()
+            .flatten()
+            .fold((0, 0), |(read, written), entry| {
+                match entry.op.to_ascii_lowercase().as_str() {
+                    "read" => (read + entry.value, written),
+                    "write" => (read, written + entry.value),
+                    _ => (read, written),
+                }
+            });
+        self.block_read
+            .get_or_create(container_labels)
+            .inc_by(read as f64);
+        self.block_written
+            .get_or_create(container_labels)
+            .inc_by(written as f64);
+    }
+
+    fn register(self, registry: &mut Registry) {
+        registry.register(
+            "container_cpu_usage_seconds",
+            "CPU time consumed by the container",
+            self.cpu,
+        );
+        registry.register(
+            "container_memory_usage_bytes",
+            "Memory used by the container",
+            self.memory,
+        );
+        registry.register(
+            "container_memory_limit_bytes",
+            "Memory limit of the container",
+            self.memory_limit,
+        );
+        registry.register(
+            "container_network_received_bytes",
+            "Bytes received by the container",
+            self.received,
+        );
+        registry.register(
+            "container_network_transmitted_bytes",
+            "Bytes transmitted by the container",
+            self.transmitted,
+        );
+        registry.register(
+            "container_block_read_bytes",
+            "Bytes read from block devices by the container",
+            self.block_read,
+        );
+        registry.register(
+            "container_block_written_bytes",
+            "Bytes written to block devices by the container",
+            self.block_written,
+        );
+    }
+}
+
+/// Containers whose stats are fetched at the same time, each fetch takes about a second.
+const STATS_CONCURRENCY: usize = 16;
+
+async fn container_stats(docker: &Docker, id: &str, name: &str) -> Option<Stats> {
+    let options = StatsOptions {
+        stream: false,
+        one_shot: true,
+    };
+    match docker.stats(id, Some(options)).next().await? {
+        Ok(stats) => Some(stats),
+        Err(err) => {
+            warn!("Failed to get stats of container {name}: {err}");
+            None
+        }
+    }
+}
+
+async fn register_containers(registry: &mut Registry) -> Result<()> {
+    let docker = Docker::connect_with_defaults().context("Failed to connect to Docker")?;
+    let mut running = vec![];
+    for summary in containers::service_containers(&docker, "").await? {
+        let (Some(id), Some(state)) = (summary.id, summary.state) else {
+            continue;
+        };
+        if state != "running" {
+            continue;
+        }
+        let name = summary
+            .names
+            .and_then(|names| names.into_iter().next())
+            .unwrap_or_else(|| id.clone());
+        let service = summary
+            .labels
+            .and_then(|labels| labels.get(containers::SERVICE_LABEL).cloned())
+            .unwrap_or_default();
+        running.push((id, name, service));
+    }
+    let docker = &docker;
+    let stats: Vec<_> = stream::iter(running)
+        .map(|(id, name, service)| async move {
+            let stats = container_stats(docker, &id, &name).await;
+            (name, service, stats)
+        })
+        .buffer_unordered(STATS_CONCURRENCY)
+        .collect()
+        .await;
+    let metrics = ContainerMetrics::default();
+    for (name, service, stats) in stats {
+        let Some(stats) = stats else {
+            continue;
+        };
+        let container_labels = labels([
+            ("name", name.trim_start_matches('/')),
+            ("service", service.as_str()),
+        ]);
+        metrics.add(&container_labels, &stats);
+    }
+    metrics.register(registry);
+    Ok(())
+}
+
+/// Collect and encode all metrics.
+pub async fn render(state: &AppState, system_info: &SystemInfo) -> Result<String> {
+    let mut registry = Registry::default();
+    register_system(&mut registry, system_info);
+    register_io(&mut registry);
+    if let Err(err) = register_containers(&mut registry).await {
+        warn!("Failed to collect container metrics: {err:?}");
+    }
+    state.rpc_metrics().register(&mut registry);
+    let mut body = String::new();
+    encode(&mut body, &registry).context("Failed to encode metrics")?;
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const METHODS: &[&str] = &["Tappd.TdxQuote", "Tappd.Info"];
+
+    #[test]
+    fn test_method_of() {
+        assert_eq!(method_of("/prpc/Tappd.TdxQuote", METHODS), "TdxQuote");
+        assert_eq!(method_of("/prpc/Info", METHODS), "Info");
+        assert_eq!(method_of("/prpc/Whatever", METHODS), UNKNOWN_METHOD);
+        assert_eq!(method_of("/", METHODS), UNKNOWN_METHOD);
+    }
+
+    #[test]
+    fn test_rpc_metrics_encoding() {
+        let metrics = RpcMetrics::default();
+        metrics.observe("Tappd", "Info", true, Duration::from_millis(3));
+        metrics.observe("Tappd", "Info", false, Duration::from_millis(30));
+        let mut registry = Registry::default();
+        metrics.register(&mut registry);
+        register_gauge(&mut registry, "system_num_cpus", "Number of CPUs", 4.0);
+        let mut body = String::new();
+        encode(&mut body, &registry).unwrap();
+        assert!(body.contains(
+            r#"dstack_rpc_duration_seconds_count{service="Tappd",method="Info",status="ok"} 1"#
+        ));
+        assert!(body.contains("system_num_cpus 4"));
+        assert!(body.ends_with("# EOF\n"));
+    }
+
+    #[test]
+    fn test_legacy_system_metrics() {
+        let info = SystemInfo {
+            os_name: "Linux".into(),
+            total_memory: 1024,
+            loadavg_one: 150,
+            disks: vec![guest_api::DiskInfo {
+                name: "vda".into(),
+                mount_point: "/".into(),
+                total_size: 200,
+                free_size: 50,
+            }],
+            ..Default::default()
+        };
+        let mut registry = Registry::default();
+        register_system(&mut registry, &info);
+        let mut body = String::new();
+        encode(&mut body, &registry).unwrap();
+        for series in [
+            r#"system_os_name{os_name="Linux"} 1.0"#,
+            "system_memory_total 1024.0",
+            "system_memory_total_bytes 1024.0",
+            "system_load_average_1m 150.0",
+            "system_load_1m 1.5",
+            r#"disk_used_size{name="vda",mount_point="/"} 150.0"#,
+            r#"disk_usage_percentage{name="vda",mount_point="/"} 75.0"#,
+        ] {
+            assert!(body.contains(series), "{series} missing in:\n{body}");
+        }
+    }
+}
@@ -45,9 +45,3 @@ pub struct Dashboard {
     pub public_logs: bool,
     pub public_tcbinfo: bool,
 }
-
-#[derive(Template)]
-#[template(path = "metrics.tpl", escape = "none")]
-pub struct Metrics {
-    pub system_info: SystemInfo,
-}
@@ -0,0 +1,108 @@
+//! Discovery of the other instances of the app through dstack-gateway.
+//!
+//! The gateway identifies the caller by the attestation in its RA-TLS client certificate and only
+//! returns instances of the same app id, so the listed peers need no further filtering here.
+use anyhow::{bail, Context, Result};
+use dstack_gateway_rpc::gateway_client::GatewayClient;
+use dstack_guest_agent_rpc::PeerInfo;
+use ra_rpc::client::{CertInfo, RaClientConfig};
+use tracing::warn;
+
+use crate::rpc_service::AppState;
+
+/// Check that the server certificate belongs to the gateway app trusted by the KMS.
+fn validate_gateway(allowed_app_id: &str, cert: Option<CertInfo>) -> Result<()> {
+    if allowed_app_id == "any" {
+        return Ok(());
+    }
+    let Some(cert) = cert else {
+        bail!("Missing TLS certificate info");
+    };
+    let Some(app_id) = cert.app_id else {
+        bail!("Missing app id");
+    };
+    let app_id = hex::encode(app_id);
+    if !allowed_app_id
+        .to_lowercase()
+        .contains(&app_id.to_lowercase())
+    {
+        bail!("Invalid dstack-gateway app id: {app_id}");
+    }
+    Ok(())
+}
+
+async fn list_peers_from(state: &AppState, gateway_url: &str) -> Result<Vec<PeerInfo>> {
+    let (cert, key) = state.gateway_client_cert().await?;
+    let keys = state.keys();
+    let allowed_app_id = keys.gateway_app_id.clone();
+    let client = RaClientConfig::builder()
+        .remote_uri(format!("{gateway_url}/prpc"))
+        .maybe_pccs_url(state.config().pccs_url.clone())
+        .tls_client_cert(cert.clone())
+        .tls_client_key(key.clone())
+        .tls_ca_cert(keys.ca_cert.clone())
+        .tls_built_in_root_certs(false)
+        .tls_no_check(allowed_app_id == "any")
+        .verify_server_attestation(false)
+        .cert_validator(Box::new(move |cert| {
+            validate_gateway(&allowed_app_id, cert)
+        }))
+        .build()
+        .into_client()
+        .context("Failed to create RA client")?;
+    let response = GatewayClient::new(client)
+        .list_peers()
+        .await
+        .context("Failed to list peers")?;
+    Ok(response
+        .peers
+        .into_iter()
+        .map(|peer| PeerInfo {
+            instance_id: peer.instance_id,
+            ip: peer.ip,
+            last_seen: peer.last_seen,
+        })
+        .collect())
+}
+
+/// List the other live instances of the app, asking the gateways in turn.
+pub async fn list_peers(state: &AppState) -> Result<Vec<PeerInfo>> {
+    if !state.config().app_compose.gateway_enabled() {
+        bail!("dstack-gateway is not enabled");
+    }
+    let gateway_urls = state.gateway_urls();
+    if gateway_urls.is_empty() {
+        bail!("Missing gateway urls");
+    }
+    for url in gateway_urls {
+        match list_peers_from(state, url).await {
+            Ok(peers) => return Ok(peers),
+            Err(err) => warn!("Failed to list peers from {url}: {err:?}"),
+        }
+    }
+    bail!("Failed to list peers, all dstack-gateway urls are down")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cert_info(app_id: Option<Vec<u8>>) -> Option<CertInfo> {
+        Some(CertInfo {
+            cert_der: vec![],
+            attestation: None,
+            special_usage: None,
+            app_id,
+        })
+    }
+
+    #[test]
+    fn test_validate_gateway() {
+        assert!(validate_gateway("any", None).is_ok());
+        assert!(validate_gateway("abcd", cert_info(Some(vec![0xab, 0xcd]))).is_ok());
+        assert!(validate_gateway("ABCD", cert_info(Some(vec![0xab, 0xcd]))).is_ok());
+        assert!(validate_gateway("abcd", cert_info(Some(vec![0x12]))).is_err());
+        assert!(validate_gateway("abcd", cert_info(None)).is_err());
+        assert!(validate_gateway("abcd", None).is_err());
+    }
+}
@@ -1,31 +1,42 @@
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use cert_client::CertRequestClient;
 use dstack_guest_agent_rpc::{
     dstack_guest_server::{DstackGuestRpc, DstackGuestServer},
     tappd_server::{TappdRpc, TappdServer},
     worker_server::{WorkerRpc, WorkerServer},
-    AppInfo, DeriveK256KeyResponse, DeriveKeyArgs, EmitEventArgs, GetKeyArgs, GetKeyResponse,
-    GetQuoteResponse, GetTlsKeyArgs, GetTlsKeyResponse, RawQuoteArgs, TdxQuoteArgs,
-    TdxQuoteResponse, WorkerVersion,
+    AppInfo, DeriveK256KeyResponse, DeriveKeyArgs, EmitEventArgs, EventLogQuery, EventLogResponse,
+    GetKeyArgs, GetKeyResponse, GetPublicKeyArgs, GetPublicKeyResponse, GetQuoteResponse,
+    GetTlsKeyArgs, GetTlsKeyResponse, ListPeersResponse, RawQuoteArgs, SealArgs, SealResponse,
+    SignArgs, SignResponse, TdxQuoteArgs, TdxQuoteResponse, UnsealArgs, UnsealResponse, VerifyArgs,
+    VerifyResponse, WorkerVersion,
 };
 use dstack_types::{AppKeys, SysConfig};
 use fs_err as fs;
-use k256::ecdsa::SigningKey;
 use ra_rpc::{Attestation, CallContext, RpcCall};
 use ra_tls::{
     attestation::{QuoteContentType, DEFAULT_HASH_ALGORITHM},
     cert::CertConfig,
-    kdf::{derive_ecdsa_key, derive_ecdsa_key_pair_from_bytes},
+    kdf::derive_ecdsa_key_pair_from_bytes,
 };
-use rcgen::KeyPair;
+use rcgen::{KeyPair, PKCS_ECDSA_P256_SHA256};
 use ring::rand::{SecureRandom, SystemRandom};
 use serde_json::json;
-use sha3::{Digest, Keccak256};
-use tdx_attest::eventlog::read_event_logs;
-
-use crate::config::Config;
+use tdx_attest::eventlog::{read_event_logs, TdxEventLog};
+
+use crate::compose_upgrade::UPGRADE_EVENT;
+use crate::config::{AppComposeWrapper, Config};
+use crate::event_log::{quote_rtmrs, replay, EventFilter};
+use crate::keys::{
+    derive_key, derive_signing_key, legacy_context, path_allowed, verify, DerivedKey, KeyAlgorithm,
+    SignMode,
+};
+use crate::managed_certs::CertStatusMap;
+use crate::metrics::RpcMetrics;
+use crate::peers;
+use crate::seal::{seal, BootIdentity, SealPolicy, SealedBlob};
+use crate::simulator::SimulatedTd;
 
 #[derive(Clone)]
 pub struct AppState {
@@ -38,6 +49,20 @@ struct AppStateInner {
     vm_config: String,
     cert_client: CertRequestClient,
     demo_cert: String,
+    cert_status: CertStatusMap,
+    upgrade_lock: tokio::sync::Mutex<()>,
+    rpc_metrics: RpcMetrics,
+    gateway_urls: Vec<String>,
+    gateway_client_cert: tokio::sync::OnceCell<(String, String)>,
+    simulated_td: Option<SimulatedTd>,
+    compose: RwLock<RunningCompose>,
+}
+
+/// The compose the app runs, which in-place upgrades replace.
+struct RunningCompose {
+    compose: Arc<AppComposeWrapper>,
+    /// Hash of the compose upgraded to since boot, if any
+    upgraded_hash: Option<Vec<u8>>,
 }
 
 impl AppState {
@@ -47,7 +72,17 @@ impl AppState {
         let sys_config: SysConfig =
             serde_json::from_str(&fs::read_to_string(&config.sys_config_file)?)
                 .context("Failed to parse VM config")?;
-        let vm_config = sys_config.vm_config;
+        let SysConfig {
+            vm_config,
+            gateway_urls,
+            ..
+        } = sys_config;
+        let simulated_td = match &config.simulator.profile {
+            Some(profile) if config.simulator.enabled => Some(
+                SimulatedTd::new(profile.clone()).context("Failed to create the simulated TD")?,
+            ),
+            _ => None,
+        };
         let cert_client =
             CertRequestClient::create(&keys, config.pccs_url.as_deref(), vm_config.clone())
                 .await
@@ -69,6 +104,10 @@ impl AppState {
             .await
             .context("Failed to get app cert")?
             .join("\n");
+        let compose = RwLock::new(RunningCompose {
+            compose: Arc::new(config.app_compose.clone()),
+            upgraded_hash: None,
+        });
         Ok(Self {
             inner: Arc::new(AppStateInner {
                 config,
@@ -76,6 +115,13 @@ impl AppState {
                 cert_client,
                 demo_cert,
                 vm_config,
+                cert_status: CertStatusMap::default(),
+                upgrade_lock: Default::default(),
+                rpc_metrics: RpcMetrics::default(),
+                gateway_urls,
+                gateway_client_cert: Default::default(),
+                simulated_td,
+                compose,
             }),
         })
     }
@@ -83,6 +129,154 @@ impl AppState {
     pub fn config(&self) -> &Config {
         &self.inner.config
     }
+
+    pub fn cert_status(&self) -> &CertStatusMap {
+        &self.inner.cert_status
+    }
+
+    pub fn rpc_metrics(&self) -> &RpcMetrics {
+        &self.inner.rpc_metrics
+    }
+
+    pub fn keys(&self) -> &AppKeys {
+        &self.inner.keys
+    }
+
+    pub fn gateway_urls(&self) -> &[String] {
+        &self.inner.gateway_urls
+    }
+
+    /// The RA-TLS client certificate and key used to talk to dstack-gateway, created on first use.
+    pub async fn gateway_client_cert(&self) -> Result<&(String, String)> {
+        self.inner
+            .gateway_client_cert
+            .get_or_try_init(|| async {
+                let key = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256)
+                    .context("Failed to generate client key")?;
+                let certs = self
+                    .request_cert(
+                        &key,
+                        CertConfig {
+                            org_name: None,
+                            subject: "dstack-guest-agent".to_string(),
+                            subject_alt_names: vec![],
+                            usage_server_auth: false,
+                            usage_client_auth: true,
+                            ext_quote: true,
+                        },
+                    )
+                    .await?;
+                Ok::<_, anyhow::Error>((certs.join("\n"), key.serialize_pem()))
+            })
+            .await
+    }
+
+    /// Request a certificate for `key` from the KMS, or the local CA if there is no KMS.
+    pub async fn request_cert(&self, key: &KeyPair, config: CertConfig) -> Result<Vec<String>> {
+        self.inner
+            .cert_client
+            .request_cert(key, config, self.config().simulator.enabled)
+            .await
+            .context("Failed to sign the CSR")
+    }
+
+    /// Ask the KMS whether the app may switch to the compose with the given hash.
+    pub async fn check_app_upgrade(&self, compose_hash: &[u8]) -> Result<()> {
+        self.inner.cert_client.check_app_upgrade(compose_hash).await
+    }
+
+    /// The compose the app runs, including in-place upgrades.
+    ///
+    /// Fields consumed during boot can not be upgraded, so they may be read from the config too.
+    pub fn app_compose(&self) -> Arc<AppComposeWrapper> {
+        self.inner
+            .compose
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .compose
+            .clone()
+    }
+
+    /// Record a successful in-place upgrade to `compose`.
+    pub fn set_upgraded_compose(&self, compose: AppComposeWrapper, compose_hash: Vec<u8>) {
+        *self
+            .inner
+            .compose
+            .write()
+            .unwrap_or_else(|e| e.into_inner()) = RunningCompose {
+            compose: Arc::new(compose),
+            upgraded_hash: Some(compose_hash),
+        };
+    }
+
+    /// Serializes compose upgrades.
+    pub async fn lock_upgrade(&self) -> tokio::sync::MutexGuard<'_, ()> {
+        self.inner.upgrade_lock.lock().await
+    }
+
+    /// Extend RTMR3 with an event, or append it to the simulated event log in simulator mode.
+    pub fn extend_rtmr3(&self, event: &str, payload: &[u8]) -> Result<()> {
+        if self.config().simulator.enabled {
+            if let Some(td) = &self.inner.simulated_td {
+                td.extend_rtmr3(event, payload);
+            }
+            return Ok(());
+        }
+        tdx_attest::extend_rtmr3(event, payload)
+    }
+
+    fn event_logs(&self) -> Result<Vec<TdxEventLog>> {
+        if let Some(td) = &self.inner.simulated_td {
+            return Ok(td.event_log());
+        }
+        let simulator = &self.config().simulator;
+        if simulator.enabled {
+            let event_log = fs::read_to_string(&simulator.event_log_file)
+                .context("Failed to read event log file")?;
+            return serde_json::from_str(&event_log).context("Failed to parse event log");
+        }
+        read_event_logs().context("Failed to decode event log")
+    }
+
+    /// The identity new data is sealed to: the boot identity, with the compose hash of the
+    /// latest in-place upgrade if there was one.
+    fn current_identity(&self) -> Result<BootIdentity> {
+        let mut identity = self.boot_identity()?;
+        let upgraded_hash = self
+            .inner
+            .compose
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .upgraded_hash
+            .clone();
+        if let Some(compose_hash) = upgraded_hash {
+            identity.compose_hash = compose_hash;
+        }
+        Ok(identity)
+    }
+
+    /// The compose hash and instance id measured into RTMR3 during boot.
+    ///
+    /// In-place upgrades are ignored here: they are tracked by the agent itself, since an app
+    /// could forge upgrade events in RTMR3 only after boot.
+    fn boot_identity(&self) -> Result<BootIdentity> {
+        let mut identity = BootIdentity::default();
+        let mut booted = false;
+        for event in self.event_logs()? {
+            if event.imr != 3 {
+                continue;
+            }
+            match event.event.as_str() {
+                "system-ready" => booted = true,
+                // Events emitted by the app after boot must not be mistaken for these
+                _ if booted => {}
+                "compose-hash" => identity.compose_hash = event.event_payload,
+                "instance-id" => identity.instance_id = event.event_payload,
+                _ => {}
+            }
+        }
+        Ok(identity)
+    }
 }
 
 pub struct InternalRpcHandler {
@@ -111,7 +305,7 @@ pub async fn get_info(state: &AppState, external: bool) -> Result<AppInfo> {
     let tcb_info = if hide_tcb_info {
         "".to_string()
     } else {
-        let app_compose = state.config().app_compose.raw.clone();
+        let app_compose = state.app_compose().raw.clone();
         serde_json::to_string_pretty(&json!({
             "mrtd": hex::encode(app_info.mrtd),
             "rtmr0": hex::encode(app_info.rtmr0),
@@ -133,7 +327,7 @@ pub async fn get_info(state: &AppState, external: bool) -> Result<AppInfo> {
         state.inner.vm_config.clone()
     };
     Ok(AppInfo {
-        app_name: state.config().app_compose.name.clone(),
+        app_name: state.app_compose().name.clone(),
         app_id: app_info.app_id,
         instance_id: app_info.instance_id,
         device_id: app_info.device_id,
@@ -144,9 +338,34 @@ pub async fn get_info(state: &AppState, external: bool) -> Result<AppInfo> {
         app_cert: state.inner.demo_cert.clone(),
         tcb_info,
         vm_config,
+        managed_certs: state.cert_status().list(),
     })
 }
 
+/// The purpose recorded in the provenance signature of signing keys.
+const SIGNING_KEY_PURPOSE: &str = "sign";
+
+impl InternalRpcHandler {
+    /// Derive the in-enclave signing key at `path` if the app compose allows it.
+    fn signing_key(&self, path: &str, algorithm: &str) -> Result<DerivedKey> {
+        let allowed = &self.state.config().app_compose.allowed_sign_paths;
+        if !path_allowed(allowed, path) {
+            bail!("Signing with key path {path:?} is not allowed by the app compose");
+        }
+        let algorithm: KeyAlgorithm = algorithm.parse()?;
+        if algorithm == KeyAlgorithm::X25519 {
+            bail!("x25519 keys can not sign");
+        }
+        derive_signing_key(&self.state.inner.keys.k256_key, path, algorithm)
+    }
+
+    fn signature_chain(&self, key: &DerivedKey) -> Result<Vec<Vec<u8>>> {
+        let keys = &self.state.inner.keys;
+        let signature = key.sign_provenance(&keys.k256_key, SIGNING_KEY_PURPOSE)?;
+        Ok(vec![signature, keys.k256_signature.clone()])
+    }
+}
+
 impl DstackGuestRpc for InternalRpcHandler {
     async fn get_tls_key(self, request: GetTlsKeyArgs) -> anyhow::Result<GetTlsKeyResponse> {
         let mut seed = [0u8; 32];
@@ -163,13 +382,7 @@ impl DstackGuestRpc for InternalRpcHandler {
             usage_client_auth: request.usage_client_auth,
             ext_quote: request.usage_ra_tls,
         };
-        let certificate_chain = self
-            .state
-            .inner
-            .cert_client
-            .request_cert(&derived_key, config, self.state.config().simulator.enabled)
-            .await
-            .context("Failed to sign the CSR")?;
+        let certificate_chain = self.state.request_cert(&derived_key, config).await?;
         Ok(GetTlsKeyResponse {
             key: derived_key.serialize_pem(),
             certificate_chain,
@@ -178,29 +391,100 @@ impl DstackGuestRpc for InternalRpcHandler {
 
     async fn get_key(self, request: GetKeyArgs) -> Result<GetKeyResponse> {
         let k256_app_key = &self.state.inner.keys.k256_key;
-        let derived_k256_key = derive_ecdsa_key(k256_app_key, &[request.path.as_bytes()], 32)
-            .context("Failed to derive k256 key")?;
-        let derived_k256_key =
-            SigningKey::from_slice(&derived_k256_key).context("Failed to parse k256 key")?;
-        let derived_k256_pubkey = derived_k256_key.verifying_key();
-        let msg_to_sign = format!(
-            "{}:{}",
-            request.purpose,
-            hex::encode(derived_k256_pubkey.to_sec1_bytes())
-        );
-        let app_signing_key =
-            SigningKey::from_slice(k256_app_key).context("Failed to parse app k256 key")?;
-        let digest = Keccak256::new_with_prefix(msg_to_sign);
-        let (signature, recid) = app_signing_key.sign_digest_recoverable(digest)?;
-        let mut signature = signature.to_vec();
-        signature.push(recid.to_byte());
-
+        let algorithm: KeyAlgorithm = request.algorithm.parse()?;
+        let derived_key = derive_key(k256_app_key, &request.path, algorithm)?;
+        let signature = derived_key.sign_provenance(k256_app_key, &request.purpose)?;
         Ok(GetKeyResponse {
-            key: derived_k256_key.to_bytes().to_vec(),
+            key: derived_key.private_key,
             signature_chain: vec![signature, self.state.inner.keys.k256_signature.clone()],
+            public_key: derived_key.public_key,
+            algorithm: algorithm.to_string(),
+        })
+    }
+
+    async fn sign(self, request: SignArgs) -> Result<SignResponse> {
+        let key = self.signing_key(&request.path, &request.algorithm)?;
+        let mode: SignMode = request.mode.parse()?;
+        let signature = key.sign(&request.data, mode)?;
+        Ok(SignResponse {
+            signature,
+            signature_chain: self.signature_chain(&key)?,
+            public_key: key.public_key,
+        })
+    }
+
+    async fn verify(self, request: VerifyArgs) -> Result<VerifyResponse> {
+        let algorithm: KeyAlgorithm = request.algorithm.parse()?;
+        let mode: SignMode = request.mode.parse()?;
+        let public_key = match request.public_key {
+            Some(public_key) => public_key,
+            None => {
+                self.signing_key(&request.path, &request.algorithm)?
+                    .public_key
+            }
+        };
+        let valid = verify(
+            algorithm,
+            &public_key,
+            &request.data,
+            &request.signature,
+            mode,
+        )?;
+        Ok(VerifyResponse { valid })
+    }
+
+    async fn get_public_key(self, request: GetPublicKeyArgs) -> Result<GetPublicKeyResponse> {
+        let key = self.signing_key(&request.path, &request.algorithm)?;
+        Ok(GetPublicKeyResponse {
+            signature_chain: self.signature_chain(&key)?,
+            algorithm: key.algorithm.to_string(),
+            public_key: key.public_key,
         })
     }
 
+    async fn seal(self, request: SealArgs) -> Result<SealResponse> {
+        let policy = SealPolicy {
+            bind_compose_hash: request.bind_compose_hash,
+            bind_instance_id: request.bind_instance_id,
+        };
+        let identity = if policy.is_bound() {
+            self.state.current_identity()?
+        } else {
+            BootIdentity::default()
+        };
+        let sealed = seal(
+            &self.state.inner.keys.k256_key,
+            &request.label,
+            policy,
+            &identity,
+            &request.data,
+        )?;
+        Ok(SealResponse { sealed })
+    }
+
+    async fn unseal(self, request: UnsealArgs) -> Result<UnsealResponse> {
+        let blob = SealedBlob::parse(&request.sealed)?;
+        let key = &self.state.inner.keys.k256_key;
+        if !blob.policy.is_bound() {
+            let data = blob.open(key, &request.label, &BootIdentity::default())?;
+            return Ok(UnsealResponse { data });
+        }
+        let current = self.state.current_identity()?;
+        let data = match blob.open(key, &request.label, &current) {
+            Ok(data) => data,
+            // Data sealed before an in-place upgrade is bound to the boot compose hash
+            Err(err) if blob.policy.bind_compose_hash => {
+                let boot = self.state.boot_identity()?;
+                if boot == current {
+                    return Err(err);
+                }
+                blob.open(key, &request.label, &boot)?
+            }
+            Err(err) => return Err(err),
+        };
+        Ok(UnsealResponse { data })
+    }
+
     async fn get_quote(self, request: RawQuoteArgs) -> Result<GetQuoteResponse> {
         fn pad64(data: &[u8]) -> Option<[u8; 64]> {
             if data.len() > 64 {
@@ -212,11 +496,7 @@ impl DstackGuestRpc for InternalRpcHandler {
         }
         let report_data = pad64(&request.report_data).context("Report data is too long")?;
         if self.state.config().simulator.enabled {
-            return simulate_quote(
-                self.state.config(),
-                report_data,
-                &self.state.inner.vm_config,
-            );
+            return simulate_quote(&self.state, report_data, &self.state.inner.vm_config);
         }
         let (_, quote) =
             tdx_attest::get_quote(&report_data, None).context("Failed to get quote")?;
@@ -233,10 +513,52 @@ impl DstackGuestRpc for InternalRpcHandler {
     }
 
     async fn emit_event(self, request: EmitEventArgs) -> Result<()> {
-        if self.state.config().simulator.enabled {
-            return Ok(());
+        if request.event == UPGRADE_EVENT {
+            bail!("Event name {UPGRADE_EVENT} is reserved");
         }
-        tdx_attest::extend_rtmr3(&request.event, &request.payload)
+        self.state.extend_rtmr3(&request.event, &request.payload)
+    }
+
+    async fn get_event_log(self, request: EventLogQuery) -> Result<EventLogResponse> {
+        // Events emitted while the quote is being generated would make the replay differ from
+        // the quote, so retry until the event log is stable around the quote.
+        const MAX_ATTEMPTS: usize = 3;
+        let (event_log, quote) = if request.with_quote {
+            let mut attempt = 0;
+            loop {
+                attempt += 1;
+                let before = self.state.event_logs()?.len();
+                let quote = InternalRpcHandler {
+                    state: self.state.clone(),
+                }
+                .get_quote(RawQuoteArgs {
+                    report_data: request.report_data.clone(),
+                })
+                .await?
+                .quote;
+                let event_log = self.state.event_logs()?;
+                if event_log.len() == before || attempt >= MAX_ATTEMPTS {
+                    break (event_log, quote);
+                }
+            }
+        } else {
+            (self.state.event_logs()?, vec![])
+        };
+        let rtmrs = replay(&event_log)?;
+        let replay_matches_quote = !quote.is_empty() && quote_rtmrs(&quote)? == rtmrs;
+        Ok(EventLogResponse {
+            events: EventFilter::from(&request).apply(&event_log),
+            total_events: event_log.len() as u32,
+            rtmrs,
+            quote,
+            replay_matches_quote,
+        })
+    }
+
+    async fn list_peers(self) -> Result<ListPeersResponse> {
+        Ok(ListPeersResponse {
+            peers: peers::list_peers(&self.state).await?,
+        })
     }
 
     async fn info(self) -> Result<AppInfo> {
@@ -245,10 +567,21 @@ impl DstackGuestRpc for InternalRpcHandler {
 }
 
 fn simulate_quote(
-    config: &Config,
+    state: &AppState,
     report_data: [u8; 64],
     vm_config: &str,
 ) -> Result<GetQuoteResponse> {
+    if let Some(td) = &state.inner.simulated_td {
+        let (quote, event_log) = td.quote(&report_data)?;
+        return Ok(GetQuoteResponse {
+            quote,
+            event_log: serde_json::to_string(&event_log)
+                .context("Failed to serialize event log")?,
+            report_data: report_data.to_vec(),
+            vm_config: vm_config.to_string(),
+        });
+    }
+    let config = state.config();
     let quote_file =
         fs::read_to_string(&config.simulator.quote_file).context("Failed to read quote file")?;
     let mut quote = hex::decode(quote_file.trim()).context("Failed to decode quote")?;
@@ -291,7 +624,7 @@ impl TappdRpc for InternalRpcHandlerV0 {
         } else {
             &self.state.inner.keys.k256_key
         };
-        let derived_key = derive_ecdsa_key_pair_from_bytes(seed, &[request.path.as_bytes()])
+        let derived_key = derive_ecdsa_key_pair_from_bytes(seed, &[legacy_context(&request.path)])
             .context("Failed to derive key")?;
         let config = CertConfig {
             org_name: None,
@@ -315,6 +648,10 @@ impl TappdRpc for InternalRpcHandlerV0 {
     }
 
     async fn derive_k256_key(self, request: GetKeyArgs) -> Result<DeriveK256KeyResponse> {
+        let request = GetKeyArgs {
+            algorithm: KeyAlgorithm::Secp256k1.to_string(),
+            ..request
+        };
         let res = InternalRpcHandler { state: self.state }
             .get_key(request)
             .await?;
@@ -343,11 +680,7 @@ impl TappdRpc for InternalRpcHandlerV0 {
         let report_data =
             content_type.to_report_data_with_hash(&request.report_data, &request.hash_algorithm)?;
         if self.state.config().simulator.enabled {
-            let response = simulate_quote(
-                self.state.config(),
-                report_data,
-                &self.state.inner.vm_config,
-            )?;
+            let response = simulate_quote(&self.state, report_data, &self.state.inner.vm_config)?;
             return Ok(TdxQuoteResponse {
                 quote: response.quote,
                 event_log: response.event_log,
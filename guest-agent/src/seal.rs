@@ -0,0 +1,279 @@
+//! Sealed storage: AEAD encryption of app data with keys bound to the app.
+//!
+//! A sealed blob is laid out as `version (1) || policy (1) || nonce (12) || ciphertext || tag`.
+//! The sealing key is derived from the app key, the label and the identities selected by the
+//! policy, in a key derivation domain of its own. The header is authenticated as associated data,
+//! so blobs sealed with any policy stay decryptable as long as the bound identities match.
+use anyhow::{anyhow, bail, Context, Result};
+use ra_tls::kdf::derive_ecdsa_key;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::keys::kdf_context;
+
+pub(crate) const SEAL_DOMAIN: &[u8] = b"seal";
+const VERSION_1: u8 = 1;
+const HEADER_LEN: usize = 2;
+
+const BIND_COMPOSE_HASH: u8 = 1 << 0;
+const BIND_INSTANCE_ID: u8 = 1 << 1;
+
+/// The identities a sealed blob is bound to, in addition to the app and the label.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SealPolicy {
+    pub bind_compose_hash: bool,
+    pub bind_instance_id: bool,
+}
+
+impl SealPolicy {
+    fn to_byte(self) -> u8 {
+        let mut flags = 0;
+        if self.bind_compose_hash {
+            flags |= BIND_COMPOSE_HASH;
+        }
+        if self.bind_instance_id {
+            flags |= BIND_INSTANCE_ID;
+        }
+        flags
+    }
+
+    fn from_byte(flags: u8) -> Result<Self> {
+        if flags & !(BIND_COMPOSE_HASH | BIND_INSTANCE_ID) != 0 {
+            bail!("Unknown seal policy flags: {flags:#x}");
+        }
+        Ok(Self {
+            bind_compose_hash: flags & BIND_COMPOSE_HASH != 0,
+            bind_instance_id: flags & BIND_INSTANCE_ID != 0,
+        })
+    }
+
+    /// Whether the policy needs the identities of the running instance.
+    pub fn is_bound(&self) -> bool {
+        self.bind_compose_hash || self.bind_instance_id
+    }
+}
+
+/// The identities of the running instance, as measured into RTMR3 at boot or replaced by an
+/// in-place compose upgrade.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BootIdentity {
+    pub compose_hash: Vec<u8>,
+    pub instance_id: Vec<u8>,
+}
+
+fn sealing_key(
+    app_key: &[u8],
+    label: &str,
+    version: u8,
+    policy: SealPolicy,
+    identity: &BootIdentity,
+) -> Result<LessSafeKey> {
+    if label.contains('\0') {
+        bail!("Seal label must not contain NUL characters");
+    }
+    let header = [version, policy.to_byte()];
+    let mut parts: Vec<&[u8]> = vec![&header[..], label.as_bytes()];
+    if policy.bind_compose_hash {
+        if identity.compose_hash.is_empty() {
+            bail!("No compose hash to bind to");
+        }
+        parts.push(&identity.compose_hash);
+    }
+    if policy.bind_instance_id {
+        if identity.instance_id.is_empty() {
+            bail!("The instance has no instance id to bind to");
+        }
+        parts.push(&identity.instance_id);
+    }
+    let context = kdf_context(SEAL_DOMAIN, &parts);
+    let key =
+        derive_ecdsa_key(app_key, &[&context], 32).map_err(|_| anyhow!("Failed to derive key"))?;
+    let key = UnboundKey::new(&AES_256_GCM, &key).map_err(|_| anyhow!("Invalid sealing key"))?;
+    Ok(LessSafeKey::new(key))
+}
+
+fn aad(header: &[u8], label: &str) -> Vec<u8> {
+    [header, label.as_bytes()].concat()
+}
+
+/// Encrypt `data` under `label` with the given policy.
+pub fn seal(
+    app_key: &[u8],
+    label: &str,
+    policy: SealPolicy,
+    identity: &BootIdentity,
+    data: &[u8],
+) -> Result<Vec<u8>> {
+    let key = sealing_key(app_key, label, VERSION_1, policy, identity)?;
+    let mut nonce = [0u8; NONCE_LEN];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| anyhow!("Failed to generate nonce"))?;
+    let header = [VERSION_1, policy.to_byte()];
+    let mut sealed = data.to_vec();
+    key.seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::from(aad(&header, label)),
+        &mut sealed,
+    )
+    .map_err(|_| anyhow!("Failed to seal data"))?;
+    Ok([&header[..], &nonce[..], &sealed[..]].concat())
+}
+
+/// A parsed sealed blob.
+#[derive(Debug)]
+pub struct SealedBlob<'a> {
+    pub version: u8,
+    pub policy: SealPolicy,
+    nonce: [u8; NONCE_LEN],
+    ciphertext: &'a [u8],
+}
+
+impl<'a> SealedBlob<'a> {
+    pub fn parse(blob: &'a [u8]) -> Result<Self> {
+        if blob.len() < HEADER_LEN + NONCE_LEN + AES_256_GCM.tag_len() {
+            bail!("Sealed data is too short");
+        }
+        let version = blob[0];
+        if version != VERSION_1 {
+            bail!("Unsupported sealed data version: {version}");
+        }
+        let policy = SealPolicy::from_byte(blob[1])?;
+        let nonce = blob[HEADER_LEN..HEADER_LEN + NONCE_LEN]
+            .try_into()
+            .context("Invalid nonce")?;
+        Ok(Self {
+            version,
+            policy,
+            nonce,
+            ciphertext: &blob[HEADER_LEN + NONCE_LEN..],
+        })
+    }
+
+    /// Decrypt the blob. Fails if the label or any bound identity differs from sealing time.
+    pub fn open(&self, app_key: &[u8], label: &str, identity: &BootIdentity) -> Result<Vec<u8>> {
+        let key = sealing_key(app_key, label, self.version, self.policy, identity)?;
+        let header = [self.version, self.policy.to_byte()];
+        let mut data = self.ciphertext.to_vec();
+        let len = key
+            .open_in_place(
+                Nonce::assume_unique_for_key(self.nonce),
+                Aad::from(aad(&header, label)),
+                &mut data,
+            )
+            .map_err(|_| anyhow!("Failed to unseal data"))?
+            .len();
+        data.truncate(len);
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const APP_KEY: [u8; 32] = [0x22; 32];
+
+    fn identity() -> BootIdentity {
+        BootIdentity {
+            compose_hash: vec![1; 32],
+            instance_id: vec![2; 20],
+        }
+    }
+
+    fn unseal(label: &str, identity: &BootIdentity, blob: &[u8]) -> Result<Vec<u8>> {
+        SealedBlob::parse(blob)?.open(&APP_KEY, label, identity)
+    }
+
+    #[test]
+    fn test_seal_roundtrip() {
+        for (bind_compose_hash, bind_instance_id) in
+            [(false, false), (true, false), (false, true), (true, true)]
+        {
+            let policy = SealPolicy {
+                bind_compose_hash,
+                bind_instance_id,
+            };
+            let blob = seal(&APP_KEY, "db", policy, &identity(), b"secret").unwrap();
+            let parsed = SealedBlob::parse(&blob).unwrap();
+            assert_eq!(parsed.version, VERSION_1);
+            assert_eq!(parsed.policy, policy);
+            assert_eq!(unseal("db", &identity(), &blob).unwrap(), b"secret");
+            assert!(unseal("other", &identity(), &blob).is_err());
+        }
+        let empty = seal(&APP_KEY, "", SealPolicy::default(), &identity(), b"").unwrap();
+        assert!(unseal("", &BootIdentity::default(), &empty)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_seal_binding() {
+        let policy = SealPolicy {
+            bind_compose_hash: true,
+            bind_instance_id: false,
+        };
+        let blob = seal(&APP_KEY, "db", policy, &identity(), b"secret").unwrap();
+        let upgraded = BootIdentity {
+            compose_hash: vec![3; 32],
+            ..identity()
+        };
+        assert!(unseal("db", &upgraded, &blob).is_err());
+        let other_instance = BootIdentity {
+            instance_id: vec![4; 20],
+            ..identity()
+        };
+        assert_eq!(unseal("db", &other_instance, &blob).unwrap(), b"secret");
+
+        let no_instance_id = BootIdentity {
+            instance_id: vec![],
+            ..identity()
+        };
+        let by_instance = SealPolicy {
+            bind_compose_hash: false,
+            bind_instance_id: true,
+        };
+        assert!(seal(&APP_KEY, "db", by_instance, &no_instance_id, b"secret").is_err());
+    }
+
+    #[test]
+    fn test_seal_tampering() {
+        let blob = seal(
+            &APP_KEY,
+            "db",
+            SealPolicy::default(),
+            &identity(),
+            b"secret",
+        )
+        .unwrap();
+        assert_ne!(
+            blob,
+            seal(
+                &APP_KEY,
+                "db",
+                SealPolicy::default(),
+                &identity(),
+                b"secret"
+            )
+            .unwrap()
+        );
+
+        let mut flipped = blob.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        assert!(unseal("db", &identity(), &flipped).is_err());
+
+        // Changing the policy in the header must not unseal under a weaker binding
+        let mut policy_changed = blob.clone();
+        policy_changed[1] = BIND_COMPOSE_HASH;
+        assert!(unseal("db", &identity(), &policy_changed).is_err());
+
+        let mut version_changed = blob.clone();
+        version_changed[0] = 2;
+        assert!(SealedBlob::parse(&version_changed).is_err());
+        let mut unknown_flags = blob;
+        unknown_flags[1] = 0x80;
+        assert!(SealedBlob::parse(&unknown_flags).is_err());
+        assert!(SealedBlob::parse(&[VERSION_1, 0, 1, 2]).is_err());
+        assert!(seal(&APP_KEY, "a\0b", SealPolicy::default(), &identity(), b"").is_err());
+    }
+}
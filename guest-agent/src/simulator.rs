@@ -0,0 +1,41 @@
+//! Simulated TD backing the quotes and event log in simulator mode.
+//!
+//! Unlike the canned quote file, the quotes are synthesized from the configured measurement
+//! profile and follow the events emitted at runtime, so they verify with `ra_tls`'s test-mode
+//! verifier just like a real TD's quotes verify against Intel's collateral.
+use std::sync::Mutex;
+
+use anyhow::Result;
+use ra_tls::simulator::{rtmr3_event, QuoteSimulator, SimProfile};
+use tdx_attest::eventlog::TdxEventLog;
+
+pub struct SimulatedTd {
+    quoter: QuoteSimulator,
+    event_log: Mutex<Vec<TdxEventLog>>,
+}
+
+impl SimulatedTd {
+    pub fn new(profile: SimProfile) -> Result<Self> {
+        let quoter = QuoteSimulator::new(profile)?;
+        let event_log = Mutex::new(quoter.event_log());
+        Ok(Self { quoter, event_log })
+    }
+
+    pub fn event_log(&self) -> Vec<TdxEventLog> {
+        self.event_log.lock().unwrap().clone()
+    }
+
+    pub fn extend_rtmr3(&self, event: &str, payload: &[u8]) {
+        self.event_log
+            .lock()
+            .unwrap()
+            .push(rtmr3_event(event, payload));
+    }
+
+    /// Synthesize a quote over `report_data`, with the event log it was taken over.
+    pub fn quote(&self, report_data: &[u8; 64]) -> Result<(Vec<u8>, Vec<TdxEventLog>)> {
+        let event_log = self.event_log();
+        let quote = self.quoter.quote(report_data, &event_log)?;
+        Ok((quote, event_log))
+    }
+}
@@ -1,4 +1,6 @@
 use load_config::load_config;
+use ra_rpc::signed_request::SignedRequestPolicy;
+use ra_tls::{collateral::CollateralConfig, sev_snp::SnpConfig, tcb_policy::TcbPolicy};
 use rocket::figment::Figment;
 use serde::Deserialize;
 use std::{path::PathBuf, time::Duration};
@@ -31,6 +33,16 @@ pub(crate) struct ImageConfig {
 pub(crate) struct KmsConfig {
     pub cert_dir: PathBuf,
     pub pccs_url: Option<String>,
+    #[serde(default)]
+    pub collateral: CollateralConfig,
+    #[serde(default)]
+    pub tcb_policy: TcbPolicy,
+    #[serde(default)]
+    pub sev_snp: SnpConfig,
+    #[serde(default)]
+    pub signed_requests: Option<SignedRequestPolicy>,
+    #[serde(default)]
+    pub attestation_token: AttestationTokenConfig,
     pub auth_api: AuthApi,
     pub onboard: OnboardConfig,
     pub image: ImageConfig,
@@ -86,6 +98,25 @@ impl KmsConfig {
     }
 }
 
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub(crate) struct AttestationTokenConfig {
+    /// The issuer (`iss`) of the tokens, usually the public URL of the KMS
+    pub issuer: String,
+    /// How long the tokens are valid
+    #[serde(with = "serde_duration")]
+    pub ttl: Duration,
+}
+
+impl Default for AttestationTokenConfig {
+    fn default() -> Self {
+        Self {
+            issuer: "dstack-kms".into(),
+            ttl: Duration::from_secs(300),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(tag = "type")]
 pub(crate) enum AuthApi {
@@ -6,8 +6,8 @@ use ra_rpc::rocket_helper::QuoteVerifier;
 use rocket::{
     fairing::AdHoc,
     figment::{providers::Serialized, Figment},
-    response::content::RawHtml,
-    Shutdown,
+    response::content::{RawHtml, RawJson},
+    Shutdown, State,
 };
 use tracing::{info, warn};
 
@@ -73,6 +73,13 @@ async fn run_onboard_service(kms_config: KmsConfig, figment: Figment) -> Result<
     Ok(())
 }
 
+#[rocket::get("/.well-known/jwks.json")]
+fn jwks(state: &State<KmsState>) -> Result<RawJson<String>, String> {
+    serde_json::to_string(&state.token_jwks())
+        .map(RawJson)
+        .map_err(|err| err.to_string())
+}
+
 #[rocket::main]
 async fn main() -> Result<()> {
     {
@@ -104,7 +111,11 @@ async fn main() -> Result<()> {
         info!("  /prpc/{method}");
     }
 
+    ra_tls::collateral::init(&config.collateral).context("Failed to set up collateral cache")?;
+    ra_tls::sev_snp::init(&config.sev_snp).context("Failed to set up SEV-SNP verifier")?;
     let pccs_url = config.pccs_url.clone();
+    let tcb_policy = config.tcb_policy.clone();
+    let signed_requests = config.signed_requests.clone();
     let state = main_service::KmsState::new(config).context("Failed to initialize KMS state")?;
     let figment = figment
         .clone()
@@ -119,9 +130,22 @@ async fn main() -> Result<()> {
             "/prpc",
             ra_rpc::prpc_routes!(KmsState, RpcHandler, trim: "KMS."),
         )
+        .mount("/", rocket::routes![jwks])
         .manage(state);
 
-    let verifier = QuoteVerifier::new(pccs_url);
+    let verifier = QuoteVerifier::new(pccs_url).with_tcb_policy(tcb_policy);
+    let verifier = match signed_requests {
+        Some(policy) => verifier
+            .with_signed_requests(policy)
+            .context("Invalid signed request policy")?,
+        None => verifier,
+    };
+    #[cfg(feature = "simulator")]
+    let verifier = {
+        use ra_tls::simulator::{SimVerifier, DEFAULT_PCK_SEED};
+        warn!("Trusting quotes of the simulator, never use this build in production");
+        verifier.with_sim_verifier(SimVerifier::new(DEFAULT_PCK_SEED)?)
+    };
     rocket = rocket.manage(verifier);
 
     rocket
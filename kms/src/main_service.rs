@@ -7,18 +7,20 @@ use std::{
 use anyhow::{bail, Context, Result};
 use dstack_kms_rpc::{
     kms_server::{KmsRpc, KmsServer},
-    AppId, AppKeyResponse, ClearImageCacheRequest, GetAppKeyRequest, GetKmsKeyRequest,
-    GetMetaResponse, GetTempCaCertResponse, KmsKeyResponse, KmsKeys, PublicKeyResponse,
-    SignCertRequest, SignCertResponse,
+    AppId, AppKeyResponse, AttestationTokenKeysResponse, AttestationTokenResponse,
+    CheckAppUpgradeRequest, ClearImageCacheRequest, GetAppKeyRequest, GetKmsKeyRequest,
+    GetMetaResponse, GetTempCaCertResponse, IssueAttestationTokenRequest, KmsKeyResponse, KmsKeys,
+    PublicKeyResponse, SignCertRequest, SignCertResponse,
 };
 use dstack_types::VmConfig;
 use fs_err as fs;
 use k256::ecdsa::SigningKey;
 use ra_rpc::{Attestation, CallContext, RpcCall};
 use ra_tls::{
-    attestation::VerifiedAttestation,
+    attestation::{AppInfo, TeeKind, VerifiedAttestation},
     cert::{CaCert, CertRequest, CertSigningRequest},
     kdf,
+    token::{Jwks, TokenSigner},
 };
 use scale::Decode;
 use serde::{Deserialize, Serialize};
@@ -53,6 +55,7 @@ pub struct KmsStateInner {
     k256_key: SigningKey,
     temp_ca_cert: String,
     temp_ca_key: String,
+    token_signer: TokenSigner,
 }
 
 impl KmsState {
@@ -66,6 +69,10 @@ impl KmsState {
             fs::read_to_string(config.tmp_ca_key()).context("Faeild to read temp ca key")?;
         let temp_ca_cert =
             fs::read_to_string(config.tmp_ca_cert()).context("Faeild to read temp ca cert")?;
+        let token_key = kdf::derive_ecdsa_key_pair(&root_ca.key, &[b"attestation-token"])
+            .context("Failed to derive the attestation token key")?;
+        let token_signer = TokenSigner::new(&token_key, config.attestation_token.issuer.clone())
+            .context("Failed to load the attestation token key")?;
         Ok(Self {
             inner: Arc::new(KmsStateInner {
                 config,
@@ -73,9 +80,15 @@ impl KmsState {
                 k256_key,
                 temp_ca_cert,
                 temp_ca_key,
+                token_signer,
             }),
         })
     }
+
+    /// The JWK set verifying the attestation result tokens.
+    pub fn token_jwks(&self) -> Jwks {
+        self.token_signer.jwks()
+    }
 }
 
 pub struct RpcHandler {
@@ -208,7 +221,12 @@ impl RpcHandler {
         Ok(())
     }
 
-    async fn verify_os_image_hash(&self, vm_config: &VmConfig, report: &BootInfo) -> Result<()> {
+    async fn verify_os_image_hash(
+        &self,
+        vm_config: &VmConfig,
+        report: &BootInfo,
+        tee_kind: TeeKind,
+    ) -> Result<()> {
         if !self.state.config.image.verify {
             info!("Image verification is disabled");
             return Ok(());
@@ -221,7 +239,11 @@ impl RpcHandler {
         let cache_key = {
             let vm_config =
                 serde_json::to_vec(vm_config).context("Failed to serialize VM config")?;
-            hex::encode(sha2::Sha256::new_with_prefix(&vm_config).finalize())
+            let key = hex::encode(sha2::Sha256::new_with_prefix(&vm_config).finalize());
+            match tee_kind {
+                TeeKind::SevSnp => format!("snp-{key}"),
+                _ => key,
+            }
         };
         if let Ok(cached_mrs) = self.get_cached_mrs(&cache_key) {
             cached_mrs
@@ -250,6 +272,32 @@ impl RpcHandler {
         let image_info: dstack_types::ImageInfo =
             serde_json::from_str(&image_info).context("Failed to parse image metadata")?;
 
+        if tee_kind == TeeKind::SevSnp {
+            // The launch measurement of SEV-SNP covers the firmware, kernel, initrd and cmdline
+            // like MRTD and RTMR0-2 do for TDX. It is computed when the image is built.
+            let measurement = image_info
+                .sev_snp_measurements
+                .get(&vm_config.cpu_count)
+                .with_context(|| {
+                    format!(
+                        "Image has no SEV-SNP measurement for {} vCPUs",
+                        vm_config.cpu_count
+                    )
+                })?;
+            let zero = hex::encode([0u8; 48]);
+            let expected_mrs = Mrs {
+                mrtd: measurement.to_ascii_lowercase(),
+                rtmr0: zero.clone(),
+                rtmr1: zero.clone(),
+                rtmr2: zero,
+            };
+            self.cache_mrs(&cache_key, &expected_mrs)
+                .context("Failed to cache MRs")?;
+            return expected_mrs
+                .assert_eq(&verified_mrs)
+                .context("SEV-SNP measurement does not match");
+        }
+
         let fw_path = image_dir.join(&image_info.bios);
         let kernel_path = image_dir.join(&image_info.kernel);
         let initrd_path = image_dir.join(&image_info.initrd);
@@ -423,24 +471,49 @@ impl RpcHandler {
         use_boottime_mr: bool,
         vm_config: &str,
     ) -> Result<BootConfig> {
-        let report = att
-            .report
-            .report
-            .as_td10()
-            .context("Failed to decode TD report")?;
+        let (boot_info, vm_config) = self.boot_info_of(att, use_boottime_mr, vm_config)?;
+        self.ensure_boot_info_allowed(boot_info, is_kms, &vm_config, att.report.tee_kind())
+            .await
+    }
+
+    fn boot_info_of(
+        &self,
+        att: &VerifiedAttestation,
+        use_boottime_mr: bool,
+        vm_config: &str,
+    ) -> Result<(BootInfo, VmConfig)> {
         let app_info = att.decode_app_info(use_boottime_mr)?;
+        // SEV-SNP guests have no RTMRs, their app info carries the launch measurement instead
+        let (mrtd, rtmrs) = match att.report.tee_kind() {
+            TeeKind::Tdx => {
+                let report = att.report.as_td10().context("Failed to decode TD report")?;
+                (
+                    report.mr_td,
+                    [report.rt_mr0, report.rt_mr1, report.rt_mr2, report.rt_mr3],
+                )
+            }
+            TeeKind::SevSnp => (
+                app_info.mrtd,
+                [
+                    app_info.rtmr0,
+                    app_info.rtmr1,
+                    app_info.rtmr2,
+                    app_info.rtmr3,
+                ],
+            ),
+            TeeKind::Sgx => bail!("SGX attestation is not supported"),
+        };
         debug!("vm_config: {vm_config}");
         let vm_config: VmConfig =
             serde_json::from_str(vm_config).context("Failed to decode VM config")?;
-        let os_image_hash = vm_config.os_image_hash.clone();
         let boot_info = BootInfo {
-            mrtd: report.mr_td.to_vec(),
-            rtmr0: report.rt_mr0.to_vec(),
-            rtmr1: report.rt_mr1.to_vec(),
-            rtmr2: report.rt_mr2.to_vec(),
-            rtmr3: report.rt_mr3.to_vec(),
+            mrtd: mrtd.to_vec(),
+            rtmr0: rtmrs[0].to_vec(),
+            rtmr1: rtmrs[1].to_vec(),
+            rtmr2: rtmrs[2].to_vec(),
+            rtmr3: rtmrs[3].to_vec(),
             mr_aggregated: app_info.mr_aggregated.to_vec(),
-            os_image_hash: os_image_hash.clone(),
+            os_image_hash: vm_config.os_image_hash.clone(),
             mr_system: app_info.mr_system.to_vec(),
             app_id: app_info.app_id,
             compose_hash: app_info.compose_hash,
@@ -449,9 +522,20 @@ impl RpcHandler {
             key_provider_info: app_info.key_provider_info,
             event_log: String::from_utf8(att.raw_event_log.clone())
                 .context("Failed to serialize event log")?,
-            tcb_status: att.report.status.clone(),
-            advisory_ids: att.report.advisory_ids.clone(),
+            tcb_status: att.report.status().to_string(),
+            advisory_ids: att.report.advisory_ids().to_vec(),
         };
+        Ok((boot_info, vm_config))
+    }
+
+    async fn ensure_boot_info_allowed(
+        &self,
+        boot_info: BootInfo,
+        is_kms: bool,
+        vm_config: &VmConfig,
+        tee_kind: TeeKind,
+    ) -> Result<BootConfig> {
+        ensure_identity_bound(tee_kind)?;
         let response = self
             .state
             .config
@@ -461,13 +545,13 @@ impl RpcHandler {
         if !response.is_allowed {
             bail!("Boot denied: {}", response.reason);
         }
-        self.verify_os_image_hash(&vm_config, &boot_info)
+        self.verify_os_image_hash(vm_config, &boot_info, tee_kind)
             .await
             .context("Failed to verify os image hash")?;
         Ok(BootConfig {
             boot_info,
             gateway_app_id: response.gateway_app_id,
-            os_image_hash,
+            os_image_hash: vm_config.os_image_hash.clone(),
         })
     }
 
@@ -492,6 +576,33 @@ impl RpcHandler {
     }
 }
 
+/// Reject TEEs whose app identity is not measured by the guest itself.
+///
+/// Nothing binds the app identity of an SEV-SNP guest at launch yet, see [`ra_tls::sev_snp`].
+fn ensure_identity_bound(tee_kind: TeeKind) -> Result<()> {
+    if tee_kind == TeeKind::SevSnp {
+        bail!("SEV-SNP guests are not supported yet");
+    }
+    Ok(())
+}
+
+/// Check that two attestations come from the same boot of the same app instance.
+fn ensure_same_instance(caller: &AppInfo, quoted: &AppInfo) -> Result<()> {
+    if caller.app_id != quoted.app_id {
+        bail!("App id mismatch");
+    }
+    if caller.instance_id != quoted.instance_id {
+        bail!("Instance id mismatch");
+    }
+    if caller.device_id != quoted.device_id {
+        bail!("Device id mismatch");
+    }
+    if caller.mr_system != quoted.mr_system {
+        bail!("System measurement mismatch");
+    }
+    Ok(())
+}
+
 impl KmsRpc for RpcHandler {
     async fn get_app_key(self, request: GetAppKeyRequest) -> Result<AppKeyResponse> {
         if request.api_version > 1 {
@@ -616,7 +727,11 @@ impl KmsRpc for RpcHandler {
             .context("Failed to verify csr signature")?;
         let attestation = Attestation::new(csr.quote.clone(), csr.event_log.clone())
             .context("Failed to create attestation from quote and event log")?
-            .verify_with_ra_pubkey(&csr.pubkey, self.state.config.pccs_url.as_deref())
+            .verify_with_ra_pubkey(
+                &csr.pubkey,
+                self.state.config.pccs_url.as_deref(),
+                &self.state.config.tcb_policy,
+            )
             .await
             .context("Quote verification failed")?;
         let app_info = self
@@ -635,6 +750,27 @@ impl KmsRpc for RpcHandler {
         })
     }
 
+    async fn check_app_upgrade(self, request: CheckAppUpgradeRequest) -> Result<()> {
+        if request.compose_hash.len() != 32 {
+            bail!("Invalid compose hash length");
+        }
+        let vm_config: VmConfig =
+            serde_json::from_str(&request.vm_config).context("Failed to decode VM config")?;
+        // Only apps getting their keys from `get_app_key` can upgrade in place, so they are
+        // judged as apps, with exactly the boot info their running instance is admitted with.
+        let BootConfig { mut boot_info, .. } = self
+            .ensure_app_boot_allowed(&request.vm_config)
+            .await
+            .context("Current app not allowed")?;
+        // The upgraded app is judged as if it had booted with the new compose
+        boot_info.compose_hash = request.compose_hash;
+        let tee_kind = self.ensure_attested()?.report.tee_kind();
+        self.ensure_boot_info_allowed(boot_info, false, &vm_config, tee_kind)
+            .await
+            .context("Upgrade not allowed")?;
+        Ok(())
+    }
+
     async fn clear_image_cache(self, request: ClearImageCacheRequest) -> Result<()> {
         self.ensure_admin(&request.token)?;
         self.remove_cache(&self.image_cache_dir(), &request.image_hash)
@@ -643,6 +779,53 @@ impl KmsRpc for RpcHandler {
             .context("Failed to clear MR cache")?;
         Ok(())
     }
+
+    async fn issue_attestation_token(
+        self,
+        request: IssueAttestationTokenRequest,
+    ) -> Result<AttestationTokenResponse> {
+        let caller = self.ensure_attested()?;
+        let attestation = if request.quote.is_empty() {
+            caller.clone()
+        } else {
+            let attestation = Attestation::new(request.quote, request.event_log)
+                .context("Failed to create attestation from quote and event log")?;
+            // The report data is passed through to the token for the relying party to check
+            let report_data = attestation.decode_report_data()?;
+            let attestation = attestation
+                .verify(
+                    &report_data,
+                    self.state.config.pccs_url.as_deref(),
+                    &self.state.config.tcb_policy,
+                )
+                .await
+                .context("Quote verification failed")?;
+            // Otherwise anyone holding a quote could get a token vouching for their report data
+            ensure_same_instance(
+                &caller.decode_app_info(false)?,
+                &attestation.decode_app_info(false)?,
+            )
+            .context("The quote is not of the caller")?;
+            attestation
+        };
+        ensure_identity_bound(attestation.report.tee_kind())?;
+        let token = self
+            .state
+            .token_signer
+            .issue(
+                &attestation,
+                request.audience.as_deref(),
+                self.state.config.attestation_token.ttl,
+            )
+            .context("Failed to issue attestation token")?;
+        Ok(AttestationTokenResponse { token })
+    }
+
+    async fn get_attestation_token_keys(self) -> Result<AttestationTokenKeysResponse> {
+        let jwks =
+            serde_json::to_string(&self.state.token_jwks()).context("Failed to encode JWKS")?;
+        Ok(AttestationTokenKeysResponse { jwks })
+    }
 }
 
 impl RpcCall<KmsState> for RpcHandler {
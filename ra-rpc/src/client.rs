@@ -1,19 +1,28 @@
-use std::time::Duration;
+use std::{
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
 
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use futures::Stream;
 use prpc::{
     client::{Error, RequestClient},
     Message,
 };
 use ra_tls::{
     attestation::{Attestation, VerifiedAttestation},
+    tcb_policy::TcbPolicy,
     traits::CertExt,
+    verifier::{Expectation, RaTlsVerifier},
 };
 use reqwest::{tls::TlsInfo, Certificate, Client, Identity, Response};
 use serde::{de::DeserializeOwned, Serialize};
+use tracing::warn;
 
 use bon::Builder;
 
+use crate::signed_request::RequestSigner;
+
 pub struct CertInfo {
     pub cert_der: Vec<u8>,
     pub attestation: Option<VerifiedAttestation>,
@@ -23,9 +32,161 @@ pub struct CertInfo {
 
 type CertValidator = Box<dyn Fn(Option<CertInfo>) -> Result<()> + Send + Sync + 'static>;
 
+/// A server a [`RaClient`] can send requests to.
+#[derive(Debug, Clone)]
+pub struct RaEndpoint {
+    uri: String,
+    ra_tls_expectation: Option<Expectation>,
+}
+
+impl RaEndpoint {
+    pub fn new(uri: impl Into<String>) -> Self {
+        Self {
+            uri: uri.into(),
+            ra_tls_expectation: None,
+        }
+    }
+
+    /// Expect this endpoint to run `expectation`, instead of the expectation of the client.
+    pub fn with_ra_tls_expectation(mut self, expectation: Expectation) -> Self {
+        self.ra_tls_expectation = Some(expectation);
+        self
+    }
+}
+
+impl From<String> for RaEndpoint {
+    fn from(uri: String) -> Self {
+        Self::new(uri)
+    }
+}
+
+impl From<&str> for RaEndpoint {
+    fn from(uri: &str) -> Self {
+        Self::new(uri)
+    }
+}
+
+/// How a request is retried when an endpoint fails.
+///
+/// Failed connections are retried on the next endpoint. Timeouts, 5xx responses and attestation
+/// failures may come after the server acted on the request, so they are only retried for the
+/// `idempotent_methods`. Other error responses come from the application and are returned as is.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Attempts per request across all endpoints, one per endpoint if unset.
+    pub max_attempts: Option<u32>,
+    /// Delay before the first retry, doubled for each further retry.
+    pub initial_backoff: Duration,
+    /// Upper bound of the delay between retries.
+    pub max_backoff: Duration,
+    /// Timeout of each attempt, instead of the timeout of the client.
+    pub attempt_timeout: Option<Duration>,
+    /// Methods that are safe to send again once a server may have received them, by their names
+    /// without the service prefix, e.g. `GetAppKey`.
+    pub idempotent_methods: Vec<String>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: None,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(2),
+            attempt_timeout: None,
+            idempotent_methods: vec![],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Mark `methods` as safe to retry after timeouts and server errors.
+    pub fn with_idempotent_methods(mut self, methods: &[&str]) -> Self {
+        self.idempotent_methods = methods.iter().map(|m| m.to_string()).collect();
+        self
+    }
+
+    fn backoff(&self, retry: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(1 << retry.min(16))
+            .min(self.max_backoff)
+    }
+
+    fn is_idempotent(&self, path: &str) -> bool {
+        let method = path.rsplit('.').next().unwrap_or(path);
+        self.idempotent_methods.iter().any(|m| m == method)
+    }
+}
+
+/// When an endpoint is taken out of rotation.
+///
+/// After `failure_threshold` consecutive failures the endpoint is skipped for `open_duration`,
+/// unless every endpoint is. It then gets a single attempt, which puts it back into rotation if it
+/// succeeds and skips it again if it fails.
+#[derive(Debug, Clone)]
+pub struct CircuitBreakerPolicy {
+    pub failure_threshold: u32,
+    pub open_duration: Duration,
+}
+
+impl Default for CircuitBreakerPolicy {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 3,
+            open_duration: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Health {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl Health {
+    fn is_open(&self, now: Instant) -> bool {
+        self.open_until.is_some_and(|until| until > now)
+    }
+}
+
+/// The audience a request to `uri` is signed for: its `host[:port]`.
+fn audience_of(uri: &str) -> Result<String> {
+    let url = reqwest::Url::parse(uri).context("Invalid endpoint URI")?;
+    let host = url.host_str().context("Endpoint URI has no host")?;
+    Ok(match url.port() {
+        Some(port) => format!("{host}:{port}"),
+        None => host.to_string(),
+    })
+}
+
+struct EndpointClient {
+    uri: String,
+    client: Client,
+    health: Mutex<Health>,
+}
+
+/// The failure of a single attempt.
+enum AttemptError {
+    /// The request never reached the endpoint, try another one.
+    Unreachable(anyhow::Error),
+    /// The endpoint is misbehaving, try another one if the request can be repeated.
+    Endpoint(anyhow::Error),
+    /// The endpoint rejected the request.
+    Request(anyhow::Error),
+}
+
 #[derive(Builder)]
 pub struct RaClientConfig {
+    /// The first endpoint, tried before `endpoints`.
+    #[builder(into, default)]
     remote_uri: String,
+    /// Further endpoints, tried in order of health.
+    #[builder(default)]
+    endpoints: Vec<RaEndpoint>,
+    #[builder(default)]
+    retry: RetryPolicy,
+    #[builder(default)]
+    circuit_breaker: CircuitBreakerPolicy,
     #[builder(default = false)]
     tls_no_check: bool,
     #[builder(default = true)]
@@ -38,11 +199,21 @@ pub struct RaClientConfig {
     #[builder(default = true)]
     tls_built_in_root_certs: bool,
     pccs_url: Option<String>,
+    #[builder(default)]
+    tcb_policy: TcbPolicy,
     cert_validator: Option<CertValidator>,
+    /// Verify the server's RA-TLS certificate during the handshake, instead of the usual CA
+    /// based verification. Endpoints can override it.
+    ra_tls_expectation: Option<Expectation>,
+    /// Sign each request with the client key and send the certificate chain in headers, for
+    /// servers behind proxies that terminate TLS. Each request is signed for the `host[:port]`
+    /// of the endpoint it is sent to.
+    #[builder(default = false)]
+    sign_requests: bool,
 }
 
 impl RaClientConfig {
-    pub fn into_client(self) -> Result<RaClient> {
+    fn build_http_client(&self, expectation: Option<&Expectation>) -> Result<Client> {
         let mut builder = Client::builder()
             .tls_sni(true)
             .danger_accept_invalid_certs(self.tls_no_check)
@@ -53,33 +224,89 @@ impl RaClientConfig {
         if self.cert_validator.is_some() {
             builder = builder.tls_info(true);
         }
-        if let (Some(cert_pem), Some(key_pem)) = (self.tls_client_cert, self.tls_client_key) {
+        if let Some(expectation) = expectation {
+            let identity = self
+                .tls_client_cert
+                .as_deref()
+                .zip(self.tls_client_key.as_deref());
+            let tls = RaTlsVerifier::new(expectation.clone())
+                .with_pccs_url(self.pccs_url.clone())
+                .with_tcb_policy(self.tcb_policy.clone())
+                .client_config(identity)
+                .context("Failed to create RA-TLS config")?;
+            builder = builder.use_preconfigured_tls(tls);
+        }
+        if let (Some(cert_pem), Some(key_pem)) = (&self.tls_client_cert, &self.tls_client_key) {
             let identity_pem = format!("{cert_pem}\n{key_pem}");
             let identity =
                 Identity::from_pem(identity_pem.as_bytes()).context("Failed to parse identity")?;
             builder = builder.identity(identity);
         }
-        if let Some(ca) = self.tls_ca_cert {
+        if let Some(ca) = &self.tls_ca_cert {
             let ca = Certificate::from_pem(ca.as_bytes()).context("Failed to parse CA")?;
             builder = builder.add_root_certificate(ca);
         }
-        let client = builder.build().context("failed to create client")?;
+        builder.build().context("failed to create client")
+    }
+
+    pub fn into_client(self) -> Result<RaClient> {
+        let request_signer = if self.sign_requests {
+            let (Some(cert_pem), Some(key_pem)) = (&self.tls_client_cert, &self.tls_client_key)
+            else {
+                bail!("Signing requests requires a client certificate and key");
+            };
+            let signer = RequestSigner::from_pem(cert_pem, key_pem)
+                .context("Failed to create request signer")?;
+            Some(signer)
+        } else {
+            None
+        };
+        let primary = (!self.remote_uri.is_empty()).then(|| RaEndpoint::new(&self.remote_uri));
+        let mut endpoints = vec![];
+        for endpoint in primary.iter().chain(&self.endpoints) {
+            let expectation = endpoint
+                .ra_tls_expectation
+                .as_ref()
+                .or(self.ra_tls_expectation.as_ref());
+            let client = self
+                .build_http_client(expectation)
+                .with_context(|| format!("Failed to create client for {}", endpoint.uri))?;
+            endpoints.push(EndpointClient {
+                uri: endpoint.uri.clone(),
+                client,
+                health: Default::default(),
+            });
+        }
+        if endpoints.is_empty() {
+            bail!("No endpoint configured");
+        }
+        if self.retry.max_attempts == Some(0) {
+            bail!("At least one attempt is required");
+        }
         Ok(RaClient {
-            remote_uri: self.remote_uri,
+            endpoints,
+            retry: self.retry,
+            circuit_breaker: self.circuit_breaker,
+            last_endpoint: Mutex::new(None),
             pccs_url: self.pccs_url,
-            client,
+            tcb_policy: self.tcb_policy,
             cert_validator: self.cert_validator,
             verify_server_attestation: self.verify_server_attestation,
+            request_signer,
         })
     }
 }
 
 pub struct RaClient {
-    remote_uri: String,
+    endpoints: Vec<EndpointClient>,
+    retry: RetryPolicy,
+    circuit_breaker: CircuitBreakerPolicy,
+    last_endpoint: Mutex<Option<usize>>,
     pccs_url: Option<String>,
-    client: Client,
+    tcb_policy: TcbPolicy,
     cert_validator: Option<CertValidator>,
     verify_server_attestation: bool,
+    request_signer: Option<RequestSigner>,
 }
 
 impl RaClient {
@@ -135,7 +362,11 @@ impl RaClient {
                 None => None,
                 Some(attestation) => {
                     let verified_attestation = attestation
-                        .verify_with_ra_pubkey(cert.public_key().raw, self.pccs_url.as_deref())
+                        .verify_with_ra_pubkey(
+                            cert.public_key().raw,
+                            self.pccs_url.as_deref(),
+                            &self.tcb_policy,
+                        )
                         .await
                         .context("Failed to verify the attestation report")?;
                     Some(verified_attestation)
@@ -150,33 +381,204 @@ impl RaClient {
         };
         validator(Some(cert_info))
     }
-}
 
-impl RequestClient for RaClient {
-    async fn request<T, R>(&self, path: &str, body: T) -> Result<R, Error>
-    where
-        T: Message + Serialize,
-        R: Message + DeserializeOwned,
-    {
-        let body = serde_json::to_vec(&body).context("Failed to serialize body")?;
-        let url = format!("{}/{}?json", self.remote_uri, path);
-        let response = self
-            .client
-            .post(url)
-            .body(body)
-            .send()
-            .await
-            .context("Failed to send request")?;
+    /// The endpoints to try for a request, healthiest first. Endpoints with an open circuit are
+    /// left out unless all of them have one.
+    fn attempt_order(&self) -> Vec<usize> {
+        let now = Instant::now();
+        let mut order: Vec<_> = self
+            .endpoints
+            .iter()
+            .enumerate()
+            .map(|(index, endpoint)| {
+                let health = endpoint
+                    .health
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner);
+                (health.is_open(now), health.consecutive_failures, index)
+            })
+            .collect();
+        order.sort_unstable();
+        if order.iter().any(|(open, ..)| !open) {
+            order.retain(|(open, ..)| !open);
+        }
+        order.into_iter().map(|(.., index)| index).collect()
+    }
+
+    /// The URI of the endpoint that answered the last successful request.
+    pub fn last_endpoint(&self) -> Option<&str> {
+        let index = (*self
+            .last_endpoint
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner))?;
+        Some(&self.endpoints[index].uri)
+    }
+
+    fn record_outcome(&self, endpoint: &EndpointClient, healthy: bool) {
+        let mut health = endpoint
+            .health
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if healthy {
+            *health = Health::default();
+            return;
+        }
+        health.consecutive_failures = health.consecutive_failures.saturating_add(1);
+        if health.consecutive_failures >= self.circuit_breaker.failure_threshold {
+            health.open_until = Some(Instant::now() + self.circuit_breaker.open_duration);
+        }
+    }
+
+    async fn send_to(
+        &self,
+        endpoint: &EndpointClient,
+        path: &str,
+        body: &[u8],
+    ) -> Result<Response, AttemptError> {
+        let url = format!("{}/{}?json", endpoint.uri, path);
+        let mut request = endpoint.client.post(url);
+        if let Some(signer) = &self.request_signer {
+            let headers = audience_of(&endpoint.uri)
+                .and_then(|audience| signer.sign(&audience, path, body))
+                .context("Failed to sign request")
+                .map_err(AttemptError::Request)?;
+            for (name, value) in headers {
+                request = request.header(name, value);
+            }
+        }
+        if let Some(timeout) = self.retry.attempt_timeout {
+            request = request.timeout(timeout);
+        }
+        let response = request.body(body.to_vec()).send().await.map_err(|err| {
+            let unreachable = err.is_connect();
+            let err = anyhow::Error::new(err).context("Failed to send request");
+            if unreachable {
+                AttemptError::Unreachable(err)
+            } else {
+                AttemptError::Endpoint(err)
+            }
+        })?;
 
         self.try_validate_attestation(&response)
             .await
-            .context("Failed to validate attestation")?;
+            .context("Failed to validate attestation")
+            .map_err(AttemptError::Endpoint)?;
 
         let status = response.status();
         if !status.is_success() {
             let body = response.text().await.unwrap_or_default();
-            bail!("Request failed with status={status}, error={body}");
+            let err = anyhow!("Request failed with status={status}, error={body}");
+            if status.is_server_error() {
+                return Err(AttemptError::Endpoint(err));
+            }
+            return Err(AttemptError::Request(err));
         }
+        Ok(response)
+    }
+
+    async fn send(&self, path: &str, body: impl Serialize) -> Result<Response> {
+        let body = serde_json::to_vec(&body).context("Failed to serialize body")?;
+        let order = self.attempt_order();
+        let max_attempts = self.retry.max_attempts.unwrap_or(order.len() as u32);
+        let idempotent = self.retry.is_idempotent(path);
+        let mut last_err = anyhow!("No attempt made");
+        for attempt in 0..max_attempts {
+            if attempt > 0 {
+                tokio::time::sleep(self.retry.backoff(attempt - 1)).await;
+            }
+            let index = order[attempt as usize % order.len()];
+            let endpoint = &self.endpoints[index];
+            let err = match self.send_to(endpoint, path, &body).await {
+                Ok(response) => {
+                    self.record_outcome(endpoint, true);
+                    *self
+                        .last_endpoint
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner) = Some(index);
+                    return Ok(response);
+                }
+                Err(AttemptError::Request(err)) => {
+                    self.record_outcome(endpoint, true);
+                    return Err(err);
+                }
+                Err(AttemptError::Unreachable(err)) => err,
+                Err(AttemptError::Endpoint(err)) if idempotent => err,
+                Err(AttemptError::Endpoint(err)) => {
+                    // The server may have acted on the request already
+                    self.record_outcome(endpoint, false);
+                    return Err(err);
+                }
+            };
+            self.record_outcome(endpoint, false);
+            if attempt + 1 < max_attempts {
+                warn!("request {path} to {} failed: {err:?}", endpoint.uri);
+            }
+            last_err = err;
+        }
+        if max_attempts > 1 {
+            last_err = last_err.context(format!("Request failed after {max_attempts} attempts"));
+        }
+        Err(last_err)
+    }
+
+    /// Call a server-streaming method, yielding the messages as they arrive.
+    ///
+    /// The stream is cut off at the request timeout of the client.
+    pub async fn request_stream<T, R>(
+        &self,
+        path: &str,
+        body: T,
+    ) -> Result<impl Stream<Item = Result<R>>>
+    where
+        T: Serialize,
+        R: DeserializeOwned,
+    {
+        let response = self.send(path, body).await?;
+        Ok(futures::stream::unfold(
+            Some((response, Vec::new())),
+            |state| async move {
+                let (mut response, mut buf) = state?;
+                loop {
+                    if let Some(pos) = buf.iter().position(|&b| b == b'\n') {
+                        let line: Vec<u8> = buf.drain(..=pos).collect();
+                        let message = decode_stream_line(&line);
+                        let next = message.is_ok().then_some((response, buf));
+                        return Some((message, next));
+                    }
+                    match response.chunk().await {
+                        Ok(Some(chunk)) => buf.extend_from_slice(&chunk),
+                        Ok(None) if buf.is_empty() => return None,
+                        Ok(None) => return Some((Err(anyhow!("Truncated message")), None)),
+                        Err(err) => {
+                            return Some((Err(err).context("Failed to read response"), None))
+                        }
+                    }
+                }
+            },
+        ))
+    }
+}
+
+/// Decode a line of a streaming JSON response, which is an error if the stream failed.
+fn decode_stream_line<R: DeserializeOwned>(line: &[u8]) -> Result<R> {
+    #[derive(serde::Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct ErrorLine {
+        error: String,
+    }
+    if let Ok(ErrorLine { error }) = serde_json::from_slice(line) {
+        bail!("Stream failed: {error}");
+    }
+    serde_json::from_slice(line).context("Failed to deserialize message")
+}
+
+impl RequestClient for RaClient {
+    async fn request<T, R>(&self, path: &str, body: T) -> Result<R, Error>
+    where
+        T: Message + Serialize,
+        R: Message + DeserializeOwned,
+    {
+        let response = self.send(path, body).await?;
         let body = response
             .bytes()
             .await
@@ -186,3 +588,198 @@ impl RequestClient for RaClient {
         Ok(response)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    #[derive(Clone, Copy)]
+    enum Fault {
+        None,
+        ServerError,
+        Reject,
+        Hang,
+    }
+
+    /// Serve every request with `fault`, counting the requests.
+    async fn stand_in_server(fault: Fault) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/prpc", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut buf = vec![0; 4096];
+                    let _ = stream.read(&mut buf).await;
+                    let (status, body) = match fault {
+                        Fault::None => ("200 OK", r#"{"pong":true}"#),
+                        Fault::ServerError => ("500 Internal Server Error", "overloaded"),
+                        Fault::Reject => ("400 Bad Request", "not allowed"),
+                        Fault::Hang => {
+                            tokio::time::sleep(Duration::from_secs(30)).await;
+                            return;
+                        }
+                    };
+                    let response = format!(
+                        "HTTP/1.1 {status}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                        body.len()
+                    );
+                    let _ = stream.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+        (url, requests)
+    }
+
+    async fn refused_endpoint() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        format!("http://{}/prpc", listener.local_addr().unwrap())
+    }
+
+    fn fast_retry() -> RetryPolicy {
+        RetryPolicy {
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+            attempt_timeout: Some(Duration::from_millis(200)),
+            ..Default::default()
+        }
+        .with_idempotent_methods(&["Ping"])
+    }
+
+    #[tokio::test]
+    async fn test_failover() {
+        let down = refused_endpoint().await;
+        let (failing, failing_requests) = stand_in_server(Fault::ServerError).await;
+        let (healthy, healthy_requests) = stand_in_server(Fault::None).await;
+        let client = RaClientConfig::builder()
+            .remote_uri(down)
+            .endpoints(vec![failing.into(), healthy.into()])
+            .retry(fast_retry())
+            .circuit_breaker(CircuitBreakerPolicy {
+                failure_threshold: 2,
+                open_duration: Duration::from_secs(60),
+            })
+            .build()
+            .into_client()
+            .unwrap();
+
+        let response = client.send("Test.Ping", ()).await.unwrap();
+        assert_eq!(response.text().await.unwrap(), r#"{"pong":true}"#);
+        assert_eq!(failing_requests.load(Ordering::SeqCst), 1);
+        assert_eq!(healthy_requests.load(Ordering::SeqCst), 1);
+
+        // The healthy endpoint is tried first from now on
+        assert_eq!(client.attempt_order(), [2, 0, 1]);
+        client.send("Test.Ping", ()).await.unwrap();
+        assert_eq!(failing_requests.load(Ordering::SeqCst), 1);
+        assert_eq!(healthy_requests.load(Ordering::SeqCst), 2);
+
+        // A second failure opens the circuit of an endpoint
+        client.record_outcome(&client.endpoints[0], false);
+        assert_eq!(client.attempt_order(), [2, 1]);
+        client.record_outcome(&client.endpoints[2], false);
+        client.record_outcome(&client.endpoints[2], false);
+        client.record_outcome(&client.endpoints[1], false);
+        assert_eq!(client.attempt_order(), [0, 1, 2]);
+        client.record_outcome(&client.endpoints[0], true);
+        assert_eq!(client.attempt_order(), [0]);
+    }
+
+    #[tokio::test]
+    async fn test_retry_policy() {
+        let (hanging, _) = stand_in_server(Fault::Hang).await;
+        let (rejecting, rejecting_requests) = stand_in_server(Fault::Reject).await;
+        let (healthy, healthy_requests) = stand_in_server(Fault::None).await;
+        let client = RaClientConfig::builder()
+            .endpoints(vec![hanging.into(), rejecting.into(), healthy.into()])
+            .retry(fast_retry())
+            .build()
+            .into_client()
+            .unwrap();
+
+        // Rejections come from the application and are not retried
+        let err = client.send("Test.Ping", ()).await.unwrap_err();
+        assert!(format!("{err:?}").contains("status=400"));
+        assert_eq!(rejecting_requests.load(Ordering::SeqCst), 1);
+        assert_eq!(healthy_requests.load(Ordering::SeqCst), 0);
+
+        let (failing, failing_requests) = stand_in_server(Fault::ServerError).await;
+        let client = RaClientConfig::builder()
+            .remote_uri(failing)
+            .retry(RetryPolicy {
+                max_attempts: Some(3),
+                ..fast_retry()
+            })
+            .build()
+            .into_client()
+            .unwrap();
+        let err = client.send("Test.Ping", ()).await.unwrap_err();
+        assert_eq!(err.to_string(), "Request failed after 3 attempts");
+        assert_eq!(failing_requests.load(Ordering::SeqCst), 3);
+
+        assert!(RaClientConfig::builder().build().into_client().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_non_idempotent_retry() {
+        let down = refused_endpoint().await;
+        let (failing, failing_requests) = stand_in_server(Fault::ServerError).await;
+        let (healthy, healthy_requests) = stand_in_server(Fault::None).await;
+        let client = RaClientConfig::builder()
+            .remote_uri(failing)
+            .endpoints(vec![healthy.clone().into()])
+            .retry(fast_retry())
+            .build()
+            .into_client()
+            .unwrap();
+
+        // A server error may come after the server acted on the request
+        let err = client.send("Test.Create", ()).await.unwrap_err();
+        assert!(format!("{err:?}").contains("status=500"));
+        assert_eq!(failing_requests.load(Ordering::SeqCst), 1);
+        assert_eq!(healthy_requests.load(Ordering::SeqCst), 0);
+        assert_eq!(client.last_endpoint(), None);
+
+        // A request that never got through is safe to send elsewhere
+        let client = RaClientConfig::builder()
+            .remote_uri(down)
+            .endpoints(vec![healthy.clone().into()])
+            .retry(fast_retry())
+            .build()
+            .into_client()
+            .unwrap();
+        client.send("Test.Create", ()).await.unwrap();
+        assert_eq!(healthy_requests.load(Ordering::SeqCst), 1);
+        assert_eq!(client.last_endpoint(), Some(healthy.as_str()));
+    }
+
+    #[test]
+    fn test_audience_of() {
+        assert_eq!(
+            audience_of("https://kms.example:8000/prpc").unwrap(),
+            "kms.example:8000"
+        );
+        assert_eq!(
+            audience_of("https://kms.example/prpc").unwrap(),
+            "kms.example"
+        );
+        assert_eq!(
+            audience_of("https://kms.example:443").unwrap(),
+            "kms.example"
+        );
+        assert!(audience_of("kms.example").is_err());
+    }
+}
@@ -2,8 +2,9 @@
 
 use std::{net::SocketAddr, path::PathBuf};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use prpc::{codec::encode_message_to_vec, server::Service as PrpcService};
+use streaming::RpcStream;
 use tracing::{error, info};
 
 pub use ra_tls::attestation::{Attestation, VerifiedAttestation};
@@ -11,6 +12,9 @@ pub use ra_tls::attestation::{Attestation, VerifiedAttestation};
 #[cfg(feature = "rocket")]
 pub mod rocket_helper;
 
+pub mod signed_request;
+pub mod streaming;
+
 #[cfg(feature = "client")]
 pub mod client;
 
@@ -43,6 +47,20 @@ pub trait RpcCall<State>: Sized {
         is_json: bool,
         is_query: bool,
     ) -> (u16, Vec<u8>) {
+        match self.try_call(method, payload, is_json, is_query).await {
+            Ok(data) => (200, data),
+            Err(err) => (400, encode_error(is_json, err)),
+        }
+    }
+
+    /// Like [`RpcCall::call`], returning the error message unencoded.
+    async fn try_call(
+        self,
+        method: String,
+        payload: Vec<u8>,
+        is_json: bool,
+        is_query: bool,
+    ) -> Result<Vec<u8>, String> {
         dispatch_prpc(
             method,
             payload,
@@ -52,6 +70,27 @@ pub trait RpcCall<State>: Sized {
         )
         .await
     }
+
+    /// The server-streaming methods, by their names in the request path with the route prefix
+    /// trimmed. Calls to them go to [`RpcCall::call_streaming`].
+    ///
+    /// The generated pRPC servers have no streaming methods, so these are listed by hand.
+    fn streaming_methods() -> &'static [&'static str] {
+        &[]
+    }
+
+    /// Call a server-streaming method, returning the encoded messages as they are produced.
+    ///
+    /// The messages are encoded as JSON if `is_json`, see [`streaming::encode_messages`].
+    async fn call_streaming(
+        self,
+        method: String,
+        payload: Vec<u8>,
+        is_json: bool,
+    ) -> Result<RpcStream> {
+        let _ = (payload, is_json);
+        bail!("method {method} is not a streaming method")
+    }
 }
 
 async fn dispatch_prpc(
@@ -60,17 +99,15 @@ async fn dispatch_prpc(
     json: bool,
     query: bool,
     server: impl PrpcService + Send + 'static,
-) -> (u16, Vec<u8>) {
+) -> Result<Vec<u8>, String> {
     info!("dispatching request: {path}");
-    let result = server.dispatch_request(&path, data, json, query).await;
-    let (code, data) = match result {
-        Ok(data) => (200, data),
-        Err(err) => {
+    server
+        .dispatch_request(&path, data, json, query)
+        .await
+        .map_err(|err| {
             error!("rpc error: {err:?}");
-            (400, encode_error(json, format!("{err:?}")))
-        }
-    };
-    (code, data)
+            format!("{err:?}")
+        })
 }
 
 pub fn encode_error(json: bool, error: impl Into<String>) -> Vec<u8> {
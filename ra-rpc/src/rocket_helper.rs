@@ -1,36 +1,94 @@
-use std::convert::Infallible;
+use std::{convert::Infallible, pin::Pin, sync::Arc};
 
 use anyhow::{Context, Result};
-use ra_tls::{attestation::Attestation, traits::CertExt};
+use futures::Stream;
+#[cfg(feature = "simulator")]
+use ra_tls::{attestation::QuoteContentType, simulator::SimVerifier};
+use ra_tls::{
+    attestation::{Attestation, VerifiedAttestation},
+    tcb_policy::TcbPolicy,
+    traits::CertExt,
+};
 use rocket::{
     data::{ByteUnit, Data, Limits, ToByteUnit},
     http::{uri::Origin, ContentType, Method, Status},
     listener::Endpoint,
     mtls::Certificate,
     request::{FromRequest, Outcome},
-    response::{status::Custom, Responder},
+    response::{status::Custom, stream::ByteStream, Responder},
     Request,
 };
 use rocket_vsock_listener::VsockEndpoint;
 use tracing::warn;
 
-use crate::{encode_error, CallContext, RemoteEndpoint, RpcCall};
+use crate::{
+    encode_error,
+    signed_request::{ReplayGuard, SignedRequest, SignedRequestPolicy},
+    streaming::{decode_frame, encode_frame, encode_trailers, frame_stream, GRPC_OK, GRPC_UNKNOWN},
+    CallContext, RemoteEndpoint, RpcCall,
+};
 
 pub struct RpcResponse {
-    is_json: bool,
+    encoding: Encoding,
     status: Status,
-    body: Vec<u8>,
+    body: RpcBody,
+}
+
+enum RpcBody {
+    Full(Vec<u8>),
+    Stream(Pin<Box<dyn Stream<Item = Vec<u8>> + Send>>),
+}
+
+/// How request and response messages are encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    Protobuf,
+    Json,
+    GrpcWeb,
+}
+
+impl Encoding {
+    fn content_type(self, streaming: bool) -> ContentType {
+        match self {
+            Self::Protobuf => ContentType::Binary,
+            Self::Json if streaming => ContentType::new("application", "x-ndjson"),
+            Self::Json => ContentType::JSON,
+            Self::GrpcWeb => ContentType::new("application", "grpc-web+proto"),
+        }
+    }
+}
+
+impl RpcResponse {
+    fn error(encoding: Encoding, error: String) -> Self {
+        match encoding {
+            // gRPC reports failures in the trailers of a successful response
+            Encoding::GrpcWeb => Self {
+                encoding,
+                status: Status::Ok,
+                body: RpcBody::Full(encode_trailers(GRPC_UNKNOWN, &error)),
+            },
+            _ => Self {
+                encoding,
+                status: Status::BadRequest,
+                body: RpcBody::Full(encode_error(encoding == Encoding::Json, error)),
+            },
+        }
+    }
 }
 
 impl<'r> Responder<'r, 'static> for RpcResponse {
     fn respond_to(self, request: &'r Request<'_>) -> rocket::response::Result<'static> {
-        use rocket::http::ContentType;
-        let content_type = if self.is_json {
-            ContentType::JSON
-        } else {
-            ContentType::Binary
+        let (content_type, response) = match self.body {
+            RpcBody::Full(body) => (
+                self.encoding.content_type(false),
+                Custom(self.status, body).respond_to(request)?,
+            ),
+            RpcBody::Stream(stream) => {
+                let mut response = ByteStream(stream).respond_to(request)?;
+                response.set_status(self.status);
+                (self.encoding.content_type(true), response)
+            }
         };
-        let response = Custom(self.status, self.body).respond_to(request)?;
         rocket::Response::build_from(response)
             .header(content_type)
             .ok()
@@ -40,6 +98,17 @@ impl<'r> Responder<'r, 'static> for RpcResponse {
 #[derive(Debug, Clone)]
 pub struct QuoteVerifier {
     pccs_url: Option<String>,
+    tcb_policy: TcbPolicy,
+    signed_requests: Option<Arc<SignedRequests>>,
+    #[cfg(feature = "simulator")]
+    sim_verifier: Option<Arc<SimVerifier>>,
+}
+
+/// The policy and replay state of signed requests.
+#[derive(Debug)]
+struct SignedRequests {
+    policy: SignedRequestPolicy,
+    replay_guard: ReplayGuard,
 }
 
 pub mod deps {
@@ -172,7 +241,62 @@ impl<'r> FromRequest<'r> for &'r QuoteVerifier {
 
 impl QuoteVerifier {
     pub fn new(pccs_url: Option<String>) -> Self {
-        Self { pccs_url }
+        Self {
+            pccs_url,
+            tcb_policy: TcbPolicy::default(),
+            signed_requests: None,
+            #[cfg(feature = "simulator")]
+            sim_verifier: None,
+        }
+    }
+
+    pub fn with_tcb_policy(self, tcb_policy: TcbPolicy) -> Self {
+        Self { tcb_policy, ..self }
+    }
+
+    /// Accept attestation from signed request headers when the client presents no certificate,
+    /// for the methods and audiences of `policy`. Fails if the policy allows a key release
+    /// method.
+    ///
+    /// See [`crate::signed_request`] for when this is safe to enable.
+    pub fn with_signed_requests(self, policy: SignedRequestPolicy) -> Result<Self> {
+        policy.validate()?;
+        let replay_guard = ReplayGuard::new(policy.max_skew);
+        Ok(Self {
+            signed_requests: Some(Arc::new(SignedRequests {
+                policy,
+                replay_guard,
+            })),
+            ..self
+        })
+    }
+
+    /// Verify quotes against the test PCK chain of the simulator instead. Only for tests.
+    #[cfg(feature = "simulator")]
+    pub fn with_sim_verifier(self, sim_verifier: SimVerifier) -> Self {
+        Self {
+            sim_verifier: Some(Arc::new(sim_verifier)),
+            ..self
+        }
+    }
+
+    /// Verify an attestation bound to the RA-TLS public key `ra_pubkey_der`.
+    pub async fn verify(
+        &self,
+        attestation: Attestation,
+        ra_pubkey_der: &[u8],
+    ) -> Result<VerifiedAttestation> {
+        #[cfg(feature = "simulator")]
+        if let Some(sim_verifier) = &self.sim_verifier {
+            return attestation.verify_simulated(
+                &QuoteContentType::RaTlsCert.to_report_data(ra_pubkey_der),
+                sim_verifier,
+                &self.tcb_policy,
+            );
+        }
+        attestation
+            .verify_with_ra_pubkey(ra_pubkey_der, self.pccs_url.as_deref(), &self.tcb_policy)
+            .await
     }
 }
 
@@ -208,7 +332,10 @@ pub struct RpcRequest<'r> {
     origin: &'r Origin<'r>,
     limits: &'r Limits,
     content_type: Option<&'r ContentType>,
+    signed_request: Option<SignedRequest<'r>>,
     json: bool,
+    grpc_web: bool,
+    grpc: bool,
     is_get: bool,
 }
 
@@ -217,6 +344,12 @@ impl<'r> FromRequest<'r> for RpcRequest<'r> {
     type Error = Infallible;
 
     async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let grpc_sub = request
+            .content_type()
+            .filter(|content_type| content_type.top() == "application")
+            .map(|content_type| content_type.sub().as_str());
+        let grpc_web = grpc_sub.is_some_and(|sub| sub.starts_with("grpc-web"));
+        let grpc = !grpc_web && grpc_sub.is_some_and(|sub| sub.starts_with("grpc"));
         Outcome::Success(Self {
             remote_addr: from_request!(request),
             certificate: from_request!(request),
@@ -224,7 +357,10 @@ impl<'r> FromRequest<'r> for RpcRequest<'r> {
             origin: from_request!(request),
             limits: from_request!(request),
             content_type: from_request!(request),
+            signed_request: SignedRequest::from_headers(|name| request.headers().get_one(name)),
             json: request.method() == Method::Get || query_field_get_bool(request, "json"),
+            grpc_web,
+            grpc,
             is_get: request.method() == Method::Get,
         })
     }
@@ -232,19 +368,20 @@ impl<'r> FromRequest<'r> for RpcRequest<'r> {
 
 impl<S> PrpcHandler<'_, '_, S> {
     pub async fn handle<Call: RpcCall<S>>(self) -> RpcResponse {
-        let json = self.request.json;
+        let encoding = if self.request.grpc_web {
+            Encoding::GrpcWeb
+        } else if self.request.json {
+            Encoding::Json
+        } else {
+            Encoding::Protobuf
+        };
         let result = handle_prpc_impl::<S, Call>(self).await;
         match result {
             Ok(output) => output,
             Err(e) => {
                 let estr = format!("{e:?}");
                 warn!("error handling prpc: {estr}");
-                let body = encode_error(json, estr);
-                RpcResponse {
-                    is_json: json,
-                    status: Status::BadRequest,
-                    body,
-                }
+                RpcResponse::error(encoding, estr)
             }
         }
     }
@@ -280,6 +417,10 @@ pub async fn handle_prpc_impl<S, Call: RpcCall<S>>(
         method_trim_prefix,
         data,
     } = args;
+    if request.grpc {
+        anyhow::bail!("application/grpc needs HTTP/2 trailers and is not served, use gRPC-web");
+    }
+    let full_method = method;
     let method = method.trim_start_matches(method_trim_prefix.unwrap_or_default());
     let remote_app_id = request
         .certificate
@@ -287,6 +428,7 @@ pub async fn handle_prpc_impl<S, Call: RpcCall<S>>(
         .map(|cert| RocketCertificate(cert).get_app_id())
         .transpose()?
         .flatten();
+    let has_certificate = request.certificate.is_some();
     let attestation = request
         .certificate
         .as_ref()
@@ -301,8 +443,8 @@ pub async fn handle_prpc_impl<S, Call: RpcCall<S>>(
                 .public_key()
                 .raw
                 .to_vec();
-            let verified = attestation
-                .verify_with_ra_pubkey(&pubkey, quote_verifier.pccs_url.as_deref())
+            let verified = quote_verifier
+                .verify(attestation, &pubkey)
                 .await
                 .context("invalid quote")?;
             Some(verified)
@@ -321,7 +463,29 @@ pub async fn handle_prpc_impl<S, Call: RpcCall<S>>(
             .query()
             .map_or(vec![], |q| q.as_bytes().to_vec()),
     };
-    let is_json = request.json || request.content_type.map(|t| t.is_json()).unwrap_or(false);
+    // Clients without a certificate may authenticate the request itself
+    let (attestation, remote_app_id) = match (&request.signed_request, request.quote_verifier) {
+        (Some(signed), Some(quote_verifier))
+            if !has_certificate && quote_verifier.signed_requests.is_some() =>
+        {
+            let (attestation, app_id) =
+                verify_signed_request(signed, full_method, &payload, quote_verifier).await?;
+            (Some(attestation), app_id)
+        }
+        _ => (attestation, remote_app_id),
+    };
+    let encoding = if request.grpc_web {
+        Encoding::GrpcWeb
+    } else if request.json || request.content_type.map(|t| t.is_json()).unwrap_or(false) {
+        Encoding::Json
+    } else {
+        Encoding::Protobuf
+    };
+    let payload = match encoding {
+        Encoding::GrpcWeb => decode_frame(&payload).context("invalid grpc-web request")?,
+        _ => payload,
+    };
+    let is_json = encoding == Encoding::Json;
     let context = CallContext {
         state,
         attestation,
@@ -329,16 +493,75 @@ pub async fn handle_prpc_impl<S, Call: RpcCall<S>>(
         remote_app_id,
     };
     let call = Call::construct(context).context("failed to construct call")?;
+    if Call::streaming_methods().contains(&method) {
+        let stream = call
+            .call_streaming(method.to_string(), payload, is_json)
+            .await?;
+        return Ok(RpcResponse {
+            encoding,
+            status: Status::Ok,
+            body: RpcBody::Stream(Box::pin(frame_stream(stream, is_json))),
+        });
+    }
+    if encoding == Encoding::GrpcWeb {
+        let body = match call
+            .try_call(method.to_string(), payload, false, request.is_get)
+            .await
+        {
+            Ok(output) => [encode_frame(&output), encode_trailers(GRPC_OK, "")].concat(),
+            Err(err) => encode_trailers(GRPC_UNKNOWN, &err),
+        };
+        return Ok(RpcResponse {
+            encoding,
+            status: Status::Ok,
+            body: RpcBody::Full(body),
+        });
+    }
     let (status_code, output) = call
         .call(method.to_string(), payload, is_json, request.is_get)
         .await;
     Ok(RpcResponse {
-        is_json,
+        encoding,
         status: Status::new(status_code),
-        body: output,
+        body: RpcBody::Full(output),
     })
 }
 
+/// Verify a signed request and the attestation of its signing certificate, returning the
+/// attestation and the app id of the certificate.
+async fn verify_signed_request(
+    signed: &SignedRequest<'_>,
+    method: &str,
+    payload: &[u8],
+    quote_verifier: &QuoteVerifier,
+) -> Result<(VerifiedAttestation, Option<Vec<u8>>)> {
+    let signed_requests = quote_verifier
+        .signed_requests
+        .as_deref()
+        .context("signed requests are not accepted")?;
+    let leaf = signed
+        .verify(&signed_requests.policy, method, payload)
+        .context("invalid signed request")?;
+    // Reject replays before the costly quote verification
+    signed
+        .check_fresh(&leaf, &signed_requests.replay_guard)
+        .context("invalid signed request")?;
+    let attestation =
+        Attestation::from_der(&leaf)?.context("signed request without attestation")?;
+    let (_, cert) =
+        x509_parser::parse_x509_certificate(&leaf).context("failed to parse certificate")?;
+    let verified = quote_verifier
+        .verify(attestation, cert.public_key().raw)
+        .await
+        .context("invalid quote")?;
+    // Only attested signers may keep nonces in the replay guard
+    signed
+        .consume(&leaf, &signed_requests.replay_guard)
+        .context("invalid signed request")?;
+    let app_id = cert.get_app_id()?;
+    Ok((verified, app_id))
+}
+
 struct RocketCertificate<'a>(&'a rocket::mtls::Certificate<'a>);
 
 impl CertExt for RocketCertificate<'_> {
@@ -352,3 +575,188 @@ impl CertExt for RocketCertificate<'_> {
         Ok(Some(ext.value.to_vec()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use anyhow::bail;
+    use futures::{stream, StreamExt};
+    use host_api::{
+        host_api_server::{HostApiRpc, HostApiServer},
+        GetSealingKeyRequest, GetSealingKeyResponse, HostInfo, Notification,
+    };
+    use prpc::Message;
+    use rocket::local::asynchronous::Client;
+
+    use super::*;
+    use crate::streaming::{encode_messages, RpcStream};
+
+    struct TestState;
+
+    struct TestHandler;
+
+    impl RpcCall<TestState> for TestHandler {
+        type PrpcService = HostApiServer<Self>;
+
+        fn construct(_context: CallContext<'_, TestState>) -> Result<Self> {
+            Ok(Self)
+        }
+
+        fn streaming_methods() -> &'static [&'static str] {
+            &["Watch"]
+        }
+
+        async fn call_streaming(
+            self,
+            method: String,
+            _payload: Vec<u8>,
+            is_json: bool,
+        ) -> Result<RpcStream> {
+            if method != "Watch" {
+                bail!("unknown streaming method {method}");
+            }
+            let infos = stream::iter([1, 2, 3]).map(|i| {
+                if i == 3 {
+                    bail!("host went away");
+                }
+                Ok(HostInfo {
+                    name: format!("host-{i}"),
+                    version: "1".into(),
+                })
+            });
+            Ok(encode_messages(infos, is_json))
+        }
+    }
+
+    impl HostApiRpc for TestHandler {
+        async fn info(self) -> Result<HostInfo> {
+            Ok(HostInfo {
+                name: "host".into(),
+                version: "1".into(),
+            })
+        }
+
+        async fn notify(self, _request: Notification) -> Result<()> {
+            bail!("notifications are off")
+        }
+
+        async fn get_sealing_key(
+            self,
+            _request: GetSealingKeyRequest,
+        ) -> Result<GetSealingKeyResponse> {
+            bail!("no sealing key")
+        }
+    }
+
+    async fn client() -> Client {
+        let rocket = rocket::build().manage(TestState).mount(
+            "/prpc",
+            crate::prpc_routes!(TestState, TestHandler, trim: "HostApi."),
+        );
+        Client::tracked(rocket).await.unwrap()
+    }
+
+    /// Split a gRPC-web body into its frames.
+    fn frames(mut body: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        let mut frames = vec![];
+        while let Some((header, rest)) = body.split_first_chunk::<5>() {
+            let len = u32::from_be_bytes(header[1..].try_into().unwrap()) as usize;
+            frames.push((header[0], rest[..len].to_vec()));
+            body = &rest[len..];
+        }
+        frames
+    }
+
+    fn grpc_web() -> ContentType {
+        ContentType::new("application", "grpc-web+proto")
+    }
+
+    #[rocket::async_test]
+    async fn test_grpc_web_unary() {
+        let client = client().await;
+        let response = client
+            .post("/prpc/HostApi.Info")
+            .header(grpc_web())
+            .body(encode_frame(&[]))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(grpc_web()));
+        let frames = frames(&response.into_bytes().await.unwrap());
+        let [(0, message), (0x80, trailers)] = &frames[..] else {
+            panic!("unexpected frames {frames:?}");
+        };
+        assert_eq!(HostInfo::decode(&message[..]).unwrap().name, "host");
+        assert_eq!(trailers, b"grpc-status: 0\r\n");
+
+        // Failures go to the trailers
+        let request = Notification::default().encode_to_vec();
+        let response = client
+            .post("/prpc/HostApi.Notify")
+            .header(grpc_web())
+            .body(encode_frame(&request))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let frames = frames(&response.into_bytes().await.unwrap());
+        let [(0x80, trailers)] = &frames[..] else {
+            panic!("unexpected frames {frames:?}");
+        };
+        let trailers = String::from_utf8_lossy(trailers);
+        assert!(trailers.starts_with("grpc-status: 2\r\n"), "{trailers}");
+        assert!(trailers.contains("notifications%20are%20off"), "{trailers}");
+    }
+
+    #[rocket::async_test]
+    async fn test_streaming() {
+        let client = client().await;
+        let response = client
+            .post("/prpc/HostApi.Watch")
+            .header(grpc_web())
+            .body(encode_frame(&[]))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let frames = frames(&response.into_bytes().await.unwrap());
+        let [(0, first), (0, second), (0x80, trailers)] = &frames[..] else {
+            panic!("unexpected frames {frames:?}");
+        };
+        assert_eq!(HostInfo::decode(&first[..]).unwrap().name, "host-1");
+        assert_eq!(HostInfo::decode(&second[..]).unwrap().name, "host-2");
+        assert!(trailers.starts_with(b"grpc-status: 2\r\n"));
+
+        let response = client
+            .post("/prpc/HostApi.Watch?json")
+            .header(ContentType::JSON)
+            .body("{}")
+            .dispatch()
+            .await;
+        assert_eq!(
+            response.content_type(),
+            Some(ContentType::new("application", "x-ndjson"))
+        );
+        let body = response.into_string().await.unwrap();
+        let lines: Vec<serde_json::Value> = body
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3, "{body}");
+        assert_eq!(lines[0]["name"], "host-1");
+        assert_eq!(lines[1]["name"], "host-2");
+        assert!(lines[2]["error"]
+            .as_str()
+            .unwrap()
+            .contains("host went away"));
+    }
+
+    #[rocket::async_test]
+    async fn test_native_grpc_rejected() {
+        let client = client().await;
+        let response = client
+            .post("/prpc/HostApi.Info")
+            .header(ContentType::new("application", "grpc"))
+            .body(encode_frame(&[]))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+}
@@ -0,0 +1,508 @@
+//! Request-level attestation for clients that cannot present a TLS client certificate.
+//!
+//! A client behind a TLS-terminating proxy or load balancer signs each pRPC request with the key
+//! of its RA-TLS certificate and sends the certificate chain, the audience, a timestamp, a nonce
+//! and the signature in headers. The server checks the audience and the signature against the
+//! leaf certificate, verifies the attestation of the leaf certificate as it would for a TLS
+//! client certificate, and rejects stale and replayed requests.
+//!
+//! The proxy terminating TLS sees the responses, so servers only accept signed requests for the
+//! methods listed in their [`SignedRequestPolicy`], and never for the [`KEY_RELEASE_METHODS`].
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use ra_tls::rcgen::KeyPair;
+use ring::{
+    rand::{SecureRandom, SystemRandom},
+    signature::{
+        EcdsaKeyPair, UnparsedPublicKey, ECDSA_P256_SHA256_ASN1, ECDSA_P256_SHA256_ASN1_SIGNING,
+    },
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+/// Header carrying the base64-encoded DER certificates of the signer, leaf first, comma separated.
+pub const HEADER_CERT_CHAIN: &str = "x-ra-cert-chain";
+/// Header carrying the server the request is signed for, as `host[:port]` of the URL called.
+pub const HEADER_AUDIENCE: &str = "x-ra-audience";
+/// Header carrying the signing time, in seconds since the unix epoch.
+pub const HEADER_TIMESTAMP: &str = "x-ra-timestamp";
+/// Header carrying a random hex nonce, unique per request.
+pub const HEADER_NONCE: &str = "x-ra-nonce";
+/// Header carrying the base64-encoded ECDSA signature of the request.
+pub const HEADER_SIGNATURE: &str = "x-ra-signature";
+
+const SIGNATURE_DOMAIN: &str = "dstack-ra-rpc-request-v2";
+
+/// Methods whose responses carry keys, which a proxy terminating TLS must never see.
+pub const KEY_RELEASE_METHODS: &[&str] = &[
+    "GetAppKey",
+    "GetKmsKey",
+    "GetTempCaCert",
+    "GetKey",
+    "DeriveKey",
+    "GetTlsKey",
+];
+
+/// Nonces remembered per signer within the skew window, bounding the requests a signer can make
+/// in that window.
+const MAX_NONCES_PER_SIGNER: usize = 4096;
+
+/// The method name without the service prefix.
+fn short_method(method: &str) -> &str {
+    method.rsplit('.').next().unwrap_or(method)
+}
+
+/// The message signed for a request: the audience, the pRPC method, the headers and the hash of
+/// the payload.
+fn signing_message(
+    audience: &str,
+    method: &str,
+    timestamp: u64,
+    nonce: &str,
+    payload: &[u8],
+) -> Vec<u8> {
+    let payload_hash = hex::encode(Sha256::digest(payload));
+    format!("{SIGNATURE_DOMAIN}\n{audience}\n{method}\n{timestamp}\n{nonce}\n{payload_hash}")
+        .into_bytes()
+}
+
+/// Which requests a server accepts signed headers for, in place of a client certificate.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SignedRequestPolicy {
+    /// How far the signing time may be from the local clock
+    #[serde(with = "serde_duration")]
+    pub max_skew: Duration,
+    /// The names clients reach the server by, as `host[:port]` of the URL they call
+    pub audiences: Vec<String>,
+    /// The methods accepting signed requests, with or without the service prefix
+    pub methods: Vec<String>,
+}
+
+impl Default for SignedRequestPolicy {
+    fn default() -> Self {
+        Self {
+            max_skew: Duration::from_secs(60),
+            audiences: vec![],
+            methods: vec![],
+        }
+    }
+}
+
+impl SignedRequestPolicy {
+    /// Reject policies allowing a key release method.
+    pub fn validate(&self) -> Result<()> {
+        for method in &self.methods {
+            if KEY_RELEASE_METHODS.contains(&short_method(method)) {
+                bail!("Signed requests can not be accepted for {method}, it releases keys");
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether signed requests are accepted for `method`.
+    pub fn allows(&self, method: &str) -> bool {
+        self.methods
+            .iter()
+            .any(|allowed| allowed == method || allowed == short_method(method))
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Signs requests with the key of an RA-TLS certificate.
+pub struct RequestSigner {
+    key: EcdsaKeyPair,
+    cert_chain: String,
+    rng: SystemRandom,
+}
+
+impl RequestSigner {
+    /// A signer from a PEM certificate chain, leaf first, and the PEM key of the leaf.
+    pub fn from_pem(cert_pem: &str, key_pem: &str) -> Result<Self> {
+        let mut chain = vec![];
+        for pem in x509_parser::pem::Pem::iter_from_buffer(cert_pem.as_bytes()) {
+            let pem = pem.context("Failed to parse certificate chain")?;
+            chain.push(BASE64.encode(pem.contents));
+        }
+        if chain.is_empty() {
+            bail!("No certificate in chain");
+        }
+        let key = KeyPair::from_pem(key_pem).context("Failed to parse key")?;
+        let rng = SystemRandom::new();
+        let key =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, key.serialized_der(), &rng)
+                .map_err(|err| anyhow!("Unsupported request signing key: {err}"))?;
+        Ok(Self {
+            key,
+            cert_chain: chain.join(","),
+            rng,
+        })
+    }
+
+    /// The headers authenticating a call of `method` with `payload` to `audience`.
+    pub fn sign(
+        &self,
+        audience: &str,
+        method: &str,
+        payload: &[u8],
+    ) -> Result<Vec<(&'static str, String)>> {
+        let mut nonce = [0u8; 16];
+        self.rng
+            .fill(&mut nonce)
+            .map_err(|err| anyhow!("Failed to generate nonce: {err}"))?;
+        let nonce = hex::encode(nonce);
+        let timestamp = unix_now();
+        let signature = self
+            .key
+            .sign(
+                &self.rng,
+                &signing_message(audience, method, timestamp, &nonce, payload),
+            )
+            .map_err(|err| anyhow!("Failed to sign request: {err}"))?;
+        Ok(vec![
+            (HEADER_CERT_CHAIN, self.cert_chain.clone()),
+            (HEADER_AUDIENCE, audience.to_string()),
+            (HEADER_TIMESTAMP, timestamp.to_string()),
+            (HEADER_NONCE, nonce),
+            (HEADER_SIGNATURE, BASE64.encode(signature.as_ref())),
+        ])
+    }
+}
+
+/// The authentication headers of a signed request.
+#[derive(Debug, Clone)]
+pub struct SignedRequest<'r> {
+    cert_chain: &'r str,
+    audience: &'r str,
+    timestamp: &'r str,
+    nonce: &'r str,
+    signature: &'r str,
+}
+
+impl<'r> SignedRequest<'r> {
+    /// Read the headers with `get_header`, or `None` if the request is not signed.
+    pub fn from_headers(get_header: impl Fn(&str) -> Option<&'r str>) -> Option<Self> {
+        Some(Self {
+            cert_chain: get_header(HEADER_CERT_CHAIN)?,
+            audience: get_header(HEADER_AUDIENCE)?,
+            timestamp: get_header(HEADER_TIMESTAMP)?,
+            nonce: get_header(HEADER_NONCE)?,
+            signature: get_header(HEADER_SIGNATURE)?,
+        })
+    }
+
+    /// Check the audience against `policy` and the signature against the leaf certificate, and
+    /// return the DER of the leaf certificate.
+    ///
+    /// The request is not checked for freshness, see [`Self::check_fresh`].
+    pub fn verify(
+        &self,
+        policy: &SignedRequestPolicy,
+        method: &str,
+        payload: &[u8],
+    ) -> Result<Vec<u8>> {
+        if !policy.allows(method) {
+            bail!("Signed requests are not accepted for {method}");
+        }
+        if !policy.audiences.iter().any(|aud| aud == self.audience) {
+            bail!("Request signed for another audience: {}", self.audience);
+        }
+        let timestamp = self.timestamp()?;
+        let leaf = self
+            .cert_chain
+            .split(',')
+            .next()
+            .context("Empty certificate chain")?;
+        let leaf = BASE64
+            .decode(leaf.trim())
+            .context("Invalid certificate encoding")?;
+        let (_, cert) =
+            x509_parser::parse_x509_certificate(&leaf).context("Failed to parse certificate")?;
+        let signature = BASE64
+            .decode(self.signature)
+            .context("Invalid signature encoding")?;
+        UnparsedPublicKey::new(
+            &ECDSA_P256_SHA256_ASN1,
+            &cert.public_key().subject_public_key.data,
+        )
+        .verify(
+            &signing_message(self.audience, method, timestamp, self.nonce, payload),
+            &signature,
+        )
+        .map_err(|_| anyhow!("Invalid request signature"))?;
+        Ok(leaf)
+    }
+
+    /// Reject the request if it is stale or replayed by `signer`, the DER of the leaf
+    /// certificate returned by [`Self::verify`].
+    pub fn check_fresh(&self, signer: &[u8], guard: &ReplayGuard) -> Result<()> {
+        guard.check(signer, self.timestamp()?, self.nonce, unix_now())
+    }
+
+    /// Like [`Self::check_fresh`], but also record the nonce so the request can not be replayed.
+    pub fn consume(&self, signer: &[u8], guard: &ReplayGuard) -> Result<()> {
+        guard.record(signer, self.timestamp()?, self.nonce, unix_now())
+    }
+
+    fn timestamp(&self) -> Result<u64> {
+        self.timestamp.parse().context("Invalid request timestamp")
+    }
+}
+
+/// Rejects stale requests and nonces seen within the accepted clock skew, per signer.
+#[derive(Debug)]
+pub struct ReplayGuard {
+    max_skew: Duration,
+    seen: Mutex<SeenNonces>,
+}
+
+#[derive(Debug, Default)]
+struct SeenNonces {
+    by_signer: HashMap<[u8; 32], HashMap<String, u64>>,
+    last_sweep: u64,
+}
+
+impl SeenNonces {
+    /// Forget the expired nonces of `signer`, and those of all signers once per skew window.
+    fn prune(&mut self, signer: &[u8; 32], max_skew: u64, now: u64) {
+        // Nonces older than the skew window are covered by the timestamp check
+        let fresh = |nonces: &mut HashMap<String, u64>| {
+            nonces.retain(|_, seen_at| seen_at.saturating_add(max_skew) >= now);
+            !nonces.is_empty()
+        };
+        if now.saturating_sub(self.last_sweep) > max_skew {
+            self.by_signer.retain(|_, nonces| fresh(nonces));
+            self.last_sweep = now;
+        } else if let Some(nonces) = self.by_signer.get_mut(signer) {
+            if !fresh(nonces) {
+                self.by_signer.remove(signer);
+            }
+        }
+    }
+}
+
+impl ReplayGuard {
+    /// A guard accepting requests signed at most `max_skew` away from the local clock.
+    pub fn new(max_skew: Duration) -> Self {
+        Self {
+            max_skew,
+            seen: Mutex::new(SeenNonces::default()),
+        }
+    }
+
+    /// Check that a request of `signer` signed at `timestamp` with `nonce` is fresh as of
+    /// `now`, without recording it.
+    ///
+    /// Cheap enough to run before the attestation of the signer is verified.
+    pub fn check(&self, signer: &[u8], timestamp: u64, nonce: &str, now: u64) -> Result<()> {
+        self.update(signer, timestamp, nonce, now, false)
+    }
+
+    /// Accept a request of `signer` signed at `timestamp` with `nonce` if it is fresh as of
+    /// `now`, and record the nonce.
+    ///
+    /// Only signers with a verified attestation should be recorded, as each is allowed to keep
+    /// up to [`MAX_NONCES_PER_SIGNER`] nonces in memory.
+    pub fn record(&self, signer: &[u8], timestamp: u64, nonce: &str, now: u64) -> Result<()> {
+        self.update(signer, timestamp, nonce, now, true)
+    }
+
+    fn update(
+        &self,
+        signer: &[u8],
+        timestamp: u64,
+        nonce: &str,
+        now: u64,
+        record: bool,
+    ) -> Result<()> {
+        let max_skew = self.max_skew.as_secs();
+        if timestamp.abs_diff(now) > max_skew {
+            bail!("Request timestamp out of range");
+        }
+        if nonce.len() < 16 || nonce.len() > 64 {
+            bail!("Invalid request nonce");
+        }
+        let signer: [u8; 32] = Sha256::digest(signer).into();
+        let mut seen = self
+            .seen
+            .lock()
+            .map_err(|_| anyhow!("Replay guard poisoned"))?;
+        seen.prune(&signer, max_skew, now);
+        if let Some(nonces) = seen.by_signer.get(&signer) {
+            if nonces.contains_key(nonce) {
+                bail!("Replayed request");
+            }
+            if nonces.len() >= MAX_NONCES_PER_SIGNER {
+                bail!("Too many signed requests");
+            }
+        }
+        if record {
+            seen.by_signer
+                .entry(signer)
+                .or_default()
+                .insert(nonce.to_string(), timestamp);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ra_tls::rcgen::{CertificateParams, PKCS_ECDSA_P256_SHA256};
+
+    use super::*;
+
+    fn signer() -> RequestSigner {
+        let key = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256).unwrap();
+        let cert = CertificateParams::new(vec!["app.dstack".into()])
+            .unwrap()
+            .self_signed(&key)
+            .unwrap();
+        RequestSigner::from_pem(&cert.pem(), &key.serialize_pem()).unwrap()
+    }
+
+    fn header<'a>(headers: &'a [(&'static str, String)], name: &str) -> Option<&'a str> {
+        headers
+            .iter()
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn policy() -> SignedRequestPolicy {
+        SignedRequestPolicy {
+            audiences: vec!["kms.example:8000".into()],
+            methods: vec!["KMS.GetMeta".into(), "GetTempCaCert2".into()],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_signed_request() {
+        let signer = signer();
+        let policy = policy();
+        let guard = ReplayGuard::new(policy.max_skew);
+        let headers = signer
+            .sign("kms.example:8000", "KMS.GetMeta", b"{}")
+            .unwrap();
+        let request = SignedRequest::from_headers(|name| header(&headers, name)).unwrap();
+
+        // Bound to the method and the payload
+        assert!(request
+            .verify(&policy, "KMS.GetTempCaCert2", b"{}")
+            .is_err());
+        assert!(request
+            .verify(&policy, "KMS.GetMeta", b"{\"a\":1}")
+            .is_err());
+
+        let leaf = request.verify(&policy, "KMS.GetMeta", b"{}").unwrap();
+        request.check_fresh(&leaf, &guard).unwrap();
+        request.check_fresh(&leaf, &guard).unwrap();
+        request.consume(&leaf, &guard).unwrap();
+        let err = request.check_fresh(&leaf, &guard).unwrap_err();
+        assert_eq!(err.to_string(), "Replayed request");
+        assert!(request.consume(&leaf, &guard).is_err());
+
+        // Not signed by the key of the leaf certificate
+        let other = signer();
+        let forged = other
+            .sign("kms.example:8000", "KMS.GetMeta", b"{}")
+            .unwrap();
+        let forged = SignedRequest {
+            cert_chain: header(&headers, HEADER_CERT_CHAIN).unwrap(),
+            ..SignedRequest::from_headers(|name| header(&forged, name)).unwrap()
+        };
+        assert!(forged.verify(&policy, "KMS.GetMeta", b"{}").is_err());
+    }
+
+    #[test]
+    fn test_signed_request_audience() {
+        let signer = signer();
+        let policy = policy();
+        let headers = signer.sign("other.example", "KMS.GetMeta", b"{}").unwrap();
+        let request = SignedRequest::from_headers(|name| header(&headers, name)).unwrap();
+        let err = request.verify(&policy, "KMS.GetMeta", b"{}").unwrap_err();
+        assert!(err.to_string().contains("another audience"), "{err}");
+
+        // Claiming the audience of this server does not match the signature
+        let retargeted = SignedRequest {
+            audience: "kms.example:8000",
+            ..request
+        };
+        assert!(retargeted.verify(&policy, "KMS.GetMeta", b"{}").is_err());
+    }
+
+    #[test]
+    fn test_signed_request_policy() {
+        let policy = policy();
+        policy.validate().unwrap();
+        assert!(policy.allows("KMS.GetMeta"));
+        assert!(!policy.allows("GetMeta"));
+        assert!(policy.allows("KMS.GetTempCaCert2"));
+        assert!(!SignedRequestPolicy::default().allows("KMS.GetMeta"));
+        for method in ["KMS.GetAppKey", "GetKmsKey", "Tappd.DeriveKey"] {
+            let policy = SignedRequestPolicy {
+                methods: vec![method.into()],
+                ..Default::default()
+            };
+            assert!(policy.validate().is_err(), "{method}");
+        }
+    }
+
+    #[test]
+    fn test_replay_guard() {
+        let guard = ReplayGuard::new(Duration::from_secs(60));
+        let nonce = "00112233445566778899aabbccddeeff";
+        assert!(guard.record(b"a", 1000, nonce, 1061).is_err());
+        guard.check(b"a", 1000, nonce, 1030).unwrap();
+        guard.record(b"a", 1000, nonce, 1030).unwrap();
+        assert!(guard.check(b"a", 1000, nonce, 1031).is_err());
+        assert!(guard.record(b"a", 1000, nonce, 1031).is_err());
+        assert!(guard.record(b"a", 1030, "short", 1030).is_err());
+
+        // Each signer has its own nonces, and a bounded number of them
+        guard.record(b"b", 1000, nonce, 1031).unwrap();
+        for i in 1..MAX_NONCES_PER_SIGNER {
+            guard
+                .record(b"b", 1000, &format!("{i:032x}"), 1031)
+                .unwrap();
+        }
+        let err = guard
+            .check(b"b", 1000, &format!("{:032x}", u64::MAX), 1031)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Too many signed requests");
+        guard.record(b"c", 1000, nonce, 1031).unwrap();
+        // Until they expire
+        guard.record(b"b", 1100, nonce, 1100).unwrap();
+    }
+
+    #[test]
+    fn test_replay_guard_pruning() {
+        let guard = ReplayGuard::new(Duration::from_secs(60));
+        let nonce = "00112233445566778899aabbccddeeff";
+        let signers = || guard.seen.lock().unwrap().by_signer.len();
+        guard.record(b"a", 950, nonce, 1000).unwrap();
+        guard.record(b"b", 1000, nonce, 1000).unwrap();
+
+        // Within the sweep interval a request only prunes the nonces of its own signer
+        guard.check(b"b", 1020, nonce, 1020).unwrap_err();
+        assert_eq!(signers(), 2);
+        guard.check(b"a", 1030, nonce, 1030).unwrap();
+        assert_eq!(signers(), 1);
+        // Signers that do not come back are swept once the interval has passed
+        guard.check(b"c", 1070, nonce, 1070).unwrap();
+        assert_eq!(signers(), 0);
+    }
+}
@@ -0,0 +1,201 @@
+//! gRPC-compatible message framing and server-streaming responses.
+//!
+//! Requests sent with a `application/grpc-web` content type are served on the usual pRPC URLs with
+//! gRPC-web framing: each message is prefixed with a flag byte and a big-endian length, and the
+//! status goes into a trailers frame at the end of the body, so it works over both HTTP/1.1 and
+//! HTTP/2 without HTTP trailers.
+//!
+//! Server-streaming methods answer protobuf requests with the same framing, and JSON requests with
+//! one JSON message per line.
+//!
+//! Two parts of full gRPC support are split out of this module and left for later work:
+//!
+//! - Native gRPC (`application/grpc`) over HTTP/2 is not served. It needs HTTP/2 trailers, which
+//!   Rocket can't send, so such requests are rejected.
+//! - prpc-build generates no stubs for streaming methods. A service lists them in
+//!   [`RpcCall::streaming_methods`](crate::RpcCall::streaming_methods) and implements
+//!   [`RpcCall::call_streaming`](crate::RpcCall::call_streaming) by hand, usually with
+//!   [`decode_message`] and [`encode_messages`]. Clients read the JSON lines with
+//!   `RaClient::request_stream`.
+
+use std::pin::Pin;
+
+use anyhow::{bail, Context, Result};
+use futures::{Stream, StreamExt};
+use prpc::{codec::encode_message_to_vec, Message};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// A stream of encoded response messages.
+pub type RpcStream = Pin<Box<dyn Stream<Item = Result<Vec<u8>>> + Send>>;
+
+/// The gRPC status of a successful call.
+pub const GRPC_OK: u32 = 0;
+/// The gRPC status of a failed call.
+pub const GRPC_UNKNOWN: u32 = 2;
+
+const FLAG_COMPRESSED: u8 = 0x01;
+const FLAG_TRAILERS: u8 = 0x80;
+const FRAME_HEADER_LEN: usize = 5;
+
+fn frame(flags: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+    frame.push(flags);
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Frame a message.
+pub fn encode_frame(message: &[u8]) -> Vec<u8> {
+    frame(0, message)
+}
+
+/// Frame the trailers ending a response.
+pub fn encode_trailers(status: u32, message: &str) -> Vec<u8> {
+    let mut trailers = format!("grpc-status: {status}\r\n");
+    if !message.is_empty() {
+        trailers.push_str(&format!(
+            "grpc-message: {}\r\n",
+            urlencoding::encode(message)
+        ));
+    }
+    frame(FLAG_TRAILERS, trailers.as_bytes())
+}
+
+/// Decode the single message of a framed request.
+pub fn decode_frame(body: &[u8]) -> Result<Vec<u8>> {
+    let Some((header, rest)) = body.split_first_chunk::<FRAME_HEADER_LEN>() else {
+        bail!("truncated frame header");
+    };
+    let [flags, len @ ..] = *header;
+    if flags & FLAG_COMPRESSED != 0 {
+        bail!("compressed messages are not supported");
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if rest.len() != len {
+        bail!(
+            "expected a single message of {len} bytes, got {}",
+            rest.len()
+        );
+    }
+    Ok(rest.to_vec())
+}
+
+/// Decode the request message of a streaming call, from JSON if `json`.
+pub fn decode_message<M>(payload: &[u8], json: bool) -> Result<M>
+where
+    M: Message + DeserializeOwned + Default,
+{
+    if json {
+        Ok(serde_json::from_slice(payload).context("failed to decode json request")?)
+    } else {
+        Ok(M::decode(payload).context("failed to decode protobuf request")?)
+    }
+}
+
+/// Encode a stream of messages as protobuf, or as JSON if `json`.
+pub fn encode_messages<M>(
+    messages: impl Stream<Item = Result<M>> + Send + 'static,
+    json: bool,
+) -> RpcStream
+where
+    M: Message + Serialize + Send + 'static,
+{
+    Box::pin(messages.map(move |message| {
+        let message = message?;
+        if json {
+            Ok(serde_json::to_vec(&message)?)
+        } else {
+            Ok(encode_message_to_vec(&message))
+        }
+    }))
+}
+
+/// The body of a streaming response: framed messages ending with trailers, or JSON lines ending
+/// with an error line if the stream fails.
+pub fn frame_stream(stream: RpcStream, json: bool) -> impl Stream<Item = Vec<u8>> + Send + 'static {
+    futures::stream::unfold(Some(stream), move |stream| async move {
+        let mut stream = stream?;
+        let chunk = match stream.next().await {
+            Some(Ok(mut message)) if json => {
+                message.push(b'\n');
+                return Some((message, Some(stream)));
+            }
+            Some(Ok(message)) => return Some((encode_frame(&message), Some(stream))),
+            Some(Err(err)) if json => {
+                let mut line = crate::encode_error(true, format!("{err:?}"));
+                line.retain(|&b| b != b'\n');
+                line.push(b'\n');
+                line
+            }
+            Some(Err(err)) => encode_trailers(GRPC_UNKNOWN, &format!("{err:?}")),
+            None if json => return None,
+            None => encode_trailers(GRPC_OK, ""),
+        };
+        Some((chunk, None))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+
+    #[test]
+    fn test_frames() {
+        let framed = encode_frame(b"hello");
+        assert_eq!(framed, b"\x00\x00\x00\x00\x05hello");
+        assert_eq!(decode_frame(&framed).unwrap(), b"hello");
+        assert!(decode_frame(&framed[..7]).is_err());
+        assert!(decode_frame(&[framed.clone(), framed].concat()).is_err());
+        assert!(decode_frame(b"\x01\x00\x00\x00\x00").is_err());
+
+        let trailers = encode_trailers(GRPC_UNKNOWN, "no such vm");
+        assert_eq!(trailers[0], FLAG_TRAILERS);
+        assert_eq!(
+            &trailers[FRAME_HEADER_LEN..],
+            b"grpc-status: 2\r\ngrpc-message: no%20such%20vm\r\n"
+        );
+    }
+
+    #[test]
+    fn test_decode_message() {
+        use host_api::HostInfo;
+
+        let info = HostInfo {
+            name: "host".into(),
+            version: "1".into(),
+        };
+        let decoded: HostInfo = decode_message(&info.encode_to_vec(), false).unwrap();
+        assert_eq!(decoded, info);
+        let decoded: HostInfo = decode_message(br#"{"name":"host","version":"1"}"#, true).unwrap();
+        assert_eq!(decoded, info);
+        assert!(decode_message::<HostInfo>(b"{", true).is_err());
+    }
+
+    #[test]
+    fn test_frame_stream() {
+        let messages = || -> RpcStream {
+            Box::pin(futures::stream::iter([
+                Ok(b"{\"a\":1}".to_vec()),
+                Err(anyhow::anyhow!("gone")),
+                Ok(b"{\"a\":2}".to_vec()),
+            ]))
+        };
+
+        let lines = block_on(frame_stream(messages(), true).collect::<Vec<_>>());
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], b"{\"a\":1}\n");
+        assert!(lines[1].starts_with(b"{") && lines[1].ends_with(b"}\n"));
+
+        let frames = block_on(frame_stream(messages(), false).collect::<Vec<_>>());
+        assert_eq!(frames.len(), 2);
+        assert_eq!(decode_frame(&frames[0]).unwrap(), b"{\"a\":1}");
+        assert_eq!(frames[1][0], FLAG_TRAILERS);
+
+        let done: RpcStream = Box::pin(futures::stream::empty());
+        let frames = block_on(frame_stream(done, false).collect::<Vec<_>>());
+        assert_eq!(frames, [encode_trailers(GRPC_OK, "")]);
+    }
+}
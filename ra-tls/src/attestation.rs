@@ -12,7 +12,14 @@ use serde::Serialize;
 use sha2::{Digest as _, Sha384};
 use x509_parser::parse_x509_certificate;
 
-use crate::{oids, traits::CertExt};
+#[cfg(any(test, feature = "simulator"))]
+use crate::simulator::SimVerifier;
+use crate::{
+    oids,
+    sev_snp::{self, KdsSource, SnpReport, SnpVerifier, VerifiedSnpReport},
+    tcb_policy::{latest_tcb_date, TcbPolicy},
+    traits::CertExt,
+};
 use cc_eventlog::TdxEventLog as EventLog;
 use serde_human_bytes as hex_bytes;
 
@@ -29,9 +36,15 @@ pub enum QuoteContentType<'a> {
     Custom(&'a str),
 }
 
+/// TEE type of TDX in the quote header.
+const TEE_TYPE_TDX: u32 = 0x81;
+
 /// The default hash algorithm used to hash the report data.
 pub const DEFAULT_HASH_ALGORITHM: &str = "sha512";
 
+/// The RTMR3 event recording an in-place upgrade of the app compose after boot.
+pub const COMPOSE_UPGRADE_EVENT: &str = "compose-hash-upgrade";
+
 impl QuoteContentType<'_> {
     /// The tag of the content type used in the report data.
     pub fn tag(&self) -> &str {
@@ -89,7 +102,83 @@ impl QuoteContentType<'_> {
 }
 
 /// Represents a verified attestation
-pub type VerifiedAttestation = Attestation<VerifiedReport>;
+pub type VerifiedAttestation = Attestation<TeeReport>;
+
+/// The kind of TEE an attestation comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TeeKind {
+    /// Intel SGX enclave
+    Sgx,
+    /// Intel TDX trust domain
+    Tdx,
+    /// AMD SEV-SNP guest
+    SevSnp,
+}
+
+/// The verified report of an attestation, by TEE kind
+#[derive(Debug, Clone)]
+pub enum TeeReport {
+    /// Verified SGX or TDX quote
+    Intel(VerifiedReport),
+    /// Verified SEV-SNP attestation report
+    SevSnp(VerifiedSnpReport),
+}
+
+impl TeeReport {
+    /// The kind of TEE the report comes from
+    pub fn tee_kind(&self) -> TeeKind {
+        match self {
+            Self::Intel(report) => match report.report {
+                Report::SgxEnclave(_) => TeeKind::Sgx,
+                Report::TD10(_) | Report::TD15(_) => TeeKind::Tdx,
+            },
+            Self::SevSnp(_) => TeeKind::SevSnp,
+        }
+    }
+
+    /// The TCB status of the platform.
+    ///
+    /// SEV-SNP reports are `OutOfDate` when their VCEK is issued for a TCB older than the
+    /// configured minimum, see [`SnpVerifier::with_min_tcb`].
+    pub fn status(&self) -> &str {
+        match self {
+            Self::Intel(report) => &report.status,
+            Self::SevSnp(report) => &report.status,
+        }
+    }
+
+    /// The advisories affecting the platform
+    ///
+    /// Always empty for SEV-SNP: the KDS publishes no advisories per TCB version, so the TCB
+    /// of an SNP report is only judged by its status.
+    pub fn advisory_ids(&self) -> &[String] {
+        match self {
+            Self::Intel(report) => &report.advisory_ids,
+            Self::SevSnp(_) => &[],
+        }
+    }
+
+    /// The verified SGX or TDX quote
+    pub fn as_intel(&self) -> Option<&VerifiedReport> {
+        match self {
+            Self::Intel(report) => Some(report),
+            Self::SevSnp(_) => None,
+        }
+    }
+
+    /// The TD report of a TDX quote
+    pub fn as_td10(&self) -> Option<&TDReport10> {
+        self.as_intel()?.report.as_td10()
+    }
+
+    /// The verified SEV-SNP report
+    pub fn as_sev_snp(&self) -> Option<&VerifiedSnpReport> {
+        match self {
+            Self::Intel(_) => None,
+            Self::SevSnp(report) => Some(report),
+        }
+    }
+}
 
 /// Attestation data
 #[derive(Debug, Clone)]
@@ -105,6 +194,17 @@ pub struct Attestation<R = ()> {
 }
 
 impl<T> Attestation<T> {
+    /// The kind of TEE the quote comes from
+    pub fn tee_kind(&self) -> TeeKind {
+        if sev_snp::is_snp_report(&self.quote) {
+            return TeeKind::SevSnp;
+        }
+        match self.quote.get(4..8) {
+            Some(tee_type) if tee_type == TEE_TYPE_TDX.to_le_bytes() => TeeKind::Tdx,
+            _ => TeeKind::Sgx,
+        }
+    }
+
     /// Decode the quote
     pub fn decode_quote(&self) -> Result<Quote> {
         Quote::parse(&self.quote)
@@ -127,10 +227,33 @@ impl<T> Attestation<T> {
         replay_event_logs(&self.event_log, to_event)
     }
 
-    fn find_event_payload(&self, event: &str) -> Result<Vec<u8>> {
+    pub(crate) fn find_event_payload(&self, event: &str) -> Result<Vec<u8>> {
         self.find_event(3, event).map(|event| event.event_payload)
     }
 
+    /// The compose hash of the latest in-place upgrade, recorded in RTMR3 after system-ready.
+    ///
+    /// Only TDX extends the upgrade into a measurement register, so SEV-SNP always reports the
+    /// compose hash it booted with.
+    fn find_upgraded_compose_hash(&self) -> Option<Vec<u8>> {
+        if self.tee_kind() != TeeKind::Tdx {
+            return None;
+        }
+        let mut booted = false;
+        let mut upgraded = None;
+        for event in &self.event_log {
+            if event.imr != 3 {
+                continue;
+            }
+            if event.event == "system-ready" {
+                booted = true;
+            } else if booted && event.event == COMPOSE_UPGRADE_EVENT {
+                upgraded = Some(event.event_payload.clone());
+            }
+        }
+        upgraded
+    }
+
     /// Decode the app-id from the event log
     pub fn decode_app_id(&self) -> Result<String> {
         self.find_event(3, "app-id")
@@ -143,8 +266,11 @@ impl<T> Attestation<T> {
             .map(|event| hex::encode(&event.event_payload))
     }
 
-    /// Decode the upgraded app-id from the event log
+    /// Decode the current compose hash from the event log, following in-place upgrades
     pub fn decode_compose_hash(&self) -> Result<String> {
+        if let Some(upgraded) = self.find_upgraded_compose_hash() {
+            return Ok(hex::encode(upgraded));
+        }
         let event = self.find_event(3, "compose-hash").or_else(|_| {
             // Old images use this event name
             self.find_event(3, "upgraded-app-id")
@@ -154,6 +280,9 @@ impl<T> Attestation<T> {
 
     /// Decode the app info from the event log
     pub fn decode_app_info(&self, boottime_mr: bool) -> Result<AppInfo> {
+        if self.tee_kind() == TeeKind::SevSnp {
+            return self.decode_snp_app_info(boottime_mr);
+        }
         let rtmrs = self
             .replay_event_logs(boottime_mr.then_some("boot-mr-done"))
             .context("Failed to replay event logs")?;
@@ -170,6 +299,13 @@ impl<T> Attestation<T> {
         } else {
             sha256(&[&key_provider_info])
         };
+        let boot_compose_hash = self.find_event_payload("compose-hash")?;
+        let compose_hash = match boottime_mr {
+            true => boot_compose_hash,
+            false => self
+                .find_upgraded_compose_hash()
+                .unwrap_or(boot_compose_hash),
+        };
         let mr_system = sha256(&[
             &td_report.mr_td,
             &rtmrs[0],
@@ -196,7 +332,7 @@ impl<T> Attestation<T> {
         };
         Ok(AppInfo {
             app_id: self.find_event_payload("app-id").unwrap_or_default(),
-            compose_hash: self.find_event_payload("compose-hash")?,
+            compose_hash,
             instance_id: self.find_event_payload("instance-id").unwrap_or_default(),
             device_id,
             mrtd: td_report.mr_td,
@@ -211,6 +347,37 @@ impl<T> Attestation<T> {
         })
     }
 
+    /// The app info of an SEV-SNP guest, with the launch measurement in place of MRTD and the
+    /// RTMRs left zero. The app events are bound by the host data, see [`sev_snp`].
+    fn decode_snp_app_info(&self, boottime_mr: bool) -> Result<AppInfo> {
+        let report = SnpReport::parse(&self.quote)?;
+        let key_provider_info = if boottime_mr {
+            vec![]
+        } else {
+            self.find_event_payload("key-provider").unwrap_or_default()
+        };
+        let mr_key_provider = if key_provider_info.is_empty() {
+            [0u8; 32]
+        } else {
+            sha256(&[&key_provider_info])
+        };
+        Ok(AppInfo {
+            app_id: self.find_event_payload("app-id").unwrap_or_default(),
+            compose_hash: self.find_event_payload("compose-hash")?,
+            instance_id: self.find_event_payload("instance-id").unwrap_or_default(),
+            device_id: sha256(&[&report.chip_id]).to_vec(),
+            mrtd: report.measurement,
+            rtmr0: [0; 48],
+            rtmr1: [0; 48],
+            rtmr2: [0; 48],
+            rtmr3: [0; 48],
+            os_image_hash: vec![],
+            mr_system: sha256(&[&report.measurement, &mr_key_provider]),
+            mr_aggregated: sha256(&[&report.measurement, &report.host_data]),
+            key_provider_info,
+        })
+    }
+
     /// Decode the rootfs hash from the event log
     pub fn decode_rootfs_hash(&self) -> Result<String> {
         self.find_event(3, "rootfs-hash")
@@ -219,6 +386,9 @@ impl<T> Attestation<T> {
 
     /// Decode the report data in the quote
     pub fn decode_report_data(&self) -> Result<[u8; 64]> {
+        if self.tee_kind() == TeeKind::SevSnp {
+            return Ok(SnpReport::parse(&self.quote)?.report_data);
+        }
         match self.decode_quote()?.report {
             Report::SgxEnclave(report) => Ok(report.report_data),
             Report::TD10(report) => Ok(report.report_data),
@@ -261,13 +431,19 @@ impl Attestation {
         Self::from_ext_getter(|oid| cert.get_extension_bytes(oid))
     }
 
-    /// From an extension getter
+    /// From an extension getter, accepting both the quote and event log extensions and the EAT
+    /// evidence extension
     pub fn from_ext_getter(
         get_ext: impl Fn(&[u64]) -> Result<Option<Vec<u8>>>,
     ) -> Result<Option<Self>> {
         let quote = match get_ext(oids::PHALA_RATLS_QUOTE)? {
             Some(v) => v,
-            None => return Ok(None),
+            None => {
+                return match get_ext(oids::PHALA_RATLS_EVIDENCE)? {
+                    Some(evidence) => Self::from_evidence(&evidence).map(Some),
+                    None => Ok(None),
+                };
+            }
         };
         let raw_event_log = get_ext(oids::PHALA_RATLS_EVENT_LOG)?.unwrap_or_default();
         Self::new(quote, raw_event_log).map(Some)
@@ -292,20 +468,28 @@ impl Attestation {
         self,
         ra_pubkey_der: &[u8],
         pccs_url: Option<&str>,
+        policy: &TcbPolicy,
     ) -> Result<VerifiedAttestation> {
         self.verify(
             &QuoteContentType::RaTlsCert.to_report_data(ra_pubkey_der),
             pccs_url,
+            policy,
         )
         .await
     }
 
-    /// Verify the quote
+    /// Verify the quote, and check the platform against the TCB policy
     pub async fn verify(
         self,
         report_data: &[u8; 64],
         pccs_url: Option<&str>,
+        policy: &TcbPolicy,
     ) -> Result<VerifiedAttestation> {
+        if self.tee_kind() == TeeKind::SevSnp {
+            return self
+                .verify_sev_snp(report_data, sev_snp::global(), policy)
+                .await;
+        }
         let quote = &self.quote;
         if &self.decode_report_data()? != report_data {
             bail!("report data mismatch");
@@ -318,9 +502,63 @@ impl Attestation {
                 Err(_) => Cow::Borrowed(""),
             };
         }
-        let report = qvl::collateral::get_collateral_and_verify(quote, Some(pccs_url.as_ref()))
+        let collateral = crate::collateral::global()
+            .get(quote, &pccs_url)
             .await
             .context("Failed to get collateral")?;
+        let report = qvl::verify::verify(quote, &collateral, unix_now())
+            .context("Failed to verify quote")?;
+        let tcb_date = latest_tcb_date(&collateral.tcb_info);
+        self.into_verified(report, policy, tcb_date)
+    }
+
+    /// Verify a quote synthesized by the simulator against its test PCK chain.
+    ///
+    /// Only for tests: anyone knowing the seed of the test PCK chain can forge such quotes.
+    #[cfg(any(test, feature = "simulator"))]
+    pub fn verify_simulated(
+        self,
+        report_data: &[u8; 64],
+        verifier: &SimVerifier,
+        policy: &TcbPolicy,
+    ) -> Result<VerifiedAttestation> {
+        if &self.decode_report_data()? != report_data {
+            bail!("report data mismatch");
+        }
+        let report = verifier.verify(&self.quote)?;
+        self.into_verified(report, policy, None)
+    }
+
+    /// Verify an SEV-SNP report against the VCEK chains of `verifier`.
+    pub async fn verify_sev_snp<K: KdsSource>(
+        self,
+        report_data: &[u8; 64],
+        verifier: &SnpVerifier<K>,
+        policy: &TcbPolicy,
+    ) -> Result<VerifiedAttestation> {
+        if &self.decode_report_data()? != report_data {
+            bail!("report data mismatch");
+        }
+        let verified = verifier.verify(&self.quote).await?;
+        sev_snp::check_event_log(&verified.report, &self.event_log)?;
+        let report = TeeReport::SevSnp(verified);
+        policy
+            .check(&report, None, unix_now())
+            .context("Rejected by the TCB policy")?;
+        Ok(VerifiedAttestation {
+            quote: self.quote,
+            raw_event_log: self.raw_event_log,
+            event_log: self.event_log,
+            report,
+        })
+    }
+
+    fn into_verified(
+        self,
+        report: VerifiedReport,
+        policy: &TcbPolicy,
+        tcb_date: Option<u64>,
+    ) -> Result<VerifiedAttestation> {
         if let Some(report) = report.report.as_td10() {
             // Replay the event logs
             let rtmrs = self
@@ -331,6 +569,10 @@ impl Attestation {
             }
         }
         validate_tcb(&report)?;
+        let report = TeeReport::Intel(report);
+        policy
+            .check(&report, tcb_date, unix_now())
+            .context("Rejected by the TCB policy")?;
         Ok(VerifiedAttestation {
             quote: self.quote,
             raw_event_log: self.raw_event_log,
@@ -340,7 +582,12 @@ impl Attestation {
     }
 }
 
-impl Attestation<VerifiedReport> {}
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
 
 /// Validate the TCB attributes
 pub fn validate_tcb(report: &VerifiedReport) -> Result<()> {
@@ -503,4 +750,39 @@ mod tests {
             .to_report_data_with_hash(content, "invalid")
             .is_err());
     }
+
+    #[test]
+    fn test_decode_upgraded_compose_hash() {
+        let mut quote = vec![0u8; 64];
+        quote[4..8].copy_from_slice(&TEE_TYPE_TDX.to_le_bytes());
+        let event = |name: &str, payload: &[u8]| {
+            EventLog::new(3, 0x08000001, name.into(), payload.to_vec())
+        };
+        let mut attestation = Attestation {
+            quote,
+            raw_event_log: vec![],
+            event_log: vec![
+                event("compose-hash", &[1; 32]),
+                event(COMPOSE_UPGRADE_EVENT, &[9; 32]),
+                event("system-ready", b""),
+            ],
+            report: (),
+        };
+        // Upgrade events before system-ready are not trusted
+        assert_eq!(
+            attestation.decode_compose_hash().unwrap(),
+            hex::encode([1; 32])
+        );
+
+        attestation
+            .event_log
+            .push(event(COMPOSE_UPGRADE_EVENT, &[2; 32]));
+        attestation
+            .event_log
+            .push(event(COMPOSE_UPGRADE_EVENT, &[3; 32]));
+        assert_eq!(
+            attestation.decode_compose_hash().unwrap(),
+            hex::encode([3; 32])
+        );
+    }
 }
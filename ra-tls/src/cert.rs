@@ -20,7 +20,7 @@ use x509_parser::x509::SubjectPublicKeyInfo;
 use crate::attestation::QuoteContentType;
 use crate::oids::{PHALA_RATLS_APP_ID, PHALA_RATLS_CERT_USAGE};
 use crate::{
-    oids::{PHALA_RATLS_EVENT_LOG, PHALA_RATLS_QUOTE},
+    oids::{PHALA_RATLS_EVENT_LOG, PHALA_RATLS_EVIDENCE, PHALA_RATLS_QUOTE},
     traits::CertExt,
 };
 use ring::signature::{
@@ -189,6 +189,7 @@ pub struct CertRequest<'a, Key> {
     special_usage: Option<&'a str>,
     quote: Option<&'a [u8]>,
     event_log: Option<&'a [u8]>,
+    evidence: Option<&'a [u8]>,
     not_before: Option<SystemTime>,
     not_after: Option<SystemTime>,
     #[builder(default = false)]
@@ -238,6 +239,13 @@ impl<Key> CertRequest<'_, Key> {
             let ext = CustomExtension::from_oid_content(PHALA_RATLS_EVENT_LOG, content);
             params.custom_extensions.push(ext);
         }
+        if let Some(evidence) = self.evidence {
+            let content = yasna::construct_der(|writer| {
+                writer.write_bytes(evidence);
+            });
+            let ext = CustomExtension::from_oid_content(PHALA_RATLS_EVIDENCE, content);
+            params.custom_extensions.push(ext);
+        }
         if let Some(app_id) = self.app_id {
             let content = yasna::construct_der(|writer| {
                 writer.write_bytes(app_id);
@@ -0,0 +1,557 @@
+//! Cached quote collateral and offline verification.
+//!
+//! Collateral is fetched from PCCS per TEE type, FMSPC and PCK CA, and reused until the earliest
+//! `nextUpdate` of its TCB info and QE identity. With a cache directory the entries are persisted
+//! as JSON files, so that services on the same host share them. In offline mode nothing is
+//! fetched: only cached collateral and imported bundles are used, however stale they are.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+use fs_err as fs;
+use qvl::{verify::VerifiedReport, QuoteCollateralV3};
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use tracing::{info, warn};
+use x509_parser::{
+    der_parser::{der::parse_der, Oid},
+    pem::Pem,
+    prelude::{FromDer as _, X509Certificate},
+};
+
+/// The PCCS used when none is configured.
+pub const DEFAULT_PCCS_URL: &str = "https://pccs.phala.network";
+
+/// OID of the SGX extension of PCK certificates.
+const SGX_EXTENSION: &[u64] = &[1, 2, 840, 113741, 1, 13, 1];
+/// OID of the FMSPC within the SGX extension.
+const SGX_FMSPC: &[u64] = &[1, 2, 840, 113741, 1, 13, 1, 4];
+const TEE_TYPE_TDX: u32 = 0x81;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Configuration of the process-wide collateral cache.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct CollateralConfig {
+    /// Directory the collateral is persisted to. Kept in memory only if unset.
+    pub cache_dir: Option<PathBuf>,
+    /// Never fetch collateral, only use cached or imported collateral.
+    pub offline: bool,
+    /// Collateral bundles to import at startup.
+    pub bundles: Vec<PathBuf>,
+}
+
+/// Identifies the collateral needed to verify a quote.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct CollateralKey {
+    /// `tdx` or `sgx`
+    pub tee: String,
+    /// FMSPC of the platform, in upper case hex
+    pub fmspc: String,
+    /// The CA issuing the PCK certificate, `platform` or `processor`
+    pub ca: String,
+}
+
+impl fmt::Display for CollateralKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}-{}", self.tee, self.fmspc, self.ca)
+    }
+}
+
+impl CollateralKey {
+    /// Read the key from the PCK certificate embedded in a quote.
+    pub fn from_quote(quote: &[u8]) -> Result<Self> {
+        let Some(tee_type) = quote.get(4..8) else {
+            bail!("Quote is truncated");
+        };
+        let tee = if tee_type == TEE_TYPE_TDX.to_le_bytes() {
+            "tdx"
+        } else {
+            "sgx"
+        };
+        // The PCK chain is the PEM text at the end of the certification data, in all quote
+        // versions
+        let begin = find(quote, b"-----BEGIN CERTIFICATE-----").context("No PCK certificate")?;
+        let pem = Pem::iter_from_buffer(&quote[begin..])
+            .next()
+            .context("No PCK certificate")?
+            .context("Invalid PCK certificate")?;
+        let (_, pck) =
+            X509Certificate::from_der(&pem.contents).context("Invalid PCK certificate")?;
+
+        let ca = match pck.issuer().iter_common_name().next() {
+            Some(cn) if cn.as_str()?.contains("Processor") => "processor",
+            Some(cn) if cn.as_str()?.contains("Platform") => "platform",
+            _ => bail!("Unknown PCK CA"),
+        };
+        let oid = Oid::from(SGX_EXTENSION).or(Err(anyhow::anyhow!("Invalid oid")))?;
+        let ext = pck
+            .get_extension_unique(&oid)
+            .context("Failed to decode extensions")?
+            .context("No SGX extension in the PCK certificate")?;
+        let (_, ext) = parse_der(ext.value).context("Invalid SGX extension")?;
+        let fmspc_oid = Oid::from(SGX_FMSPC).or(Err(anyhow::anyhow!("Invalid oid")))?;
+        let mut fmspc = None;
+        for item in ext.as_sequence().context("Invalid SGX extension")? {
+            let [oid, value] = &item.as_sequence().context("Invalid SGX extension")?[..] else {
+                continue;
+            };
+            if oid.as_oid().ok() == Some(&fmspc_oid) {
+                fmspc = Some(hex::encode_upper(value.as_slice()?));
+            }
+        }
+        Ok(Self {
+            tee: tee.into(),
+            fmspc: fmspc.context("No FMSPC in the PCK certificate")?,
+            ca: ca.into(),
+        })
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Collateral for one [`CollateralKey`], as cached and as exchanged in bundles.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedCollateral {
+    /// What the collateral is for
+    pub key: CollateralKey,
+    /// When the collateral has to be fetched again, in seconds since the UNIX epoch
+    pub expires_at: u64,
+    /// The collateral
+    pub collateral: QuoteCollateralV3,
+}
+
+impl CachedCollateral {
+    /// Wrap fetched collateral, expiring at the earliest `nextUpdate` of its TCB info and QE
+    /// identity.
+    pub fn new(key: CollateralKey, collateral: QuoteCollateralV3) -> Result<Self> {
+        fn next_update(json: &str) -> Result<u64> {
+            #[derive(Deserialize)]
+            struct Dated {
+                #[serde(rename = "nextUpdate")]
+                next_update: String,
+            }
+            let dated: Dated = serde_json::from_str(json).context("No nextUpdate")?;
+            let time = chrono::DateTime::parse_from_rfc3339(&dated.next_update)
+                .context("Invalid nextUpdate")?;
+            Ok(time.timestamp().max(0) as u64)
+        }
+        let expires_at = next_update(&collateral.tcb_info)
+            .context("Invalid TCB info")?
+            .min(next_update(&collateral.qe_identity).context("Invalid QE identity")?);
+        Ok(Self {
+            key,
+            expires_at,
+            collateral,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct TcbInfoResponse<'a> {
+    #[serde(rename = "tcbInfo", borrow)]
+    tcb_info: &'a RawValue,
+    signature: String,
+}
+
+#[derive(Deserialize)]
+struct QeIdentityResponse<'a> {
+    #[serde(rename = "enclaveIdentity", borrow)]
+    enclave_identity: &'a RawValue,
+    signature: String,
+}
+
+/// Caches collateral by [`CollateralKey`], in memory and optionally on disk.
+#[derive(Default)]
+pub struct CollateralCache {
+    dir: Option<PathBuf>,
+    offline: bool,
+    entries: Mutex<HashMap<CollateralKey, CachedCollateral>>,
+    client: reqwest::Client,
+}
+
+impl CollateralCache {
+    /// Create a cache persisting to `dir`, if any.
+    pub fn new(dir: Option<PathBuf>, offline: bool) -> Result<Self> {
+        if let Some(dir) = &dir {
+            fs::create_dir_all(dir).context("Failed to create collateral cache dir")?;
+        }
+        Ok(Self {
+            dir,
+            offline,
+            ..Default::default()
+        })
+    }
+
+    /// Create a cache from the configuration, importing the configured bundles.
+    pub fn from_config(config: &CollateralConfig) -> Result<Self> {
+        let cache = Self::new(config.cache_dir.clone(), config.offline)?;
+        for bundle in &config.bundles {
+            let count = cache.import_bundle(bundle)?;
+            info!(
+                "Imported {count} collateral entries from {}",
+                bundle.display()
+            );
+        }
+        Ok(cache)
+    }
+
+    fn path_of(&self, key: &CollateralKey) -> Option<PathBuf> {
+        self.dir.as_ref().map(|dir| dir.join(format!("{key}.json")))
+    }
+
+    fn lookup(&self, key: &CollateralKey) -> Option<CachedCollateral> {
+        if let Some(entry) = self.entries.lock().unwrap().get(key) {
+            return Some(entry.clone());
+        }
+        let path = self.path_of(key)?;
+        if !path.exists() {
+            return None;
+        }
+        let entry: CachedCollateral = match fs::read(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|data| serde_json::from_slice(&data).context("Invalid cache entry"))
+        {
+            Ok(entry) => entry,
+            Err(err) => {
+                warn!(
+                    "Ignoring collateral cache entry {}: {err:?}",
+                    path.display()
+                );
+                return None;
+            }
+        };
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(key.clone(), entry.clone());
+        Some(entry)
+    }
+
+    /// Add an entry, replacing any entry with the same key.
+    pub fn insert(&self, entry: CachedCollateral) -> Result<()> {
+        if let Some(path) = self.path_of(&entry.key) {
+            let tmp = path.with_extension("tmp");
+            fs::write(&tmp, serde_json::to_vec(&entry)?)?;
+            fs::rename(&tmp, &path)?;
+        }
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(entry.key.clone(), entry);
+        Ok(())
+    }
+
+    /// Import a bundle written by [`Self::export_bundle`], returning the number of entries.
+    pub fn import_bundle(&self, path: impl AsRef<Path>) -> Result<usize> {
+        let data = fs::read(path).context("Failed to read collateral bundle")?;
+        let entries: Vec<CachedCollateral> =
+            serde_json::from_slice(&data).context("Invalid collateral bundle")?;
+        let count = entries.len();
+        for entry in entries {
+            self.insert(entry)?;
+        }
+        Ok(count)
+    }
+
+    /// Write every entry known to the cache to a bundle, for import on offline hosts.
+    pub fn export_bundle(&self, path: impl AsRef<Path>) -> Result<usize> {
+        if let Some(dir) = &self.dir {
+            for file in fs::read_dir(dir)? {
+                let path = file?.path();
+                if path.extension().is_some_and(|ext| ext == "json") {
+                    let data = fs::read(&path)?;
+                    if let Ok(entry) = serde_json::from_slice::<CachedCollateral>(&data) {
+                        self.lookup(&entry.key);
+                    }
+                }
+            }
+        }
+        let entries: Vec<_> = self.entries.lock().unwrap().values().cloned().collect();
+        fs::write(path, serde_json::to_vec(&entries)?).context("Failed to write bundle")?;
+        Ok(entries.len())
+    }
+
+    /// Get the collateral to verify `quote`, fetching it from `pccs_url` if it is not cached or
+    /// has expired.
+    pub async fn get(&self, quote: &[u8], pccs_url: &str) -> Result<QuoteCollateralV3> {
+        let key = CollateralKey::from_quote(quote)?;
+        let cached = self.lookup(&key);
+        if self.offline {
+            let Some(entry) = cached else {
+                bail!("No collateral for {key} in offline mode");
+            };
+            return Ok(entry.collateral);
+        }
+        if let Some(entry) = cached {
+            if entry.expires_at > unix_now() {
+                return Ok(entry.collateral);
+            }
+        }
+        let collateral = self
+            .fetch(&key, pccs_url)
+            .await
+            .with_context(|| format!("Failed to fetch collateral for {key}"))?;
+        let entry = CachedCollateral::new(key, collateral)?;
+        let collateral = entry.collateral.clone();
+        self.insert(entry)?;
+        Ok(collateral)
+    }
+
+    /// Verify `quote` with the cached collateral.
+    pub async fn verify(&self, quote: &[u8], pccs_url: &str) -> Result<VerifiedReport> {
+        let collateral = self.get(quote, pccs_url).await?;
+        qvl::verify::verify(quote, &collateral, unix_now())
+    }
+
+    async fn fetch(&self, key: &CollateralKey, pccs_url: &str) -> Result<QuoteCollateralV3> {
+        let base = if pccs_url.is_empty() {
+            DEFAULT_PCCS_URL
+        } else {
+            pccs_url
+        };
+        let base = base
+            .trim_end_matches('/')
+            .trim_end_matches("/sgx/certification/v4")
+            .trim_end_matches("/tdx/certification/v4");
+        let sgx = format!("{base}/sgx/certification/v4");
+        let tee = format!("{base}/{}/certification/v4", key.tee);
+
+        let response = self
+            .http_get(&format!("{sgx}/pckcrl?ca={}&encoding=der", key.ca))
+            .await?;
+        let pck_crl_issuer_chain = issuer_chain(&response, "SGX-PCK-CRL-Issuer-Chain")?;
+        let pck_crl = response.bytes().await?.to_vec();
+
+        let response = self
+            .http_get(&format!("{tee}/tcb?fmspc={}", key.fmspc))
+            .await?;
+        let tcb_info_issuer_chain = issuer_chain(&response, "SGX-TCB-Info-Issuer-Chain")
+            .or_else(|_| issuer_chain(&response, "TCB-Info-Issuer-Chain"))?;
+        let body = response.text().await?;
+        let tcb: TcbInfoResponse = serde_json::from_str(&body).context("Invalid TCB info")?;
+
+        let response = self
+            .http_get(&format!("{tee}/qe/identity?update=standard"))
+            .await?;
+        let qe_identity_issuer_chain =
+            issuer_chain(&response, "SGX-Enclave-Identity-Issuer-Chain")?;
+        let body = response.text().await?;
+        let qe: QeIdentityResponse = serde_json::from_str(&body).context("Invalid QE identity")?;
+
+        let root_ca_crl = self.http_get(&format!("{sgx}/rootcacrl")).await?;
+        let root_ca_crl = root_ca_crl.bytes().await?;
+        // PCCS serves the root CA CRL hex encoded
+        let root_ca_crl = match hex::decode(&root_ca_crl) {
+            Ok(der) => der,
+            Err(_) => root_ca_crl.to_vec(),
+        };
+
+        Ok(QuoteCollateralV3 {
+            pck_crl_issuer_chain,
+            root_ca_crl,
+            pck_crl,
+            tcb_info_issuer_chain,
+            tcb_info: tcb.tcb_info.get().to_string(),
+            tcb_info_signature: hex::decode(tcb.signature).context("Invalid TCB signature")?,
+            qe_identity_issuer_chain,
+            qe_identity: qe.enclave_identity.get().to_string(),
+            qe_identity_signature: hex::decode(qe.signature)
+                .context("Invalid QE identity signature")?,
+        })
+    }
+
+    async fn http_get(&self, url: &str) -> Result<reqwest::Response> {
+        self.client
+            .get(url)
+            .timeout(FETCH_TIMEOUT)
+            .send()
+            .await
+            .with_context(|| format!("Failed to fetch {url}"))?
+            .error_for_status()
+            .with_context(|| format!("Failed to fetch {url}"))
+    }
+}
+
+fn issuer_chain(response: &reqwest::Response, header: &str) -> Result<String> {
+    let value = response
+        .headers()
+        .get(header)
+        .with_context(|| format!("Missing {header}"))?
+        .to_str()?;
+    Ok(urlencoding::decode(value)?.into_owned())
+}
+
+static GLOBAL: OnceLock<CollateralCache> = OnceLock::new();
+
+/// Set up the process-wide cache used by [`Attestation::verify`]. Has to be called before the
+/// first verification, which otherwise sets up an in-memory cache.
+///
+/// [`Attestation::verify`]: crate::attestation::Attestation::verify
+pub fn init(config: &CollateralConfig) -> Result<()> {
+    let cache = CollateralCache::from_config(config)?;
+    if GLOBAL.set(cache).is_err() {
+        bail!("The collateral cache is already set up");
+    }
+    Ok(())
+}
+
+/// The process-wide collateral cache.
+pub fn global() -> &'static CollateralCache {
+    GLOBAL.get_or_init(CollateralCache::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    const QUOTE: &[u8] = include_bytes!("../assets/tdx_quote");
+
+    fn key() -> CollateralKey {
+        CollateralKey {
+            tee: "tdx".into(),
+            fmspc: "B0C06F000000".into(),
+            ca: "platform".into(),
+        }
+    }
+
+    /// Serve PCCS responses whose TCB info expires at `next_update`, counting the requests.
+    async fn stand_in_pccs(next_update: &'static str) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                let mut buf = vec![0; 4096];
+                let len = stream.read(&mut buf).await.unwrap();
+                let request = String::from_utf8_lossy(&buf[..len]).to_string();
+                let path = request.split(' ').nth(1).unwrap_or_default().to_string();
+                let chain = "-----BEGIN%20CERTIFICATE-----%0A";
+                let (header, body) = if path.contains("/pckcrl") {
+                    ("SGX-PCK-CRL-Issuer-Chain", "crl".to_string())
+                } else if path.contains("/tcb?fmspc=B0C06F000000") {
+                    (
+                        "TCB-Info-Issuer-Chain",
+                        format!(
+                            r#"{{"tcbInfo":{{"id":"TDX","nextUpdate":"{next_update}"}},"signature":"abcd"}}"#
+                        ),
+                    )
+                } else if path.contains("/qe/identity") {
+                    (
+                        "SGX-Enclave-Identity-Issuer-Chain",
+                        r#"{"enclaveIdentity":{"id":"TD_QE","nextUpdate":"2999-01-01T00:00:00Z"},"signature":"ef01"}"#.to_string(),
+                    )
+                } else if path.contains("/rootcacrl") {
+                    ("X-Unused", "0102".to_string())
+                } else {
+                    let _ = stream
+                        .write_all(b"HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\n\r\n")
+                        .await;
+                    continue;
+                };
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\n{header}: {chain}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        (url, requests)
+    }
+
+    #[test]
+    fn test_collateral_key() {
+        assert_eq!(CollateralKey::from_quote(QUOTE).unwrap(), key());
+        assert_eq!(key().to_string(), "tdx-B0C06F000000-platform");
+        assert!(CollateralKey::from_quote(&QUOTE[..1000]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_cache_fetches_once() {
+        let (url, requests) = stand_in_pccs("2999-01-01T00:00:00Z").await;
+        let dir = tempfile::tempdir().unwrap();
+        let cache = CollateralCache::new(Some(dir.path().into()), false).unwrap();
+        let collateral = cache.get(QUOTE, &url).await.unwrap();
+        assert_eq!(
+            collateral.tcb_info,
+            r#"{"id":"TDX","nextUpdate":"2999-01-01T00:00:00Z"}"#
+        );
+        assert_eq!(collateral.tcb_info_signature, [0xab, 0xcd]);
+        assert_eq!(collateral.root_ca_crl, [1, 2]);
+        assert_eq!(
+            collateral.pck_crl_issuer_chain,
+            "-----BEGIN CERTIFICATE-----\n"
+        );
+        assert_eq!(requests.load(Ordering::SeqCst), 4);
+        cache.get(QUOTE, &url).await.unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 4);
+
+        // Another service sharing the directory, with PCCS unreachable
+        let shared = CollateralCache::new(Some(dir.path().into()), true).unwrap();
+        let cached = shared.get(QUOTE, "http://127.0.0.1:1").await.unwrap();
+        assert_eq!(cached.tcb_info, collateral.tcb_info);
+    }
+
+    #[tokio::test]
+    async fn test_cache_refetches_expired() {
+        let (url, requests) = stand_in_pccs("2000-01-01T00:00:00Z").await;
+        let cache = CollateralCache::new(None, false).unwrap();
+        cache.get(QUOTE, &url).await.unwrap();
+        cache.get(QUOTE, &url).await.unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 8);
+    }
+
+    #[tokio::test]
+    async fn test_offline_bundle() {
+        let offline = CollateralCache::new(None, true).unwrap();
+        let err = offline.get(QUOTE, "").await.unwrap_err();
+        assert!(err.to_string().contains("offline"), "{err}");
+
+        let (url, _) = stand_in_pccs("2999-01-01T00:00:00Z").await;
+        let online = CollateralCache::new(None, false).unwrap();
+        online.get(QUOTE, &url).await.unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let bundle = dir.path().join("bundle.json");
+        assert_eq!(online.export_bundle(&bundle).unwrap(), 1);
+
+        let offline = CollateralCache::from_config(&CollateralConfig {
+            offline: true,
+            bundles: vec![bundle],
+            ..Default::default()
+        })
+        .unwrap();
+        offline.get(QUOTE, "").await.unwrap();
+        // Collateral is verified as usual, and the stand-in collateral is not genuine
+        assert!(offline.verify(QUOTE, "").await.is_err());
+    }
+}
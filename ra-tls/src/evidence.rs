@@ -0,0 +1,302 @@
+//! Attestation evidence encoded as an IETF Entity Attestation Token (EAT).
+//!
+//! The evidence is an unsigned claims set (UCCS, CBOR tag 601) carrying the quote, a compact event
+//! log and the app claims, and goes into the [`PHALA_RATLS_EVIDENCE`] extension of RA-TLS
+//! certificates in place of the quote and JSON event log extensions.
+//!
+//! The event log is compacted in both directions: RTMR3 events are kept without their digests,
+//! which are recomputed from the event on decode, and boot events are kept as digests only, since
+//! replaying RTMR0-2 needs nothing else. The app claims are a convenience for consumers that do not
+//! replay the event log; they are checked against the event log on decode, and the event log is
+//! checked against the quote when the attestation is verified.
+//!
+//! [`PHALA_RATLS_EVIDENCE`]: crate::oids::PHALA_RATLS_EVIDENCE
+
+use anyhow::{bail, Context, Result};
+use cc_eventlog::TdxEventLog as EventLog;
+use ciborium::value::Value;
+
+use crate::attestation::Attestation;
+
+/// The EAT profile of the evidence. Bumped together with the extension OID on format changes.
+pub const EAT_PROFILE: &str = "tag:phala.network,2025:ratls-evidence-v1";
+
+/// CBOR tag of an unprotected CWT claims set.
+const UCCS_TAG: u64 = 601;
+
+/// Claim keys. The dstack claims use the private range below -65536.
+const CLAIM_EAT_PROFILE: i64 = 265;
+const CLAIM_QUOTE: i64 = -65537;
+const CLAIM_EVENT_LOG: i64 = -65538;
+const CLAIM_APP: i64 = -65539;
+
+/// RTMR3 events surfaced as app claims, keyed by event name.
+const APP_CLAIMS: &[&str] = &[
+    "app-id",
+    "compose-hash",
+    "instance-id",
+    "os-image-hash",
+    "key-provider",
+];
+
+impl<T> Attestation<T> {
+    /// Encode the attestation as CBOR EAT evidence.
+    pub fn to_evidence(&self) -> Result<Vec<u8>> {
+        let events = self.event_log.iter().map(encode_event).collect();
+        let app = APP_CLAIMS
+            .iter()
+            .filter_map(|name| {
+                let payload = self.find_event_payload(name).ok()?;
+                Some((Value::Text(name.to_string()), Value::Bytes(payload)))
+            })
+            .collect();
+        let claims = Value::Map(vec![
+            (key(CLAIM_EAT_PROFILE), Value::Text(EAT_PROFILE.into())),
+            (key(CLAIM_QUOTE), Value::Bytes(self.quote.clone())),
+            (key(CLAIM_EVENT_LOG), Value::Array(events)),
+            (key(CLAIM_APP), Value::Map(app)),
+        ]);
+        let mut evidence = vec![];
+        ciborium::into_writer(&Value::Tag(UCCS_TAG, Box::new(claims)), &mut evidence)
+            .context("Failed to encode evidence")?;
+        Ok(evidence)
+    }
+}
+
+impl Attestation {
+    /// Decode CBOR EAT evidence produced by [`Attestation::to_evidence`].
+    ///
+    /// The event log is rebuilt in full, so the result can be verified and decoded like an
+    /// attestation read from the legacy extensions. Boot events come back without their names and
+    /// payloads.
+    pub fn from_evidence(evidence: &[u8]) -> Result<Self> {
+        let value: Value = ciborium::from_reader(evidence).context("Failed to decode evidence")?;
+        let claims = match value {
+            Value::Tag(UCCS_TAG, claims) => *claims,
+            _ => bail!("evidence is not an unsigned EAT"),
+        };
+        let claims = claims.into_map().ok().context("EAT claims are not a map")?;
+        let mut profile = None;
+        let mut quote = None;
+        let mut events = None;
+        let mut app = vec![];
+        for (key, value) in claims {
+            // Unknown claims are ignored, as EAT requires
+            let Some(key) = key.as_integer().and_then(|key| i64::try_from(key).ok()) else {
+                continue;
+            };
+            match key {
+                CLAIM_EAT_PROFILE => profile = value.into_text().ok(),
+                CLAIM_QUOTE => {
+                    quote = Some(value.into_bytes().ok().context("invalid quote claim")?);
+                }
+                CLAIM_EVENT_LOG => {
+                    events = Some(value.into_array().ok().context("invalid event log claim")?);
+                }
+                CLAIM_APP => app = value.into_map().ok().context("invalid app claims")?,
+                _ => {}
+            }
+        }
+        if profile.as_deref() != Some(EAT_PROFILE) {
+            bail!("unsupported EAT profile: {profile:?}");
+        }
+        let quote = quote.context("quote claim missing")?;
+        let event_log = events
+            .context("event log claim missing")?
+            .iter()
+            .map(decode_event)
+            .collect::<Result<Vec<_>>>()?;
+        let raw_event_log =
+            serde_json::to_vec(&event_log).context("Failed to serialize event log")?;
+        let attestation = Self {
+            quote,
+            raw_event_log,
+            event_log,
+            report: (),
+        };
+        for (name, value) in app {
+            let (Value::Text(name), Value::Bytes(value)) = (name, value) else {
+                bail!("invalid app claim");
+            };
+            if attestation.find_event_payload(&name).ok() != Some(value) {
+                bail!("app claim {name} does not match the event log");
+            }
+        }
+        Ok(attestation)
+    }
+}
+
+fn key(key: i64) -> Value {
+    Value::Integer(key.into())
+}
+
+/// Encode an event as `[imr, type, name, payload]` if its digest can be recomputed, or as
+/// `[imr, type, digest]` otherwise.
+fn encode_event(event: &EventLog) -> Value {
+    let mut fields = vec![
+        Value::Integer(event.imr.into()),
+        Value::Integer(event.event_type.into()),
+    ];
+    if event.imr == 3 && event.validate().is_ok() {
+        fields.push(Value::Text(event.event.clone()));
+        fields.push(Value::Bytes(event.event_payload.clone()));
+    } else {
+        fields.push(Value::Bytes(event.digest.to_vec()));
+    }
+    Value::Array(fields)
+}
+
+fn decode_event(value: &Value) -> Result<EventLog> {
+    let fields = value.as_array().context("event is not an array")?;
+    let event = match fields.as_slice() {
+        [imr, event_type, Value::Text(event), Value::Bytes(payload)] => EventLog::new(
+            decode_u32(imr)?,
+            decode_u32(event_type)?,
+            event.clone(),
+            payload.clone(),
+        ),
+        [imr, event_type, Value::Bytes(digest)] => EventLog {
+            imr: decode_u32(imr)?,
+            event_type: decode_u32(event_type)?,
+            digest: digest
+                .as_slice()
+                .try_into()
+                .ok()
+                .context("invalid event digest")?,
+            event: String::new(),
+            event_payload: vec![],
+        },
+        _ => bail!("invalid event"),
+    };
+    if event.imr > 3 {
+        bail!("invalid event imr: {}", event.imr);
+    }
+    Ok(event)
+}
+
+fn decode_u32(value: &Value) -> Result<u32> {
+    value
+        .as_integer()
+        .and_then(|value| u32::try_from(value).ok())
+        .context("invalid integer")
+}
+
+#[cfg(test)]
+mod tests {
+    use rcgen::{KeyPair, PKCS_ECDSA_P256_SHA256};
+
+    use super::*;
+    use crate::{
+        attestation::QuoteContentType,
+        cert::CertRequest,
+        simulator::{QuoteSimulator, SimProfile, SimVerifier, DEFAULT_PCK_SEED},
+        tcb_policy::TcbPolicy,
+    };
+
+    fn attest(report_data: &[u8; 64]) -> Attestation {
+        let simulator = QuoteSimulator::new(SimProfile {
+            app_id: vec![0x33; 20],
+            key_provider: br#"{"name":"kms","id":"00"}"#.to_vec(),
+            boot_events: vec![EventLog {
+                imr: 1,
+                event_type: 0x80000003,
+                digest: [0x44; 48],
+                event: "EV_EFI_BOOT_SERVICES_APPLICATION".into(),
+                event_payload: vec![0x55; 512],
+            }],
+            ..Default::default()
+        })
+        .unwrap();
+        let event_log = simulator.event_log();
+        let quote = simulator.quote(report_data, &event_log).unwrap();
+        Attestation::new(quote, serde_json::to_vec(&event_log).unwrap()).unwrap()
+    }
+
+    /// Re-encode `evidence` after editing its claims.
+    fn edit_claims(evidence: &[u8], edit: impl FnOnce(&mut Vec<(Value, Value)>)) -> Vec<u8> {
+        let value: Value = ciborium::from_reader(evidence).unwrap();
+        let Value::Tag(tag, claims) = value else {
+            panic!("not tagged");
+        };
+        let mut claims = claims.into_map().unwrap();
+        edit(&mut claims);
+        let mut edited = vec![];
+        ciborium::into_writer(&Value::Tag(tag, Box::new(Value::Map(claims))), &mut edited).unwrap();
+        edited
+    }
+
+    #[test]
+    fn test_evidence_round_trip() {
+        let report_data = QuoteContentType::AppData.to_report_data(b"hello");
+        let attestation = attest(&report_data);
+        let evidence = attestation.to_evidence().unwrap();
+        assert!(evidence.len() < attestation.quote.len() + attestation.raw_event_log.len());
+
+        let decoded = Attestation::from_evidence(&evidence).unwrap();
+        assert_eq!(decoded.quote, attestation.quote);
+        assert_eq!(
+            decoded.replay_event_logs(None).unwrap(),
+            attestation.replay_event_logs(None).unwrap()
+        );
+        // Boot events keep their digests only
+        assert_eq!(decoded.event_log[0].digest, [0x44; 48]);
+        assert!(decoded.event_log[0].event_payload.is_empty());
+
+        let info = decoded.decode_app_info(false).unwrap();
+        let expected = attestation.decode_app_info(false).unwrap();
+        assert_eq!(info.app_id, expected.app_id);
+        assert_eq!(info.key_provider_info, expected.key_provider_info);
+        assert_eq!(info.mr_aggregated, expected.mr_aggregated);
+
+        let verifier = SimVerifier::new(DEFAULT_PCK_SEED).unwrap();
+        decoded
+            .verify_simulated(&report_data, &verifier, &TcbPolicy::default())
+            .unwrap();
+    }
+
+    #[test]
+    fn test_cert_with_evidence() {
+        let key = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256).unwrap();
+        let report_data = QuoteContentType::RaTlsCert.to_report_data(&key.public_key_der());
+        let attestation = attest(&report_data);
+        let evidence = attestation.to_evidence().unwrap();
+        let cert = CertRequest::builder()
+            .key(&key)
+            .subject("evidence")
+            .evidence(&evidence)
+            .build()
+            .self_signed()
+            .unwrap();
+
+        let decoded = Attestation::from_der(cert.der()).unwrap().unwrap();
+        assert_eq!(decoded.quote, attestation.quote);
+        assert_eq!(
+            decoded.decode_app_id().unwrap(),
+            attestation.decode_app_id().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_reject_mismatched_claims() {
+        let attestation = attest(&[0; 64]);
+        let evidence = attestation.to_evidence().unwrap();
+
+        let forged = edit_claims(&evidence, |claims| {
+            for (key, value) in claims {
+                if *key == super::key(CLAIM_APP) {
+                    *value = Value::Map(vec![(
+                        Value::Text("app-id".into()),
+                        Value::Bytes(vec![0x66; 20]),
+                    )]);
+                }
+            }
+        });
+        let err = Attestation::from_evidence(&forged).unwrap_err();
+        assert!(err.to_string().contains("app-id"), "{err}");
+
+        let future = edit_claims(&evidence, |claims| {
+            claims[0].1 = Value::Text("tag:phala.network,2025:ratls-evidence-v2".into());
+        });
+        assert!(Attestation::from_evidence(&future).is_err());
+    }
+}
@@ -1,5 +1,6 @@
 //! Key derivation functions.
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use dstack_types::MrBindingPolicy;
 use p256::pkcs8::{DecodePrivateKey, EncodePrivateKey};
 use rcgen::{KeyPair, PKCS_ECDSA_P256_SHA256};
 use ring::{
@@ -8,6 +9,8 @@ use ring::{
 };
 use rustls_pki_types::PrivateKeyDer;
 
+use crate::attestation::AppInfo;
+
 struct AnySizeKey(usize);
 impl KeyType for AnySizeKey {
     fn len(&self) -> usize {
@@ -71,6 +74,45 @@ pub fn derive_dh_secret(from: &KeyPair, context_data: &[&[u8]]) -> Result<[u8; 3
     Ok(derived_secret)
 }
 
+/// Derives a 32-byte key from a key provider `seed`, bound to the measurements of `app_info`
+/// selected by `policy`.
+///
+/// `app_info` should be decoded with boot-time MRs, so that the key does not depend on the
+/// key-provider event recording the policy.
+pub fn derive_mr_bound_key(
+    seed: &[u8],
+    policy: &MrBindingPolicy,
+    app_info: &AppInfo,
+) -> Result<[u8; 32]> {
+    if policy.is_empty() {
+        bail!("MR binding policy selects no measurement");
+    }
+    let measurements: [(bool, &[u8]); 6] = [
+        (policy.mrtd, &app_info.mrtd),
+        (policy.rtmr0, &app_info.rtmr0),
+        (policy.rtmr1, &app_info.rtmr1),
+        (policy.rtmr2, &app_info.rtmr2),
+        (policy.rtmr3, &app_info.rtmr3),
+        (policy.compose_hash, &app_info.compose_hash),
+    ];
+    // Keys bound to different selections differ even where the measurements are equal
+    let selection = measurements
+        .iter()
+        .enumerate()
+        .fold(0u8, |bits, (i, (bound, _))| bits | (u8::from(*bound) << i));
+    let selection = [selection];
+    let mut context_data = vec![b"mr-bound-key".as_slice(), selection.as_slice()];
+    context_data.extend(
+        measurements
+            .iter()
+            .filter(|(bound, _)| *bound)
+            .map(|(_, value)| *value),
+    );
+    let key = derive_ecdsa_key(seed, &context_data, 32).or(Err(anyhow!("failed to derive key")))?;
+    key.try_into()
+        .map_err(|_| anyhow!("unexpected derived key length"))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -94,4 +136,65 @@ mod tests {
         let key = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256).unwrap();
         let _derived_key = derive_ecdsa_key_pair(&key, &[b"context one"]).unwrap();
     }
+
+    #[test]
+    fn test_derive_mr_bound_key() {
+        let app_info = AppInfo {
+            app_id: vec![1; 20],
+            compose_hash: vec![2; 32],
+            instance_id: vec![3; 20],
+            device_id: vec![4; 32],
+            mrtd: [5; 48],
+            rtmr0: [6; 48],
+            rtmr1: [7; 48],
+            rtmr2: [8; 48],
+            rtmr3: [9; 48],
+            mr_system: [0; 32],
+            mr_aggregated: [0; 32],
+            os_image_hash: vec![],
+            key_provider_info: vec![],
+        };
+        let policy = MrBindingPolicy {
+            mrtd: true,
+            compose_hash: true,
+            ..Default::default()
+        };
+        let key = derive_mr_bound_key(b"seed", &policy, &app_info).unwrap();
+        assert_eq!(
+            key,
+            derive_mr_bound_key(b"seed", &policy, &app_info).unwrap()
+        );
+        assert_ne!(
+            key,
+            derive_mr_bound_key(b"other seed", &policy, &app_info).unwrap()
+        );
+
+        // Only the selected measurements matter
+        let unbound = AppInfo {
+            rtmr3: [0; 48],
+            ..app_info.clone()
+        };
+        assert_eq!(
+            key,
+            derive_mr_bound_key(b"seed", &policy, &unbound).unwrap()
+        );
+        let upgraded = AppInfo {
+            compose_hash: vec![0; 32],
+            ..app_info.clone()
+        };
+        assert_ne!(
+            key,
+            derive_mr_bound_key(b"seed", &policy, &upgraded).unwrap()
+        );
+        let wider = MrBindingPolicy {
+            rtmr0: true,
+            ..policy
+        };
+        assert_ne!(
+            key,
+            derive_mr_bound_key(b"seed", &wider, &app_info).unwrap()
+        );
+
+        assert!(derive_mr_bound_key(b"seed", &Default::default(), &app_info).is_err());
+    }
 }
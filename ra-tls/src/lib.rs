@@ -6,6 +6,13 @@ pub extern crate rcgen;
 
 pub mod attestation;
 pub mod cert;
+pub mod collateral;
+pub mod evidence;
 pub mod kdf;
 pub mod oids;
+pub mod sev_snp;
+pub mod simulator;
+pub mod tcb_policy;
+pub mod token;
 pub mod traits;
+pub mod verifier;
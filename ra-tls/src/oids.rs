@@ -8,3 +8,5 @@ pub const PHALA_RATLS_EVENT_LOG: &[u64] = &[1, 3, 6, 1, 4, 1, 62397, 1, 2];
 pub const PHALA_RATLS_APP_ID: &[u64] = &[1, 3, 6, 1, 4, 1, 62397, 1, 3];
 /// OID for Special Certificate Usage.
 pub const PHALA_RATLS_CERT_USAGE: &[u64] = &[1, 3, 6, 1, 4, 1, 62397, 1, 4];
+/// OID for the CBOR-encoded EAT evidence extension, version 1.
+pub const PHALA_RATLS_EVIDENCE: &[u64] = &[1, 3, 6, 1, 4, 1, 62397, 1, 5];
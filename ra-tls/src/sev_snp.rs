@@ -0,0 +1,871 @@
+//! AMD SEV-SNP attestation reports.
+//!
+//! An SNP report is signed by the VCEK of the chip, which AMD's Key Distribution Service (KDS)
+//! issues per chip and TCB version under the ASK and ARK of the product. The report is carried
+//! in the same RA-TLS extension as TDX quotes, and told apart from them by its layout, see
+//! [`is_snp_report`].
+//!
+//! SNP has no runtime measurement registers, so the app identity must be bound at launch instead:
+//! `HOST_DATA` carries [`app_digest`] of the app-id, compose-hash, instance-id and key-provider
+//! events, which the guest then reports in its event log as usual. Verification checks that
+//! binding, but nothing sets `HOST_DATA` at launch or produces SNP reports in the guest yet, so a
+//! host could claim any app identity. Until that exists the KMS rejects SNP guests.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use cc_eventlog::TdxEventLog as EventLog;
+use fs_err as fs;
+use ring::signature::{
+    UnparsedPublicKey, VerificationAlgorithm, ECDSA_P384_SHA384_ASN1, ECDSA_P384_SHA384_FIXED,
+    RSA_PSS_2048_8192_SHA384,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use x509_parser::{
+    der_parser::{
+        der::{parse_der_integer, parse_der_octetstring},
+        Oid,
+    },
+    prelude::{FromDer as _, X509Certificate},
+};
+
+use crate::tcb_policy::{STATUS_OUT_OF_DATE, STATUS_UP_TO_DATE};
+
+/// Length of an SNP attestation report.
+pub const REPORT_LEN: usize = 0x4a0;
+/// Length of the signed part of the report.
+const SIGNED_LEN: usize = 0x2a0;
+/// Signature algorithm of the report: ECDSA P-384 with SHA-384.
+const SIG_ALGO_ECDSA_P384_SHA384: u32 = 1;
+/// Guest policy bit allowing the guest to be debugged.
+const POLICY_DEBUG: u64 = 1 << 19;
+
+/// The AMD KDS.
+pub const DEFAULT_KDS_URL: &str = "https://kdsintf.amd.com";
+/// The product assumed for reports that do not carry the CPUID of the chip.
+pub const DEFAULT_PRODUCT: &str = "Milan";
+
+const OID_BL_SPL: &[u64] = &[1, 3, 6, 1, 4, 1, 3704, 1, 3, 1];
+const OID_TEE_SPL: &[u64] = &[1, 3, 6, 1, 4, 1, 3704, 1, 3, 2];
+const OID_SNP_SPL: &[u64] = &[1, 3, 6, 1, 4, 1, 3704, 1, 3, 3];
+const OID_UCODE_SPL: &[u64] = &[1, 3, 6, 1, 4, 1, 3704, 1, 3, 8];
+const OID_HWID: &[u64] = &[1, 3, 6, 1, 4, 1, 3704, 1, 4];
+
+const OID_RSASSA_PSS: &str = "1.2.840.113549.1.1.10";
+const OID_ECDSA_SHA384: &str = "1.2.840.10045.4.3.3";
+
+/// Whether `evidence` is an SNP report rather than an SGX or TDX quote.
+///
+/// Reports start with a 32 bit version, while the 16 bit version of quotes is followed by the
+/// attestation key type, which is never zero.
+pub fn is_snp_report(evidence: &[u8]) -> bool {
+    evidence.len() == REPORT_LEN && evidence[2..4] == [0, 0] && (2..=5).contains(&evidence[0])
+}
+
+/// The security patch levels of the SNP firmware components.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct TcbVersion {
+    /// SPL of the bootloader
+    pub bootloader: u8,
+    /// SPL of the PSP OS
+    pub tee: u8,
+    /// SPL of the SNP firmware
+    pub snp: u8,
+    /// SPL of the CPU microcode
+    pub microcode: u8,
+}
+
+impl TcbVersion {
+    /// Whether every component is at least that of `min`.
+    pub fn meets(&self, min: &TcbVersion) -> bool {
+        self.bootloader >= min.bootloader
+            && self.tee >= min.tee
+            && self.snp >= min.snp
+            && self.microcode >= min.microcode
+    }
+
+    fn from_raw(raw: u64) -> Self {
+        let bytes = raw.to_le_bytes();
+        Self {
+            bootloader: bytes[0],
+            tee: bytes[1],
+            snp: bytes[6],
+            microcode: bytes[7],
+        }
+    }
+}
+
+/// A parsed SNP attestation report.
+#[derive(Debug, Clone)]
+pub struct SnpReport {
+    /// Version of the report format
+    pub version: u32,
+    /// SVN of the guest
+    pub guest_svn: u32,
+    /// Guest policy
+    pub policy: u64,
+    /// Family id provided at launch
+    pub family_id: [u8; 16],
+    /// Image id provided at launch
+    pub image_id: [u8; 16],
+    /// VMPL the report was requested from
+    pub vmpl: u32,
+    /// Signature algorithm of the report
+    pub signature_algo: u32,
+    /// Current TCB of the platform
+    pub current_tcb: TcbVersion,
+    /// Data provided by the guest
+    pub report_data: [u8; 64],
+    /// Launch measurement
+    pub measurement: [u8; 48],
+    /// Data provided by the host at launch
+    pub host_data: [u8; 32],
+    /// Digest of the id key
+    pub id_key_digest: [u8; 48],
+    /// Digest of the author key
+    pub author_key_digest: [u8; 48],
+    /// Id of the guest, unique per launch
+    pub report_id: [u8; 32],
+    /// TCB the report is signed for, and the VCEK issued for
+    pub reported_tcb: TcbVersion,
+    /// CPUID family, model and stepping of the chip, zero before version 3
+    pub cpuid: [u8; 3],
+    /// Id of the chip
+    pub chip_id: [u8; 64],
+    /// Committed TCB of the platform
+    pub committed_tcb: TcbVersion,
+    /// TCB at the time the guest was launched
+    pub launch_tcb: TcbVersion,
+    raw: Vec<u8>,
+}
+
+impl SnpReport {
+    /// Parse a report.
+    pub fn parse(raw: &[u8]) -> Result<Self> {
+        if !is_snp_report(raw) {
+            bail!("Not an SNP attestation report");
+        }
+        fn array<const N: usize>(raw: &[u8], offset: usize) -> [u8; N] {
+            raw[offset..offset + N].try_into().expect("in bounds")
+        }
+        let u32_at = |offset| u32::from_le_bytes(array(raw, offset));
+        let u64_at = |offset| u64::from_le_bytes(array(raw, offset));
+        Ok(Self {
+            version: u32_at(0x00),
+            guest_svn: u32_at(0x04),
+            policy: u64_at(0x08),
+            family_id: array(raw, 0x10),
+            image_id: array(raw, 0x20),
+            vmpl: u32_at(0x30),
+            signature_algo: u32_at(0x34),
+            current_tcb: TcbVersion::from_raw(u64_at(0x38)),
+            report_data: array(raw, 0x50),
+            measurement: array(raw, 0x90),
+            host_data: array(raw, 0xc0),
+            id_key_digest: array(raw, 0xe0),
+            author_key_digest: array(raw, 0x110),
+            report_id: array(raw, 0x140),
+            reported_tcb: TcbVersion::from_raw(u64_at(0x180)),
+            cpuid: array(raw, 0x188),
+            chip_id: array(raw, 0x1a0),
+            committed_tcb: TcbVersion::from_raw(u64_at(0x1e0)),
+            launch_tcb: TcbVersion::from_raw(u64_at(0x1f0)),
+            raw: raw.to_vec(),
+        })
+    }
+
+    /// Whether the guest policy allows debugging.
+    pub fn is_debug(&self) -> bool {
+        self.policy & POLICY_DEBUG != 0
+    }
+
+    /// The product name of the chip as used by the KDS, if the report carries its CPUID.
+    pub fn product(&self) -> Option<&'static str> {
+        let [family, model, _] = self.cpuid;
+        match (family, model) {
+            (0x19, 0x00..=0x0f) => Some("Milan"),
+            (0x19, 0x10..=0x1f | 0xa0..=0xaf) => Some("Genoa"),
+            (0x1a, 0x00..=0x11) => Some("Turin"),
+            _ => None,
+        }
+    }
+
+    /// The ECDSA signature as fixed size big-endian `r || s`.
+    fn signature(&self) -> Vec<u8> {
+        // r and s are stored as 72 byte little-endian integers
+        let sig = &self.raw[SIGNED_LEN..];
+        let mut fixed = Vec::with_capacity(96);
+        fixed.extend(sig[..48].iter().rev());
+        fixed.extend(sig[72..72 + 48].iter().rev());
+        fixed
+    }
+}
+
+/// The certificates endorsing a VCEK, DER encoded.
+#[derive(Debug, Clone)]
+pub struct VcekChain {
+    /// The AMD root key of the product, self signed
+    pub ark: Vec<u8>,
+    /// The AMD SEV key, signed by the ARK
+    pub ask: Vec<u8>,
+    /// The VCEK of the chip at a TCB version, signed by the ASK
+    pub vcek: Vec<u8>,
+}
+
+/// Where VCEK chains come from.
+pub trait KdsSource: Send + Sync {
+    /// Get the VCEK chain of a chip at a TCB version.
+    fn vcek_chain(
+        &self,
+        product: &str,
+        chip_id: &[u8; 64],
+        tcb: TcbVersion,
+    ) -> impl Future<Output = Result<VcekChain>> + Send;
+}
+
+/// Fetches VCEK chains from the AMD KDS, or a mirror of it, and keeps them in memory.
+pub struct AmdKds {
+    base_url: String,
+    client: reqwest::Client,
+    chains: Mutex<HashMap<(String, [u8; 64], TcbVersion), VcekChain>>,
+}
+
+impl AmdKds {
+    /// Create a source fetching from `base_url`.
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Self {
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            client: reqwest::Client::new(),
+            chains: Default::default(),
+        }
+    }
+
+    async fn fetch(&self, url: &str) -> Result<Vec<u8>> {
+        let response = self
+            .client
+            .get(url)
+            .timeout(Duration::from_secs(60))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .with_context(|| format!("Failed to fetch {url}"))?;
+        Ok(response.bytes().await?.to_vec())
+    }
+}
+
+impl KdsSource for AmdKds {
+    async fn vcek_chain(
+        &self,
+        product: &str,
+        chip_id: &[u8; 64],
+        tcb: TcbVersion,
+    ) -> Result<VcekChain> {
+        let key = (product.to_string(), *chip_id, tcb);
+        if let Some(chain) = self.chains.lock().unwrap().get(&key) {
+            return Ok(chain.clone());
+        }
+        let base = format!("{}/vcek/v1/{product}", self.base_url);
+        let pem = self.fetch(&format!("{base}/cert_chain")).await?;
+        let mut certs = x509_parser::pem::Pem::iter_from_buffer(&pem)
+            .map(|pem| pem.map(|pem| pem.contents))
+            .collect::<Result<Vec<_>, _>>()
+            .context("Invalid cert chain")?;
+        let (Some(ark), Some(ask), None) = (certs.pop(), certs.pop(), certs.pop()) else {
+            bail!("The cert chain is not ASK, ARK");
+        };
+        let vcek = self
+            .fetch(&format!(
+                "{base}/{}?blSPL={}&teeSPL={}&snpSPL={}&ucodeSPL={}",
+                hex::encode(chip_id),
+                tcb.bootloader,
+                tcb.tee,
+                tcb.snp,
+                tcb.microcode
+            ))
+            .await?;
+        let chain = VcekChain { ark, ask, vcek };
+        self.chains.lock().unwrap().insert(key, chain.clone());
+        Ok(chain)
+    }
+}
+
+/// A report whose signature chains up to a trusted ARK.
+#[derive(Debug, Clone)]
+pub struct VerifiedSnpReport {
+    /// The product the VCEK was issued for
+    pub product: String,
+    /// TCB status of the TCB version the VCEK was issued for
+    pub status: String,
+    /// The report
+    pub report: SnpReport,
+}
+
+/// Configuration of the process-wide SNP verifier.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SnpConfig {
+    /// Base URL of the KDS
+    pub kds_url: String,
+    /// The ARK certificates to trust, in PEM or DER
+    pub ark_certs: Vec<PathBuf>,
+    /// The product assumed for reports that do not carry the CPUID of the chip
+    pub product: String,
+    /// The latest TCB version, reports signed for an older one are `OutOfDate`
+    pub min_tcb: Option<TcbVersion>,
+}
+
+impl Default for SnpConfig {
+    fn default() -> Self {
+        Self {
+            kds_url: DEFAULT_KDS_URL.into(),
+            ark_certs: vec![],
+            product: DEFAULT_PRODUCT.into(),
+            min_tcb: None,
+        }
+    }
+}
+
+/// Verifies SNP reports against VCEK chains from a [`KdsSource`].
+pub struct SnpVerifier<K> {
+    kds: K,
+    trusted_arks: Vec<Vec<u8>>,
+    default_product: String,
+    min_tcb: Option<TcbVersion>,
+}
+
+impl<K: KdsSource> SnpVerifier<K> {
+    /// Create a verifier trusting the given DER encoded ARK certificates.
+    pub fn new(kds: K, trusted_arks: Vec<Vec<u8>>) -> Self {
+        Self {
+            kds,
+            trusted_arks,
+            default_product: DEFAULT_PRODUCT.into(),
+            min_tcb: None,
+        }
+    }
+
+    /// Set the product assumed for reports that do not carry the CPUID of the chip.
+    pub fn with_default_product(self, product: impl Into<String>) -> Self {
+        Self {
+            default_product: product.into(),
+            ..self
+        }
+    }
+
+    /// Set the latest TCB version. Reports signed for an older one are `OutOfDate`, which the
+    /// [`TcbPolicy`] of the caller decides on.
+    ///
+    /// [`TcbPolicy`]: crate::tcb_policy::TcbPolicy
+    pub fn with_min_tcb(self, min_tcb: Option<TcbVersion>) -> Self {
+        Self { min_tcb, ..self }
+    }
+
+    /// Verify a report.
+    pub async fn verify(&self, raw_report: &[u8]) -> Result<VerifiedSnpReport> {
+        let report = SnpReport::parse(raw_report)?;
+        if report.signature_algo != SIG_ALGO_ECDSA_P384_SHA384 {
+            bail!("Unsupported signature algorithm {}", report.signature_algo);
+        }
+        if report.chip_id == [0; 64] {
+            bail!("The chip id is masked, the VCEK can not be looked up");
+        }
+        let product = match report.product() {
+            Some(product) => product.to_string(),
+            None => self.default_product.clone(),
+        };
+        let chain = self
+            .kds
+            .vcek_chain(&product, &report.chip_id, report.reported_tcb)
+            .await
+            .context("Failed to get the VCEK chain")?;
+        let parse = |der| {
+            X509Certificate::from_der(der)
+                .map(|(_, cert)| cert)
+                .context("Invalid certificate")
+        };
+        let ark = parse(&chain.ark)?;
+        let ask = parse(&chain.ask)?;
+        let vcek = parse(&chain.vcek)?;
+        if !self
+            .trusted_arks
+            .iter()
+            .any(|trusted| *trusted == chain.ark)
+        {
+            bail!("The ARK is not trusted");
+        }
+        verify_signed_by(&ark, &ark).context("Invalid ARK signature")?;
+        verify_signed_by(&ask, &ark).context("Invalid ASK signature")?;
+        verify_signed_by(&vcek, &ask).context("Invalid VCEK signature")?;
+        let vcek_tcb =
+            check_vcek(&vcek, &report).context("The VCEK is not issued for the report")?;
+
+        let vcek_key = &vcek.public_key().subject_public_key.data;
+        UnparsedPublicKey::new(&ECDSA_P384_SHA384_FIXED, vcek_key)
+            .verify(&raw_report[..SIGNED_LEN], &report.signature())
+            .ok()
+            .context("Invalid report signature")?;
+        if report.is_debug() {
+            bail!("Debug mode is not allowed");
+        }
+        // Only the guest kernel runs at VMPL0, a report of a less privileged level may come from
+        // code it does not control
+        if report.vmpl != 0 {
+            bail!("The report was requested from VMPL{}", report.vmpl);
+        }
+        // The VCEK of an old TCB can still sign reports on a platform since updated, so the
+        // report is only as trustworthy as the TCB its VCEK was issued for.
+        let status = match &self.min_tcb {
+            Some(min_tcb) if !vcek_tcb.meets(min_tcb) => STATUS_OUT_OF_DATE,
+            _ => STATUS_UP_TO_DATE,
+        };
+        Ok(VerifiedSnpReport {
+            product,
+            status: status.into(),
+            report,
+        })
+    }
+}
+
+fn verify_signed_by(cert: &X509Certificate, issuer: &X509Certificate) -> Result<()> {
+    let algorithm: &dyn VerificationAlgorithm =
+        match cert.signature_algorithm.algorithm.to_id_string().as_str() {
+            OID_RSASSA_PSS => &RSA_PSS_2048_8192_SHA384,
+            OID_ECDSA_SHA384 => &ECDSA_P384_SHA384_ASN1,
+            other => bail!("Unsupported signature algorithm {other}"),
+        };
+    let issuer_key = &issuer.public_key().subject_public_key.data;
+    UnparsedPublicKey::new(algorithm, issuer_key)
+        .verify(cert.tbs_certificate.as_ref(), &cert.signature_value.data)
+        .ok()
+        .context("Signature mismatch")
+}
+
+/// Check that the VCEK is issued for the chip and TCB of the report, returning the TCB.
+fn check_vcek(vcek: &X509Certificate, report: &SnpReport) -> Result<TcbVersion> {
+    let extension = |oid: &[u64]| -> Result<&[u8]> {
+        let oid = Oid::from(oid).or(Err(anyhow::anyhow!("Invalid oid")))?;
+        Ok(vcek
+            .get_extension_unique(&oid)
+            .context("Failed to decode extensions")?
+            .with_context(|| format!("Missing extension {oid}"))?
+            .value)
+    };
+    let hwid = extension(OID_HWID)?;
+    // The KDS puts the raw chip id into the extension, DER wrap it is accepted as well
+    let hwid = match parse_der_octetstring(hwid) {
+        Ok((_, wrapped)) if hwid.len() != 64 => wrapped.as_slice()?,
+        _ => hwid,
+    };
+    if hwid != report.chip_id {
+        bail!("Chip id mismatch");
+    }
+    let spl = |oid| -> Result<u8> {
+        let (_, value) = parse_der_integer(extension(oid)?).context("Invalid SPL")?;
+        Ok(value.as_u32()?.try_into()?)
+    };
+    let tcb = TcbVersion {
+        bootloader: spl(OID_BL_SPL)?,
+        tee: spl(OID_TEE_SPL)?,
+        snp: spl(OID_SNP_SPL)?,
+        microcode: spl(OID_UCODE_SPL)?,
+    };
+    if tcb != report.reported_tcb {
+        bail!(
+            "TCB mismatch: VCEK {tcb:?}, report {:?}",
+            report.reported_tcb
+        );
+    }
+    Ok(tcb)
+}
+
+/// The `HOST_DATA` binding the app identity to an SNP guest at launch.
+///
+/// Not set by the VMM yet, see the [module documentation](self).
+pub fn app_digest(
+    app_id: &[u8],
+    compose_hash: &[u8],
+    instance_id: &[u8],
+    key_provider: &[u8],
+) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"dstack-sev-snp-app:");
+    for field in [app_id, compose_hash, instance_id, key_provider] {
+        hasher.update((field.len() as u32).to_le_bytes());
+        hasher.update(field);
+    }
+    hasher.finalize().into()
+}
+
+/// Check that the app events of the event log are the ones bound in `HOST_DATA` at launch.
+///
+/// The events are looked up in RTMR3 up to `system-ready`, the same way they are decoded.
+pub(crate) fn check_event_log(report: &SnpReport, event_log: &[EventLog]) -> Result<()> {
+    let boot_events = event_log
+        .iter()
+        .filter(|event| event.imr == 3)
+        .take_while(|event| event.event != "system-ready");
+    let payload = |name: &str| -> Result<&[u8]> {
+        let Some(event) = boot_events.clone().find(|event| event.event == name) else {
+            return Ok(&[]);
+        };
+        event
+            .validate()
+            .context("Failed to validate event digest")?;
+        Ok(&event.event_payload)
+    };
+    let digest = app_digest(
+        payload("app-id")?,
+        payload("compose-hash")?,
+        payload("instance-id")?,
+        payload("key-provider")?,
+    );
+    if digest != report.host_data {
+        bail!("The event log does not match the host data");
+    }
+    Ok(())
+}
+
+fn read_ark(path: &Path) -> Result<Vec<u8>> {
+    let data = fs::read(path)?;
+    match x509_parser::pem::parse_x509_pem(&data) {
+        Ok((_, pem)) => Ok(pem.contents),
+        Err(_) => Ok(data),
+    }
+}
+
+static GLOBAL: OnceLock<SnpVerifier<AmdKds>> = OnceLock::new();
+
+/// Set up the process-wide verifier used by [`Attestation::verify`]. Without it no ARK is
+/// trusted, and every SNP report is rejected.
+///
+/// [`Attestation::verify`]: crate::attestation::Attestation::verify
+pub fn init(config: &SnpConfig) -> Result<()> {
+    let arks = config
+        .ark_certs
+        .iter()
+        .map(|path| read_ark(path))
+        .collect::<Result<Vec<_>>>()
+        .context("Failed to read ARK certificates")?;
+    let verifier = SnpVerifier::new(AmdKds::new(&config.kds_url), arks)
+        .with_default_product(&config.product)
+        .with_min_tcb(config.min_tcb);
+    if GLOBAL.set(verifier).is_err() {
+        bail!("The SNP verifier is already set up");
+    }
+    Ok(())
+}
+
+/// The process-wide SNP verifier.
+pub fn global() -> &'static SnpVerifier<AmdKds> {
+    GLOBAL.get_or_init(|| SnpVerifier::new(AmdKds::new(DEFAULT_KDS_URL), vec![]))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use rcgen::{
+        BasicConstraints, Certificate, CertificateParams, CustomExtension, DnType, IsCa, KeyPair,
+        PKCS_ECDSA_P384_SHA384,
+    };
+    use ring::{
+        rand::SystemRandom,
+        signature::{EcdsaKeyPair, ECDSA_P384_SHA384_FIXED_SIGNING},
+    };
+
+    use super::*;
+    use crate::{
+        attestation::{Attestation, QuoteContentType, TeeKind, TeeReport},
+        simulator::rtmr3_event,
+        tcb_policy::TcbPolicy,
+    };
+
+    const CHIP_ID: [u8; 64] = [0x5a; 64];
+    const TCB: TcbVersion = TcbVersion {
+        bootloader: 3,
+        tee: 0,
+        snp: 14,
+        microcode: 209,
+    };
+
+    /// A test ARK, ASK and VCEK, standing in for the KDS.
+    struct TestKds {
+        ark: Certificate,
+        ask: Certificate,
+        vcek: Certificate,
+        vcek_key: KeyPair,
+        requests: AtomicUsize,
+    }
+
+    fn ca(name: &str) -> (CertificateParams, KeyPair) {
+        let mut params = CertificateParams::default();
+        params.distinguished_name.push(DnType::CommonName, name);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        (
+            params,
+            KeyPair::generate_for(&PKCS_ECDSA_P384_SHA384).unwrap(),
+        )
+    }
+
+    fn der_integer(value: u8) -> Vec<u8> {
+        if value < 0x80 {
+            vec![0x02, 1, value]
+        } else {
+            vec![0x02, 2, 0, value]
+        }
+    }
+
+    impl TestKds {
+        fn new(chip_id: [u8; 64], tcb: TcbVersion) -> Self {
+            let (params, ark_key) = ca("ARK-Test");
+            let ark = params.self_signed(&ark_key).unwrap();
+            let (params, ask_key) = ca("SEV-Test");
+            let ask = params.signed_by(&ask_key, &ark, &ark_key).unwrap();
+            let vcek_key = KeyPair::generate_for(&PKCS_ECDSA_P384_SHA384).unwrap();
+            let mut params = CertificateParams::default();
+            params
+                .distinguished_name
+                .push(DnType::CommonName, "SEV-VCEK");
+            for (oid, content) in [
+                (OID_BL_SPL, der_integer(tcb.bootloader)),
+                (OID_TEE_SPL, der_integer(tcb.tee)),
+                (OID_SNP_SPL, der_integer(tcb.snp)),
+                (OID_UCODE_SPL, der_integer(tcb.microcode)),
+                (OID_HWID, chip_id.to_vec()),
+            ] {
+                params
+                    .custom_extensions
+                    .push(CustomExtension::from_oid_content(oid, content));
+            }
+            let vcek = params.signed_by(&vcek_key, &ask, &ask_key).unwrap();
+            Self {
+                ark,
+                ask,
+                vcek,
+                vcek_key,
+                requests: AtomicUsize::new(0),
+            }
+        }
+
+        fn verifier(self) -> SnpVerifier<Self> {
+            let ark = self.ark.der().to_vec();
+            SnpVerifier::new(self, vec![ark])
+        }
+
+        /// Sign a version 3 report on a Genoa chip.
+        fn report(
+            &self,
+            report_data: [u8; 64],
+            measurement: [u8; 48],
+            host_data: [u8; 32],
+            policy: u64,
+        ) -> Vec<u8> {
+            let tcb = |tcb: TcbVersion| {
+                u64::from_le_bytes([tcb.bootloader, tcb.tee, 0, 0, 0, 0, tcb.snp, tcb.microcode])
+                    .to_le_bytes()
+            };
+            let mut raw = vec![0u8; REPORT_LEN];
+            raw[0x00..0x04].copy_from_slice(&3u32.to_le_bytes());
+            raw[0x08..0x10].copy_from_slice(&policy.to_le_bytes());
+            raw[0x34..0x38].copy_from_slice(&SIG_ALGO_ECDSA_P384_SHA384.to_le_bytes());
+            raw[0x38..0x40].copy_from_slice(&tcb(TCB));
+            raw[0x50..0x90].copy_from_slice(&report_data);
+            raw[0x90..0xc0].copy_from_slice(&measurement);
+            raw[0xc0..0xe0].copy_from_slice(&host_data);
+            raw[0x180..0x188].copy_from_slice(&tcb(TCB));
+            raw[0x188..0x18b].copy_from_slice(&[0x19, 0x11, 0x01]);
+            raw[0x1a0..0x1e0].copy_from_slice(&CHIP_ID);
+            self.sign(raw)
+        }
+
+        fn sign(&self, mut raw: Vec<u8>) -> Vec<u8> {
+            let rng = SystemRandom::new();
+            let key = EcdsaKeyPair::from_pkcs8(
+                &ECDSA_P384_SHA384_FIXED_SIGNING,
+                &self.vcek_key.serialize_der(),
+                &rng,
+            )
+            .unwrap();
+            let signature = key.sign(&rng, &raw[..SIGNED_LEN]).unwrap();
+            let (r, s) = signature.as_ref().split_at(48);
+            for (i, byte) in r.iter().rev().enumerate() {
+                raw[SIGNED_LEN + i] = *byte;
+            }
+            for (i, byte) in s.iter().rev().enumerate() {
+                raw[SIGNED_LEN + 72 + i] = *byte;
+            }
+            raw
+        }
+    }
+
+    impl KdsSource for TestKds {
+        async fn vcek_chain(
+            &self,
+            product: &str,
+            chip_id: &[u8; 64],
+            tcb: TcbVersion,
+        ) -> Result<VcekChain> {
+            self.requests.fetch_add(1, Ordering::SeqCst);
+            assert_eq!(product, "Genoa");
+            assert_eq!(chip_id, &CHIP_ID);
+            assert_eq!(tcb, TCB);
+            Ok(VcekChain {
+                ark: self.ark.der().to_vec(),
+                ask: self.ask.der().to_vec(),
+                vcek: self.vcek.der().to_vec(),
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_verify_report() {
+        let kds = TestKds::new(CHIP_ID, TCB);
+        let raw = kds.report([1; 64], [2; 48], [3; 32], 0x30000);
+        assert!(is_snp_report(&raw));
+        let verified = kds.verifier().verify(&raw).await.unwrap();
+        assert_eq!(verified.product, "Genoa");
+        assert_eq!(verified.report.report_data, [1; 64]);
+        assert_eq!(verified.report.measurement, [2; 48]);
+        assert_eq!(verified.report.host_data, [3; 32]);
+        assert_eq!(verified.report.reported_tcb, TCB);
+        assert!(!verified.report.is_debug());
+    }
+
+    #[tokio::test]
+    async fn test_reject_report() {
+        let kds = TestKds::new(CHIP_ID, TCB);
+        let raw = kds.report([1; 64], [2; 48], [3; 32], 0x30000);
+        let debug = kds.report([1; 64], [2; 48], [3; 32], 0x30000 | POLICY_DEBUG);
+        let verifier = kds.verifier();
+        let mut tampered = raw.clone();
+        tampered[0x90] ^= 1;
+        assert!(verifier.verify(&tampered).await.is_err());
+        assert!(verifier.verify(&debug).await.is_err());
+        let mut vmpl1 = raw.clone();
+        vmpl1[0x30..0x34].copy_from_slice(&1u32.to_le_bytes());
+        let err = verifier.verify(&kds.sign(vmpl1)).await.unwrap_err();
+        assert!(err.to_string().contains("VMPL1"), "{err}");
+
+        let untrusted = SnpVerifier::new(TestKds::new(CHIP_ID, TCB), vec![]);
+        let err = untrusted.verify(&raw).await.unwrap_err();
+        assert!(err.to_string().contains("not trusted"), "{err}");
+
+        // A VCEK of another chip, or of another TCB version, does not endorse the report
+        let other_chip = TestKds::new([0x6b; 64], TCB);
+        let raw = other_chip.report([1; 64], [2; 48], [3; 32], 0x30000);
+        assert!(other_chip.verifier().verify(&raw).await.is_err());
+        let old_tcb = TestKds::new(CHIP_ID, TcbVersion { snp: 8, ..TCB });
+        let raw = old_tcb.report([1; 64], [2; 48], [3; 32], 0x30000);
+        assert!(old_tcb.verifier().verify(&raw).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_snp_attestation() {
+        let events = [
+            rtmr3_event("app-id", &[0x11; 20]),
+            rtmr3_event("compose-hash", &[0x22; 32]),
+            rtmr3_event("instance-id", &[0x33; 20]),
+            rtmr3_event("key-provider", b"kms"),
+        ];
+        let host_data = app_digest(&[0x11; 20], &[0x22; 32], &[0x33; 20], b"kms");
+        let report_data = QuoteContentType::RaTlsCert.to_report_data(b"pubkey");
+        let kds = TestKds::new(CHIP_ID, TCB);
+        let raw = kds.report(report_data, [0x44; 48], host_data, 0x30000);
+        let verifier = kds.verifier();
+        let event_log = serde_json::to_vec(&events).unwrap();
+        let attestation = Attestation::new(raw.clone(), event_log.clone()).unwrap();
+        assert_eq!(attestation.tee_kind(), TeeKind::SevSnp);
+
+        let verified = attestation
+            .verify_sev_snp(&report_data, &verifier, &TcbPolicy::default())
+            .await
+            .unwrap();
+        assert_eq!(verified.report.tee_kind(), TeeKind::SevSnp);
+        assert_eq!(verified.report.status(), "UpToDate");
+        let info = verified.decode_app_info(false).unwrap();
+        assert_eq!(info.app_id, [0x11; 20]);
+        assert_eq!(info.compose_hash, [0x22; 32]);
+        assert_eq!(info.instance_id, [0x33; 20]);
+        assert_eq!(info.key_provider_info, b"kms");
+        assert_eq!(info.mrtd, [0x44; 48]);
+        assert_eq!(info.rtmr3, [0; 48]);
+        assert_eq!(info.device_id, sha2::Sha256::digest(CHIP_ID).to_vec());
+        assert_eq!(verifier.kds.requests.load(Ordering::SeqCst), 1);
+
+        // Events not bound at launch are rejected
+        let forged = serde_json::to_vec(&[
+            rtmr3_event("app-id", &[0x11; 20]),
+            rtmr3_event("compose-hash", &[0x55; 32]),
+        ])
+        .unwrap();
+        let attestation = Attestation::new(raw.clone(), forged).unwrap();
+        assert!(attestation
+            .verify_sev_snp(&report_data, &verifier, &TcbPolicy::default())
+            .await
+            .is_err());
+        let attestation = Attestation::new(raw, event_log).unwrap();
+        assert!(attestation
+            .verify_sev_snp(&[0; 64], &verifier, &TcbPolicy::default())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_snp_tcb_status() {
+        let kds = TestKds::new(CHIP_ID, TCB);
+        let raw = kds.report([1; 64], [2; 48], [3; 32], 0x30000);
+        let verifier = kds.verifier().with_min_tcb(Some(TCB));
+        assert_eq!(verifier.verify(&raw).await.unwrap().status, "UpToDate");
+        let verifier = verifier.with_min_tcb(Some(TcbVersion { snp: 15, ..TCB }));
+        let verified = verifier.verify(&raw).await.unwrap();
+        assert_eq!(verified.status, "OutOfDate");
+
+        let up_to_date_only = TcbPolicy {
+            accepted_statuses: vec!["UpToDate".into()],
+            ..Default::default()
+        };
+        let report = TeeReport::SevSnp(verified);
+        assert!(up_to_date_only.check(&report, None, 0).is_err());
+        assert!(TcbPolicy::default().check(&report, None, 0).is_ok());
+    }
+
+    #[test]
+    fn test_check_event_log_boot_events_only() {
+        let kds = TestKds::new(CHIP_ID, TCB);
+        let host_data = app_digest(&[0x11; 20], &[0x22; 32], &[], &[]);
+        let report = SnpReport::parse(&kds.report([0; 64], [0; 48], host_data, 0x30000)).unwrap();
+        let mut other_imr = rtmr3_event("compose-hash", &[0x55; 32]);
+        other_imr.imr = 1;
+        let events = vec![
+            other_imr,
+            rtmr3_event("app-id", &[0x11; 20]),
+            rtmr3_event("compose-hash", &[0x22; 32]),
+            rtmr3_event("system-ready", &[]),
+            rtmr3_event("instance-id", &[0x33; 20]),
+        ];
+        check_event_log(&report, &events).unwrap();
+
+        // Events emitted after boot do not count, even if nothing of that name came before
+        let late = vec![
+            rtmr3_event("app-id", &[0x11; 20]),
+            rtmr3_event("system-ready", &[]),
+            rtmr3_event("compose-hash", &[0x22; 32]),
+        ];
+        assert!(check_event_log(&report, &late).is_err());
+    }
+
+    #[test]
+    fn test_tell_apart_from_quotes() {
+        let quote = include_bytes!("../assets/tdx_quote");
+        assert!(!is_snp_report(quote));
+        assert!(!is_snp_report(&quote[..REPORT_LEN]));
+        assert!(SnpReport::parse(&[0; REPORT_LEN]).is_err());
+    }
+}
@@ -0,0 +1,565 @@
+//! Synthesized TDX quotes for testing without TDX hardware.
+//!
+//! [`QuoteSimulator`] builds TDX quote v4 structures from a [`SimProfile`] of measurements,
+//! together with an event log that replays to the RTMRs in the quote. The quotes are signed by a
+//! test PCK chain derived from a seed, so [`Attestation::verify`] rejects them as it only trusts
+//! Intel's root CA. [`SimVerifier`] checks them against the test root CA instead.
+//!
+//! Anyone knowing the seed can forge these quotes, so the verifier must only be used in tests.
+//! It is only built with the non-default `simulator` feature.
+//!
+//! [`Attestation::verify`]: crate::attestation::Attestation::verify
+
+use anyhow::{Context, Result};
+use cc_eventlog::TdxEventLog as EventLog;
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, CustomExtension, DnType, IsCa, KeyPair,
+};
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair as _, ECDSA_P256_SHA256_FIXED_SIGNING};
+use serde::{Deserialize, Serialize};
+use serde_human_bytes as hex_bytes;
+use sha2::{Digest as _, Sha256};
+#[cfg(any(test, feature = "simulator"))]
+use {
+    anyhow::bail,
+    qvl::{quote::Quote, verify::VerifiedReport},
+    ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_ASN1, ECDSA_P256_SHA256_FIXED},
+    x509_parser::{pem::Pem, prelude::X509Certificate},
+};
+
+use crate::{
+    attestation::replay_event_logs, kdf::derive_ecdsa_key_pair_from_bytes, traits::CertExt,
+};
+
+/// The seed of the test PCK chain used when none is configured.
+pub const DEFAULT_PCK_SEED: &str = "dstack simulated PCK";
+
+/// OID of the TCB status carried by the simulated PCK certificate. Outside of the RA-TLS arc as
+/// real certificates never carry it.
+const SIM_TCB_OID: &[u64] = &[1, 3, 6, 1, 4, 1, 62397, 99, 1];
+/// Event type of the events extended to RTMR3 by dstack.
+const DSTACK_EVENT_TYPE: u32 = 0x08000001;
+
+const QUOTE_VERSION: u16 = 4;
+const ATT_KEY_TYPE_ECDSA_P256: u16 = 2;
+const TEE_TYPE_TDX: u32 = 0x81;
+const INTEL_QE_VENDOR_ID: [u8; 16] = [
+    0x93, 0x9a, 0x72, 0x33, 0xf7, 0x9c, 0x4c, 0xa9, 0x94, 0x0a, 0x0d, 0xb3, 0x95, 0x7f, 0x06, 0x07,
+];
+const HEADER_LEN: usize = 48;
+const TD_REPORT10_LEN: usize = 584;
+const QE_REPORT_LEN: usize = 384;
+const QE_REPORT_DATA_OFFSET: usize = 320;
+const CERT_TYPE_PCK_CHAIN: u16 = 5;
+const CERT_TYPE_QE_REPORT: u16 = 6;
+const QE_AUTH_DATA: [u8; 32] = {
+    let mut data = [0u8; 32];
+    let mut i = 0;
+    while i < 32 {
+        data[i] = i as u8;
+        i += 1;
+    }
+    data
+};
+
+/// Build an event extended to RTMR3 at runtime, as `tdx_attest::extend_rtmr3` does.
+pub fn rtmr3_event(event: &str, payload: &[u8]) -> EventLog {
+    EventLog::new(3, DSTACK_EVENT_TYPE, event.to_string(), payload.to_vec())
+}
+
+/// The measurements and TCB of a simulated TD.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SimProfile {
+    /// MRTD, the measurement of the virtual firmware
+    #[serde(with = "hex_bytes")]
+    pub mr_td: [u8; 48],
+    /// MRCONFIGID
+    #[serde(with = "hex_bytes")]
+    pub mr_config_id: [u8; 48],
+    /// MROWNER
+    #[serde(with = "hex_bytes")]
+    pub mr_owner: [u8; 48],
+    /// MROWNERCONFIG
+    #[serde(with = "hex_bytes")]
+    pub mr_owner_config: [u8; 48],
+    /// Measurement of the TDX module
+    #[serde(with = "hex_bytes")]
+    pub mr_seam: [u8; 48],
+    /// SVNs of the TDX module and the platform
+    #[serde(with = "hex_bytes")]
+    pub tee_tcb_svn: [u8; 16],
+    /// TD attributes, with the debug bit at bit 0
+    #[serde(with = "hex_bytes")]
+    pub td_attributes: [u8; 8],
+    /// XFAM
+    #[serde(with = "hex_bytes")]
+    pub xfam: [u8; 8],
+    /// User data in the quote header, from which the device id is derived
+    #[serde(with = "hex_bytes")]
+    pub user_data: [u8; 20],
+    /// Events measured into RTMR0-2 during boot
+    pub boot_events: Vec<EventLog>,
+    /// App id measured into RTMR3
+    #[serde(with = "hex_bytes")]
+    pub app_id: Vec<u8>,
+    /// Compose hash measured into RTMR3
+    #[serde(with = "hex_bytes")]
+    pub compose_hash: Vec<u8>,
+    /// Instance id measured into RTMR3
+    #[serde(with = "hex_bytes")]
+    pub instance_id: Vec<u8>,
+    /// Key provider info measured into RTMR3, omitted if empty
+    #[serde(with = "hex_bytes")]
+    pub key_provider: Vec<u8>,
+    /// TCB status reported by the verifier, e.g. `UpToDate` or `OutOfDate`
+    pub tcb_status: String,
+    /// Advisory ids reported by the verifier
+    pub advisory_ids: Vec<String>,
+    /// Seed of the test PCK chain signing the quotes
+    pub pck_seed: String,
+}
+
+impl Default for SimProfile {
+    fn default() -> Self {
+        Self {
+            mr_td: [0; 48],
+            mr_config_id: [0; 48],
+            mr_owner: [0; 48],
+            mr_owner_config: [0; 48],
+            mr_seam: [0; 48],
+            tee_tcb_svn: [0; 16],
+            td_attributes: [0; 8],
+            xfam: [0; 8],
+            user_data: [0; 20],
+            boot_events: vec![],
+            app_id: vec![0; 20],
+            compose_hash: vec![0; 32],
+            instance_id: vec![0; 20],
+            key_provider: vec![],
+            tcb_status: "UpToDate".into(),
+            advisory_ids: vec![],
+            pck_seed: DEFAULT_PCK_SEED.into(),
+        }
+    }
+}
+
+impl SimProfile {
+    /// The event log of the TD at the time the app is started, in the order dstack-util
+    /// measures the app.
+    pub fn event_log(&self) -> Vec<EventLog> {
+        let mut events = self.boot_events.clone();
+        events.extend([
+            rtmr3_event("system-preparing", &[]),
+            rtmr3_event("app-id", &self.app_id),
+            rtmr3_event("compose-hash", &self.compose_hash),
+            rtmr3_event("instance-id", &self.instance_id),
+            rtmr3_event("boot-mr-done", &[]),
+        ]);
+        if !self.key_provider.is_empty() {
+            events.push(rtmr3_event("key-provider", &self.key_provider));
+        }
+        events.push(rtmr3_event("system-ready", &[]));
+        events
+    }
+}
+
+/// TCB info carried by the simulated PCK certificate.
+#[derive(Debug, Serialize, Deserialize)]
+struct SimTcb {
+    status: String,
+    advisory_ids: Vec<String>,
+}
+
+fn root_key(seed: &str) -> Result<KeyPair> {
+    derive_ecdsa_key_pair_from_bytes(seed.as_bytes(), &[b"root-ca"])
+}
+
+fn ring_key(key: &KeyPair) -> Result<EcdsaKeyPair> {
+    EcdsaKeyPair::from_pkcs8(
+        &ECDSA_P256_SHA256_FIXED_SIGNING,
+        key.serialized_der(),
+        &SystemRandom::new(),
+    )
+    .ok()
+    .context("Failed to load signing key")
+}
+
+fn ca_params(name: &str) -> Result<CertificateParams> {
+    let mut params = CertificateParams::new(vec![])?;
+    params.distinguished_name.push(DnType::CommonName, name);
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    Ok(params)
+}
+
+/// Synthesizes quotes of a simulated TD.
+pub struct QuoteSimulator {
+    profile: SimProfile,
+    pck_chain: String,
+    pck_key: EcdsaKeyPair,
+    attestation_key: EcdsaKeyPair,
+    rng: SystemRandom,
+}
+
+impl QuoteSimulator {
+    /// Create a simulator with a test PCK chain derived from the profile's seed.
+    pub fn new(profile: SimProfile) -> Result<Self> {
+        let seed = profile.pck_seed.as_bytes();
+        let root_key = root_key(&profile.pck_seed)?;
+        let pck_key = derive_ecdsa_key_pair_from_bytes(seed, &[b"pck"])?;
+        let attestation_key = derive_ecdsa_key_pair_from_bytes(seed, &[b"attestation-key"])?;
+
+        let root = ca_params("dstack Simulated Root CA")?.self_signed(&root_key)?;
+        let mut pck_params = CertificateParams::new(vec![])?;
+        pck_params
+            .distinguished_name
+            .push(DnType::CommonName, "dstack Simulated PCK Certificate");
+        let tcb = serde_json::to_vec(&SimTcb {
+            status: profile.tcb_status.clone(),
+            advisory_ids: profile.advisory_ids.clone(),
+        })?;
+        let content = yasna::construct_der(|writer| writer.write_bytes(&tcb));
+        pck_params
+            .custom_extensions
+            .push(CustomExtension::from_oid_content(SIM_TCB_OID, content));
+        let pck: Certificate = pck_params.signed_by(&pck_key, &root, &root_key)?;
+        Ok(Self {
+            profile,
+            pck_chain: format!("{}{}", pck.pem(), root.pem()),
+            pck_key: ring_key(&pck_key)?,
+            attestation_key: ring_key(&attestation_key)?,
+            rng: SystemRandom::new(),
+        })
+    }
+
+    /// The profile of the simulated TD.
+    pub fn profile(&self) -> &SimProfile {
+        &self.profile
+    }
+
+    /// The event log of the TD at the time the app is started.
+    pub fn event_log(&self) -> Vec<EventLog> {
+        self.profile.event_log()
+    }
+
+    fn sign(&self, key: &EcdsaKeyPair, message: &[u8]) -> Result<Vec<u8>> {
+        let signature = key
+            .sign(&self.rng, message)
+            .ok()
+            .context("Failed to sign")?;
+        Ok(signature.as_ref().to_vec())
+    }
+
+    /// Synthesize a quote over `report_data`, with the RTMRs replayed from `event_log`.
+    pub fn quote(&self, report_data: &[u8; 64], event_log: &[EventLog]) -> Result<Vec<u8>> {
+        let rtmrs = replay_event_logs(event_log, None).context("Failed to replay event log")?;
+        let profile = &self.profile;
+
+        let mut quote = Vec::with_capacity(HEADER_LEN + TD_REPORT10_LEN);
+        quote.extend(QUOTE_VERSION.to_le_bytes());
+        quote.extend(ATT_KEY_TYPE_ECDSA_P256.to_le_bytes());
+        quote.extend(TEE_TYPE_TDX.to_le_bytes());
+        // QE and PCE SVNs, reserved in v4
+        quote.extend([0u8; 4]);
+        quote.extend(INTEL_QE_VENDOR_ID);
+        quote.extend(profile.user_data);
+
+        quote.extend(profile.tee_tcb_svn);
+        quote.extend(profile.mr_seam);
+        // MRSIGNERSEAM, zero for Intel's TDX module
+        quote.extend([0u8; 48]);
+        // SEAM attributes
+        quote.extend([0u8; 8]);
+        quote.extend(profile.td_attributes);
+        quote.extend(profile.xfam);
+        quote.extend(profile.mr_td);
+        quote.extend(profile.mr_config_id);
+        quote.extend(profile.mr_owner);
+        quote.extend(profile.mr_owner_config);
+        for rtmr in rtmrs {
+            quote.extend(rtmr);
+        }
+        quote.extend(report_data);
+
+        let signature = self.sign(&self.attestation_key, &quote)?;
+        // Raw public key, without the SEC1 tag
+        let attestation_key = &self.attestation_key.public_key().as_ref()[1..];
+
+        let mut qe_report = [0u8; QE_REPORT_LEN];
+        let qe_report_data = sha256(&[attestation_key, &QE_AUTH_DATA]);
+        qe_report[QE_REPORT_DATA_OFFSET..QE_REPORT_DATA_OFFSET + 32]
+            .copy_from_slice(&qe_report_data);
+        let qe_report_signature = self.sign(&self.pck_key, &qe_report)?;
+
+        let mut qe_cert_data = qe_report.to_vec();
+        qe_cert_data.extend(qe_report_signature);
+        qe_cert_data.extend((QE_AUTH_DATA.len() as u16).to_le_bytes());
+        qe_cert_data.extend(QE_AUTH_DATA);
+        qe_cert_data.extend(CERT_TYPE_PCK_CHAIN.to_le_bytes());
+        qe_cert_data.extend((self.pck_chain.len() as u32).to_le_bytes());
+        qe_cert_data.extend(self.pck_chain.as_bytes());
+
+        let mut auth_data = signature;
+        auth_data.extend(attestation_key);
+        auth_data.extend(CERT_TYPE_QE_REPORT.to_le_bytes());
+        auth_data.extend((qe_cert_data.len() as u32).to_le_bytes());
+        auth_data.extend(qe_cert_data);
+
+        quote.extend((auth_data.len() as u32).to_le_bytes());
+        quote.extend(auth_data);
+        Ok(quote)
+    }
+}
+
+/// Verifies quotes synthesized by [`QuoteSimulator`]. Only for tests.
+#[cfg(any(test, feature = "simulator"))]
+#[derive(Debug)]
+pub struct SimVerifier {
+    root_public_key: Vec<u8>,
+}
+
+#[cfg(any(test, feature = "simulator"))]
+impl SimVerifier {
+    /// Create a verifier trusting the test root CA derived from `pck_seed`.
+    pub fn new(pck_seed: &str) -> Result<Self> {
+        Ok(Self {
+            root_public_key: root_key(pck_seed)?.public_key_raw().to_vec(),
+        })
+    }
+
+    /// Verify the signatures of the quote up to the test root CA.
+    ///
+    /// The TCB status and advisory ids are taken from the test PCK certificate.
+    pub fn verify(&self, quote: &[u8]) -> Result<VerifiedReport> {
+        let mut reader = Reader(quote);
+        let header = reader.take(HEADER_LEN)?;
+        if header[0..2] != QUOTE_VERSION.to_le_bytes() {
+            bail!("Unsupported quote version");
+        }
+        if header[2..4] != ATT_KEY_TYPE_ECDSA_P256.to_le_bytes() {
+            bail!("Unsupported attestation key type");
+        }
+        if header[4..8] != TEE_TYPE_TDX.to_le_bytes() {
+            bail!("Unsupported TEE type");
+        }
+        reader.take(TD_REPORT10_LEN)?;
+        let signed = &quote[..HEADER_LEN + TD_REPORT10_LEN];
+
+        let auth_len = reader.u32()?;
+        let mut auth = Reader(reader.take(auth_len)?);
+        let signature = auth.take(64)?;
+        let attestation_key = auth.take(64)?;
+        if auth.u16()? != CERT_TYPE_QE_REPORT {
+            bail!("Unsupported certification data type");
+        }
+        let qe_len = auth.u32()?;
+        let mut qe = Reader(auth.take(qe_len)?);
+        let qe_report = qe.take(QE_REPORT_LEN)?;
+        let qe_report_signature = qe.take(64)?;
+        let qe_auth_len = qe.u16()? as usize;
+        let qe_auth_data = qe.take(qe_auth_len)?;
+        if qe.u16()? != CERT_TYPE_PCK_CHAIN {
+            bail!("Unsupported QE certification data type");
+        }
+        let chain_len = qe.u32()?;
+        let (pck_public_key, tcb) = self.verify_pck_chain(qe.take(chain_len)?)?;
+
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, &pck_public_key)
+            .verify(qe_report, qe_report_signature)
+            .ok()
+            .context("Invalid QE report signature")?;
+        let qe_report_data = sha256(&[attestation_key, qe_auth_data]);
+        if qe_report[QE_REPORT_DATA_OFFSET..QE_REPORT_DATA_OFFSET + 32] != qe_report_data {
+            bail!("QE report does not match the attestation key");
+        }
+        let attestation_key = [&[0x04][..], attestation_key].concat();
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, &attestation_key)
+            .verify(signed, signature)
+            .ok()
+            .context("Invalid quote signature")?;
+
+        let quote = Quote::parse(quote).context("Failed to parse quote")?;
+        Ok(VerifiedReport {
+            status: tcb.status,
+            advisory_ids: tcb.advisory_ids,
+            report: quote.report,
+        })
+    }
+
+    /// Verify the PCK chain, returning the PCK public key and the TCB info it carries.
+    fn verify_pck_chain(&self, chain: &[u8]) -> Result<(Vec<u8>, SimTcb)> {
+        let pems = Pem::iter_from_buffer(chain)
+            .collect::<Result<Vec<_>, _>>()
+            .context("Invalid PCK chain")?;
+        let certs = pems
+            .iter()
+            .map(|pem| pem.parse_x509())
+            .collect::<Result<Vec<_>, _>>()
+            .context("Invalid PCK certificate")?;
+        let [pck, root] = &certs[..] else {
+            bail!("Invalid PCK chain length");
+        };
+        if root.public_key().subject_public_key.data.as_ref() != self.root_public_key {
+            bail!("PCK chain is not issued by the simulated root CA");
+        }
+        verify_signed_by(root, root)?;
+        verify_signed_by(pck, root)?;
+        if !pck.validity().is_valid() {
+            bail!("PCK certificate is not valid at this time");
+        }
+        let tcb = pck
+            .get_extension_bytes(SIM_TCB_OID)?
+            .context("PCK certificate carries no TCB info")?;
+        let tcb = serde_json::from_slice(&tcb).context("Invalid TCB info")?;
+        Ok((pck.public_key().subject_public_key.data.to_vec(), tcb))
+    }
+}
+
+#[cfg(any(test, feature = "simulator"))]
+fn verify_signed_by(cert: &X509Certificate, issuer: &X509Certificate) -> Result<()> {
+    UnparsedPublicKey::new(
+        &ECDSA_P256_SHA256_ASN1,
+        issuer.public_key().subject_public_key.data.as_ref(),
+    )
+    .verify(
+        cert.tbs_certificate.as_ref(),
+        cert.signature_value.data.as_ref(),
+    )
+    .ok()
+    .context("Invalid PCK chain signature")
+}
+
+fn sha256(data: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for d in data {
+        hasher.update(d);
+    }
+    hasher.finalize().into()
+}
+
+/// Reads the little-endian fields of a quote.
+#[cfg(any(test, feature = "simulator"))]
+struct Reader<'a>(&'a [u8]);
+
+#[cfg(any(test, feature = "simulator"))]
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.0.len() < len {
+            bail!("Quote is truncated");
+        }
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into()?))
+    }
+
+    fn u32(&mut self) -> Result<usize> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        attestation::{Attestation, QuoteContentType},
+        tcb_policy::TcbPolicy,
+    };
+
+    fn profile() -> SimProfile {
+        SimProfile {
+            mr_td: [0x11; 48],
+            compose_hash: vec![0x22; 32],
+            app_id: vec![0x33; 20],
+            key_provider: br#"{"name":"kms","id":"00"}"#.to_vec(),
+            boot_events: vec![EventLog {
+                imr: 0,
+                event_type: 1,
+                digest: [0x44; 48],
+                event: String::new(),
+                event_payload: vec![],
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn attest(simulator: &QuoteSimulator, report_data: &[u8; 64]) -> Attestation {
+        let event_log = simulator.event_log();
+        let quote = simulator.quote(report_data, &event_log).unwrap();
+        Attestation::new(quote, serde_json::to_vec(&event_log).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_simulated_quote_verifies() {
+        let simulator = QuoteSimulator::new(profile()).unwrap();
+        let verifier = SimVerifier::new(DEFAULT_PCK_SEED).unwrap();
+        let report_data = QuoteContentType::AppData.to_report_data(b"hello");
+        let verified = attest(&simulator, &report_data)
+            .verify_simulated(&report_data, &verifier, &TcbPolicy::default())
+            .unwrap();
+        assert_eq!(verified.report.status(), "UpToDate");
+
+        let info = verified.decode_app_info(false).unwrap();
+        assert_eq!(info.mrtd, [0x11; 48]);
+        assert_eq!(info.compose_hash, [0x22; 32]);
+        assert_eq!(info.app_id, [0x33; 20]);
+        assert_eq!(info.key_provider_info, profile().key_provider);
+        let td_report = verified.report.as_td10().unwrap();
+        assert_eq!(td_report.report_data, report_data);
+        assert_ne!(td_report.rt_mr0, [0; 48]);
+    }
+
+    #[test]
+    fn test_simulated_tcb_status() {
+        let simulator = QuoteSimulator::new(SimProfile {
+            tcb_status: "OutOfDate".into(),
+            advisory_ids: vec!["INTEL-SA-00837".into()],
+            ..profile()
+        })
+        .unwrap();
+        let event_log = simulator.event_log();
+        let quote = simulator.quote(&[0; 64], &event_log).unwrap();
+        let report = SimVerifier::new(DEFAULT_PCK_SEED)
+            .unwrap()
+            .verify(&quote)
+            .unwrap();
+        assert_eq!(report.status, "OutOfDate");
+        assert_eq!(report.advisory_ids, ["INTEL-SA-00837"]);
+    }
+
+    #[test]
+    fn test_simulated_quote_rejected() {
+        let simulator = QuoteSimulator::new(profile()).unwrap();
+        let verifier = SimVerifier::new(DEFAULT_PCK_SEED).unwrap();
+        let event_log = simulator.event_log();
+        let quote = simulator.quote(&[0; 64], &event_log).unwrap();
+
+        let mut tampered = quote.clone();
+        tampered[HEADER_LEN + 200] ^= 1;
+        assert!(verifier.verify(&tampered).is_err());
+        assert!(verifier.verify(&quote[..quote.len() - 1]).is_err());
+        let other_root = SimVerifier::new("another seed").unwrap();
+        assert!(other_root.verify(&quote).is_err());
+
+        // An event log that does not replay to the RTMRs of the quote
+        let mut attestation = attest(&simulator, &[0; 64]);
+        attestation.event_log.push(rtmr3_event("forged", b""));
+        assert!(attestation
+            .verify_simulated(&[0; 64], &verifier, &TcbPolicy::default())
+            .is_err());
+
+        let debug = QuoteSimulator::new(SimProfile {
+            td_attributes: [1, 0, 0, 0, 0, 0, 0, 0],
+            ..profile()
+        })
+        .unwrap();
+        let attestation = attest(&debug, &[0; 64]);
+        assert!(attestation
+            .verify_simulated(&[0; 64], &verifier, &TcbPolicy::default())
+            .is_err());
+    }
+}
@@ -0,0 +1,387 @@
+//! Policy deciding which verified quotes are acceptable.
+//!
+//! Quote verification only establishes that a quote was produced by a genuine TEE and reports
+//! the TCB status of the platform. Whether an out of date platform, or one affected by a given
+//! advisory, is still trusted is up to each service, and is configured with a [`TcbPolicy`].
+
+use std::time::Duration;
+
+use qvl::quote::Report;
+use serde::{Deserialize, Serialize};
+use serde_human_bytes as hex_bytes;
+use thiserror::Error;
+
+use crate::attestation::TeeReport;
+
+/// TCB status of a platform running the latest TCB.
+pub const STATUS_UP_TO_DATE: &str = "UpToDate";
+/// TCB status of a platform running the latest TCB, but needing software mitigations.
+pub const STATUS_SW_HARDENING_NEEDED: &str = "SWHardeningNeeded";
+/// TCB status of a platform running the latest TCB, but needing configuration changes.
+pub const STATUS_CONFIGURATION_NEEDED: &str = "ConfigurationNeeded";
+/// TCB status of a platform needing both configuration changes and software mitigations.
+pub const STATUS_CONFIGURATION_AND_SW_HARDENING_NEEDED: &str = "ConfigurationAndSWHardeningNeeded";
+/// TCB status of a platform missing the latest TCB recovery.
+pub const STATUS_OUT_OF_DATE: &str = "OutOfDate";
+/// TCB status of a platform missing the latest TCB recovery and needing configuration changes.
+pub const STATUS_OUT_OF_DATE_CONFIGURATION_NEEDED: &str = "OutOfDateConfigurationNeeded";
+
+/// A rule on a bit field of the report: the bits selected by `mask` must equal those of `value`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BitsRule {
+    /// The bits the rule applies to
+    #[serde(with = "hex_bytes")]
+    pub mask: [u8; 8],
+    /// The required values of the masked bits
+    #[serde(with = "hex_bytes")]
+    pub value: [u8; 8],
+}
+
+impl BitsRule {
+    fn matches(&self, bits: &[u8; 8]) -> bool {
+        (0..8).all(|i| bits[i] & self.mask[i] == self.value[i] & self.mask[i])
+    }
+}
+
+/// Which verified quotes are acceptable.
+///
+/// The default accepts every status the verifier does not reject outright, which is what
+/// services did before the policy was configurable.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TcbPolicy {
+    /// Accepted TCB statuses
+    pub accepted_statuses: Vec<String>,
+    /// How long out of date platforms stay acceptable after the latest TCB recovery was
+    /// published, `never` meaning forever
+    #[serde(with = "serde_duration")]
+    pub out_of_date_grace: Duration,
+    /// If set, every advisory affecting the platform must be listed
+    pub allowed_advisories: Option<Vec<String>>,
+    /// Advisories that must not affect the platform
+    pub denied_advisories: Vec<String>,
+    /// Rule on the TD attributes, not applicable to SGX quotes
+    pub td_attributes: Option<BitsRule>,
+    /// Rule on the XFAM, not applicable to SGX quotes
+    pub xfam: Option<BitsRule>,
+}
+
+impl Default for TcbPolicy {
+    fn default() -> Self {
+        Self {
+            accepted_statuses: [
+                STATUS_UP_TO_DATE,
+                STATUS_SW_HARDENING_NEEDED,
+                STATUS_CONFIGURATION_NEEDED,
+                STATUS_CONFIGURATION_AND_SW_HARDENING_NEEDED,
+                STATUS_OUT_OF_DATE,
+                STATUS_OUT_OF_DATE_CONFIGURATION_NEEDED,
+            ]
+            .map(String::from)
+            .to_vec(),
+            out_of_date_grace: Duration::MAX,
+            allowed_advisories: None,
+            denied_advisories: vec![],
+            td_attributes: None,
+            xfam: None,
+        }
+    }
+}
+
+/// The rule of a [`TcbPolicy`] a verified quote failed.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum TcbPolicyError {
+    /// The TCB status is not accepted
+    #[error("TCB status {0} is not accepted")]
+    StatusNotAccepted(String),
+    /// The grace period of an out of date platform can not be checked
+    #[error(
+        "TCB status {0} is only accepted within the grace period, but the TCB date is unknown"
+    )]
+    TcbDateUnknown(String),
+    /// The platform is out of date for longer than the grace period
+    #[error("TCB status {status} is out of the grace period since {expired_at}")]
+    GracePeriodExpired {
+        /// The TCB status
+        status: String,
+        /// When the grace period ended, in seconds since the UNIX epoch
+        expired_at: u64,
+    },
+    /// An advisory affecting the platform is denied
+    #[error("advisory {0} is denied")]
+    AdvisoryDenied(String),
+    /// An advisory affecting the platform is not in the allowed list
+    #[error("advisory {0} is not allowed")]
+    AdvisoryNotAllowed(String),
+    /// The TD attributes do not match the rule
+    #[error("TD attributes {actual} do not match {value} under mask {mask}")]
+    TdAttributesMismatch {
+        /// The TD attributes of the report, in hex
+        actual: String,
+        /// The mask of the rule, in hex
+        mask: String,
+        /// The value of the rule, in hex
+        value: String,
+    },
+    /// The XFAM does not match the rule
+    #[error("XFAM {actual} does not match {value} under mask {mask}")]
+    XfamMismatch {
+        /// The XFAM of the report, in hex
+        actual: String,
+        /// The mask of the rule, in hex
+        mask: String,
+        /// The value of the rule, in hex
+        value: String,
+    },
+    /// A TD rule is configured, but the quote is not from a TD
+    #[error("the policy has TD rules, but the quote is not a TD quote")]
+    NotTd,
+}
+
+impl TcbPolicy {
+    /// Check a verified report against the policy.
+    ///
+    /// `tcb_date` is when the latest TCB recovery was published, see [`latest_tcb_date`], and
+    /// `now` the current time, both in seconds since the UNIX epoch.
+    pub fn check(
+        &self,
+        report: &TeeReport,
+        tcb_date: Option<u64>,
+        now: u64,
+    ) -> Result<(), TcbPolicyError> {
+        let status = report.status();
+        if !self
+            .accepted_statuses
+            .iter()
+            .any(|accepted| accepted == status)
+        {
+            return Err(TcbPolicyError::StatusNotAccepted(status.into()));
+        }
+        let out_of_date =
+            status == STATUS_OUT_OF_DATE || status == STATUS_OUT_OF_DATE_CONFIGURATION_NEEDED;
+        if out_of_date && self.out_of_date_grace != Duration::MAX {
+            let Some(tcb_date) = tcb_date else {
+                return Err(TcbPolicyError::TcbDateUnknown(status.into()));
+            };
+            let expired_at = tcb_date.saturating_add(self.out_of_date_grace.as_secs());
+            if now >= expired_at {
+                return Err(TcbPolicyError::GracePeriodExpired {
+                    status: status.into(),
+                    expired_at,
+                });
+            }
+        }
+        for advisory in report.advisory_ids() {
+            if self.denied_advisories.contains(advisory) {
+                return Err(TcbPolicyError::AdvisoryDenied(advisory.clone()));
+            }
+            if let Some(allowed) = &self.allowed_advisories {
+                if !allowed.contains(advisory) {
+                    return Err(TcbPolicyError::AdvisoryNotAllowed(advisory.clone()));
+                }
+            }
+        }
+        if self.td_attributes.is_none() && self.xfam.is_none() {
+            return Ok(());
+        }
+        let td = match report.as_intel().map(|report| &report.report) {
+            Some(Report::TD10(report)) => report,
+            Some(Report::TD15(report)) => &report.base,
+            Some(Report::SgxEnclave(_)) | None => return Err(TcbPolicyError::NotTd),
+        };
+        if let Some(rule) = &self.td_attributes {
+            if !rule.matches(&td.td_attributes) {
+                return Err(TcbPolicyError::TdAttributesMismatch {
+                    actual: hex::encode(td.td_attributes),
+                    mask: hex::encode(rule.mask),
+                    value: hex::encode(rule.value),
+                });
+            }
+        }
+        if let Some(rule) = &self.xfam {
+            if !rule.matches(&td.xfam) {
+                return Err(TcbPolicyError::XfamMismatch {
+                    actual: hex::encode(td.xfam),
+                    mask: hex::encode(rule.mask),
+                    value: hex::encode(rule.value),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// When the latest TCB recovery was published: the latest `tcbDate` of the `UpToDate` levels of
+/// a TCB info, in seconds since the UNIX epoch.
+pub fn latest_tcb_date(tcb_info: &str) -> Option<u64> {
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct TcbLevel {
+        tcb_date: String,
+        tcb_status: String,
+    }
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct TcbInfo {
+        tcb_levels: Vec<TcbLevel>,
+    }
+    let info: TcbInfo = serde_json::from_str(tcb_info).ok()?;
+    info.tcb_levels
+        .iter()
+        .filter(|level| level.tcb_status == STATUS_UP_TO_DATE)
+        .filter_map(|level| chrono::DateTime::parse_from_rfc3339(&level.tcb_date).ok())
+        .map(|date| date.timestamp().max(0) as u64)
+        .max()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::{QuoteSimulator, SimProfile, SimVerifier, DEFAULT_PCK_SEED};
+
+    const DAY: u64 = 24 * 3600;
+
+    fn report(profile: SimProfile) -> TeeReport {
+        let simulator = QuoteSimulator::new(profile).unwrap();
+        let quote = simulator.quote(&[0; 64], &simulator.event_log()).unwrap();
+        let report = SimVerifier::new(DEFAULT_PCK_SEED)
+            .unwrap()
+            .verify(&quote)
+            .unwrap();
+        TeeReport::Intel(report)
+    }
+
+    fn out_of_date() -> TeeReport {
+        report(SimProfile {
+            tcb_status: STATUS_OUT_OF_DATE.into(),
+            advisory_ids: vec!["INTEL-SA-00837".into(), "INTEL-SA-00960".into()],
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_default_policy() {
+        let policy = TcbPolicy::default();
+        assert_eq!(policy.check(&out_of_date(), None, 0), Ok(()));
+        let revoked = report(SimProfile {
+            tcb_status: "Revoked".into(),
+            ..Default::default()
+        });
+        assert_eq!(
+            policy.check(&revoked, None, 0),
+            Err(TcbPolicyError::StatusNotAccepted("Revoked".into()))
+        );
+    }
+
+    #[test]
+    fn test_out_of_date_grace() {
+        let policy = TcbPolicy {
+            out_of_date_grace: Duration::from_secs(30 * DAY),
+            ..Default::default()
+        };
+        let report = out_of_date();
+        assert_eq!(policy.check(&report, Some(100 * DAY), 129 * DAY), Ok(()));
+        assert_eq!(
+            policy.check(&report, Some(100 * DAY), 130 * DAY),
+            Err(TcbPolicyError::GracePeriodExpired {
+                status: STATUS_OUT_OF_DATE.into(),
+                expired_at: 130 * DAY,
+            })
+        );
+        assert_eq!(
+            policy.check(&report, None, 0),
+            Err(TcbPolicyError::TcbDateUnknown(STATUS_OUT_OF_DATE.into()))
+        );
+        let up_to_date = self::report(Default::default());
+        assert_eq!(policy.check(&up_to_date, None, 0), Ok(()));
+    }
+
+    #[test]
+    fn test_advisories() {
+        let report = out_of_date();
+        let denied = TcbPolicy {
+            denied_advisories: vec!["INTEL-SA-00960".into()],
+            ..Default::default()
+        };
+        assert_eq!(
+            denied.check(&report, None, 0),
+            Err(TcbPolicyError::AdvisoryDenied("INTEL-SA-00960".into()))
+        );
+        let allowed = TcbPolicy {
+            allowed_advisories: Some(vec!["INTEL-SA-00837".into()]),
+            ..Default::default()
+        };
+        assert_eq!(
+            allowed.check(&report, None, 0),
+            Err(TcbPolicyError::AdvisoryNotAllowed("INTEL-SA-00960".into()))
+        );
+        let allowed = TcbPolicy {
+            allowed_advisories: Some(vec!["INTEL-SA-00837".into(), "INTEL-SA-00960".into()]),
+            ..Default::default()
+        };
+        assert_eq!(allowed.check(&report, None, 0), Ok(()));
+    }
+
+    #[test]
+    fn test_td_rules() {
+        let report = report(SimProfile {
+            td_attributes: [0, 0, 0, 0x10, 0, 0, 0, 0],
+            xfam: [0xe7, 0x02, 0x06, 0, 0, 0, 0, 0],
+            ..Default::default()
+        });
+        let policy = TcbPolicy {
+            td_attributes: Some(BitsRule {
+                mask: [0, 0, 0, 0x10, 0, 0, 0, 0],
+                value: [0, 0, 0, 0x10, 0, 0, 0, 0],
+            }),
+            xfam: Some(BitsRule {
+                mask: [0xff, 0x02, 0, 0, 0, 0, 0, 0],
+                value: [0xe7, 0x02, 0, 0, 0, 0, 0, 0],
+            }),
+            ..Default::default()
+        };
+        assert_eq!(policy.check(&report, None, 0), Ok(()));
+
+        let policy = TcbPolicy {
+            xfam: Some(BitsRule {
+                mask: [0, 0, 0x06, 0, 0, 0, 0, 0],
+                value: [0; 8],
+            }),
+            ..Default::default()
+        };
+        assert_eq!(
+            policy.check(&report, None, 0),
+            Err(TcbPolicyError::XfamMismatch {
+                actual: "e702060000000000".into(),
+                mask: "0000060000000000".into(),
+                value: "0000000000000000".into(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_latest_tcb_date() {
+        let tcb_info = r#"{"tcbLevels":[
+            {"tcb":{},"tcbDate":"2024-03-13T00:00:00Z","tcbStatus":"UpToDate"},
+            {"tcb":{},"tcbDate":"2023-08-09T00:00:00Z","tcbStatus":"OutOfDate"}
+        ]}"#;
+        assert_eq!(latest_tcb_date(tcb_info), Some(1710288000));
+        assert_eq!(latest_tcb_date("{}"), None);
+    }
+
+    #[test]
+    fn test_policy_config() {
+        let policy: TcbPolicy = serde_json::from_str(
+            r#"{
+                "accepted_statuses": ["UpToDate", "OutOfDate"],
+                "out_of_date_grace": "30d",
+                "td_attributes": {"mask": "0100000000000000", "value": "0000000000000000"}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(policy.accepted_statuses, ["UpToDate", "OutOfDate"]);
+        assert_eq!(policy.out_of_date_grace, Duration::from_secs(30 * DAY));
+        assert_eq!(policy.denied_advisories, Vec::<String>::new());
+        assert!(policy.xfam.is_none());
+    }
+}
@@ -0,0 +1,466 @@
+//! Attestation result tokens.
+//!
+//! A verifier service turns a [`VerifiedAttestation`] into a JWT signed with ES256, carrying the
+//! app identity and the TCB status as claims. Relying parties outside dstack can then accept
+//! dstack apps with ordinary JWT tooling, checking the token against the JWK set the verifier
+//! publishes instead of verifying quotes themselves.
+
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use rcgen::KeyPair;
+use ring::{
+    rand::SystemRandom,
+    signature::{
+        EcdsaKeyPair, KeyPair as _, UnparsedPublicKey, ECDSA_P256_SHA256_FIXED,
+        ECDSA_P256_SHA256_FIXED_SIGNING,
+    },
+};
+use serde::{Deserialize, Serialize};
+use serde_human_bytes as hex_bytes;
+use sha2::{Digest as _, Sha256};
+
+use crate::attestation::{TeeKind, VerifiedAttestation};
+
+/// The EAT profile of the token claims.
+pub const TOKEN_PROFILE: &str = "tag:phala.network,2025:attestation-result-v1";
+
+/// The JWS algorithm of the tokens.
+const ALG_ES256: &str = "ES256";
+
+/// The claims of an attestation result token.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AttestationClaims {
+    /// Issuer, the verifier service
+    pub iss: String,
+    /// Subject, the hex-encoded app id
+    pub sub: String,
+    /// Audience the token was requested for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aud: Option<String>,
+    /// Issue time, in seconds since the unix epoch
+    pub iat: u64,
+    /// Expiry time, in seconds since the unix epoch
+    pub exp: u64,
+    /// EAT profile of the claims, see [`TOKEN_PROFILE`]
+    pub eat_profile: String,
+    /// The kind of TEE: `tdx`, `sev-snp` or `sgx`
+    pub tee: String,
+    /// App ID
+    #[serde(with = "hex_bytes")]
+    pub app_id: Vec<u8>,
+    /// SHA256 of the app compose file
+    #[serde(with = "hex_bytes")]
+    pub compose_hash: Vec<u8>,
+    /// ID of the CVM instance
+    #[serde(with = "hex_bytes")]
+    pub instance_id: Vec<u8>,
+    /// ID of the device
+    #[serde(with = "hex_bytes")]
+    pub device_id: Vec<u8>,
+    /// Measurement of the entire vm execution environment
+    #[serde(with = "hex_bytes")]
+    pub mr_aggregated: Vec<u8>,
+    /// Measurement of the OS image
+    #[serde(with = "hex_bytes")]
+    pub os_image_hash: Vec<u8>,
+    /// The report data of the quote, binding the token to a nonce or key of the relying party
+    #[serde(with = "hex_bytes")]
+    pub report_data: Vec<u8>,
+    /// TCB status of the platform
+    pub tcb_status: String,
+    /// Advisories affecting the platform
+    pub advisory_ids: Vec<String>,
+}
+
+impl AttestationClaims {
+    /// The claims of a verified attestation, valid for `ttl` from `now`.
+    pub fn new(
+        attestation: &VerifiedAttestation,
+        issuer: &str,
+        audience: Option<&str>,
+        now: u64,
+        ttl: Duration,
+    ) -> Result<Self> {
+        let app_info = attestation
+            .decode_app_info(false)
+            .context("Failed to decode app info")?;
+        let tee = match attestation.report.tee_kind() {
+            TeeKind::Sgx => "sgx",
+            TeeKind::Tdx => "tdx",
+            TeeKind::SevSnp => "sev-snp",
+        };
+        Ok(Self {
+            iss: issuer.to_string(),
+            sub: hex::encode(&app_info.app_id),
+            aud: audience.map(str::to_string),
+            iat: now,
+            exp: now.saturating_add(ttl.as_secs()),
+            eat_profile: TOKEN_PROFILE.to_string(),
+            tee: tee.to_string(),
+            app_id: app_info.app_id,
+            compose_hash: app_info.compose_hash,
+            instance_id: app_info.instance_id,
+            device_id: app_info.device_id,
+            mr_aggregated: app_info.mr_aggregated.to_vec(),
+            os_image_hash: app_info.os_image_hash,
+            report_data: attestation.decode_report_data()?.to_vec(),
+            tcb_status: attestation.report.status().to_string(),
+            advisory_ids: attestation.report.advisory_ids().to_vec(),
+        })
+    }
+}
+
+/// A public key verifying tokens, as a JSON Web Key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Jwk {
+    /// Key type, always `EC`
+    pub kty: String,
+    /// Curve, always `P-256`
+    pub crv: String,
+    /// Base64url-encoded x coordinate
+    pub x: String,
+    /// Base64url-encoded y coordinate
+    pub y: String,
+    /// Key id, the RFC 7638 thumbprint of the key
+    pub kid: String,
+    /// Algorithm the key is used with
+    #[serde(default)]
+    pub alg: String,
+    /// Intended use of the key
+    #[serde(rename = "use", default)]
+    pub usage: String,
+}
+
+impl Jwk {
+    /// The JWK of an uncompressed P-256 public key.
+    pub fn from_public_key(public_key: &[u8]) -> Result<Self> {
+        let [0x04, coordinates @ ..] = public_key else {
+            bail!("public key is not an uncompressed point");
+        };
+        if coordinates.len() != 64 {
+            bail!("public key is not a P-256 point");
+        }
+        let x = URL_SAFE_NO_PAD.encode(&coordinates[..32]);
+        let y = URL_SAFE_NO_PAD.encode(&coordinates[32..]);
+        // The members required for EC keys, in lexicographic order, as RFC 7638 requires
+        let thumbprint = format!(r#"{{"crv":"P-256","kty":"EC","x":"{x}","y":"{y}"}}"#);
+        Ok(Self {
+            kty: "EC".into(),
+            crv: "P-256".into(),
+            kid: URL_SAFE_NO_PAD.encode(Sha256::digest(thumbprint.as_bytes())),
+            x,
+            y,
+            alg: ALG_ES256.into(),
+            usage: "sig".into(),
+        })
+    }
+
+    fn public_key(&self) -> Result<Vec<u8>> {
+        if self.kty != "EC" || self.crv != "P-256" {
+            bail!("unsupported key type: {} {}", self.kty, self.crv);
+        }
+        let mut public_key = vec![0x04];
+        for coordinate in [&self.x, &self.y] {
+            let coordinate = URL_SAFE_NO_PAD
+                .decode(coordinate)
+                .context("invalid key coordinate")?;
+            if coordinate.len() != 32 {
+                bail!("invalid key coordinate length");
+            }
+            public_key.extend(coordinate);
+        }
+        Ok(public_key)
+    }
+}
+
+/// A JSON Web Key set, as published by the verifier service.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Jwks {
+    /// The keys
+    pub keys: Vec<Jwk>,
+}
+
+impl Jwks {
+    /// Fetch the key set published at `url`.
+    pub async fn fetch(url: &str) -> Result<Self> {
+        let response = reqwest::get(url)
+            .await
+            .context("Failed to fetch the JWK set")?
+            .error_for_status()
+            .context("Failed to fetch the JWK set")?;
+        response.json().await.context("Failed to parse the JWK set")
+    }
+
+    /// The key with the given id.
+    pub fn find(&self, kid: &str) -> Option<&Jwk> {
+        self.keys.iter().find(|key| key.kid == kid)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    alg: String,
+    #[serde(default)]
+    typ: String,
+    #[serde(default)]
+    kid: String,
+}
+
+/// Issues attestation result tokens.
+pub struct TokenSigner {
+    key: EcdsaKeyPair,
+    jwk: Jwk,
+    issuer: String,
+}
+
+impl TokenSigner {
+    /// A signer of tokens from `issuer`, signing with the P-256 key `key`.
+    pub fn new(key: &KeyPair, issuer: impl Into<String>) -> Result<Self> {
+        let rng = SystemRandom::new();
+        let key =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, key.serialized_der(), &rng)
+                .map_err(|err| anyhow!("invalid token signing key: {err}"))?;
+        let jwk = Jwk::from_public_key(key.public_key().as_ref())?;
+        Ok(Self {
+            key,
+            jwk,
+            issuer: issuer.into(),
+        })
+    }
+
+    /// The issuer of the tokens.
+    pub fn issuer(&self) -> &str {
+        &self.issuer
+    }
+
+    /// The key set verifying the tokens, to be published to relying parties.
+    pub fn jwks(&self) -> Jwks {
+        Jwks {
+            keys: vec![self.jwk.clone()],
+        }
+    }
+
+    /// Issue a token for a verified attestation, valid for `ttl`.
+    pub fn issue(
+        &self,
+        attestation: &VerifiedAttestation,
+        audience: Option<&str>,
+        ttl: Duration,
+    ) -> Result<String> {
+        let claims = AttestationClaims::new(attestation, &self.issuer, audience, unix_now(), ttl)?;
+        self.sign(&claims)
+    }
+
+    /// Sign `claims` as a compact JWS.
+    pub fn sign(&self, claims: &AttestationClaims) -> Result<String> {
+        let header = Header {
+            alg: ALG_ES256.into(),
+            typ: "JWT".into(),
+            kid: self.jwk.kid.clone(),
+        };
+        let header = serde_json::to_vec(&header).context("Failed to encode token header")?;
+        let claims = serde_json::to_vec(claims).context("Failed to encode token claims")?;
+        let signing_input = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(header),
+            URL_SAFE_NO_PAD.encode(claims)
+        );
+        let signature = self
+            .key
+            .sign(&SystemRandom::new(), signing_input.as_bytes())
+            .map_err(|err| anyhow!("Failed to sign token: {err}"))?;
+        Ok(format!(
+            "{signing_input}.{}",
+            URL_SAFE_NO_PAD.encode(signature.as_ref())
+        ))
+    }
+}
+
+/// Verifies attestation result tokens on the relying party side.
+#[derive(Debug, Clone)]
+pub struct TokenVerifier {
+    jwks: Jwks,
+    issuer: String,
+    audience: Option<String>,
+}
+
+impl TokenVerifier {
+    /// A verifier of tokens from `issuer`, signed with a key in `jwks`.
+    pub fn new(jwks: Jwks, issuer: impl Into<String>) -> Self {
+        Self {
+            jwks,
+            issuer: issuer.into(),
+            audience: None,
+        }
+    }
+
+    /// Only accept tokens issued for `audience`.
+    pub fn with_audience(mut self, audience: impl Into<String>) -> Self {
+        self.audience = Some(audience.into());
+        self
+    }
+
+    /// Verify a token and return its claims.
+    pub fn verify(&self, token: &str) -> Result<AttestationClaims> {
+        self.verify_at(token, unix_now())
+    }
+
+    /// Verify a token as of `now`, in seconds since the unix epoch.
+    pub fn verify_at(&self, token: &str, now: u64) -> Result<AttestationClaims> {
+        let (signing_input, signature) = token.rsplit_once('.').context("malformed token")?;
+        let (header, claims) = signing_input.split_once('.').context("malformed token")?;
+        let header: Header = decode_part(header).context("invalid token header")?;
+        if header.alg != ALG_ES256 {
+            bail!("unsupported token algorithm: {}", header.alg);
+        }
+        let key = self
+            .jwks
+            .find(&header.kid)
+            .with_context(|| format!("unknown token key: {}", header.kid))?;
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .context("invalid token signature")?;
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, key.public_key()?)
+            .verify(signing_input.as_bytes(), &signature)
+            .map_err(|_| anyhow!("invalid token signature"))?;
+        let claims: AttestationClaims = decode_part(claims).context("invalid token claims")?;
+        if claims.iss != self.issuer {
+            bail!("unexpected token issuer: {}", claims.iss);
+        }
+        if claims.exp <= now {
+            bail!("token expired at {}", claims.exp);
+        }
+        if let Some(audience) = &self.audience {
+            if claims.aud.as_ref() != Some(audience) {
+                bail!("unexpected token audience: {:?}", claims.aud);
+            }
+        }
+        Ok(claims)
+    }
+}
+
+fn decode_part<T: serde::de::DeserializeOwned>(part: &str) -> Result<T> {
+    let json = URL_SAFE_NO_PAD.decode(part).context("invalid base64")?;
+    serde_json::from_slice(&json).context("invalid json")
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use rcgen::PKCS_ECDSA_P256_SHA256;
+
+    use super::*;
+    use crate::{
+        attestation::{Attestation, QuoteContentType},
+        simulator::{QuoteSimulator, SimProfile, SimVerifier, DEFAULT_PCK_SEED},
+        tcb_policy::TcbPolicy,
+    };
+
+    const ISSUER: &str = "https://kms.dstack.example";
+
+    fn verified_attestation() -> VerifiedAttestation {
+        let simulator = QuoteSimulator::new(SimProfile {
+            app_id: vec![0x33; 20],
+            tcb_status: "SWHardeningNeeded".into(),
+            advisory_ids: vec!["INTEL-SA-00615".into()],
+            ..Default::default()
+        })
+        .unwrap();
+        let report_data = QuoteContentType::AppData.to_report_data(b"nonce");
+        let event_log = simulator.event_log();
+        let quote = simulator.quote(&report_data, &event_log).unwrap();
+        Attestation::new(quote, serde_json::to_vec(&event_log).unwrap())
+            .unwrap()
+            .verify_simulated(
+                &report_data,
+                &SimVerifier::new(DEFAULT_PCK_SEED).unwrap(),
+                &TcbPolicy::default(),
+            )
+            .unwrap()
+    }
+
+    fn signer() -> TokenSigner {
+        let key = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256).unwrap();
+        TokenSigner::new(&key, ISSUER).unwrap()
+    }
+
+    #[test]
+    fn test_issue_and_verify() {
+        let signer = signer();
+        let attestation = verified_attestation();
+        let token = signer
+            .issue(&attestation, Some("backend"), Duration::from_secs(60))
+            .unwrap();
+
+        // Relying parties only see the published key set
+        let jwks: Jwks =
+            serde_json::from_str(&serde_json::to_string(&signer.jwks()).unwrap()).unwrap();
+        let claims = TokenVerifier::new(jwks, ISSUER)
+            .with_audience("backend")
+            .verify(&token)
+            .unwrap();
+        assert_eq!(claims.sub, hex::encode([0x33; 20]));
+        assert_eq!(claims.app_id, [0x33; 20]);
+        assert_eq!(claims.tee, "tdx");
+        assert_eq!(claims.tcb_status, "SWHardeningNeeded");
+        assert_eq!(claims.advisory_ids, ["INTEL-SA-00615"]);
+        assert_eq!(
+            claims.report_data,
+            QuoteContentType::AppData.to_report_data(b"nonce")
+        );
+        let app_info = attestation.decode_app_info(false).unwrap();
+        assert_eq!(claims.mr_aggregated, app_info.mr_aggregated);
+        assert_eq!(claims.compose_hash, app_info.compose_hash);
+    }
+
+    #[test]
+    fn test_reject_tokens() {
+        let signer = signer();
+        let attestation = verified_attestation();
+        let now = unix_now();
+        let claims =
+            AttestationClaims::new(&attestation, ISSUER, None, now, Duration::from_secs(60))
+                .unwrap();
+        let token = signer.sign(&claims).unwrap();
+        let verifier = TokenVerifier::new(signer.jwks(), ISSUER);
+        verifier.verify_at(&token, now).unwrap();
+
+        // Expired
+        assert!(verifier.verify_at(&token, now + 60).is_err());
+        // Wrong issuer or audience
+        assert!(TokenVerifier::new(signer.jwks(), "https://evil.example")
+            .verify_at(&token, now)
+            .is_err());
+        assert!(verifier
+            .clone()
+            .with_audience("backend")
+            .verify_at(&token, now)
+            .is_err());
+        // Signed by a key outside the set
+        assert!(TokenVerifier::new(self::signer().jwks(), ISSUER)
+            .verify_at(&token, now)
+            .is_err());
+
+        // Tampered claims
+        let forged = AttestationClaims {
+            tcb_status: "UpToDate".into(),
+            ..claims
+        };
+        let (header, _) = token.split_once('.').unwrap();
+        let (_, signature) = token.rsplit_once('.').unwrap();
+        let forged = format!(
+            "{header}.{}.{signature}",
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&forged).unwrap())
+        );
+        let err = verifier.verify_at(&forged, now).unwrap_err();
+        assert!(err.to_string().contains("signature"), "{err}");
+    }
+}
@@ -0,0 +1,426 @@
+//! RA-TLS certificate verifiers for rustls.
+//!
+//! [`RaTlsVerifier`] implements both [`ServerCertVerifier`] and [`ClientCertVerifier`]: instead
+//! of chaining the peer certificate up to a CA, it verifies the quote in the certificate against
+//! the certificate's public key, and checks the attested app against an [`Expectation`]. The
+//! attestation is the identity of the peer, so the server name is not checked.
+//!
+//! rustls verifiers are synchronous while quote verification may have to fetch collateral, so
+//! each verification runs on a scoped thread with its own runtime. Collateral is cached, see
+//! [`crate::collateral`], so only the first handshake with a platform waits for PCCS.
+
+use std::{fmt, future::Future, sync::Arc};
+
+use anyhow::{anyhow, bail, Context, Result};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    server::danger::{ClientCertVerified, ClientCertVerifier},
+    ClientConfig, DigitallySignedStruct, DistinguishedName, ServerConfig, SignatureScheme,
+};
+use x509_parser::parse_x509_certificate;
+
+#[cfg(any(test, feature = "simulator"))]
+use crate::simulator::SimVerifier;
+use crate::{
+    attestation::{AppInfo, Attestation, QuoteContentType, VerifiedAttestation},
+    tcb_policy::TcbPolicy,
+};
+
+/// What the attested peer must be running. Unset fields match anything.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Expectation {
+    /// App id of the peer
+    pub app_id: Option<Vec<u8>>,
+    /// Hash of the app compose file of the peer
+    pub compose_hash: Option<Vec<u8>>,
+    /// Measurement of the entire VM of the peer
+    pub mr_aggregated: Option<Vec<u8>>,
+    /// Hash of the OS image of the peer
+    pub os_image_hash: Option<Vec<u8>>,
+}
+
+impl Expectation {
+    /// Check the app info of a verified attestation against the expectation.
+    pub fn check(&self, info: &AppInfo) -> Result<()> {
+        fn check_field(name: &str, expected: &Option<Vec<u8>>, actual: &[u8]) -> Result<()> {
+            match expected {
+                Some(expected) if expected != actual => bail!(
+                    "{name} mismatch: expected {}, got {}",
+                    hex::encode(expected),
+                    hex::encode(actual)
+                ),
+                _ => Ok(()),
+            }
+        }
+        check_field("app_id", &self.app_id, &info.app_id)?;
+        check_field("compose_hash", &self.compose_hash, &info.compose_hash)?;
+        check_field("mr_aggregated", &self.mr_aggregated, &info.mr_aggregated)?;
+        check_field("os_image_hash", &self.os_image_hash, &info.os_image_hash)?;
+        Ok(())
+    }
+}
+
+/// Verifies RA-TLS certificates of TLS servers and clients.
+pub struct RaTlsVerifier {
+    expectation: Expectation,
+    pccs_url: Option<String>,
+    tcb_policy: TcbPolicy,
+    #[cfg(any(test, feature = "simulator"))]
+    sim_verifier: Option<Arc<SimVerifier>>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl fmt::Debug for RaTlsVerifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("RaTlsVerifier");
+        debug
+            .field("expectation", &self.expectation)
+            .field("pccs_url", &self.pccs_url)
+            .field("tcb_policy", &self.tcb_policy);
+        #[cfg(any(test, feature = "simulator"))]
+        debug.field("simulated", &self.sim_verifier.is_some());
+        debug.finish_non_exhaustive()
+    }
+}
+
+impl RaTlsVerifier {
+    /// Create a verifier accepting peers that meet `expectation`.
+    pub fn new(expectation: Expectation) -> Self {
+        Self {
+            expectation,
+            pccs_url: None,
+            tcb_policy: TcbPolicy::default(),
+            #[cfg(any(test, feature = "simulator"))]
+            sim_verifier: None,
+            provider: Arc::new(ring::default_provider()),
+        }
+    }
+
+    /// Set the PCCS to fetch collateral from.
+    pub fn with_pccs_url(self, pccs_url: Option<String>) -> Self {
+        Self { pccs_url, ..self }
+    }
+
+    /// Set the TCB policy the peer platform must meet.
+    pub fn with_tcb_policy(self, tcb_policy: TcbPolicy) -> Self {
+        Self { tcb_policy, ..self }
+    }
+
+    /// Verify quotes against the test PCK chain of the simulator instead. Only for tests.
+    #[cfg(any(test, feature = "simulator"))]
+    pub fn with_sim_verifier(self, sim_verifier: SimVerifier) -> Self {
+        Self {
+            sim_verifier: Some(Arc::new(sim_verifier)),
+            ..self
+        }
+    }
+
+    /// Verify the attestation of a DER encoded RA-TLS certificate.
+    pub async fn verify_cert(&self, cert_der: &[u8]) -> Result<VerifiedAttestation> {
+        let (_, cert) = parse_x509_certificate(cert_der).context("Failed to parse certificate")?;
+        if !cert.validity().is_valid() {
+            bail!("The certificate is expired or not yet valid");
+        }
+        let attestation =
+            Attestation::from_cert(&cert)?.context("The certificate carries no attestation")?;
+        let pubkey = cert.public_key().raw;
+        #[cfg(any(test, feature = "simulator"))]
+        {
+            if let Some(sim_verifier) = &self.sim_verifier {
+                let verified = attestation.verify_simulated(
+                    &QuoteContentType::RaTlsCert.to_report_data(pubkey),
+                    sim_verifier,
+                    &self.tcb_policy,
+                )?;
+                return self.check_expectation(verified);
+            }
+        }
+        let verified = attestation
+            .verify_with_ra_pubkey(pubkey, self.pccs_url.as_deref(), &self.tcb_policy)
+            .await?;
+        self.check_expectation(verified)
+    }
+
+    fn check_expectation(&self, verified: VerifiedAttestation) -> Result<VerifiedAttestation> {
+        let info = verified.decode_app_info(false)?;
+        self.expectation
+            .check(&info)
+            .context("The peer does not meet the expectation")?;
+        Ok(verified)
+    }
+
+    fn verify_cert_blocking(&self, cert_der: &[u8]) -> Result<(), rustls::Error> {
+        block_on(self.verify_cert(cert_der))
+            .map(|_| ())
+            .map_err(|err| rustls::Error::General(format!("RA-TLS verification failed: {err:#}")))
+    }
+
+    /// TLS configuration for connecting to an RA-TLS server, optionally authenticating with a
+    /// PEM encoded certificate chain and key.
+    pub fn client_config(self, identity: Option<(&str, &str)>) -> Result<ClientConfig> {
+        let provider = self.provider.clone();
+        let builder = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(self));
+        match identity {
+            Some((cert_pem, key_pem)) => {
+                let (chain, key) = parse_identity(cert_pem, key_pem)?;
+                builder
+                    .with_client_auth_cert(chain, key)
+                    .context("Failed to set client certificate")
+            }
+            None => Ok(builder.with_no_client_auth()),
+        }
+    }
+
+    /// TLS configuration for an RA-TLS server requiring RA-TLS clients, serving a PEM encoded
+    /// certificate chain and key.
+    pub fn server_config(self, cert_pem: &str, key_pem: &str) -> Result<ServerConfig> {
+        let (chain, key) = parse_identity(cert_pem, key_pem)?;
+        ServerConfig::builder_with_provider(self.provider.clone())
+            .with_safe_default_protocol_versions()?
+            .with_client_cert_verifier(Arc::new(self))
+            .with_single_cert(chain, key)
+            .context("Failed to set server certificate")
+    }
+}
+
+fn parse_identity(
+    cert_pem: &str,
+    key_pem: &str,
+) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    let chain = CertificateDer::pem_slice_iter(cert_pem.as_bytes())
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to parse certificate chain")?;
+    let key = PrivateKeyDer::from_pem_slice(key_pem.as_bytes()).context("Failed to parse key")?;
+    Ok((chain, key))
+}
+
+/// Run `fut` to completion from synchronous code, whether or not a runtime is running.
+fn block_on<T: Send>(fut: impl Future<Output = Result<T>> + Send) -> Result<T> {
+    std::thread::scope(|scope| {
+        scope
+            .spawn(|| {
+                tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .context("Failed to build runtime")?
+                    .block_on(fut)
+            })
+            .join()
+            .map_err(|_| anyhow!("Verification panicked"))?
+    })
+}
+
+impl ServerCertVerifier for RaTlsVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.verify_cert_blocking(end_entity)?;
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+impl ClientCertVerifier for RaTlsVerifier {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        self.verify_cert_blocking(end_entity)?;
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rcgen::{KeyPair, PKCS_ECDSA_P256_SHA256};
+    use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+    use super::*;
+    use crate::{
+        cert::CertRequest,
+        simulator::{QuoteSimulator, SimProfile, DEFAULT_PCK_SEED},
+    };
+
+    const APP_ID: [u8; 20] = [0x33; 20];
+
+    /// An RA-TLS certificate and key of a simulated TD running `app_id`.
+    fn ra_tls_identity(app_id: [u8; 20], quoted: bool) -> (String, String) {
+        let simulator = QuoteSimulator::new(SimProfile {
+            app_id: app_id.to_vec(),
+            ..Default::default()
+        })
+        .unwrap();
+        let key = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256).unwrap();
+        let report_data = QuoteContentType::RaTlsCert.to_report_data(&key.public_key_der());
+        let event_log = simulator.event_log();
+        let quote = simulator.quote(&report_data, &event_log).unwrap();
+        let event_log = serde_json::to_vec(&event_log).unwrap();
+        let alt_names = ["app.dstack".to_string()];
+        let cert = CertRequest::builder()
+            .subject("app.dstack")
+            .alt_names(&alt_names)
+            .maybe_quote(quoted.then_some(&quote[..]))
+            .maybe_event_log(quoted.then_some(&event_log[..]))
+            .usage_server_auth(true)
+            .usage_client_auth(true)
+            .key(&key)
+            .build()
+            .self_signed()
+            .unwrap();
+        (cert.pem(), key.serialize_pem())
+    }
+
+    fn verifier(app_id: [u8; 20]) -> RaTlsVerifier {
+        RaTlsVerifier::new(Expectation {
+            app_id: Some(app_id.to_vec()),
+            ..Default::default()
+        })
+        .with_sim_verifier(SimVerifier::new(DEFAULT_PCK_SEED).unwrap())
+    }
+
+    async fn handshake(
+        client: RaTlsVerifier,
+        client_identity: &(String, String),
+        server: RaTlsVerifier,
+        server_identity: &(String, String),
+    ) -> (bool, bool) {
+        let client_config = client
+            .client_config(Some((&client_identity.0, &client_identity.1)))
+            .unwrap();
+        let server_config = server
+            .server_config(&server_identity.0, &server_identity.1)
+            .unwrap();
+        let connector = TlsConnector::from(Arc::new(client_config));
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+        let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+        let server_name = ServerName::try_from("app.dstack").unwrap();
+        let (client, server) = tokio::join!(
+            connector.connect(server_name, client_io),
+            acceptor.accept(server_io)
+        );
+        (client.is_ok(), server.is_ok())
+    }
+
+    #[tokio::test]
+    async fn test_mutual_ra_tls() {
+        let alice = ra_tls_identity(APP_ID, true);
+        let bob = ra_tls_identity(APP_ID, true);
+        assert_eq!(
+            handshake(verifier(APP_ID), &alice, verifier(APP_ID), &bob).await,
+            (true, true)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reject_unexpected_peer() {
+        let alice = ra_tls_identity(APP_ID, true);
+        let mallory = ra_tls_identity([0x44; 20], true);
+        assert_ne!(
+            handshake(verifier(APP_ID), &alice, verifier(APP_ID), &mallory).await,
+            (true, true)
+        );
+        assert_ne!(
+            handshake(verifier(APP_ID), &mallory, verifier(APP_ID), &alice).await,
+            (true, true)
+        );
+        let unquoted = ra_tls_identity(APP_ID, false);
+        assert_ne!(
+            handshake(verifier(APP_ID), &alice, verifier(APP_ID), &unquoted).await,
+            (true, true)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_verify_cert() {
+        let (cert_pem, _) = ra_tls_identity(APP_ID, true);
+        let cert = CertificateDer::from_pem_slice(cert_pem.as_bytes()).unwrap();
+        let verified = verifier(APP_ID).verify_cert(&cert).await.unwrap();
+        assert_eq!(verified.decode_app_info(false).unwrap().app_id, APP_ID);
+
+        let err = verifier([0x44; 20]).verify_cert(&cert).await.unwrap_err();
+        assert!(format!("{err:#}").contains("app_id mismatch"), "{err:#}");
+    }
+}
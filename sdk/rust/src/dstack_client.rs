@@ -77,6 +77,79 @@ pub struct TlsKeyConfig {
     pub usage_client_auth: bool,
 }
 
+/// Filter of the events returned by `get_event_log`
+#[derive(bon::Builder, Default)]
+pub struct EventLogQuery {
+    /// Only events extended to this IMR
+    pub imr: Option<u32>,
+    /// Only events with this name
+    #[builder(into)]
+    pub event: Option<String>,
+    /// Only events with an index not less than this
+    pub start: Option<u32>,
+    /// Only events with an index less than this
+    pub end: Option<u32>,
+    /// Include a quote taken over the same event log
+    #[builder(default = false)]
+    pub with_quote: bool,
+    /// Report data of the quote, up to 64 bytes
+    #[builder(default = Vec::new())]
+    pub report_data: Vec<u8>,
+}
+
+/// An event in the event log, with its index in the full log
+#[derive(Serialize, Deserialize)]
+pub struct EventLogEntry {
+    #[serde(default)]
+    pub index: u32,
+    #[serde(flatten)]
+    pub event: EventLog,
+    /// Value of the IMR right after this event is extended to it, in hexadecimal format
+    #[serde(default)]
+    pub imr_after: String,
+}
+
+/// Response of an event log query
+#[derive(Serialize, Deserialize)]
+pub struct EventLogResponse {
+    /// The matching events
+    #[serde(default)]
+    pub events: Vec<EventLogEntry>,
+    /// Number of events in the full event log
+    #[serde(default)]
+    pub total_events: u32,
+    /// RTMR0-3 replayed by the agent from the full event log, in hexadecimal format
+    pub rtmrs: Vec<String>,
+    /// Quote taken over the same event log in hexadecimal format, if requested
+    #[serde(default)]
+    pub quote: String,
+    /// Whether the replayed RTMRs equal the RTMRs in the quote, as checked by the agent
+    #[serde(default)]
+    pub replay_matches_quote: bool,
+}
+
+/// Algorithm of a key derived with `GetKey`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyAlgorithm {
+    #[default]
+    Secp256k1,
+    Ed25519,
+    Secp256r1,
+    X25519,
+}
+
+impl KeyAlgorithm {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KeyAlgorithm::Secp256k1 => "secp256k1",
+            KeyAlgorithm::Ed25519 => "ed25519",
+            KeyAlgorithm::Secp256r1 => "secp256r1",
+            KeyAlgorithm::X25519 => "x25519",
+        }
+    }
+}
+
 /// Response containing a key and its signature chain
 #[derive(Serialize, Deserialize)]
 pub struct GetKeyResponse {
@@ -84,6 +157,12 @@ pub struct GetKeyResponse {
     pub key: String,
     /// The chain of signatures verifying the key
     pub signature_chain: Vec<String>,
+    /// The public key in hexadecimal format
+    #[serde(default)]
+    pub public_key: String,
+    /// The algorithm of the key, empty for agents that only support secp256k1
+    #[serde(default)]
+    pub algorithm: String,
 }
 
 impl GetKeyResponse {
@@ -91,9 +170,93 @@ impl GetKeyResponse {
         hex::decode(&self.key)
     }
 
+    pub fn decode_public_key(&self) -> Result<Vec<u8>, FromHexError> {
+        hex::decode(&self.public_key)
+    }
+
     pub fn decode_signature_chain(&self) -> Result<Vec<Vec<u8>>, FromHexError> {
         self.signature_chain.iter().map(hex::decode).collect()
     }
+
+    /// The message signed by the app key to certify the derived public key.
+    pub fn provenance_message(&self, purpose: &str) -> String {
+        match self.algorithm.as_str() {
+            "" | "secp256k1" => format!("{purpose}:{}", self.public_key),
+            algorithm => format!("{algorithm}:{purpose}:{}", self.public_key),
+        }
+    }
+
+    /// Recover the app public key (compressed SEC1) from the first signature of the chain.
+    ///
+    /// The caller should check the recovered key against the app key it trusts, or verify the
+    /// rest of the chain.
+    pub fn recover_app_public_key(&self, purpose: &str) -> Result<Vec<u8>> {
+        use k256::ecdsa::{RecoveryId, Signature, VerifyingKey};
+        use sha3::Keccak256;
+
+        if self.public_key.is_empty() {
+            anyhow::bail!("The response has no public key");
+        }
+        let chain = self.decode_signature_chain()?;
+        let Some([signature @ .., recid]) = chain.first().map(Vec::as_slice) else {
+            anyhow::bail!("Empty signature chain");
+        };
+        let signature = Signature::from_slice(signature)?;
+        let recid =
+            RecoveryId::from_byte(*recid).ok_or_else(|| anyhow::anyhow!("Invalid recovery id"))?;
+        let digest = Keccak256::new_with_prefix(self.provenance_message(purpose));
+        let app_key = VerifyingKey::recover_from_digest(digest, &signature, recid)?;
+        Ok(app_key.to_sec1_bytes().to_vec())
+    }
+}
+
+/// Response containing a signature made by an in-enclave key
+#[derive(Serialize, Deserialize)]
+pub struct SignResponse {
+    /// The signature in hexadecimal format
+    pub signature: String,
+    /// The public key of the signing key in hexadecimal format
+    pub public_key: String,
+    /// The chain of signatures verifying the signing key
+    pub signature_chain: Vec<String>,
+}
+
+impl SignResponse {
+    pub fn decode_signature(&self) -> Result<Vec<u8>, FromHexError> {
+        hex::decode(&self.signature)
+    }
+
+    pub fn decode_public_key(&self) -> Result<Vec<u8>, FromHexError> {
+        hex::decode(&self.public_key)
+    }
+}
+
+/// Response containing the public key of an in-enclave signing key
+#[derive(Serialize, Deserialize)]
+pub struct GetPublicKeyResponse {
+    /// The public key in hexadecimal format
+    pub public_key: String,
+    /// The algorithm of the key
+    pub algorithm: String,
+    /// The chain of signatures verifying the key
+    pub signature_chain: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct SealResponse {
+    sealed: String,
+}
+
+#[derive(Deserialize)]
+struct UnsealResponse {
+    #[serde(default)]
+    data: String,
+}
+
+#[derive(Deserialize)]
+struct VerifyResponse {
+    #[serde(default)]
+    valid: bool,
 }
 
 /// Response containing a quote and associated event log
@@ -200,6 +363,24 @@ pub struct GetTlsKeyResponse {
     pub certificate_chain: Vec<String>,
 }
 
+/// Another live instance of the same app
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PeerInfo {
+    /// The instance identifier
+    pub instance_id: String,
+    /// The WireGuard IP address of the instance
+    pub ip: String,
+    /// The last time the instance was seen by the gateway, in seconds since the UNIX epoch
+    pub last_seen: u64,
+}
+
+/// Response containing the peers of this instance
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ListPeersResponse {
+    /// The other live instances of the app
+    pub peers: Vec<PeerInfo>,
+}
+
 pub trait BaseClient {}
 
 /// The main client for interacting with the dstack service
@@ -272,10 +453,21 @@ impl DstackClient {
         &self,
         path: Option<String>,
         purpose: Option<String>,
+    ) -> Result<GetKeyResponse> {
+        self.get_key_with_algorithm(path, purpose, KeyAlgorithm::default())
+            .await
+    }
+
+    pub async fn get_key_with_algorithm(
+        &self,
+        path: Option<String>,
+        purpose: Option<String>,
+        algorithm: KeyAlgorithm,
     ) -> Result<GetKeyResponse> {
         let data = json!({
             "path": path.unwrap_or_default(),
             "purpose": purpose.unwrap_or_default(),
+            "algorithm": algorithm.as_str(),
         });
         let response = self.send_rpc_request("/GetKey", &data).await?;
         let response = serde_json::from_value::<GetKeyResponse>(response)?;
@@ -283,6 +475,84 @@ impl DstackClient {
         Ok(response)
     }
 
+    /// Sign `data` with the in-enclave key at `path`. `mode` is one of `raw`, `prehashed` or
+    /// `eth_personal`, an empty string selects `raw`.
+    pub async fn sign(
+        &self,
+        path: &str,
+        algorithm: KeyAlgorithm,
+        data: Vec<u8>,
+        mode: &str,
+    ) -> Result<SignResponse> {
+        let data = json!({
+            "path": path,
+            "algorithm": algorithm.as_str(),
+            "data": hex_encode(data),
+            "mode": mode,
+        });
+        let response = self.send_rpc_request("/Sign", &data).await?;
+        Ok(serde_json::from_value::<SignResponse>(response)?)
+    }
+
+    /// Verify a signature made by the in-enclave key at `path`.
+    pub async fn verify(
+        &self,
+        path: &str,
+        algorithm: KeyAlgorithm,
+        data: Vec<u8>,
+        signature: Vec<u8>,
+        mode: &str,
+    ) -> Result<bool> {
+        let data = json!({
+            "path": path,
+            "algorithm": algorithm.as_str(),
+            "data": hex_encode(data),
+            "signature": hex_encode(signature),
+            "mode": mode,
+        });
+        let response = self.send_rpc_request("/Verify", &data).await?;
+        Ok(serde_json::from_value::<VerifyResponse>(response)?.valid)
+    }
+
+    /// Get the public key of the in-enclave signing key at `path`.
+    pub async fn get_public_key(
+        &self,
+        path: &str,
+        algorithm: KeyAlgorithm,
+    ) -> Result<GetPublicKeyResponse> {
+        let data = json!({ "path": path, "algorithm": algorithm.as_str() });
+        let response = self.send_rpc_request("/GetPublicKey", &data).await?;
+        Ok(serde_json::from_value::<GetPublicKeyResponse>(response)?)
+    }
+
+    /// Encrypt `data` with a key bound to the app and `label`, and optionally to the compose hash
+    /// and the instance id.
+    pub async fn seal(
+        &self,
+        label: &str,
+        data: Vec<u8>,
+        bind_compose_hash: bool,
+        bind_instance_id: bool,
+    ) -> Result<Vec<u8>> {
+        let data = json!({
+            "label": label,
+            "data": hex_encode(data),
+            "bind_compose_hash": bind_compose_hash,
+            "bind_instance_id": bind_instance_id,
+        });
+        let response = self.send_rpc_request("/Seal", &data).await?;
+        let response = serde_json::from_value::<SealResponse>(response)?;
+        Ok(hex::decode(response.sealed)?)
+    }
+
+    /// Decrypt data sealed by [`DstackClient::seal`] with the same label.
+    pub async fn unseal(&self, label: &str, sealed: Vec<u8>) -> Result<Vec<u8>> {
+        let data = json!({ "label": label, "sealed": hex_encode(sealed) });
+        let response = self.send_rpc_request("/Unseal", &data).await?;
+        let response = serde_json::from_value::<UnsealResponse>(response)?;
+        Ok(hex::decode(response.data)?)
+    }
+
     pub async fn get_quote(&self, report_data: Vec<u8>) -> Result<GetQuoteResponse> {
         if report_data.is_empty() || report_data.len() > 64 {
             anyhow::bail!("Invalid report data length")
@@ -310,10 +580,34 @@ impl DstackClient {
         Ok(())
     }
 
+    /// Query the event log. The replayed RTMRs and the quote let the caller verify the returned
+    /// events without fetching the whole log.
+    pub async fn get_event_log(&self, query: EventLogQuery) -> Result<EventLogResponse> {
+        if query.report_data.len() > 64 {
+            anyhow::bail!("Invalid report data length")
+        }
+        let data = json!({
+            "imr": query.imr,
+            "event": query.event,
+            "start": query.start,
+            "end": query.end,
+            "with_quote": query.with_quote,
+            "report_data": hex_encode(query.report_data),
+        });
+        let response = self.send_rpc_request("/GetEventLog", &data).await?;
+        Ok(serde_json::from_value::<EventLogResponse>(response)?)
+    }
+
     pub async fn get_tls_key(&self, tls_key_config: TlsKeyConfig) -> Result<GetTlsKeyResponse> {
         let response = self.send_rpc_request("/GetTlsKey", &tls_key_config).await?;
         let response = serde_json::from_value::<GetTlsKeyResponse>(response)?;
 
         Ok(response)
     }
+
+    /// List the other live instances of this app, as registered in dstack-gateway.
+    pub async fn list_peers(&self) -> Result<ListPeersResponse> {
+        let response = self.send_rpc_request("/ListPeers", &json!({})).await?;
+        Ok(serde_json::from_value::<ListPeersResponse>(response)?)
+    }
 }
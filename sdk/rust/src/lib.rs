@@ -1,3 +1,4 @@
 pub mod dstack_client;
 pub mod ethereum;
+pub mod peer;
 pub mod tappd_client;
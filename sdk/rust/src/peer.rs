@@ -0,0 +1,278 @@
+//! Mutually attested TLS between instances of the same app.
+//!
+//! Certificates returned by `GetTlsKey` under the KMS key provider chain up to the KMS root CA
+//! and carry the app id of the instance, which the KMS only signs after verifying its quote.
+//! Trusting only that root and requiring the peer's app id to equal ours therefore authenticates
+//! the other side as an attested instance of the same app, in both directions.
+//!
+//! Peers are usually reached by their WireGuard IP from [`DstackClient::list_peers`], so every
+//! peer certificate is issued for the fixed name [`PEER_SERVER_NAME`] instead.
+//!
+//! [`DstackClient::list_peers`]: crate::dstack_client::DstackClient::list_peers
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context, Result};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{
+    ClientConfig, DigitallySignedStruct, DistinguishedName, RootCertStore, ServerConfig,
+    SignatureScheme,
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::{client, server, TlsAcceptor, TlsConnector};
+use x509_parser::der_parser::der::parse_der_octetstring;
+use x509_parser::der_parser::Oid;
+use x509_parser::prelude::{FromDer as _, X509Certificate};
+
+use crate::dstack_client::{DstackClient, TlsKeyConfig};
+
+/// OID of the certificate extension carrying the app id.
+const PHALA_RATLS_APP_ID: &[u64] = &[1, 3, 6, 1, 4, 1, 62397, 1, 3];
+
+/// The name every peer certificate is issued for and verified against.
+pub const PEER_SERVER_NAME: &str = "dstack-peer";
+
+/// Read the app id from the extension of a DER encoded certificate.
+pub fn cert_app_id(cert_der: &[u8]) -> Result<Vec<u8>> {
+    let (_, cert) = X509Certificate::from_der(cert_der).context("Failed to parse certificate")?;
+    let oid = Oid::from(PHALA_RATLS_APP_ID).or(Err(anyhow!("Invalid oid")))?;
+    let Some(ext) = cert
+        .get_extension_unique(&oid)
+        .context("Failed to decode extensions")?
+    else {
+        bail!("Certificate carries no app id");
+    };
+    let (_, app_id) = parse_der_octetstring(ext.value).context("Failed to decode app id")?;
+    Ok(app_id
+        .as_slice()
+        .context("Failed to decode app id")?
+        .to_vec())
+}
+
+fn check_app_id(expected: &[u8], cert: &CertificateDer<'_>) -> Result<(), rustls::Error> {
+    let app_id = cert_app_id(cert).map_err(|err| rustls::Error::General(format!("{err:#}")))?;
+    if app_id != expected {
+        return Err(rustls::Error::General(format!(
+            "Peer app id {} does not match {}",
+            hex::encode(app_id),
+            hex::encode(expected)
+        )));
+    }
+    Ok(())
+}
+
+/// Verifies that the server is an instance of our app.
+#[derive(Debug)]
+struct PeerServerVerifier {
+    app_id: Vec<u8>,
+    inner: Arc<WebPkiServerVerifier>,
+}
+
+impl ServerCertVerifier for PeerServerVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+        check_app_id(&self.app_id, end_entity)?;
+        Ok(verified)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// Verifies that the client is an instance of our app.
+#[derive(Debug)]
+struct PeerClientVerifier {
+    app_id: Vec<u8>,
+    inner: Arc<dyn ClientCertVerifier>,
+}
+
+impl ClientCertVerifier for PeerClientVerifier {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        self.inner.root_hint_subjects()
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        let verified = self
+            .inner
+            .verify_client_cert(end_entity, intermediates, now)?;
+        check_app_id(&self.app_id, end_entity)?;
+        Ok(verified)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// The TLS identity of this instance towards its peers.
+pub struct PeerTls {
+    app_id: Vec<u8>,
+    roots: Arc<RootCertStore>,
+    cert_chain: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl PeerTls {
+    /// Request a new RA-TLS key from the guest agent, usable as both client and server.
+    pub async fn new(client: &DstackClient) -> Result<Self> {
+        let config = TlsKeyConfig::builder()
+            .subject(PEER_SERVER_NAME)
+            .alt_names(vec![PEER_SERVER_NAME.to_string()])
+            .usage_ra_tls(true)
+            .usage_server_auth(true)
+            .usage_client_auth(true)
+            .build();
+        let response = client.get_tls_key(config).await?;
+        Self::from_pem(&response.key, &response.certificate_chain)
+    }
+
+    /// Build the identity from a key and its certificate chain, ending with the KMS root CA.
+    pub fn from_pem(key_pem: &str, certificate_chain: &[String]) -> Result<Self> {
+        let mut cert_chain = certificate_chain
+            .iter()
+            .map(|pem| CertificateDer::from_pem_slice(pem.as_bytes()))
+            .collect::<Result<Vec<_>, _>>()
+            .context("Failed to parse certificate chain")?;
+        if cert_chain.len() < 2 {
+            bail!("Certificate chain is not issued by the KMS");
+        }
+        let root = cert_chain.pop().context("Empty certificate chain")?;
+        let mut roots = RootCertStore::empty();
+        roots.add(root).context("Invalid root certificate")?;
+        let app_id = cert_app_id(&cert_chain[0])?;
+        let key = PrivateKeyDer::from_pem_slice(key_pem.as_bytes()).context("Invalid key")?;
+        Ok(Self {
+            app_id,
+            roots: Arc::new(roots),
+            cert_chain,
+            key,
+            provider: Arc::new(ring::default_provider()),
+        })
+    }
+
+    /// The app id the peers must carry.
+    pub fn app_id(&self) -> &[u8] {
+        &self.app_id
+    }
+
+    /// TLS configuration for connecting to a peer.
+    pub fn client_config(&self) -> Result<ClientConfig> {
+        let inner =
+            WebPkiServerVerifier::builder_with_provider(self.roots.clone(), self.provider.clone())
+                .build()
+                .context("Failed to build server verifier")?;
+        let verifier = PeerServerVerifier {
+            app_id: self.app_id.clone(),
+            inner,
+        };
+        ClientConfig::builder_with_provider(self.provider.clone())
+            .with_safe_default_protocol_versions()?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_client_auth_cert(self.cert_chain.clone(), self.key.clone_key())
+            .context("Failed to set client certificate")
+    }
+
+    /// TLS configuration for accepting connections from peers.
+    pub fn server_config(&self) -> Result<ServerConfig> {
+        let inner =
+            WebPkiClientVerifier::builder_with_provider(self.roots.clone(), self.provider.clone())
+                .build()
+                .context("Failed to build client verifier")?;
+        let verifier = PeerClientVerifier {
+            app_id: self.app_id.clone(),
+            inner,
+        };
+        ServerConfig::builder_with_provider(self.provider.clone())
+            .with_safe_default_protocol_versions()?
+            .with_client_cert_verifier(Arc::new(verifier))
+            .with_single_cert(self.cert_chain.clone(), self.key.clone_key())
+            .context("Failed to set server certificate")
+    }
+
+    /// Run the client side of the handshake over `stream`, connected to a peer.
+    pub async fn connect<IO>(&self, stream: IO) -> Result<client::TlsStream<IO>>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin,
+    {
+        let connector = TlsConnector::from(Arc::new(self.client_config()?));
+        let server_name = ServerName::try_from(PEER_SERVER_NAME)?;
+        connector
+            .connect(server_name, stream)
+            .await
+            .context("Peer handshake failed")
+    }
+
+    /// Run the server side of the handshake over `stream`, accepted from a peer.
+    pub async fn accept<IO>(&self, stream: IO) -> Result<server::TlsStream<IO>>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin,
+    {
+        let acceptor = TlsAcceptor::from(Arc::new(self.server_config()?));
+        acceptor
+            .accept(stream)
+            .await
+            .context("Peer handshake failed")
+    }
+}
@@ -1,5 +1,5 @@
 use dcap_qvl::quote::Quote;
-use dstack_sdk::dstack_client::DstackClient as AsyncDstackClient;
+use dstack_sdk::dstack_client::{DstackClient as AsyncDstackClient, EventLogQuery, KeyAlgorithm};
 
 #[tokio::test]
 async fn test_async_client_get_key() {
@@ -9,6 +9,106 @@ async fn test_async_client_get_key() {
     assert_eq!(result.decode_key().unwrap().len(), 32);
 }
 
+#[tokio::test]
+async fn test_async_client_get_key_algorithms() {
+    let client = AsyncDstackClient::new(None);
+    for algorithm in [
+        KeyAlgorithm::Secp256k1,
+        KeyAlgorithm::Ed25519,
+        KeyAlgorithm::Secp256r1,
+        KeyAlgorithm::X25519,
+    ] {
+        let result = client
+            .get_key_with_algorithm(Some("test".into()), Some("purpose".into()), algorithm)
+            .await
+            .unwrap();
+        assert_eq!(result.algorithm, algorithm.as_str());
+        assert_eq!(result.decode_key().unwrap().len(), 32);
+        assert!(!result.decode_public_key().unwrap().is_empty());
+        assert_eq!(result.recover_app_public_key("purpose").unwrap().len(), 33);
+    }
+    let default_key = client.get_key(Some("test".into()), None).await.unwrap();
+    let ed25519_key = client
+        .get_key_with_algorithm(Some("test".into()), None, KeyAlgorithm::Ed25519)
+        .await
+        .unwrap();
+    assert_ne!(default_key.key, ed25519_key.key);
+}
+
+#[tokio::test]
+async fn test_async_client_sign() {
+    let client = AsyncDstackClient::new(None);
+    for algorithm in [
+        KeyAlgorithm::Secp256k1,
+        KeyAlgorithm::Ed25519,
+        KeyAlgorithm::Secp256r1,
+    ] {
+        let signed = client
+            .sign("test/signer", algorithm, b"hello".to_vec(), "")
+            .await
+            .unwrap();
+        let public_key = client
+            .get_public_key("test/signer", algorithm)
+            .await
+            .unwrap();
+        assert_eq!(signed.public_key, public_key.public_key);
+        let signature = signed.decode_signature().unwrap();
+        assert!(client
+            .verify(
+                "test/signer",
+                algorithm,
+                b"hello".to_vec(),
+                signature.clone(),
+                ""
+            )
+            .await
+            .unwrap());
+        assert!(!client
+            .verify("test/signer", algorithm, b"world".to_vec(), signature, "")
+            .await
+            .unwrap());
+    }
+    assert!(client
+        .sign("wallet", KeyAlgorithm::Secp256k1, b"hello".to_vec(), "")
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_async_client_seal() {
+    let client = AsyncDstackClient::new(None);
+    for (bind_compose_hash, bind_instance_id) in [(false, false), (true, true)] {
+        let sealed = client
+            .seal(
+                "test",
+                b"secret".to_vec(),
+                bind_compose_hash,
+                bind_instance_id,
+            )
+            .await
+            .unwrap();
+        let data = client.unseal("test", sealed.clone()).await.unwrap();
+        assert_eq!(data, b"secret");
+        assert!(client.unseal("other", sealed).await.is_err());
+    }
+}
+
+#[tokio::test]
+async fn test_async_client_get_event_log() {
+    let client = AsyncDstackClient::new(None);
+    let query = EventLogQuery::builder()
+        .imr(3)
+        .event("compose-hash")
+        .with_quote(true)
+        .build();
+    let result = client.get_event_log(query).await.unwrap();
+    assert_eq!(result.events.len(), 1);
+    assert_eq!(result.events[0].event.imr, 3);
+    assert!(result.total_events as usize > result.events.len());
+    assert_eq!(result.rtmrs.len(), 4);
+    assert!(!result.quote.is_empty());
+}
+
 #[tokio::test]
 async fn test_async_client_get_quote() {
     let client = AsyncDstackClient::new(None);
@@ -0,0 +1,104 @@
+use dstack_sdk::peer::{cert_app_id, PeerTls, PEER_SERVER_NAME};
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, CustomExtension, DnType,
+    ExtendedKeyUsagePurpose, IsCa, KeyPair,
+};
+
+const PHALA_RATLS_APP_ID: &[u64] = &[1, 3, 6, 1, 4, 1, 62397, 1, 3];
+
+struct Ca {
+    cert: Certificate,
+    key: KeyPair,
+}
+
+fn ca_params(name: &str) -> CertificateParams {
+    let mut params = CertificateParams::default();
+    params.distinguished_name.push(DnType::CommonName, name);
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params
+}
+
+fn root_ca() -> Ca {
+    let key = KeyPair::generate().unwrap();
+    let cert = ca_params("KMS root").self_signed(&key).unwrap();
+    Ca { cert, key }
+}
+
+fn app_ca(root: &Ca, app_id: &[u8]) -> Ca {
+    let key = KeyPair::generate().unwrap();
+    let name = format!("App CA {}", hex::encode(app_id));
+    let cert = ca_params(&name)
+        .signed_by(&key, &root.cert, &root.key)
+        .unwrap();
+    Ca { cert, key }
+}
+
+/// Issue a peer identity the way the KMS does: a leaf with the app id, under the app CA.
+fn peer_tls(root: &Ca, app_id: &[u8]) -> PeerTls {
+    let app_ca = app_ca(root, app_id);
+    let key = KeyPair::generate().unwrap();
+    let mut params = CertificateParams::new(vec![PEER_SERVER_NAME.to_string()]).unwrap();
+    params
+        .distinguished_name
+        .push(DnType::CommonName, PEER_SERVER_NAME);
+    params.extended_key_usages = vec![
+        ExtendedKeyUsagePurpose::ServerAuth,
+        ExtendedKeyUsagePurpose::ClientAuth,
+    ];
+    let mut content = vec![0x04, app_id.len() as u8];
+    content.extend_from_slice(app_id);
+    params
+        .custom_extensions
+        .push(CustomExtension::from_oid_content(
+            PHALA_RATLS_APP_ID,
+            content,
+        ));
+    let cert = params.signed_by(&key, &app_ca.cert, &app_ca.key).unwrap();
+    let chain = [cert.pem(), app_ca.cert.pem(), root.cert.pem()];
+    PeerTls::from_pem(&key.serialize_pem(), &chain).unwrap()
+}
+
+async fn handshake(client: &PeerTls, server: &PeerTls) -> (bool, bool) {
+    let (client_io, server_io) = tokio::io::duplex(16 * 1024);
+    let (client_result, server_result) =
+        tokio::join!(client.connect(client_io), server.accept(server_io));
+    (client_result.is_ok(), server_result.is_ok())
+}
+
+#[tokio::test]
+async fn test_peer_tls_same_app() {
+    let root = root_ca();
+    let alice = peer_tls(&root, &[0xaa; 20]);
+    let bob = peer_tls(&root, &[0xaa; 20]);
+    assert_eq!(alice.app_id(), [0xaa; 20]);
+    assert_eq!(handshake(&alice, &bob).await, (true, true));
+}
+
+#[tokio::test]
+async fn test_peer_tls_rejects_other_app() {
+    let root = root_ca();
+    let alice = peer_tls(&root, &[0xaa; 20]);
+    let mallory = peer_tls(&root, &[0xbb; 20]);
+    assert_ne!(handshake(&alice, &mallory).await, (true, true));
+    assert_ne!(handshake(&mallory, &alice).await, (true, true));
+}
+
+#[tokio::test]
+async fn test_peer_tls_rejects_other_root() {
+    let alice = peer_tls(&root_ca(), &[0xaa; 20]);
+    let mallory = peer_tls(&root_ca(), &[0xaa; 20]);
+    assert_ne!(handshake(&alice, &mallory).await, (true, true));
+}
+
+#[test]
+fn test_peer_tls_requires_app_id() {
+    let root = root_ca();
+    let key = KeyPair::generate().unwrap();
+    let cert = CertificateParams::new(vec![PEER_SERVER_NAME.to_string()])
+        .unwrap()
+        .signed_by(&key, &root.cert, &root.key)
+        .unwrap();
+    assert!(cert_app_id(cert.der()).is_err());
+    let chain = [cert.pem(), root.cert.pem()];
+    assert!(PeerTls::from_pem(&key.serialize_pem(), &chain).is_err());
+}
@@ -3,7 +3,7 @@ use std::{path::Path, sync::Arc, time::Duration};
 use anyhow::{Context, Result};
 use http_client::http_request;
 use log::{error, info};
-use supervisor::{ProcessConfig, ProcessInfo, Response};
+use supervisor::{CgroupConfig, ProcessConfig, ProcessInfo, Response};
 
 pub use supervisor;
 
@@ -129,6 +129,11 @@ impl SupervisorClient {
             .await
     }
 
+    pub async fn update_cgroup(&self, id: &str, cgroup: CgroupConfig) -> Result<()> {
+        self.http_request("POST", &format!("/update_cgroup/{}", id), cgroup)
+            .await
+    }
+
     pub async fn list(&self) -> Result<Vec<ProcessInfo>> {
         self.http_get("/list").await
     }
@@ -207,6 +212,10 @@ impl SupervisorClientSync {
         self.http_request("DELETE", &format!("/remove/{}", id), ())
     }
 
+    pub fn update_cgroup(&self, id: &str, cgroup: CgroupConfig) -> Result<()> {
+        self.http_request("POST", &format!("/update_cgroup/{}", id), cgroup)
+    }
+
     pub fn list(&self) -> Result<Vec<ProcessInfo>> {
         self.http_get("/list")
     }
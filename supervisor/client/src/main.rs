@@ -66,6 +66,7 @@ async fn main() -> Result<()> {
                 pidfile: String::new(),
                 cid: None,
                 note: String::new(),
+                cgroup: None,
             };
             print_json(&client.deploy(config).await?);
         }
@@ -0,0 +1,219 @@
+//! cgroup v2 placement and resource limits for supervised processes.
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use fs_err as fs;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+const CONTROLLERS: &str = "+cpu +memory +io +pids";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CpuMax {
+    /// Allowed CPU time per period in microseconds
+    pub quota_us: u64,
+    /// Period in microseconds
+    pub period_us: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IoMax {
+    /// Block device in `major:minor` form
+    pub device: String,
+    #[serde(default)]
+    pub rbps: Option<u64>,
+    #[serde(default)]
+    pub wbps: Option<u64>,
+    #[serde(default)]
+    pub riops: Option<u64>,
+    #[serde(default)]
+    pub wiops: Option<u64>,
+}
+
+/// The cgroup a process is placed in and the limits applied to it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CgroupConfig {
+    /// Path of the cgroup relative to the cgroup v2 mount point
+    pub path: String,
+    #[serde(default)]
+    pub cpu_weight: Option<u64>,
+    #[serde(default)]
+    pub cpu_max: Option<CpuMax>,
+    /// memory.max in bytes
+    #[serde(default)]
+    pub memory_max: Option<u64>,
+    #[serde(default)]
+    pub io_max: Vec<IoMax>,
+    #[serde(default)]
+    pub pids_max: Option<u64>,
+}
+
+fn limit(value: Option<u64>) -> String {
+    match value {
+        Some(v) => v.to_string(),
+        None => "max".into(),
+    }
+}
+
+impl CgroupConfig {
+    /// Absolute path of the cgroup directory.
+    pub fn dir(&self) -> Result<PathBuf> {
+        let rel = Path::new(&self.path);
+        if self.path.is_empty() || !rel.components().all(|c| matches!(c, Component::Normal(_))) {
+            bail!("Invalid cgroup path: {}", self.path);
+        }
+        Ok(Path::new(CGROUP_ROOT).join(rel))
+    }
+
+    /// The interface files to write and their content.
+    fn limit_files(&self) -> Vec<(&'static str, String)> {
+        let mut files = vec![];
+        if let Some(weight) = self.cpu_weight {
+            files.push(("cpu.weight", weight.clamp(1, 10000).to_string()));
+        }
+        let cpu_max = match &self.cpu_max {
+            Some(m) => format!("{} {}", m.quota_us, m.period_us),
+            None => "max".into(),
+        };
+        files.push(("cpu.max", cpu_max));
+        files.push(("memory.max", limit(self.memory_max)));
+        for io in &self.io_max {
+            files.push((
+                "io.max",
+                format!(
+                    "{} rbps={} wbps={} riops={} wiops={}",
+                    io.device,
+                    limit(io.rbps),
+                    limit(io.wbps),
+                    limit(io.riops),
+                    limit(io.wiops)
+                ),
+            ));
+        }
+        files.push(("pids.max", limit(self.pids_max)));
+        files
+    }
+
+    /// Create the cgroup, enabling the controllers along its ancestors, and apply the limits.
+    pub fn create(&self) -> Result<PathBuf> {
+        let dir = self.dir()?;
+        let mut ancestor = PathBuf::from(CGROUP_ROOT);
+        for component in Path::new(&self.path).components() {
+            // Processes can not live in inner cgroups that delegate controllers, so the
+            // ancestors are expected to be empty.
+            let subtree_control = ancestor.join("cgroup.subtree_control");
+            fs::write(&subtree_control, CONTROLLERS)
+                .context("Failed to enable cgroup controllers")?;
+            ancestor.push(component);
+            if !ancestor.exists() {
+                fs::create_dir(&ancestor).context("Failed to create cgroup")?;
+            }
+        }
+        self.apply()?;
+        Ok(dir)
+    }
+
+    /// Apply the limits to an existing cgroup.
+    pub fn apply(&self) -> Result<()> {
+        let dir = self.dir()?;
+        for (file, value) in self.limit_files() {
+            fs::write(dir.join(file), &value)
+                .with_context(|| format!("Failed to set {file} to {value}"))?;
+        }
+        Ok(())
+    }
+
+    /// Remove the cgroup once all its processes are gone.
+    pub fn remove(&self) {
+        let Ok(dir) = self.dir() else {
+            return;
+        };
+        if dir.exists() {
+            if let Err(err) = fs::remove_dir(&dir) {
+                warn!("Failed to remove cgroup: {err}");
+            }
+        }
+    }
+
+    /// Path of `cgroup.procs` as a C string, prepared for use in [`join_cgroup`].
+    pub fn procs_file(&self) -> Result<CString> {
+        let path = self.dir()?.join("cgroup.procs");
+        CString::new(path.as_os_str().as_bytes()).context("Invalid cgroup path")
+    }
+}
+
+/// Move the calling process into the cgroup.
+///
+/// Intended to be called between fork and exec, so it only performs async-signal-safe calls.
+pub fn join_cgroup(procs_file: &CString) -> std::io::Result<()> {
+    // SAFETY: procs_file is a valid NUL terminated string and the buffer outlives the write
+    unsafe {
+        let fd = libc::open(procs_file.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let written = libc::write(fd, b"0".as_ptr().cast(), 1);
+        let result = if written < 0 {
+            Err(std::io::Error::last_os_error())
+        } else {
+            Ok(())
+        };
+        libc::close(fd);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limit_files() {
+        let config = CgroupConfig {
+            path: "dstack/vm-1".into(),
+            cpu_weight: Some(200),
+            cpu_max: Some(CpuMax {
+                quota_us: 200000,
+                period_us: 100000,
+            }),
+            memory_max: Some(1 << 30),
+            io_max: vec![IoMax {
+                device: "8:0".into(),
+                wbps: Some(1048576),
+                ..Default::default()
+            }],
+            pids_max: None,
+        };
+        assert_eq!(
+            config.limit_files(),
+            [
+                ("cpu.weight", "200".to_string()),
+                ("cpu.max", "200000 100000".to_string()),
+                ("memory.max", "1073741824".to_string()),
+                (
+                    "io.max",
+                    "8:0 rbps=max wbps=1048576 riops=max wiops=max".to_string()
+                ),
+                ("pids.max", "max".to_string()),
+            ]
+        );
+        assert_eq!(
+            config.dir().unwrap(),
+            Path::new("/sys/fs/cgroup/dstack/vm-1")
+        );
+    }
+
+    #[test]
+    fn test_invalid_path() {
+        for path in ["", "/abs", "../escape", "a/../b"] {
+            let config = CgroupConfig {
+                path: path.into(),
+                ..Default::default()
+            };
+            assert!(config.dir().is_err(), "{path}");
+        }
+    }
+}
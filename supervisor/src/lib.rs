@@ -1,5 +1,7 @@
+mod cgroup;
 mod process;
 mod supervisor;
 pub mod web_api;
+pub use cgroup::{CgroupConfig, CpuMax, IoMax};
 pub use process::{ProcessConfig, ProcessInfo, ProcessState, ProcessStatus};
 pub use web_api::Response;
@@ -19,6 +19,8 @@ use tokio::sync::oneshot;
 use tracing::Instrument;
 use tracing::{error, info};
 
+use crate::cgroup::{join_cgroup, CgroupConfig};
+
 #[derive(Debug, Clone, Builder, Serialize, Deserialize)]
 pub struct ProcessConfig {
     pub id: String,
@@ -41,6 +43,8 @@ pub struct ProcessConfig {
     pub cid: Option<u32>,
     #[serde(default)]
     pub note: String,
+    #[serde(default)]
+    pub cgroup: Option<CgroupConfig>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -187,6 +191,14 @@ impl Process {
         } else {
             command.stderr(Stdio::null());
         }
+        if let Some(cgroup) = &self.config.cgroup {
+            cgroup.create()?;
+            let procs_file = cgroup.procs_file()?;
+            // SAFETY: join_cgroup only performs async-signal-safe calls
+            unsafe {
+                command.pre_exec(move || join_cgroup(&procs_file));
+            }
+        }
 
         let mut process = command.spawn()?;
         let pid = process.id();
@@ -230,6 +242,7 @@ impl Process {
         // Task for waiting on process
         {
             let process_uuid = self.config.id.clone();
+            let cgroup = self.config.cgroup.clone();
             let weak_state = Arc::downgrade(&self.state);
 
             let span = tracing::info_span!("process", id = process_uuid);
@@ -258,6 +271,9 @@ impl Process {
                             ProcessStatus::Error(e.to_string())
                         }
                     };
+                    if let Some(cgroup) = cgroup {
+                        cgroup.remove();
+                    }
                     if let Some(state) = state {
                         let mut state = state.lock().unwrap();
                         state.status = next_status;
@@ -290,6 +306,26 @@ impl Process {
         }
     }
 
+    /// Change the cgroup limits of the process, applying them immediately if it is running.
+    pub fn update_cgroup(&self, cgroup: CgroupConfig) -> Result<Self> {
+        let Some(current) = &self.config.cgroup else {
+            bail!("Process is not placed in a cgroup");
+        };
+        if current.path != cgroup.path {
+            bail!("Cgroup path can not be changed");
+        }
+        if self.lock().is_running() {
+            cgroup.apply()?;
+        }
+        Ok(Self {
+            config: Arc::new(ProcessConfig {
+                cgroup: Some(cgroup),
+                ..(*self.config).clone()
+            }),
+            state: self.state.clone(),
+        })
+    }
+
     pub fn info(&self) -> ProcessInfo {
         let state = self.state.lock().unwrap();
         ProcessInfo {
@@ -1,3 +1,4 @@
+use crate::cgroup::CgroupConfig;
 use crate::process::{Process, ProcessConfig, ProcessInfo};
 use anyhow::{bail, Context, Result};
 use dashmap::DashMap;
@@ -94,6 +95,15 @@ impl Supervisor {
         Ok(())
     }
 
+    pub fn update_cgroup(&self, id: &str, cgroup: CgroupConfig) -> Result<()> {
+        let process = self.processes.get(id).context("Process not found")?;
+        let updated = process.update_cgroup(cgroup)?;
+        drop(process);
+        self.processes.insert(id.to_string(), updated);
+        info!("Updated cgroup of process {id}");
+        Ok(())
+    }
+
     pub fn list(&self) -> Vec<ProcessInfo> {
         self.processes
             .iter()
@@ -6,6 +6,7 @@ use serde::{Deserialize, Serialize};
 use tokio::signal;
 use tracing::info;
 
+use crate::cgroup::CgroupConfig;
 use crate::process::{ProcessConfig, ProcessInfo};
 use crate::supervisor::Supervisor;
 
@@ -52,6 +53,15 @@ async fn remove(supervisor: &State<Supervisor>, id: &str) -> Json<Response<()>>
     to_json(supervisor.remove(id))
 }
 
+#[post("/update_cgroup/<id>", data = "<cgroup>")]
+async fn update_cgroup(
+    supervisor: &State<Supervisor>,
+    id: &str,
+    cgroup: Json<CgroupConfig>,
+) -> Json<Response<()>> {
+    to_json(supervisor.update_cgroup(id, cgroup.into_inner()))
+}
+
 #[get("/list")]
 fn list(supervisor: &State<Supervisor>) -> Json<Response<Vec<ProcessInfo>>> {
     to_json(Ok(supervisor.list()))
@@ -94,7 +104,18 @@ pub fn rocket(figment: Figment) -> Rocket<Build> {
     let supervisor = Supervisor::new();
     let rocket = rocket::custom(figment).manage(supervisor.clone()).mount(
         "/",
-        routes![deploy, start, stop, remove, list, info, ping, clear, shutdown],
+        routes![
+            deploy,
+            start,
+            stop,
+            remove,
+            update_cgroup,
+            list,
+            info,
+            ping,
+            clear,
+            shutdown
+        ],
     );
     tokio::spawn(handle_shutdown_signals(supervisor));
     rocket
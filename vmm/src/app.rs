@@ -10,22 +10,32 @@ use dstack_vmm_rpc::{self as pb, GpuInfo, StatusRequest, StatusResponse, VmConfi
 use fs_err as fs;
 use guest_api::client::DefaultClient as GuestClient;
 use id_pool::IdPool;
-use ra_rpc::client::RaClient;
+use ra_rpc::client::{RaClient, RaClientConfig, RaEndpoint, RetryPolicy};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::collections::{BTreeSet, HashMap};
 use std::net::IpAddr;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex, MutexGuard};
-use supervisor_client::SupervisorClient;
+use supervisor_client::{supervisor::ProcessInfo, SupervisorClient};
+use tokio::sync::OwnedMutexGuard;
 use tracing::{error, info};
 
-pub use image::{Image, ImageInfo};
+pub use cgroup::ResourceLimits;
+pub use disk::{DataDisk, DiskManager, QemuImg, QemuImgCli, SnapshotKind};
+pub use image::{validate_image_name, Image, ImageInfo};
+pub use metrics::{VmMetrics, VmUsage};
 pub use qemu::{VmConfig, VmWorkDir};
+pub use template::VmTemplate;
 
+mod cgroup;
+mod disk;
 mod id_pool;
 mod image;
+mod metrics;
 mod qemu;
+mod registry;
+mod template;
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct PortMapping {
@@ -35,6 +45,13 @@ pub struct PortMapping {
     pub to: u16,
 }
 
+/// Where the configuration of new VMs is taken from.
+#[derive(Debug, Clone, Copy)]
+pub enum VmSource<'a> {
+    Template(&'a str),
+    Vm(&'a str),
+}
+
 #[derive(Deserialize, Serialize, Clone, Builder, Debug)]
 pub struct Manifest {
     pub id: String,
@@ -56,6 +73,12 @@ pub struct Manifest {
     pub kms_urls: Vec<String>,
     #[serde(default)]
     pub gateway_urls: Vec<String>,
+    #[serde(default)]
+    #[builder(default)]
+    pub data_disks: Vec<DataDisk>,
+    #[serde(default)]
+    #[builder(default)]
+    pub limits: ResourceLimits,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -109,6 +132,7 @@ pub struct GpuSpec {
 pub struct App {
     pub config: Arc<Config>,
     pub supervisor: SupervisorClient,
+    pub qemu_img: Arc<dyn QemuImg>,
     state: Arc<Mutex<AppState>>,
 }
 
@@ -131,6 +155,7 @@ impl App {
         let cid_pool = IdPool::new(cid_start, cid_end);
         Self {
             supervisor: supervisor.clone(),
+            qemu_img: Arc::new(QemuImgCli::default()),
             state: Arc::new(Mutex::new(AppState {
                 cid_pool,
                 vms: HashMap::new(),
@@ -147,15 +172,7 @@ impl App {
     ) -> Result<()> {
         let vm_work_dir = VmWorkDir::new(work_dir.as_ref());
         let manifest = vm_work_dir.manifest().context("Failed to read manifest")?;
-        if manifest.image.len() > 64
-            || manifest.image.contains("..")
-            || !manifest
-                .image
-                .chars()
-                .all(|c| c.is_alphanumeric() || c == '_' || c == '-' || c == '.')
-        {
-            bail!("Invalid image name");
-        }
+        validate_image_name(&manifest.image)?;
         let image_path = self.config.image_path.join(&manifest.image);
         let image = Image::load(&image_path).context("Failed to load image")?;
         let vm_id = manifest.id.clone();
@@ -194,6 +211,7 @@ impl App {
     }
 
     pub async fn start_vm(&self, id: &str) -> Result<()> {
+        let _ops = self.lock_vm_ops(id).await?;
         self.sync_dynamic_config(id)?;
         let is_running = self
             .supervisor
@@ -221,7 +239,8 @@ impl App {
             }
 
             let devices = self.try_allocate_gpus(&vm_config.manifest)?;
-            let process_config = vm_config.config_qemu(&work_dir, &self.config.cvm, &devices)?;
+            let process_config =
+                vm_config.config_qemu(&work_dir, &self.config.cvm, &devices, &*self.qemu_img)?;
             self.supervisor
                 .deploy(process_config)
                 .await
@@ -273,6 +292,84 @@ impl App {
         Ok(())
     }
 
+    /// Serialize the operations on a VM that must not interleave, like starting it while its disks
+    /// are being changed.
+    async fn lock_vm_ops(&self, id: &str) -> Result<OwnedMutexGuard<()>> {
+        let ops = self.lock().get(id).context("VM not found")?.ops.clone();
+        Ok(ops.lock_owned().await)
+    }
+
+    async fn ensure_vm_stopped(&self, id: &str) -> Result<()> {
+        let info = self.supervisor.info(id).await?;
+        if info.is_some_and(|i| i.state.status.is_running()) {
+            bail!("VM is running, stop it first");
+        }
+        Ok(())
+    }
+
+    /// Apply the resource limits in the manifest to the cgroup of a running VM.
+    pub async fn update_vm_limits(&self, id: &str) -> Result<()> {
+        let work_dir = self.work_dir(id);
+        let manifest = work_dir.manifest().context("Failed to read manifest")?;
+        let Some(cgroup) =
+            cgroup::cgroup_config(&manifest, &self.config.cvm.cgroup, work_dir.path())
+        else {
+            bail!("cgroup isolation is disabled");
+        };
+        self.supervisor.update_cgroup(id, cgroup).await
+    }
+
+    pub fn list_data_disks(&self, id: &str) -> Result<Vec<DataDisk>> {
+        let manifest = self
+            .work_dir(id)
+            .manifest()
+            .context("Failed to read manifest")?;
+        Ok(manifest.data_disks)
+    }
+
+    /// Apply an operation to the data disks of a VM and persist the updated manifest.
+    ///
+    /// Operations touching the disk images require `offline` as qemu holds the images while running.
+    pub async fn update_data_disks<T>(
+        &self,
+        id: &str,
+        offline: bool,
+        f: impl FnOnce(&DiskManager, &mut Manifest) -> Result<T>,
+    ) -> Result<T> {
+        let _ops = self.lock_vm_ops(id).await?;
+        if offline {
+            self.ensure_vm_stopped(id).await?;
+        }
+        let work_dir = self.work_dir(id);
+        let mut manifest = work_dir.manifest().context("Failed to read manifest")?;
+        let disks = DiskManager::new(&*self.qemu_img, work_dir.disks_dir());
+        let files_before = disks.files().context("Failed to list disk images")?;
+        let result = f(&disks, &mut manifest).and_then(|output| {
+            work_dir
+                .put_manifest(&manifest)
+                .context("Failed to write manifest")?;
+            Ok(output)
+        });
+        let output = match result {
+            Ok(output) => output,
+            Err(err) => {
+                // Images not recorded in the manifest would be orphaned
+                for file in disks.files().unwrap_or_default() {
+                    if !files_before.contains(&file) {
+                        if let Err(err) = fs::remove_file(&file) {
+                            error!("Failed to remove {}: {err:?}", file.display());
+                        }
+                    }
+                }
+                return Err(err);
+            }
+        };
+        self.load_vm(&work_dir, &Default::default(), false)
+            .await
+            .context("Failed to load VM")?;
+        Ok(output)
+    }
+
     pub async fn reload_vms(&self) -> Result<()> {
         let vm_path = self.vm_dir();
         let running_vms = self.supervisor.list().await.context("Failed to list VMs")?;
@@ -335,6 +432,7 @@ impl App {
                 .cmp(&b.config.manifest.created_at_ms)
         });
 
+        let usage = metrics::summarize(&self.vm_metrics(&vms));
         let total = infos.len() as u32;
         let vms = paginate(infos, request.page, request.page_size)
             .map(|vm| {
@@ -349,9 +447,47 @@ impl App {
             vms,
             port_mapping_enabled: self.config.cvm.port_mapping.enabled,
             total,
+            usage: Some(usage),
         })
     }
 
+    /// Sample the host side resource usage of all VMs.
+    fn vm_metrics(&self, processes: &HashMap<String, ProcessInfo>) -> Vec<VmMetrics> {
+        let manifests = self
+            .lock()
+            .iter_vms()
+            .map(|vm| vm.config.manifest.clone())
+            .collect::<Vec<_>>();
+        manifests
+            .into_iter()
+            .map(|manifest| {
+                let process = processes.get(&manifest.id);
+                let running = process.is_some_and(|p| p.state.status.is_running());
+                let pid = process.filter(|_| running).and_then(|p| p.state.pid);
+                let work_dir = self.work_dir(&manifest.id);
+                let usage = VmUsage::sample(pid, &work_dir);
+                VmMetrics {
+                    manifest,
+                    running,
+                    usage,
+                }
+            })
+            .collect()
+    }
+
+    /// Render the resource usage of all VMs in the Prometheus text format.
+    pub async fn prometheus_metrics(&self) -> Result<String> {
+        let processes = self
+            .supervisor
+            .list()
+            .await
+            .context("Failed to list VMs")?
+            .into_iter()
+            .map(|p| (p.config.id.clone(), p))
+            .collect::<HashMap<_, _>>();
+        Ok(metrics::render_prometheus(&self.vm_metrics(&processes)))
+    }
+
     pub fn list_images(&self) -> Result<Vec<(String, ImageInfo)>> {
         let image_path = self.config.image_path.clone();
         let images = fs::read_dir(image_path).context("Failed to read image directory")?;
@@ -364,6 +500,213 @@ impl App {
             .collect())
     }
 
+    pub async fn pull_image(
+        &self,
+        request: &pb::PullImageRequest,
+    ) -> Result<pb::PullImageResponse> {
+        let cfg = &self.config.image_registry;
+        // Extract into the image directory so that the final move is an atomic rename
+        let tmp_root = self.config.image_path.join(".tmp");
+        fs::create_dir_all(&tmp_root).context("Failed to create temporary directory")?;
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("pull-")
+            .tempdir_in(&tmp_root)
+            .context("Failed to create temporary directory")?;
+        let tarball = tmp_dir.path().join("image.tar.gz");
+        tokio::time::timeout(
+            cfg.download_timeout,
+            registry::fetch_bundle(&request.url, &tarball),
+        )
+        .await
+        .context("Download image timeout")??;
+        let bundle_dir =
+            registry::extract_bundle(&tarball, &tmp_dir.path().join("extracted")).await?;
+        let os_image_hash = registry::verify_bundle(&bundle_dir).await?;
+        let hex_os_image_hash = hex::encode(&os_image_hash);
+        if let Some(expected) = &request.os_image_hash {
+            let expected = expected.strip_prefix("0x").unwrap_or(expected);
+            if !expected.eq_ignore_ascii_case(&hex_os_image_hash) {
+                bail!("os_image_hash mismatch: expected {expected}, got {hex_os_image_hash}");
+            }
+        }
+        let signer = if !request.signature.is_empty() {
+            registry::verify_signature(&os_image_hash, &request.signature, &cfg.trusted_signers)?
+        } else if cfg.require_signature {
+            bail!("Image signature is required");
+        } else {
+            String::new()
+        };
+        let info = ImageInfo::load(bundle_dir.join("metadata.json"))?;
+        let name = match &request.name {
+            Some(name) => name.clone(),
+            None => registry::default_image_name(&info)?,
+        };
+        validate_image_name(&name)?;
+        fs::write(bundle_dir.join("digest.txt"), &hex_os_image_hash)
+            .context("Failed to write digest")?;
+        let measurements =
+            registry::measure_image(&bundle_dir, &cfg.measure_profiles, &self.config.cvm)?;
+        fs::write(
+            bundle_dir.join("measurements.json"),
+            serde_json::to_string(&measurements)?,
+        )
+        .context("Failed to write measurements")?;
+        Image::load(&bundle_dir).context("Invalid image")?;
+
+        let dst = self.config.image_path.join(&name);
+        if dst.exists() {
+            if !request.overwrite {
+                bail!("Image {name} already exists");
+            }
+            if self.referenced_images()?.contains(&name) {
+                bail!("Image {name} is in use, cannot overwrite");
+            }
+            fs::remove_dir_all(&dst).context("Failed to remove old image")?;
+        }
+        fs::rename(&bundle_dir, &dst).context("Failed to install image")?;
+        info!("Image {name} installed, os_image_hash: {hex_os_image_hash}");
+        Ok(pb::PullImageResponse {
+            name,
+            os_image_hash: hex_os_image_hash,
+            signer,
+            measurements: measurements
+                .into_iter()
+                .map(|m| pb::ImageMeasurement {
+                    vcpu: m.vcpu as u32,
+                    memory: m.memory,
+                    mrtd: m.mrtd,
+                    rtmr0: m.rtmr0,
+                    rtmr1: m.rtmr1,
+                    rtmr2: m.rtmr2,
+                })
+                .collect(),
+        })
+    }
+
+    /// Names of the images referenced by any VM manifest
+    fn referenced_images(&self) -> Result<BTreeSet<String>> {
+        let mut images = BTreeSet::new();
+        let vm_path = self.vm_dir();
+        if !vm_path.exists() {
+            return Ok(images);
+        }
+        for entry in fs::read_dir(vm_path).context("Failed to read VM directory")? {
+            let path = entry.context("Failed to read directory entry")?.path();
+            if let Ok(manifest) = VmWorkDir::new(&path).manifest() {
+                images.insert(manifest.image);
+            }
+        }
+        // Templates keep their images alive so that they can still be instantiated
+        for template in self.templates().list()? {
+            images.insert(template.manifest.image);
+        }
+        Ok(images)
+    }
+
+    fn templates(&self) -> template::TemplateStore<'_> {
+        template::TemplateStore {
+            dir: &self.config.template_path,
+        }
+    }
+
+    pub fn save_template(
+        &self,
+        request: &pb::SaveTemplateRequest,
+        now_ms: u64,
+    ) -> Result<pb::TemplateInfo> {
+        let work_dir = self.work_dir(&request.id);
+        if !work_dir.manifest_path().exists() {
+            bail!("VM {} not found", request.id);
+        }
+        let template = self.templates().save(
+            &request.name,
+            &request.description,
+            &work_dir,
+            request.overwrite,
+            now_ms,
+        )?;
+        info!("Saved VM {} as template {}", request.id, request.name);
+        Ok(template.to_pb(&self.templates().template_dir(&template.name)?))
+    }
+
+    pub fn list_templates(&self) -> Result<Vec<pb::TemplateInfo>> {
+        let store = self.templates();
+        store
+            .list()?
+            .iter()
+            .map(|t| Ok(t.to_pb(&store.template_dir(&t.name)?)))
+            .collect()
+    }
+
+    pub fn remove_template(&self, name: &str) -> Result<()> {
+        self.templates().remove(name)?;
+        info!("Template {name} removed");
+        Ok(())
+    }
+
+    /// The VM configurations to create from a template or an existing VM.
+    pub fn derive_vm_configs(
+        &self,
+        source: VmSource<'_>,
+        overrides: Option<&pb::VmOverrides>,
+        count: u32,
+    ) -> Result<Vec<VmConfiguration>> {
+        let mut config = match source {
+            VmSource::Template(name) => {
+                let store = self.templates();
+                store
+                    .load(name)?
+                    .manifest
+                    .to_vm_configuration(&store.template_dir(name)?)
+            }
+            VmSource::Vm(id) => {
+                let work_dir = self.work_dir(id);
+                let manifest = work_dir
+                    .manifest()
+                    .with_context(|| format!("VM {id} not found"))?;
+                manifest.to_vm_configuration(&work_dir.shared_dir())
+            }
+        };
+        let ports_in_use =
+            matches!(source, VmSource::Vm(_)) && !overrides.is_some_and(|o| o.update_ports);
+        if let Some(overrides) = overrides {
+            template::apply_overrides(&mut config, overrides);
+        }
+        template::expand_copies(config, count, ports_in_use)
+    }
+
+    pub fn remove_image(&self, name: &str) -> Result<()> {
+        validate_image_name(name)?;
+        if self.referenced_images()?.contains(name) {
+            bail!("Image {name} is in use");
+        }
+        let path = self.config.image_path.join(name);
+        if !path.is_dir() {
+            bail!("Image {name} not found");
+        }
+        fs::remove_dir_all(&path).context("Failed to remove image")?;
+        info!("Image {name} removed");
+        Ok(())
+    }
+
+    /// Remove all images that are not referenced by any VM. Returns the removed image names.
+    pub fn gc_images(&self, dry_run: bool) -> Result<Vec<String>> {
+        let referenced = self.referenced_images()?;
+        let mut removed = vec![];
+        for (name, _) in self.list_images()? {
+            if referenced.contains(&name) {
+                continue;
+            }
+            if !dry_run {
+                fs::remove_dir_all(self.config.image_path.join(&name))
+                    .with_context(|| format!("Failed to remove image {name}"))?;
+                info!("Image {name} removed");
+            }
+            removed.push(name);
+        }
+        Ok(removed)
+    }
+
     pub async fn vm_info(&self, id: &str) -> Result<Option<pb::VmInfo>> {
         let proc_state = self.supervisor.info(id).await?;
         let state = self.lock();
@@ -579,11 +922,27 @@ impl App {
     }
 
     pub(crate) fn kms_client(&self) -> Result<KmsClient<RaClient>> {
-        if self.config.kms_url.is_empty() {
+        // The KMS of the VMM goes first, the ones given to the CVMs serve as fallbacks
+        let mut urls: Vec<&String> = vec![];
+        for url in std::iter::once(&self.config.kms_url).chain(&self.config.cvm.kms_urls) {
+            if !url.is_empty() && !urls.contains(&url) {
+                urls.push(url);
+            }
+        }
+        if urls.is_empty() {
             bail!("KMS is not configured");
         }
-        let url = format!("{}/prpc", self.config.kms_url);
-        let prpc_client = RaClient::new(url, true)?;
+        let endpoints = urls
+            .into_iter()
+            .map(|url| RaEndpoint::new(format!("{url}/prpc")))
+            .collect();
+        let prpc_client = RaClientConfig::builder()
+            .tls_no_check(true)
+            .endpoints(endpoints)
+            .retry(RetryPolicy::default().with_idempotent_methods(&["GetAppEnvEncryptPubKey"]))
+            .build()
+            .into_client()
+            .context("Failed to create KMS client")?;
         Ok(KmsClient::new(prpc_client))
     }
 
@@ -668,6 +1027,7 @@ fn paginate<T>(items: Vec<T>, page: u32, page_size: u32) -> impl Iterator<Item =
 pub struct VmState {
     pub(crate) config: Arc<VmConfig>,
     state: VmStateMut,
+    ops: Arc<tokio::sync::Mutex<()>>,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -701,6 +1061,7 @@ impl VmState {
         Self {
             config: Arc::new(config),
             state: VmStateMut::default(),
+            ops: Default::default(),
         }
     }
 }
@@ -0,0 +1,241 @@
+//! cgroup v2 isolation of the QEMU processes.
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use dstack_vmm_rpc as pb;
+use fs_err as fs;
+use serde::{Deserialize, Serialize};
+use supervisor_client::supervisor::{CgroupConfig as ProcessCgroup, CpuMax, IoMax};
+use tracing::warn;
+
+use super::Manifest;
+use crate::config::CgroupConfig;
+
+const CPU_PERIOD_US: u64 = 100_000;
+
+/// Per-VM overrides of the host default resource limits.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ResourceLimits {
+    /// cpu.weight, defaults to 100 per vCPU
+    #[serde(default)]
+    pub cpu_weight: Option<u32>,
+    /// CPU quota in percent of a single core, 0 for unlimited
+    #[serde(default)]
+    pub cpu_quota: Option<u32>,
+    /// Bytes per second read from the backing disk, 0 for unlimited
+    #[serde(default)]
+    pub io_read_bps: Option<u64>,
+    /// Bytes per second written to the backing disk, 0 for unlimited
+    #[serde(default)]
+    pub io_write_bps: Option<u64>,
+    /// Read IOPS on the backing disk, 0 for unlimited
+    #[serde(default)]
+    pub io_read_iops: Option<u64>,
+    /// Write IOPS on the backing disk, 0 for unlimited
+    #[serde(default)]
+    pub io_write_iops: Option<u64>,
+    /// Max number of tasks, 0 for unlimited
+    #[serde(default)]
+    pub pids_max: Option<u64>,
+}
+
+impl ResourceLimits {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Override the limits that are set in the request.
+    pub fn update(&mut self, limits: &pb::ResourceLimits) {
+        macro_rules! update {
+            ($($field:ident),*) => {
+                $(if let Some(v) = limits.$field {
+                    self.$field = Some(v);
+                })*
+            };
+        }
+        update!(
+            cpu_weight,
+            cpu_quota,
+            io_read_bps,
+            io_write_bps,
+            io_read_iops,
+            io_write_iops,
+            pids_max
+        );
+    }
+
+    pub fn to_pb(&self) -> pb::ResourceLimits {
+        pb::ResourceLimits {
+            cpu_weight: self.cpu_weight,
+            cpu_quota: self.cpu_quota,
+            io_read_bps: self.io_read_bps,
+            io_write_bps: self.io_write_bps,
+            io_read_iops: self.io_read_iops,
+            io_write_iops: self.io_write_iops,
+            pids_max: self.pids_max,
+        }
+    }
+}
+
+fn nonzero(value: u64) -> Option<u64> {
+    (value != 0).then_some(value)
+}
+
+/// The cgroup the QEMU process of a VM runs in, or None if cgroup isolation is disabled.
+pub fn cgroup_config(
+    manifest: &Manifest,
+    cfg: &CgroupConfig,
+    workdir: &Path,
+) -> Option<ProcessCgroup> {
+    if !cfg.enabled {
+        return None;
+    }
+    let limits = &manifest.limits;
+    let vcpu = manifest.vcpu.max(1) as u64;
+    let cpu_weight = match limits.cpu_weight {
+        Some(weight) if weight > 0 => weight as u64,
+        _ => 100 * vcpu,
+    };
+    let default_quota = if cfg.cpu_quota { 100 * vcpu } else { 0 };
+    let cpu_quota = limits.cpu_quota.map(u64::from).unwrap_or(default_quota);
+    let memory_max = (manifest.memory as u64 + cfg.memory_overhead as u64) * 1024 * 1024;
+
+    let io = IoMax {
+        device: String::new(),
+        rbps: nonzero(limits.io_read_bps.unwrap_or(cfg.io_read_bps)),
+        wbps: nonzero(limits.io_write_bps.unwrap_or(cfg.io_write_bps)),
+        riops: nonzero(limits.io_read_iops.unwrap_or(cfg.io_read_iops)),
+        wiops: nonzero(limits.io_write_iops.unwrap_or(cfg.io_write_iops)),
+    };
+    let has_io_limit = io != IoMax::default();
+    let io_max = match has_io_limit.then(|| backing_device(workdir)).flatten() {
+        Some(device) => vec![IoMax { device, ..io }],
+        None => {
+            if has_io_limit {
+                warn!(
+                    "No block device found for {}, io limits are ignored",
+                    workdir.display()
+                );
+            }
+            vec![]
+        }
+    };
+
+    Some(ProcessCgroup {
+        path: format!("{}/{}", cfg.parent.trim_matches('/'), manifest.id),
+        cpu_weight: Some(cpu_weight),
+        cpu_max: nonzero(cpu_quota).map(|quota| CpuMax {
+            quota_us: quota * CPU_PERIOD_US / 100,
+            period_us: CPU_PERIOD_US,
+        }),
+        memory_max: Some(memory_max),
+        io_max,
+        pids_max: nonzero(limits.pids_max.unwrap_or(cfg.pids_max)),
+    })
+}
+
+/// Decode a Linux dev_t into its major and minor numbers.
+fn major_minor(dev: u64) -> (u64, u64) {
+    let major = ((dev >> 32) & 0xffff_f000) | ((dev >> 8) & 0x0000_0fff);
+    let minor = ((dev >> 12) & 0xffff_ff00) | (dev & 0x0000_00ff);
+    (major, minor)
+}
+
+/// The whole disk device (`major:minor`) holding `path`. io.max does not accept partitions.
+fn backing_device(path: &Path) -> Option<String> {
+    let (major, minor) = major_minor(fs::metadata(path).ok()?.dev());
+    let sysfs = Path::new("/sys/dev/block").join(format!("{major}:{minor}"));
+    if !sysfs.exists() {
+        return None;
+    }
+    if !sysfs.join("partition").exists() {
+        return Some(format!("{major}:{minor}"));
+    }
+    let disk = fs::read_to_string(sysfs.join("..").join("dev")).ok()?;
+    Some(disk.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(limits: ResourceLimits) -> Manifest {
+        Manifest::builder()
+            .id("vm-1".into())
+            .name("test".into())
+            .app_id("app".into())
+            .vcpu(2)
+            .memory(1024)
+            .disk_size(10)
+            .image("dstack-0.5.0".into())
+            .port_map(vec![])
+            .created_at_ms(0)
+            .hugepages(false)
+            .pin_numa(false)
+            .kms_urls(vec![])
+            .gateway_urls(vec![])
+            .limits(limits)
+            .build()
+    }
+
+    fn config() -> CgroupConfig {
+        CgroupConfig {
+            enabled: true,
+            parent: "/dstack-vmm/".into(),
+            cpu_quota: true,
+            memory_overhead: 256,
+            pids_max: 1024,
+            io_read_bps: 0,
+            io_write_bps: 0,
+            io_read_iops: 0,
+            io_write_iops: 0,
+        }
+    }
+
+    #[test]
+    fn test_default_limits() {
+        let cgroup =
+            cgroup_config(&manifest(Default::default()), &config(), Path::new("/")).unwrap();
+        assert_eq!(cgroup.path, "dstack-vmm/vm-1");
+        assert_eq!(cgroup.cpu_weight, Some(200));
+        assert_eq!(
+            cgroup.cpu_max,
+            Some(CpuMax {
+                quota_us: 200_000,
+                period_us: CPU_PERIOD_US
+            })
+        );
+        assert_eq!(cgroup.memory_max, Some(1280 * 1024 * 1024));
+        assert_eq!(cgroup.pids_max, Some(1024));
+        assert!(cgroup.io_max.is_empty());
+
+        let disabled = CgroupConfig {
+            enabled: false,
+            ..config()
+        };
+        assert!(cgroup_config(&manifest(Default::default()), &disabled, Path::new("/")).is_none());
+    }
+
+    #[test]
+    fn test_overrides() {
+        let mut limits = ResourceLimits::default();
+        limits.update(&pb::ResourceLimits {
+            cpu_weight: Some(50),
+            cpu_quota: Some(0),
+            pids_max: Some(0),
+            ..Default::default()
+        });
+        assert!(!limits.is_empty());
+        let cgroup = cgroup_config(&manifest(limits), &config(), Path::new("/")).unwrap();
+        assert_eq!(cgroup.cpu_weight, Some(50));
+        assert_eq!(cgroup.cpu_max, None);
+        assert_eq!(cgroup.pids_max, None);
+    }
+
+    #[test]
+    fn test_major_minor() {
+        // makedev(8, 1) and makedev(259, 65536)
+        assert_eq!(major_minor(0x801), (8, 1));
+        assert_eq!(major_minor(0x1001_0300), (259, 65536));
+    }
+}
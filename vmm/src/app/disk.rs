@@ -0,0 +1,655 @@
+//! Data disk management
+//!
+//! Besides the system disk (`hda.img`), a VM can own any number of named qcow2 data disks
+//! stored under `<workdir>/disks`. Disks are attached on the next boot of the VM. External
+//! snapshots are kept as a backing chain next to the active image:
+//!
+//! ```text
+//! disks/data@snap1.qcow2 <- disks/data@snap2.qcow2 <- disks/data.qcow2 (active)
+//! ```
+use std::collections::BTreeSet;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use fs_err as fs;
+use serde::{Deserialize, Serialize};
+
+use dstack_vmm_rpc as pb;
+
+use super::Manifest;
+
+/// Abstraction over the `qemu-img` tool.
+pub trait QemuImg: Send + Sync {
+    /// Create a qcow2 image. If `backing_file` is given, the image is created as an overlay of it.
+    fn create(&self, path: &Path, backing_file: Option<&Path>, size: Option<&str>) -> Result<()>;
+    /// Resize an image to the given size.
+    fn resize(&self, path: &Path, size: &str) -> Result<()>;
+    /// Create an internal snapshot.
+    fn snapshot_create(&self, path: &Path, name: &str) -> Result<()>;
+    /// Delete an internal snapshot.
+    fn snapshot_delete(&self, path: &Path, name: &str) -> Result<()>;
+    /// Flatten an image (or one of its internal snapshots) into a standalone qcow2 image.
+    fn convert(&self, src: &Path, snapshot: Option<&str>, dst: &Path) -> Result<()>;
+    /// Change the backing file of an image, keeping its guest visible content.
+    fn rebase(&self, path: &Path, backing_file: Option<&Path>) -> Result<()>;
+}
+
+/// [`QemuImg`] implementation that runs the `qemu-img` executable.
+pub struct QemuImgCli {
+    exe: PathBuf,
+}
+
+impl Default for QemuImgCli {
+    fn default() -> Self {
+        Self::new("qemu-img")
+    }
+}
+
+impl QemuImgCli {
+    pub fn new(exe: impl Into<PathBuf>) -> Self {
+        Self { exe: exe.into() }
+    }
+
+    fn run<I, S>(&self, what: &str, args: I) -> Result<()>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        let output = Command::new(&self.exe)
+            .args(args)
+            .output()
+            .with_context(|| format!("Failed to run qemu-img to {what}"))?;
+        if !output.status.success() {
+            bail!(
+                "Failed to {what}: {}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        Ok(())
+    }
+}
+
+impl QemuImg for QemuImgCli {
+    fn create(&self, path: &Path, backing_file: Option<&Path>, size: Option<&str>) -> Result<()> {
+        let mut args = vec!["create".to_string(), "-f".into(), "qcow2".into()];
+        if let Some(backing_file) = backing_file {
+            args.push("-o".into());
+            args.push(format!("backing_file={}", backing_file.display()));
+            args.push("-o".into());
+            args.push("backing_fmt=qcow2".into());
+        }
+        args.push(path.display().to_string());
+        if let Some(size) = size {
+            args.push(size.into());
+        }
+        self.run("create disk", &args)
+    }
+
+    fn resize(&self, path: &Path, size: &str) -> Result<()> {
+        self.run(
+            "resize disk",
+            [OsStr::new("resize"), path.as_os_str(), size.as_ref()],
+        )
+    }
+
+    fn snapshot_create(&self, path: &Path, name: &str) -> Result<()> {
+        self.run(
+            "create snapshot",
+            [
+                OsStr::new("snapshot"),
+                "-c".as_ref(),
+                name.as_ref(),
+                path.as_os_str(),
+            ],
+        )
+    }
+
+    fn snapshot_delete(&self, path: &Path, name: &str) -> Result<()> {
+        self.run(
+            "delete snapshot",
+            [
+                OsStr::new("snapshot"),
+                "-d".as_ref(),
+                name.as_ref(),
+                path.as_os_str(),
+            ],
+        )
+    }
+
+    fn convert(&self, src: &Path, snapshot: Option<&str>, dst: &Path) -> Result<()> {
+        let mut args = vec!["convert".to_string(), "-O".into(), "qcow2".into()];
+        if let Some(snapshot) = snapshot {
+            args.push("-l".into());
+            args.push(format!("snapshot.name={snapshot}"));
+        }
+        args.push(src.display().to_string());
+        args.push(dst.display().to_string());
+        self.run("convert disk", &args)
+    }
+
+    fn rebase(&self, path: &Path, backing_file: Option<&Path>) -> Result<()> {
+        let backing = backing_file
+            .map(|p| p.display().to_string())
+            .unwrap_or_default();
+        let mut args = vec!["rebase", "-f", "qcow2"];
+        if backing_file.is_some() {
+            args.extend(["-F", "qcow2"]);
+        }
+        args.extend(["-b", backing.as_str()]);
+        let path = path.display().to_string();
+        args.push(&path);
+        self.run("rebase disk", args)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SnapshotKind {
+    /// Snapshot stored inside the qcow2 file
+    Internal,
+    /// Snapshot frozen as a separate file in the backing chain
+    External,
+}
+
+impl SnapshotKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SnapshotKind::Internal => "internal",
+            SnapshotKind::External => "external",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiskSnapshot {
+    pub name: String,
+    pub kind: SnapshotKind,
+    /// The image file holding the snapshot, relative to the disks directory
+    pub file: String,
+    pub created_at_ms: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DataDisk {
+    pub name: String,
+    /// Disk size in GB
+    pub size: u32,
+    /// Whether the disk is attached to the VM on next boot
+    #[serde(default)]
+    pub attached: bool,
+    pub created_at_ms: u64,
+    /// Snapshots in creation order
+    #[serde(default)]
+    pub snapshots: Vec<DiskSnapshot>,
+}
+
+impl DataDisk {
+    /// File name of the active image, relative to the disks directory
+    pub fn file(&self) -> String {
+        format!("{}.qcow2", self.name)
+    }
+
+    fn external_file(&self, snapshot: &str) -> String {
+        format!("{}@{snapshot}.qcow2", self.name)
+    }
+
+    fn snapshot(&self, name: &str) -> Option<&DiskSnapshot> {
+        self.snapshots.iter().find(|s| s.name == name)
+    }
+
+    /// The external snapshot chain, from the bottom to the top.
+    pub fn external_chain(&self) -> impl Iterator<Item = &DiskSnapshot> {
+        self.snapshots
+            .iter()
+            .filter(|s| s.kind == SnapshotKind::External)
+    }
+
+    pub fn to_pb(&self) -> pb::DataDiskInfo {
+        pb::DataDiskInfo {
+            name: self.name.clone(),
+            size: self.size,
+            attached: self.attached,
+            snapshots: self
+                .snapshots
+                .iter()
+                .map(|s| pb::DataDiskSnapshot {
+                    name: s.name.clone(),
+                    kind: s.kind.as_str().into(),
+                    created_at_ms: s.created_at_ms,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// The disk name is used as the virtio-blk serial, which is limited to 20 bytes. Snapshot names
+/// share the limit.
+const MAX_DISK_NAME_LEN: usize = 20;
+
+/// Validate a disk or snapshot name. Valid chars are alphanumeric, dash and underscore.
+pub fn validate_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.len() > MAX_DISK_NAME_LEN {
+        bail!("Invalid name length: {name}");
+    }
+    if name
+        .chars()
+        .any(|c| !c.is_ascii_alphanumeric() && c != '-' && c != '_')
+    {
+        bail!("Invalid name: {name}");
+    }
+    Ok(())
+}
+
+/// Operations on the data disks of a VM.
+///
+/// All operations update the given manifest in memory; the caller is responsible for persisting it.
+pub struct DiskManager<'a> {
+    img: &'a dyn QemuImg,
+    dir: PathBuf,
+}
+
+impl<'a> DiskManager<'a> {
+    pub fn new(img: &'a dyn QemuImg, dir: impl Into<PathBuf>) -> Self {
+        Self {
+            img,
+            dir: dir.into(),
+        }
+    }
+
+    fn path(&self, file: &str) -> PathBuf {
+        self.dir.join(file)
+    }
+
+    /// The files in the disks directory.
+    pub fn files(&self) -> Result<BTreeSet<PathBuf>> {
+        if !self.dir.exists() {
+            return Ok(BTreeSet::new());
+        }
+        let mut files = BTreeSet::new();
+        for entry in fs::read_dir(&self.dir)? {
+            files.insert(entry?.path());
+        }
+        Ok(files)
+    }
+
+    fn find<'m>(manifest: &'m mut Manifest, name: &str) -> Result<&'m mut DataDisk> {
+        manifest
+            .data_disks
+            .iter_mut()
+            .find(|d| d.name == name)
+            .with_context(|| format!("Data disk {name} not found"))
+    }
+
+    fn ensure_absent(manifest: &Manifest, name: &str) -> Result<()> {
+        validate_name(name)?;
+        if manifest.data_disks.iter().any(|d| d.name == name) {
+            bail!("Data disk {name} already exists");
+        }
+        Ok(())
+    }
+
+    pub fn create(
+        &self,
+        manifest: &mut Manifest,
+        name: &str,
+        size: u32,
+        attach: bool,
+        now_ms: u64,
+    ) -> Result<()> {
+        Self::ensure_absent(manifest, name)?;
+        if size == 0 {
+            bail!("Disk size must be greater than 0");
+        }
+        let disk = DataDisk {
+            name: name.to_string(),
+            size,
+            attached: attach,
+            created_at_ms: now_ms,
+            snapshots: vec![],
+        };
+        fs::create_dir_all(&self.dir).context("Failed to create disks directory")?;
+        let path = self.path(&disk.file());
+        if path.exists() {
+            bail!("Disk image {} already exists", path.display());
+        }
+        self.img.create(&path, None, Some(&format!("{size}G")))?;
+        manifest.data_disks.push(disk);
+        Ok(())
+    }
+
+    pub fn set_attached(&self, manifest: &mut Manifest, name: &str, attached: bool) -> Result<()> {
+        Self::find(manifest, name)?.attached = attached;
+        Ok(())
+    }
+
+    pub fn remove(&self, manifest: &mut Manifest, name: &str) -> Result<()> {
+        let disk = Self::find(manifest, name)?;
+        if disk.attached {
+            bail!("Data disk {name} is attached, detach it first");
+        }
+        let mut files = vec![disk.file()];
+        files.extend(disk.external_chain().map(|s| s.file.clone()));
+        for file in files {
+            let path = self.path(&file);
+            if path.exists() {
+                fs::remove_file(&path)?;
+            }
+        }
+        manifest.data_disks.retain(|d| d.name != name);
+        Ok(())
+    }
+
+    pub fn resize(&self, manifest: &mut Manifest, name: &str, size: u32) -> Result<()> {
+        let disk = Self::find(manifest, name)?;
+        if size < disk.size {
+            bail!("Cannot shrink disk size");
+        }
+        if size == disk.size {
+            return Ok(());
+        }
+        self.img
+            .resize(&self.dir.join(disk.file()), &format!("{size}G"))?;
+        disk.size = size;
+        Ok(())
+    }
+
+    pub fn create_snapshot(
+        &self,
+        manifest: &mut Manifest,
+        name: &str,
+        snapshot: &str,
+        kind: SnapshotKind,
+        now_ms: u64,
+    ) -> Result<()> {
+        validate_name(snapshot)?;
+        let disk = Self::find(manifest, name)?;
+        if disk.snapshot(snapshot).is_some() {
+            bail!("Snapshot {snapshot} already exists");
+        }
+        let active_file = disk.file();
+        let active = self.path(&active_file);
+        let file = match kind {
+            SnapshotKind::Internal => {
+                self.img.snapshot_create(&active, snapshot)?;
+                active_file
+            }
+            SnapshotKind::External => {
+                // Freeze the active image and continue writing to a new overlay on top of it.
+                let frozen_file = disk.external_file(snapshot);
+                let frozen = self.path(&frozen_file);
+                if frozen.exists() {
+                    bail!("Snapshot image {} already exists", frozen.display());
+                }
+                fs::rename(&active, &frozen)?;
+                // Relative backing file keeps the chain valid if the VM directory is moved.
+                if let Err(err) = self
+                    .img
+                    .create(&active, Some(Path::new(&frozen_file)), None)
+                {
+                    fs::rename(&frozen, &active)?;
+                    return Err(err);
+                }
+                // Internal snapshots taken on the active image now live in the frozen one.
+                for s in disk.snapshots.iter_mut() {
+                    if s.kind == SnapshotKind::Internal && s.file == active_file {
+                        s.file = frozen_file.clone();
+                    }
+                }
+                frozen_file
+            }
+        };
+        disk.snapshots.push(DiskSnapshot {
+            name: snapshot.to_string(),
+            kind,
+            file,
+            created_at_ms: now_ms,
+        });
+        Ok(())
+    }
+
+    pub fn delete_snapshot(
+        &self,
+        manifest: &mut Manifest,
+        name: &str,
+        snapshot: &str,
+    ) -> Result<()> {
+        let disk = Self::find(manifest, name)?;
+        let snap = disk
+            .snapshot(snapshot)
+            .with_context(|| format!("Snapshot {snapshot} not found"))?
+            .clone();
+        match snap.kind {
+            SnapshotKind::Internal => {
+                self.img.snapshot_delete(&self.path(&snap.file), snapshot)?;
+            }
+            SnapshotKind::External => {
+                if disk
+                    .snapshots
+                    .iter()
+                    .any(|s| s.kind == SnapshotKind::Internal && s.file == snap.file)
+                {
+                    bail!("Snapshot {snapshot} contains internal snapshots, delete them first");
+                }
+                let chain = disk.external_chain().collect::<Vec<_>>();
+                let pos = chain
+                    .iter()
+                    .position(|s| s.name == snapshot)
+                    .context("Snapshot not in chain")?;
+                let parent = pos.checked_sub(1).map(|i| chain[i].file.clone());
+                let child = chain
+                    .get(pos + 1)
+                    .map(|s| s.file.clone())
+                    .unwrap_or_else(|| disk.file());
+                // Pull the data of the snapshot into its child before dropping it from the chain.
+                self.img
+                    .rebase(&self.path(&child), parent.as_deref().map(Path::new))?;
+                fs::remove_file(self.path(&snap.file))?;
+            }
+        }
+        disk.snapshots.retain(|s| s.name != snapshot);
+        Ok(())
+    }
+
+    /// Create a new data disk from the current state or a snapshot of an existing one.
+    pub fn clone_disk(
+        &self,
+        manifest: &mut Manifest,
+        source: &str,
+        snapshot: Option<&str>,
+        name: &str,
+        attach: bool,
+        now_ms: u64,
+    ) -> Result<()> {
+        Self::ensure_absent(manifest, name)?;
+        let src_disk = Self::find(manifest, source)?.clone();
+        let dst_disk = DataDisk {
+            name: name.to_string(),
+            size: src_disk.size,
+            attached: attach,
+            created_at_ms: now_ms,
+            snapshots: vec![],
+        };
+        let dst = self.path(&dst_disk.file());
+        if dst.exists() {
+            bail!("Disk image {} already exists", dst.display());
+        }
+        match snapshot {
+            None => self.img.convert(&self.path(&src_disk.file()), None, &dst)?,
+            Some(snapshot) => {
+                let snap = src_disk
+                    .snapshot(snapshot)
+                    .with_context(|| format!("Snapshot {snapshot} not found"))?;
+                let src = self.path(&snap.file);
+                match snap.kind {
+                    SnapshotKind::Internal => self.img.convert(&src, Some(snapshot), &dst)?,
+                    SnapshotKind::External => self.img.convert(&src, None, &dst)?,
+                }
+            }
+        }
+        manifest.data_disks.push(dst_disk);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Records the invoked operations and touches the output files.
+    #[derive(Default)]
+    struct MockImg {
+        calls: Mutex<Vec<String>>,
+    }
+
+    impl MockImg {
+        fn log(&self, call: String) {
+            self.calls.lock().unwrap().push(call);
+        }
+
+        fn take(&self) -> Vec<String> {
+            std::mem::take(&mut *self.calls.lock().unwrap())
+        }
+    }
+
+    fn name(path: &Path) -> String {
+        path.file_name().unwrap().to_string_lossy().to_string()
+    }
+
+    impl QemuImg for MockImg {
+        fn create(&self, path: &Path, backing: Option<&Path>, size: Option<&str>) -> Result<()> {
+            fs::write(path, b"")?;
+            self.log(format!(
+                "create {} {:?} {:?}",
+                name(path),
+                backing.map(name),
+                size
+            ));
+            Ok(())
+        }
+
+        fn resize(&self, path: &Path, size: &str) -> Result<()> {
+            self.log(format!("resize {} {size}", name(path)));
+            Ok(())
+        }
+
+        fn snapshot_create(&self, path: &Path, snapshot: &str) -> Result<()> {
+            self.log(format!("snapshot -c {snapshot} {}", name(path)));
+            Ok(())
+        }
+
+        fn snapshot_delete(&self, path: &Path, snapshot: &str) -> Result<()> {
+            self.log(format!("snapshot -d {snapshot} {}", name(path)));
+            Ok(())
+        }
+
+        fn convert(&self, src: &Path, snapshot: Option<&str>, dst: &Path) -> Result<()> {
+            fs::write(dst, b"")?;
+            self.log(format!("convert {} {snapshot:?} {}", name(src), name(dst)));
+            Ok(())
+        }
+
+        fn rebase(&self, path: &Path, backing: Option<&Path>) -> Result<()> {
+            self.log(format!("rebase {} {:?}", name(path), backing.map(name)));
+            Ok(())
+        }
+    }
+
+    fn test_manifest() -> Manifest {
+        Manifest::builder()
+            .id("vm".into())
+            .name("vm".into())
+            .app_id("app".into())
+            .vcpu(1)
+            .memory(1024)
+            .disk_size(10)
+            .image("img".into())
+            .port_map(vec![])
+            .created_at_ms(0)
+            .hugepages(false)
+            .pin_numa(false)
+            .kms_urls(vec![])
+            .gateway_urls(vec![])
+            .build()
+    }
+
+    #[test]
+    fn test_disk_lifecycle() {
+        let dir = tempfile::tempdir().unwrap();
+        let img = MockImg::default();
+        let mgr = DiskManager::new(&img, dir.path());
+        let mut manifest = test_manifest();
+
+        mgr.create(&mut manifest, "data", 10, true, 1).unwrap();
+        assert!(mgr.create(&mut manifest, "data", 10, true, 1).is_err());
+        assert!(mgr.create(&mut manifest, "../x", 10, true, 1).is_err());
+        assert!(mgr
+            .create(&mut manifest, &"d".repeat(21), 10, true, 1)
+            .is_err());
+        assert_eq!(mgr.files().unwrap().len(), 1);
+        mgr.resize(&mut manifest, "data", 20).unwrap();
+        assert!(mgr.resize(&mut manifest, "data", 5).is_err());
+        assert_eq!(
+            img.take(),
+            [
+                "create data.qcow2 None Some(\"10G\")",
+                "resize data.qcow2 20G"
+            ]
+        );
+        assert!(mgr.remove(&mut manifest, "data").is_err());
+        mgr.set_attached(&mut manifest, "data", false).unwrap();
+        mgr.remove(&mut manifest, "data").unwrap();
+        assert!(manifest.data_disks.is_empty());
+        assert!(!dir.path().join("data.qcow2").exists());
+    }
+
+    #[test]
+    fn test_snapshots() {
+        let dir = tempfile::tempdir().unwrap();
+        let img = MockImg::default();
+        let mgr = DiskManager::new(&img, dir.path());
+        let mut manifest = test_manifest();
+
+        mgr.create(&mut manifest, "data", 10, true, 1).unwrap();
+        mgr.create_snapshot(&mut manifest, "data", "i1", SnapshotKind::Internal, 2)
+            .unwrap();
+        mgr.create_snapshot(&mut manifest, "data", "e1", SnapshotKind::External, 3)
+            .unwrap();
+        mgr.create_snapshot(&mut manifest, "data", "e2", SnapshotKind::External, 4)
+            .unwrap();
+        let disk = &manifest.data_disks[0];
+        assert_eq!(disk.snapshot("i1").unwrap().file, "data@e1.qcow2");
+        assert!(dir.path().join("data@e1.qcow2").exists());
+        assert!(dir.path().join("data@e2.qcow2").exists());
+
+        // e1 still holds the internal snapshot i1
+        assert!(mgr.delete_snapshot(&mut manifest, "data", "e1").is_err());
+        mgr.clone_disk(&mut manifest, "data", Some("i1"), "c1", false, 5)
+            .unwrap();
+        mgr.clone_disk(&mut manifest, "data", Some("e2"), "c2", false, 5)
+            .unwrap();
+        mgr.delete_snapshot(&mut manifest, "data", "i1").unwrap();
+        mgr.delete_snapshot(&mut manifest, "data", "e1").unwrap();
+        mgr.delete_snapshot(&mut manifest, "data", "e2").unwrap();
+        assert!(!dir.path().join("data@e1.qcow2").exists());
+        assert!(!dir.path().join("data@e2.qcow2").exists());
+        assert_eq!(
+            img.take(),
+            [
+                "create data.qcow2 None Some(\"10G\")",
+                "snapshot -c i1 data.qcow2",
+                "create data.qcow2 Some(\"data@e1.qcow2\") None",
+                "create data.qcow2 Some(\"data@e2.qcow2\") None",
+                "convert data@e1.qcow2 Some(\"i1\") c1.qcow2",
+                "convert data@e2.qcow2 None c2.qcow2",
+                "snapshot -d i1 data@e1.qcow2",
+                "rebase data@e2.qcow2 None",
+                "rebase data.qcow2 None",
+            ]
+        );
+        assert_eq!(manifest.data_disks.len(), 3);
+        assert!(manifest.data_disks[0].snapshots.is_empty());
+    }
+}
@@ -113,6 +113,21 @@ impl Image {
     }
 }
 
+/// Validate the name of an image directory under the image path.
+pub fn validate_image_name(name: &str) -> Result<()> {
+    if name.is_empty()
+        || name.len() > 64
+        || name.starts_with('.')
+        || name.contains("..")
+        || !name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == '-' || c == '.')
+    {
+        bail!("Invalid image name");
+    }
+    Ok(())
+}
+
 fn guess_version(base_path: &Path) -> Option<String> {
     // name pattern: dstack-dev-0.2.3 or dstack-0.2.3
     let basename = base_path.file_name()?.to_str()?.to_string();
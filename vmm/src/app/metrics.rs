@@ -0,0 +1,293 @@
+//! Host side resource accounting of the VM processes.
+use std::fmt::Write as _;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use dstack_vmm_rpc as pb;
+use fs_err as fs;
+
+use super::{Manifest, VmWorkDir};
+
+/// Resource usage of a single VM, sampled from the host.
+#[derive(Debug, Clone, Default)]
+pub struct VmUsage {
+    /// CPU time (user + system) consumed by the QEMU process in seconds
+    pub cpu_seconds: f64,
+    /// Resident memory of the QEMU process in bytes
+    pub memory_rss_bytes: u64,
+    /// Bytes read from the storage layer by the QEMU process
+    pub io_read_bytes: u64,
+    /// Bytes written to the storage layer by the QEMU process
+    pub io_write_bytes: u64,
+    /// Allocated size of the disk images on the host in bytes
+    pub disk_allocated_bytes: u64,
+    /// Bytes received on the tap interface, if the VM uses one
+    pub net_rx_bytes: Option<u64>,
+    /// Bytes transmitted on the tap interface, if the VM uses one
+    pub net_tx_bytes: Option<u64>,
+}
+
+pub struct VmMetrics {
+    pub manifest: Manifest,
+    pub running: bool,
+    pub usage: VmUsage,
+}
+
+impl VmUsage {
+    /// Sample the usage of a VM. `pid` is the pid of the process started by the supervisor.
+    pub fn sample(pid: Option<u32>, workdir: &VmWorkDir) -> Self {
+        let mut usage = Self::default();
+        let pid = pid.map(qemu_pid);
+        if let Some(pid) = pid {
+            if let Ok(stat) = fs::read_to_string(format!("/proc/{pid}/stat")) {
+                usage.cpu_seconds = parse_cpu_seconds(&stat).unwrap_or_default();
+            }
+            if let Ok(status) = fs::read_to_string(format!("/proc/{pid}/status")) {
+                usage.memory_rss_bytes = parse_kv(&status, "VmRSS:").unwrap_or_default() * 1024;
+            }
+            // Only readable if the VMM has permission to trace the process
+            if let Ok(io) = fs::read_to_string(format!("/proc/{pid}/io")) {
+                usage.io_read_bytes = parse_kv(&io, "read_bytes:").unwrap_or_default();
+                usage.io_write_bytes = parse_kv(&io, "write_bytes:").unwrap_or_default();
+            }
+        }
+        usage.disk_allocated_bytes =
+            allocated_size(&workdir.hda_path()) + dir_allocated_size(&workdir.disks_dir());
+        if let Some(ifname) = pid.and_then(tap_ifname) {
+            let stats = Path::new("/sys/class/net").join(ifname).join("statistics");
+            let read = |name: &str| {
+                fs::read_to_string(stats.join(name))
+                    .ok()
+                    .and_then(|s| s.trim().parse().ok())
+            };
+            usage.net_rx_bytes = read("rx_bytes");
+            usage.net_tx_bytes = read("tx_bytes");
+        }
+        usage
+    }
+}
+
+/// The QEMU process may be wrapped by `sudo`, which forks it as a child.
+fn qemu_pid(pid: u32) -> u32 {
+    let comm = fs::read_to_string(format!("/proc/{pid}/comm")).unwrap_or_default();
+    if comm.trim() != "sudo" {
+        return pid;
+    }
+    fs::read_to_string(format!("/proc/{pid}/task/{pid}/children"))
+        .ok()
+        .and_then(|children| children.split_whitespace().next()?.parse().ok())
+        .unwrap_or(pid)
+}
+
+fn clock_ticks_per_second() -> f64 {
+    // SAFETY: sysconf has no memory safety requirements
+    let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    if ticks > 0 {
+        ticks as f64
+    } else {
+        100.0
+    }
+}
+
+/// Parse utime + stime from the content of `/proc/<pid>/stat`.
+fn parse_cpu_seconds(stat: &str) -> Option<f64> {
+    // The comm field may contain spaces, so split after its closing parenthesis
+    let (_, rest) = stat.rsplit_once(')')?;
+    let mut fields = rest.split_whitespace();
+    // utime and stime are the 14th and 15th fields, rest starts from the 3rd
+    let utime: u64 = fields.nth(11)?.parse().ok()?;
+    let stime: u64 = fields.next()?.parse().ok()?;
+    Some((utime + stime) as f64 / clock_ticks_per_second())
+}
+
+/// Parse the first number following `key` in a `key: value` formatted text.
+fn parse_kv(text: &str, key: &str) -> Option<u64> {
+    text.lines()
+        .find_map(|line| line.strip_prefix(key))?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+fn allocated_size(path: &Path) -> u64 {
+    fs::metadata(path)
+        .map(|m| m.blocks() * 512)
+        .unwrap_or_default()
+}
+
+fn dir_allocated_size(dir: &Path) -> u64 {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return 0;
+    };
+    entries
+        .flatten()
+        .map(|entry| allocated_size(&entry.path()))
+        .sum()
+}
+
+/// Find the tap interface opened by the QEMU process, which is private to the VM. VMs on user mode
+/// networking have none.
+fn tap_ifname(pid: u32) -> Option<String> {
+    let fds = fs::read_dir(format!("/proc/{pid}/fdinfo")).ok()?;
+    fds.flatten().find_map(|fd| {
+        let fdinfo = fs::read_to_string(fd.path()).ok()?;
+        parse_tun_ifname(&fdinfo).map(String::from)
+    })
+}
+
+/// Parse the interface name from the fdinfo of a `/dev/net/tun` file descriptor.
+fn parse_tun_ifname(fdinfo: &str) -> Option<&str> {
+    fdinfo
+        .lines()
+        .find_map(|line| line.strip_prefix("iff:"))
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+}
+
+/// Summarize the usage of all VMs.
+pub fn summarize(metrics: &[VmMetrics]) -> pb::ResourceUsage {
+    let mut summary = pb::ResourceUsage::default();
+    for vm in metrics.iter() {
+        summary.total_vms += 1;
+        summary.disk_allocated_bytes += vm.usage.disk_allocated_bytes;
+        if !vm.running {
+            continue;
+        }
+        summary.running_vms += 1;
+        summary.allocated_vcpu += vm.manifest.vcpu;
+        summary.allocated_memory_mb += vm.manifest.memory as u64;
+        summary.cpu_seconds += vm.usage.cpu_seconds;
+        summary.memory_rss_bytes += vm.usage.memory_rss_bytes;
+        summary.io_read_bytes += vm.usage.io_read_bytes;
+        summary.io_write_bytes += vm.usage.io_write_bytes;
+        summary.net_rx_bytes += vm.usage.net_rx_bytes.unwrap_or_default();
+        summary.net_tx_bytes += vm.usage.net_tx_bytes.unwrap_or_default();
+    }
+    summary
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Render the metrics in the Prometheus text exposition format.
+pub fn render_prometheus(metrics: &[VmMetrics]) -> String {
+    type Getter = fn(&VmMetrics) -> Option<f64>;
+    let families: &[(&str, &str, &str, Getter)] = &[
+        (
+            "dstack_vm_up",
+            "gauge",
+            "Whether the VM process is running",
+            |vm| Some(if vm.running { 1.0 } else { 0.0 }),
+        ),
+        (
+            "dstack_vm_vcpus",
+            "gauge",
+            "Number of vCPUs allocated to the VM",
+            |vm| Some(vm.manifest.vcpu as f64),
+        ),
+        (
+            "dstack_vm_memory_allocated_bytes",
+            "gauge",
+            "Memory allocated to the VM",
+            |vm| Some(vm.manifest.memory as f64 * 1024.0 * 1024.0),
+        ),
+        (
+            "dstack_vm_cpu_seconds_total",
+            "counter",
+            "CPU time consumed by the VM process",
+            |vm| vm.running.then_some(vm.usage.cpu_seconds),
+        ),
+        (
+            "dstack_vm_memory_rss_bytes",
+            "gauge",
+            "Resident memory of the VM process",
+            |vm| vm.running.then_some(vm.usage.memory_rss_bytes as f64),
+        ),
+        (
+            "dstack_vm_io_read_bytes_total",
+            "counter",
+            "Bytes read from storage by the VM process",
+            |vm| vm.running.then_some(vm.usage.io_read_bytes as f64),
+        ),
+        (
+            "dstack_vm_io_write_bytes_total",
+            "counter",
+            "Bytes written to storage by the VM process",
+            |vm| vm.running.then_some(vm.usage.io_write_bytes as f64),
+        ),
+        (
+            "dstack_vm_disk_allocated_bytes",
+            "gauge",
+            "Allocated size of the VM disk images on the host",
+            |vm| Some(vm.usage.disk_allocated_bytes as f64),
+        ),
+        (
+            "dstack_vm_network_receive_bytes_total",
+            "counter",
+            "Bytes received on the VM tap interface",
+            |vm| vm.usage.net_rx_bytes.map(|v| v as f64),
+        ),
+        (
+            "dstack_vm_network_transmit_bytes_total",
+            "counter",
+            "Bytes transmitted on the VM tap interface",
+            |vm| vm.usage.net_tx_bytes.map(|v| v as f64),
+        ),
+    ];
+    let mut out = String::new();
+    for (name, kind, help, get) in families {
+        _ = writeln!(out, "# HELP {name} {help}");
+        _ = writeln!(out, "# TYPE {name} {kind}");
+        for vm in metrics {
+            let Some(value) = get(vm) else {
+                continue;
+            };
+            _ = writeln!(
+                out,
+                "{name}{{id=\"{}\",name=\"{}\",app_id=\"{}\"}} {value}",
+                escape_label(&vm.manifest.id),
+                escape_label(&vm.manifest.name),
+                escape_label(&vm.manifest.app_id),
+            );
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_proc() {
+        let stat = "1234 (qemu-system (x86)) S 1 1234 1234 0 -1 4194560 100 0 0 0 250 150 0 0 20 0 8 0 100 0 0";
+        let ticks = clock_ticks_per_second();
+        assert_eq!(parse_cpu_seconds(stat), Some(400.0 / ticks));
+        assert_eq!(parse_cpu_seconds("garbage"), None);
+
+        let status = "Name:\tqemu\nVmPeak:\t  200 kB\nVmRSS:\t  1024 kB\n";
+        assert_eq!(parse_kv(status, "VmRSS:"), Some(1024));
+        let io = "rchar: 1\nwchar: 2\nread_bytes: 4096\nwrite_bytes: 8192\n";
+        assert_eq!(parse_kv(io, "read_bytes:"), Some(4096));
+        assert_eq!(parse_kv(io, "write_bytes:"), Some(8192));
+        assert_eq!(parse_kv(io, "missing:"), None);
+    }
+
+    #[test]
+    fn test_parse_tun_ifname() {
+        let tun = "pos:\t0\nflags:\t0104002\nmnt_id:\t25\nino:\t1041\niff:\ttap-vm0\n";
+        assert_eq!(parse_tun_ifname(tun), Some("tap-vm0"));
+        let file = "pos:\t0\nflags:\t02\nmnt_id:\t25\nino:\t1041\n";
+        assert_eq!(parse_tun_ifname(file), None);
+    }
+
+    #[test]
+    fn test_escape_label() {
+        assert_eq!(escape_label("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
@@ -12,7 +12,7 @@ use std::{
     time::{Duration, SystemTime},
 };
 
-use super::{image::Image, GpuConfig, VmState};
+use super::{cgroup, disk::QemuImg, image::Image, GpuConfig, VmState};
 use anyhow::{bail, Context, Result};
 use base64::prelude::*;
 use bon::Builder;
@@ -64,29 +64,46 @@ pub struct State {
     started: bool,
 }
 
-fn create_hd(
-    image_file: impl AsRef<Path>,
-    backing_file: Option<impl AsRef<Path>>,
-    size: &str,
-) -> Result<()> {
-    let mut command = Command::new("qemu-img");
-    command.arg("create").arg("-f").arg("qcow2");
-    if let Some(backing_file) = backing_file {
-        command
-            .arg("-o")
-            .arg(format!("backing_file={}", backing_file.as_ref().display()));
-        command.arg("-o").arg("backing_fmt=qcow2");
-    }
-    command.arg(image_file.as_ref());
-    command.arg(size);
-    let output = command.output()?;
-    if !output.status.success() {
-        bail!(
-            "Failed to create disk: {}",
-            String::from_utf8_lossy(&output.stderr)
-        );
-    }
-    Ok(())
+impl Manifest {
+    /// The configuration of a VM. `files_dir` holds the compose file, user config and encrypted env.
+    pub fn to_vm_configuration(&self, files_dir: &Path) -> pb::VmConfiguration {
+        pb::VmConfiguration {
+            name: self.name.clone(),
+            image: self.image.clone(),
+            compose_file: fs::read_to_string(files_dir.join(APP_COMPOSE)).unwrap_or_default(),
+            encrypted_env: fs::read(files_dir.join(ENCRYPTED_ENV)).unwrap_or_default(),
+            user_config: fs::read_to_string(files_dir.join(USER_CONFIG)).unwrap_or_default(),
+            vcpu: self.vcpu,
+            memory: self.memory,
+            disk_size: self.disk_size,
+            ports: self
+                .port_map
+                .iter()
+                .map(|pm| pb::PortMapping {
+                    protocol: pm.protocol.as_str().into(),
+                    host_address: pm.address.to_string(),
+                    host_port: pm.from as u32,
+                    vm_port: pm.to as u32,
+                })
+                .collect(),
+            app_id: Some(self.app_id.clone()),
+            hugepages: self.hugepages,
+            pin_numa: self.pin_numa,
+            gpus: self.gpus.as_ref().map(|g| pb::GpuConfig {
+                attach_mode: g.attach_mode.to_string(),
+                gpus: g
+                    .gpus
+                    .iter()
+                    .map(|gpu| pb::GpuSpec {
+                        slot: gpu.slot.clone(),
+                    })
+                    .collect(),
+            }),
+            kms_urls: self.kms_urls.clone(),
+            gateway_urls: self.gateway_urls.clone(),
+            limits: Some(self.limits.to_pb()),
+        }
+    }
 }
 
 impl VmInfo {
@@ -104,56 +121,7 @@ impl VmInfo {
             configuration: if brief {
                 None
             } else {
-                let vm_config = workdir.manifest();
-                let kms_urls = vm_config
-                    .as_ref()
-                    .map(|c| c.kms_urls.clone())
-                    .unwrap_or_default();
-                let gateway_urls = vm_config
-                    .as_ref()
-                    .map(|c| c.gateway_urls.clone())
-                    .unwrap_or_default();
-
-                Some(pb::VmConfiguration {
-                    name: self.manifest.name.clone(),
-                    image: self.manifest.image.clone(),
-                    compose_file: {
-                        fs::read_to_string(workdir.app_compose_path()).unwrap_or_default()
-                    },
-                    encrypted_env: { fs::read(workdir.encrypted_env_path()).unwrap_or_default() },
-                    user_config: {
-                        fs::read_to_string(workdir.user_config_path()).unwrap_or_default()
-                    },
-                    vcpu: self.manifest.vcpu,
-                    memory: self.manifest.memory,
-                    disk_size: self.manifest.disk_size,
-                    ports: self
-                        .manifest
-                        .port_map
-                        .iter()
-                        .map(|pm| pb::PortMapping {
-                            protocol: pm.protocol.as_str().into(),
-                            host_address: pm.address.to_string(),
-                            host_port: pm.from as u32,
-                            vm_port: pm.to as u32,
-                        })
-                        .collect(),
-                    app_id: Some(self.manifest.app_id.clone()),
-                    hugepages: self.manifest.hugepages,
-                    pin_numa: self.manifest.pin_numa,
-                    gpus: self.manifest.gpus.as_ref().map(|g| pb::GpuConfig {
-                        attach_mode: g.attach_mode.to_string(),
-                        gpus: g
-                            .gpus
-                            .iter()
-                            .map(|gpu| pb::GpuSpec {
-                                slot: gpu.slot.clone(),
-                            })
-                            .collect(),
-                    }),
-                    kms_urls,
-                    gateway_urls,
-                })
+                Some(self.manifest.to_vm_configuration(&workdir.shared_dir()))
             },
             app_url: self
                 .gateway_enabled
@@ -223,6 +191,7 @@ impl VmConfig {
         workdir: impl AsRef<Path>,
         cfg: &CvmConfig,
         gpus: &GpuConfig,
+        qemu_img: &dyn QemuImg,
     ) -> Result<ProcessConfig> {
         let workdir = VmWorkDir::new(workdir);
         let serial_file = workdir.serial_file();
@@ -231,7 +200,7 @@ impl VmConfig {
         let disk_size = format!("{}G", self.manifest.disk_size);
         let hda_path = workdir.hda_path();
         if !hda_path.exists() {
-            create_hd(&hda_path, self.image.hda.as_ref(), &disk_size)?;
+            qemu_img.create(&hda_path, self.image.hda.as_deref(), Some(&disk_size))?;
         }
         if !cfg.user.is_empty() {
             fs_err::set_permissions(&hda_path, Permissions::from_mode(0o660))?;
@@ -307,6 +276,28 @@ impl VmConfig {
             .arg(format!("file={},if=none,id=hd1", hda_path.display()))
             .arg("-device")
             .arg("virtio-blk-pci,drive=hd1");
+        let attached_disks = self.manifest.data_disks.iter().filter(|d| d.attached);
+        for (i, disk) in attached_disks.enumerate() {
+            let disks_dir = workdir.disks_dir();
+            let disk_path = disks_dir.join(disk.file());
+            if !disk_path.exists() {
+                bail!("Data disk {} does not exist", disk.name);
+            }
+            if !cfg.user.is_empty() {
+                let chain = disk.external_chain().map(|s| disks_dir.join(&s.file));
+                for path in chain.chain([disk_path.clone()]) {
+                    fs::set_permissions(&path, Permissions::from_mode(0o660))?;
+                }
+            }
+            command
+                .arg("-drive")
+                .arg(format!(
+                    "file={},if=none,id=data{i},format=qcow2",
+                    disk_path.display()
+                ))
+                .arg("-device")
+                .arg(format!("virtio-blk-pci,drive=data{i},serial={}", disk.name));
+        }
         let netdev = match &self.networking {
             Networking::User(netcfg) => {
                 let mut netdev = format!(
@@ -517,6 +508,7 @@ impl VmConfig {
         let stdout_path = workdir.stdout_file();
         let stderr_path = workdir.stderr_file();
 
+        let cgroup = cgroup::cgroup_config(&self.manifest, &cfg.cgroup, workdir.path());
         let workdir = workdir.path();
 
         let mut cmd_args = vec![];
@@ -550,6 +542,7 @@ impl VmConfig {
             pidfile: pidfile_path.to_string_lossy().to_string(),
             cid: Some(self.cid),
             note,
+            cgroup,
         };
 
         Ok(process_config)
@@ -724,6 +717,10 @@ impl VmWorkDir {
         self.workdir.join("hda.img")
     }
 
+    pub fn disks_dir(&self) -> PathBuf {
+        self.workdir.join("disks")
+    }
+
     pub fn qmp_socket(&self) -> PathBuf {
         self.workdir.join("qmp.sock")
     }
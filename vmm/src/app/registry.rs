@@ -0,0 +1,260 @@
+//! Image registry: pulling, verifying and measuring guest OS image bundles.
+//!
+//! An image bundle is a tar.gz archive containing `metadata.json`, the files it refers to and a
+//! `sha256sum.txt` listing all of them. The os_image_hash of the bundle is the sha256 of the
+//! `sha256sum.txt`.
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use fs_err as fs;
+use k256::ecdsa::{RecoveryId, Signature, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use serde_human_bytes as hex_bytes;
+use sha2::Digest;
+use tokio::{io::AsyncWriteExt, process::Command};
+use tracing::info;
+
+use super::image::ImageInfo;
+use crate::config::{CvmConfig, MeasureProfile};
+
+/// Prefix of the message signed by image signers.
+const SIGNATURE_PREFIX: &[u8] = b"dstack-os-image";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageMeasurement {
+    pub vcpu: u8,
+    /// Memory in MB
+    pub memory: u32,
+    #[serde(with = "hex_bytes")]
+    pub mrtd: Vec<u8>,
+    #[serde(with = "hex_bytes")]
+    pub rtmr0: Vec<u8>,
+    #[serde(with = "hex_bytes")]
+    pub rtmr1: Vec<u8>,
+    #[serde(with = "hex_bytes")]
+    pub rtmr2: Vec<u8>,
+}
+
+/// Fetch an image bundle into `dst`. `src` is an http(s) URL, a `file://` URL or a local path.
+pub async fn fetch_bundle(src: &str, dst: &Path) -> Result<()> {
+    if src.starts_with("http://") || src.starts_with("https://") {
+        info!("Downloading image from {src}");
+        let mut response = reqwest::get(src)
+            .await
+            .context("Failed to download image")?;
+        if !response.status().is_success() {
+            bail!(
+                "Failed to download image: HTTP status {}, url: {src}",
+                response.status(),
+            );
+        }
+        let mut file = tokio::fs::File::create(dst)
+            .await
+            .context("Failed to create tarball file")?;
+        while let Some(chunk) = response.chunk().await? {
+            file.write_all(&chunk)
+                .await
+                .context("Failed to write chunk to file")?;
+        }
+        file.flush().await?;
+    } else {
+        let path = src.strip_prefix("file://").unwrap_or(src);
+        info!("Copying image from {path}");
+        tokio::fs::copy(path, dst)
+            .await
+            .with_context(|| format!("Failed to copy image from {path}"))?;
+    }
+    Ok(())
+}
+
+/// Extract a tar.gz bundle into `dst` and return the directory holding the image files.
+pub async fn extract_bundle(tarball: &Path, dst: &Path) -> Result<PathBuf> {
+    fs::create_dir_all(dst).context("Failed to create extraction directory")?;
+    let output = Command::new("tar")
+        .arg("xzf")
+        .arg(tarball)
+        .current_dir(dst)
+        .output()
+        .await
+        .context("Failed to extract tarball")?;
+    if !output.status.success() {
+        bail!(
+            "Failed to extract tarball: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    if dst.join("sha256sum.txt").exists() {
+        return Ok(dst.to_path_buf());
+    }
+    // Release tarballs wrap the files in a top level directory
+    let entries = fs::read_dir(dst)?.collect::<Result<Vec<_>, _>>()?;
+    match &entries[..] {
+        [entry] if entry.path().join("sha256sum.txt").exists() => Ok(entry.path()),
+        _ => bail!("sha256sum.txt not found in the image bundle"),
+    }
+}
+
+/// Verify the files listed in `sha256sum.txt`, remove unlisted files and return the os_image_hash.
+pub async fn verify_bundle(dir: &Path) -> Result<Vec<u8>> {
+    let output = Command::new("sha256sum")
+        .arg("-c")
+        .arg("sha256sum.txt")
+        .current_dir(dir)
+        .output()
+        .await
+        .context("Failed to verify checksum")?;
+    if !output.status.success() {
+        bail!(
+            "Checksum verification failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    let files_doc =
+        fs::read_to_string(dir.join("sha256sum.txt")).context("Failed to read sha256sum.txt")?;
+    let listed_files: Vec<&OsStr> = files_doc
+        .lines()
+        .flat_map(|line| line.split_whitespace().nth(1))
+        .map(|s| s.as_ref())
+        .collect();
+    if !listed_files.contains(&OsStr::new("metadata.json")) {
+        bail!("metadata.json is not listed in sha256sum.txt");
+    }
+    for file in fs::read_dir(dir).context("Failed to read directory")? {
+        let file = file.context("Failed to read directory entry")?;
+        let filename = file.file_name();
+        if filename == "sha256sum.txt" || listed_files.contains(&filename.as_os_str()) {
+            continue;
+        }
+        if file.path().is_dir() {
+            fs::remove_dir_all(file.path()).context("Failed to remove directory")?;
+        } else {
+            fs::remove_file(file.path()).context("Failed to remove file")?;
+        }
+    }
+    Ok(sha2::Sha256::new_with_prefix(files_doc.as_bytes())
+        .finalize()
+        .to_vec())
+}
+
+/// Verify a recoverable secp256k1 signature over the os_image_hash.
+///
+/// The signed digest is `keccak256("dstack-os-image:" || os_image_hash)` and the signature is
+/// 65 bytes long with the recovery id appended. Returns the hex encoded compressed public key of
+/// the signer, which must be one of `trusted_signers`.
+pub fn verify_signature(
+    os_image_hash: &[u8],
+    signature: &[u8],
+    trusted_signers: &[String],
+) -> Result<String> {
+    use sha3::{Digest, Keccak256};
+
+    let [sig @ .., recid] = signature else {
+        bail!("Empty signature");
+    };
+    let sig = Signature::from_slice(sig).context("Invalid signature")?;
+    let recid = RecoveryId::from_byte(*recid).context("Invalid recovery id")?;
+    let digest = Keccak256::new_with_prefix([SIGNATURE_PREFIX, b":", os_image_hash].concat());
+    let pubkey = VerifyingKey::recover_from_digest(digest, &sig, recid)
+        .context("Failed to recover signer")?;
+    let signer = hex::encode(pubkey.to_sec1_bytes());
+    let trusted = trusted_signers.iter().any(|s| {
+        s.strip_prefix("0x")
+            .unwrap_or(s)
+            .eq_ignore_ascii_case(&signer)
+    });
+    if !trusted {
+        bail!("Untrusted image signer: {signer}");
+    }
+    Ok(signer)
+}
+
+/// Precompute the measurements of an image for the given machine profiles.
+pub fn measure_image(
+    dir: &Path,
+    profiles: &[MeasureProfile],
+    cvm: &CvmConfig,
+) -> Result<Vec<ImageMeasurement>> {
+    let info = ImageInfo::load(dir.join("metadata.json"))?;
+    let Some(bios) = &info.bios else {
+        bail!("Image has no firmware, cannot be measured");
+    };
+    let fw_path = dir.join(bios).display().to_string();
+    let kernel_path = dir.join(&info.kernel).display().to_string();
+    let initrd_path = dir.join(&info.initrd).display().to_string();
+    let kernel_cmdline = info.cmdline.clone().unwrap_or_default() + " initrd=initrd";
+    profiles
+        .iter()
+        .map(|profile| {
+            let mrs = dstack_mr::Machine::builder()
+                .cpu_count(profile.vcpu)
+                .memory_size(profile.memory as u64 * 1024 * 1024)
+                .firmware(&fw_path)
+                .kernel(&kernel_path)
+                .initrd(&initrd_path)
+                .kernel_cmdline(&kernel_cmdline)
+                .root_verity(true)
+                .hotplug_off(cvm.qemu_hotplug_off)
+                .two_pass_add_pages(cvm.qemu_single_pass_add_pages)
+                .pic(cvm.qemu_pic)
+                .maybe_pci_hole64_size(if cvm.qemu_pci_hole64_size > 0 {
+                    Some(cvm.qemu_pci_hole64_size)
+                } else {
+                    None
+                })
+                .hugepages(false)
+                .num_gpus(0)
+                .num_nvswitches(0)
+                .build()
+                .measure()
+                .context("Failed to measure image")?;
+            Ok(ImageMeasurement {
+                vcpu: profile.vcpu,
+                memory: profile.memory,
+                mrtd: mrs.mrtd,
+                rtmr0: mrs.rtmr0,
+                rtmr1: mrs.rtmr1,
+                rtmr2: mrs.rtmr2,
+            })
+        })
+        .collect()
+}
+
+/// The default directory name of an image, following the `dstack[-dev]-<version>` convention.
+pub fn default_image_name(info: &ImageInfo) -> Result<String> {
+    if info.version.is_empty() {
+        bail!("Image version is missing, please specify the image name");
+    }
+    Ok(if info.is_dev {
+        format!("dstack-dev-{}", info.version)
+    } else {
+        format!("dstack-{}", info.version)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k256::ecdsa::SigningKey;
+    use sha3::Keccak256;
+
+    #[test]
+    fn test_verify_signature() {
+        let key = SigningKey::from_slice(&[7u8; 32]).unwrap();
+        let signer = hex::encode(key.verifying_key().to_sec1_bytes());
+        let os_image_hash = [1u8; 32];
+        let digest = Keccak256::new_with_prefix([SIGNATURE_PREFIX, b":", &os_image_hash].concat());
+        let (sig, recid) = key.sign_digest_recoverable(digest).unwrap();
+        let mut signature = sig.to_vec();
+        signature.push(recid.to_byte());
+
+        let trusted = vec![format!("0x{signer}")];
+        assert_eq!(
+            verify_signature(&os_image_hash, &signature, &trusted).unwrap(),
+            signer
+        );
+        assert!(verify_signature(&[2u8; 32], &signature, &trusted).is_err());
+        assert!(verify_signature(&os_image_hash, &signature, &[]).is_err());
+        assert!(verify_signature(&os_image_hash, &[], &trusted).is_err());
+    }
+}
@@ -0,0 +1,318 @@
+//! VM templates: the configuration of a VM saved under a name, to create more instances of an app.
+//!
+//! A template directory holds `template.json` and copies of the compose file, user config and
+//! encrypted env, using the same file names as the shared directory of a VM.
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use dstack_types::shared_filenames::{APP_COMPOSE, ENCRYPTED_ENV, USER_CONFIG};
+use dstack_vmm_rpc as pb;
+use fs_err as fs;
+use serde::{Deserialize, Serialize};
+
+use super::{Manifest, VmWorkDir};
+
+const TEMPLATE_FILE: &str = "template.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VmTemplate {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    /// The VM the template was saved from
+    pub source_vm_id: String,
+    pub created_at_ms: u64,
+    /// The manifest of the source VM, without its data disks
+    pub manifest: Manifest,
+}
+
+impl VmTemplate {
+    pub fn to_pb(&self, dir: &Path) -> pb::TemplateInfo {
+        pb::TemplateInfo {
+            name: self.name.clone(),
+            description: self.description.clone(),
+            source_vm_id: self.source_vm_id.clone(),
+            created_at_ms: self.created_at_ms,
+            configuration: Some(self.manifest.to_vm_configuration(dir)),
+        }
+    }
+}
+
+/// Template names are used as directory names.
+pub fn validate_name(name: &str) -> Result<()> {
+    if name.is_empty()
+        || name.starts_with('.')
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        bail!("Invalid template name: {name}");
+    }
+    Ok(())
+}
+
+pub struct TemplateStore<'a> {
+    pub dir: &'a Path,
+}
+
+impl TemplateStore<'_> {
+    pub fn template_dir(&self, name: &str) -> Result<PathBuf> {
+        validate_name(name)?;
+        Ok(self.dir.join(name))
+    }
+
+    /// Save the configuration of a VM as a template.
+    pub fn save(
+        &self,
+        name: &str,
+        description: &str,
+        vm: &VmWorkDir,
+        overwrite: bool,
+        now_ms: u64,
+    ) -> Result<VmTemplate> {
+        let dir = self.template_dir(name)?;
+        if dir.exists() && !overwrite {
+            bail!("Template {name} already exists");
+        }
+        let mut manifest = vm.manifest().context("Failed to read manifest")?;
+        manifest.data_disks.clear();
+        let template = VmTemplate {
+            name: name.to_string(),
+            description: description.to_string(),
+            source_vm_id: manifest.id.clone(),
+            created_at_ms: now_ms,
+            manifest,
+        };
+
+        // Write to a temporary directory first so that a failed save never leaves a partial template
+        fs::create_dir_all(self.dir).context("Failed to create template directory")?;
+        let tmp_dir = self.dir.join(format!(".{name}.tmp"));
+        if tmp_dir.exists() {
+            fs::remove_dir_all(&tmp_dir)?;
+        }
+        fs::create_dir(&tmp_dir)?;
+        let shared_dir = vm.shared_dir();
+        for file in [APP_COMPOSE, USER_CONFIG, ENCRYPTED_ENV] {
+            let src = shared_dir.join(file);
+            if src.exists() {
+                fs::copy(&src, tmp_dir.join(file))
+                    .with_context(|| format!("Failed to copy {file}"))?;
+            }
+        }
+        if !tmp_dir.join(APP_COMPOSE).exists() {
+            bail!("The VM has no compose file");
+        }
+        fs::write(
+            tmp_dir.join(TEMPLATE_FILE),
+            serde_json::to_string_pretty(&template)?,
+        )?;
+        if dir.exists() {
+            fs::remove_dir_all(&dir).context("Failed to remove the old template")?;
+        }
+        fs::rename(&tmp_dir, &dir).context("Failed to save template")?;
+        Ok(template)
+    }
+
+    pub fn load(&self, name: &str) -> Result<VmTemplate> {
+        let dir = self.template_dir(name)?;
+        let path = dir.join(TEMPLATE_FILE);
+        if !path.exists() {
+            bail!("Template {name} not found");
+        }
+        let content = fs::read_to_string(path)?;
+        serde_json::from_str(&content).context("Failed to parse template")
+    }
+
+    pub fn list(&self) -> Result<Vec<VmTemplate>> {
+        if !self.dir.exists() {
+            return Ok(vec![]);
+        }
+        let mut templates = vec![];
+        for entry in fs::read_dir(self.dir).context("Failed to read template directory")? {
+            let name = entry?.file_name();
+            let Some(name) = name.to_str() else {
+                continue;
+            };
+            if validate_name(name).is_err() {
+                continue;
+            }
+            if let Ok(template) = self.load(name) {
+                templates.push(template);
+            }
+        }
+        templates.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(templates)
+    }
+
+    pub fn remove(&self, name: &str) -> Result<()> {
+        let dir = self.template_dir(name)?;
+        if !dir.join(TEMPLATE_FILE).exists() {
+            bail!("Template {name} not found");
+        }
+        fs::remove_dir_all(&dir).context("Failed to remove template")
+    }
+}
+
+/// Overrides applied to a base configuration when creating VMs from a template or another VM.
+pub fn apply_overrides(config: &mut pb::VmConfiguration, overrides: &pb::VmOverrides) {
+    if let Some(name) = &overrides.name {
+        config.name = name.clone();
+    }
+    if let Some(image) = &overrides.image {
+        config.image = image.clone();
+    }
+    if let Some(vcpu) = overrides.vcpu {
+        config.vcpu = vcpu;
+    }
+    if let Some(memory) = overrides.memory {
+        config.memory = memory;
+    }
+    if let Some(disk_size) = overrides.disk_size {
+        config.disk_size = disk_size;
+    }
+    if overrides.update_ports {
+        config.ports = overrides.ports.clone();
+    }
+    if let Some(gpus) = &overrides.gpus {
+        config.gpus = Some(gpus.clone());
+    }
+    if let Some(limits) = &overrides.limits {
+        config.limits = Some(limits.clone());
+    }
+}
+
+/// The configurations of `count` copies. Copies are named `<name>-<n>` if there are more than one.
+///
+/// `ports_in_use` tells that the port mappings are those of an existing VM, which the copies can
+/// not bind again.
+pub fn expand_copies(
+    config: pb::VmConfiguration,
+    count: u32,
+    ports_in_use: bool,
+) -> Result<Vec<pb::VmConfiguration>> {
+    if config.compose_file.is_empty() {
+        bail!("Missing compose file");
+    }
+    let count = count.max(1);
+    if !config.ports.is_empty() && (count > 1 || ports_in_use) {
+        bail!("Port mappings can not be shared with other VMs, override the ports");
+    }
+    if count == 1 {
+        return Ok(vec![config]);
+    }
+    Ok((1..=count)
+        .map(|n| pb::VmConfiguration {
+            name: format!("{}-{n}", config.name),
+            ..config.clone()
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vm_config() -> pb::VmConfiguration {
+        pb::VmConfiguration {
+            name: "app".into(),
+            image: "dstack-0.5.0".into(),
+            compose_file: "{}".into(),
+            vcpu: 1,
+            memory: 1024,
+            disk_size: 20,
+            ports: vec![pb::PortMapping {
+                protocol: "tcp".into(),
+                host_address: "127.0.0.1".into(),
+                host_port: 8080,
+                vm_port: 80,
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let tmp = tempfile::tempdir().unwrap();
+        let vm = VmWorkDir::new(tmp.path().join("vm"));
+        fs::create_dir_all(vm.shared_dir()).unwrap();
+        fs::write(vm.app_compose_path(), "{}").unwrap();
+        fs::write(vm.encrypted_env_path(), b"env").unwrap();
+        let manifest = Manifest::builder()
+            .id("vm-1".into())
+            .name("app".into())
+            .app_id("app".into())
+            .vcpu(2)
+            .memory(1024)
+            .disk_size(10)
+            .image("dstack-0.5.0".into())
+            .port_map(vec![])
+            .created_at_ms(0)
+            .hugepages(false)
+            .pin_numa(false)
+            .kms_urls(vec![])
+            .gateway_urls(vec![])
+            .build();
+        vm.put_manifest(&manifest).unwrap();
+
+        let store = TemplateStore {
+            dir: &tmp.path().join("templates"),
+        };
+        store.save("web", "", &vm, false, 1).unwrap();
+        assert!(store.save("web", "", &vm, false, 2).is_err());
+        store.save("web", "updated", &vm, true, 3).unwrap();
+
+        let template = store.load("web").unwrap();
+        assert_eq!(template.source_vm_id, "vm-1");
+        assert_eq!(template.description, "updated");
+        let config = template
+            .manifest
+            .to_vm_configuration(&store.template_dir("web").unwrap());
+        assert_eq!(config.compose_file, "{}");
+        assert_eq!(config.encrypted_env, b"env");
+        assert_eq!(config.vcpu, 2);
+
+        assert_eq!(store.list().unwrap().len(), 1);
+        store.remove("web").unwrap();
+        assert!(store.list().unwrap().is_empty());
+        assert!(store.load("web").is_err());
+    }
+
+    #[test]
+    fn test_validate_name() {
+        assert!(validate_name("web-v1.2_a").is_ok());
+        for name in ["", ".hidden", "a/b", "..", "a b"] {
+            assert!(validate_name(name).is_err(), "{name}");
+        }
+    }
+
+    #[test]
+    fn test_overrides() {
+        let mut config = vm_config();
+        apply_overrides(
+            &mut config,
+            &pb::VmOverrides {
+                name: Some("web".into()),
+                vcpu: Some(4),
+                update_ports: true,
+                ..Default::default()
+            },
+        );
+        assert_eq!(config.name, "web");
+        assert_eq!(config.vcpu, 4);
+        assert_eq!(config.memory, 1024);
+        assert!(config.ports.is_empty());
+
+        let copies = expand_copies(config.clone(), 3, false).unwrap();
+        let names: Vec<_> = copies.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, ["web-1", "web-2", "web-3"]);
+        assert_eq!(
+            expand_copies(config.clone(), 0, true).unwrap()[0].name,
+            "web"
+        );
+        assert!(expand_copies(vm_config(), 2, false).is_err());
+
+        // A single clone would bind the same host ports as the source VM
+        assert!(expand_copies(vm_config(), 1, true).is_err());
+        assert_eq!(expand_copies(vm_config(), 1, false).unwrap().len(), 1);
+    }
+}
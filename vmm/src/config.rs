@@ -1,4 +1,4 @@
-use std::{net::IpAddr, path::PathBuf, str::FromStr};
+use std::{net::IpAddr, path::PathBuf, str::FromStr, time::Duration};
 
 use anyhow::{bail, Context, Result};
 use load_config::load_config;
@@ -130,6 +130,28 @@ pub struct CvmConfig {
     pub qemu_pci_hole64_size: u64,
     /// QEMU hotplug_off
     pub qemu_hotplug_off: bool,
+
+    /// cgroup v2 isolation of the VM processes
+    pub cgroup: CgroupConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CgroupConfig {
+    /// Whether to place each VM in its own cgroup
+    pub enabled: bool,
+    /// The parent cgroup of the VMs, relative to the cgroup v2 mount point
+    pub parent: String,
+    /// Limit the CPU time of a VM to its number of vCPUs
+    pub cpu_quota: bool,
+    /// Memory allowed for QEMU on top of the guest memory, in MB
+    pub memory_overhead: u32,
+    /// Max number of tasks of a VM, 0 for unlimited
+    pub pids_max: u64,
+    /// Default IO limits of a VM on the disk backing the run path, 0 for unlimited
+    pub io_read_bps: u64,
+    pub io_write_bps: u64,
+    pub io_read_iops: u64,
+    pub io_write_iops: u64,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -171,6 +193,27 @@ impl GpuConfig {
     }
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct MeasureProfile {
+    /// Number of vCPUs
+    pub vcpu: u8,
+    /// Memory in MB
+    pub memory: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ImageRegistryConfig {
+    /// Timeout for fetching an image bundle
+    #[serde(with = "serde_duration")]
+    pub download_timeout: Duration,
+    /// Reject images that are not signed by one of the trusted signers
+    pub require_signature: bool,
+    /// Hex encoded compressed secp256k1 public keys of the trusted image signers
+    pub trusted_signers: Vec<String>,
+    /// Machine profiles to precompute the image measurements for
+    pub measure_profiles: Vec<MeasureProfile>,
+}
+
 #[derive(Debug, Clone, Default, Deserialize)]
 pub struct AuthConfig {
     /// Whether to enable API token authentication
@@ -202,7 +245,9 @@ pub struct Config {
     pub image_path: PathBuf,
     #[serde(default)]
     pub run_path: PathBuf,
-    /// The URL of the KMS server
+    #[serde(default)]
+    pub template_path: PathBuf,
+    /// The URL of the KMS server, tried before the `kms_urls` of the CVMs
     pub kms_url: String,
 
     /// CVM configuration
@@ -224,6 +269,9 @@ pub struct Config {
 
     /// Key provider configuration
     pub key_provider: KeyProviderConfig,
+
+    /// Image registry configuration
+    pub image_registry: ImageRegistryConfig,
 }
 
 impl Config {
@@ -231,6 +279,7 @@ impl Config {
         Ok(Self {
             image_path: self.image_path.absolutize()?.to_path_buf(),
             run_path: self.run_path.absolutize()?.to_path_buf(),
+            template_path: self.template_path.absolutize()?.to_path_buf(),
             ..self
         })
     }
@@ -280,6 +329,9 @@ impl Config {
             if me.run_path == PathBuf::default() {
                 me.run_path = app_home.join("vm");
             }
+            if me.template_path == PathBuf::default() {
+                me.template_path = app_home.join("template");
+            }
             if me.cvm.qemu_path == PathBuf::default() {
                 let cpu_arch = std::env::consts::ARCH;
                 let qemu_path = which::which(format!("qemu-system-{}", cpu_arch))
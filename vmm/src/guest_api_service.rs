@@ -1,8 +1,12 @@
 use crate::App as AppState;
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
+use fs_err as fs;
 use guest_api::{
     proxied_guest_api_server::{ProxiedGuestApiRpc, ProxiedGuestApiServer},
-    GuestInfo, Id, ListContainersResponse, NetworkInformation, SystemInfo,
+    ComposeConfigResponse, GuestInfo, Id, ListContainersResponse, NetworkInformation,
+    ProxiedServiceHealthRequest, ProxiedServiceLogsRequest, ProxiedServiceRequest,
+    ProxiedUpgradeComposeRequest, ServiceHealthResponse, ServiceLogsResponse, SystemInfo,
+    UpgradeComposeResponse,
 };
 use ra_rpc::{CallContext, RpcCall};
 use std::ops::Deref;
@@ -51,4 +55,58 @@ impl ProxiedGuestApiRpc for GuestApiHandler {
     async fn shutdown(self, request: Id) -> Result<()> {
         self.guest_agent_client(&request.id)?.shutdown().await
     }
+
+    async fn restart_service(self, request: ProxiedServiceRequest) -> Result<()> {
+        let inner = request.request.context("missing request")?;
+        self.guest_agent_client(&request.id)?
+            .restart_service(inner)
+            .await
+    }
+
+    async fn stop_service(self, request: ProxiedServiceRequest) -> Result<()> {
+        let inner = request.request.context("missing request")?;
+        self.guest_agent_client(&request.id)?
+            .stop_service(inner)
+            .await
+    }
+
+    async fn service_logs(self, request: ProxiedServiceLogsRequest) -> Result<ServiceLogsResponse> {
+        let inner = request.request.context("missing request")?;
+        self.guest_agent_client(&request.id)?
+            .service_logs(inner)
+            .await
+    }
+
+    async fn service_health(
+        self,
+        request: ProxiedServiceHealthRequest,
+    ) -> Result<ServiceHealthResponse> {
+        let inner = request.request.unwrap_or_default();
+        self.guest_agent_client(&request.id)?
+            .service_health(inner)
+            .await
+    }
+
+    async fn compose_config(self, request: Id) -> Result<ComposeConfigResponse> {
+        self.guest_agent_client(&request.id)?.compose_config().await
+    }
+
+    async fn upgrade_compose(
+        self,
+        request: ProxiedUpgradeComposeRequest,
+    ) -> Result<UpgradeComposeResponse> {
+        let inner = request.request.context("missing request")?;
+        let compose_file_path = self.compose_file_path(&request.id);
+        if !compose_file_path.exists() {
+            bail!("The instance {} not found", request.id);
+        }
+        let response = self
+            .guest_agent_client(&request.id)?
+            .upgrade_compose(inner.clone())
+            .await?;
+        // The next boot measures the compose the app is running now
+        fs::write(compose_file_path, &inner.compose_file)
+            .context("Failed to write compose file")?;
+        Ok(response)
+    }
 }
@@ -39,6 +39,16 @@ async fn res(path: &str) -> Result<(ContentType, String), Custom<String>> {
     }
 }
 
+#[get("/metrics")]
+async fn metrics(_auth: Authorized, app: &State<App>) -> Result<String, Custom<String>> {
+    app.prometheus_metrics().await.map_err(|err| {
+        Custom(
+            rocket::http::Status::InternalServerError,
+            format!("Failed to collect metrics: {err:?}"),
+        )
+    })
+}
+
 static STREAM_CREATED_COUNTER: AtomicUsize = AtomicUsize::new(0);
 static STREAM_DROPPED_COUNTER: AtomicUsize = AtomicUsize::new(0);
 
@@ -149,5 +159,5 @@ fn vm_logs(
 }
 
 pub fn routes() -> Vec<Route> {
-    routes![index, res, vm_logs]
+    routes![index, res, metrics, vm_logs]
 }
@@ -6,16 +6,23 @@ use dstack_types::AppCompose;
 use dstack_vmm_rpc as rpc;
 use dstack_vmm_rpc::vmm_server::{VmmRpc, VmmServer};
 use dstack_vmm_rpc::{
-    AppId, ComposeHash as RpcComposeHash, GatewaySettings, GetInfoResponse, GetMetaResponse, Id,
-    ImageInfo as RpcImageInfo, ImageListResponse, KmsSettings, ListGpusResponse, PublicKeyResponse,
-    ResizeVmRequest, ResourcesSettings, StatusRequest, StatusResponse, UpgradeAppRequest,
-    VersionResponse, VmConfiguration,
+    AppId, CloneDataDiskRequest, CloneVmRequest, ComposeHash as RpcComposeHash,
+    CreateDataDiskRequest, CreatedVms, DataDiskRequest, DataDiskSnapshotRequest, GatewaySettings,
+    GcImagesRequest, GcImagesResponse, GetInfoResponse, GetMetaResponse, Id,
+    ImageInfo as RpcImageInfo, ImageListResponse, ImageName, InstantiateTemplateRequest,
+    KmsSettings, ListDataDisksResponse, ListGpusResponse, ListTemplatesResponse, PublicKeyResponse,
+    PullImageRequest, PullImageResponse, ResizeDataDiskRequest, ResizeVmRequest, ResourcesSettings,
+    SaveTemplateRequest, StatusRequest, StatusResponse, TemplateInfo, TemplateName,
+    UpgradeAppRequest, VersionResponse, VmConfiguration,
 };
 use fs_err as fs;
 use ra_rpc::{CallContext, RpcCall};
 use tracing::{info, warn};
 
-use crate::app::{App, AttachMode, GpuConfig, GpuSpec, Manifest, PortMapping, VmWorkDir};
+use crate::app::{
+    App, AttachMode, GpuConfig, GpuSpec, Manifest, PortMapping, ResourceLimits, SnapshotKind,
+    VmSource, VmWorkDir,
+};
 
 fn hex_sha256(data: &str) -> String {
     use sha2::Digest;
@@ -36,6 +43,13 @@ impl Deref for RpcHandler {
     }
 }
 
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
 fn app_id_of(compose_file: &str) -> String {
     fn truncate40(s: &str) -> &str {
         if s.len() > 40 {
@@ -117,10 +131,8 @@ impl RpcHandler {
         }
         Ok(gpus)
     }
-}
 
-impl VmmRpc for RpcHandler {
-    async fn create_vm(self, request: VmConfiguration) -> Result<Id> {
+    async fn create_vm_with(&self, request: &VmConfiguration) -> Result<Id> {
         validate_label(&request.name)?;
 
         let pm_cfg = &self.app.config.cvm.port_mapping;
@@ -156,14 +168,18 @@ impl VmmRpc for RpcHandler {
             None => app_id_of(&request.compose_file),
         };
         let id = uuid::Uuid::new_v4().to_string();
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_millis() as u64;
+        let now = now_ms();
         let gpus = match &request.gpus {
             Some(gpus) => self.resolve_gpus(gpus)?,
             None => GpuConfig::default(),
         };
+        if request.limits.is_some() && !self.app.config.cvm.cgroup.enabled {
+            bail!("cgroup isolation is disabled");
+        }
+        let mut limits = ResourceLimits::default();
+        if let Some(request_limits) = &request.limits {
+            limits.update(request_limits);
+        }
         let manifest = Manifest::builder()
             .id(id.clone())
             .name(request.name.clone())
@@ -179,12 +195,13 @@ impl VmmRpc for RpcHandler {
             .gpus(gpus)
             .kms_urls(request.kms_urls.clone())
             .gateway_urls(request.gateway_urls.clone())
+            .limits(limits)
             .build();
         let vm_work_dir = self.app.work_dir(&id);
         vm_work_dir
             .put_manifest(&manifest)
             .context("Failed to write manifest")?;
-        let work_dir = self.prepare_work_dir(&id, &request, &app_id)?;
+        let work_dir = self.prepare_work_dir(&id, request, &app_id)?;
         if let Err(err) = vm_work_dir.set_started(true) {
             warn!("Failed to set started: {}", err);
         }
@@ -208,6 +225,58 @@ impl VmmRpc for RpcHandler {
         Ok(Id { id })
     }
 
+    /// Create the VMs one by one, stopping at the first failure.
+    async fn create_vms(&self, configs: Vec<VmConfiguration>) -> Result<CreatedVms> {
+        let mut ids = vec![];
+        for config in configs {
+            let id = self.create_vm_with(&config).await.with_context(|| {
+                format!("Failed to create VM {}, created: {ids:?}", config.name)
+            })?;
+            ids.push(id.id);
+        }
+        Ok(CreatedVms { ids })
+    }
+}
+
+impl VmmRpc for RpcHandler {
+    async fn create_vm(self, request: VmConfiguration) -> Result<Id> {
+        self.create_vm_with(&request).await
+    }
+
+    async fn save_template(self, request: SaveTemplateRequest) -> Result<TemplateInfo> {
+        self.app
+            .save_template(&request, now_ms())
+            .context("Failed to save template")
+    }
+
+    async fn list_templates(self) -> Result<ListTemplatesResponse> {
+        Ok(ListTemplatesResponse {
+            templates: self.app.list_templates()?,
+        })
+    }
+
+    async fn remove_template(self, request: TemplateName) -> Result<()> {
+        self.app.remove_template(&request.name)
+    }
+
+    async fn instantiate_template(self, request: InstantiateTemplateRequest) -> Result<CreatedVms> {
+        let configs = self.app.derive_vm_configs(
+            VmSource::Template(&request.name),
+            request.overrides.as_ref(),
+            request.count,
+        )?;
+        self.create_vms(configs).await
+    }
+
+    async fn clone_vm(self, request: CloneVmRequest) -> Result<CreatedVms> {
+        let configs = self.app.derive_vm_configs(
+            VmSource::Vm(&request.id),
+            request.overrides.as_ref(),
+            request.count,
+        )?;
+        self.create_vms(configs).await
+    }
+
     async fn start_vm(self, request: Id) -> Result<()> {
         self.app
             .start_vm(&request.id)
@@ -252,6 +321,22 @@ impl VmmRpc for RpcHandler {
         })
     }
 
+    async fn pull_image(self, request: PullImageRequest) -> Result<PullImageResponse> {
+        self.app
+            .pull_image(&request)
+            .await
+            .context("Failed to pull image")
+    }
+
+    async fn remove_image(self, request: ImageName) -> Result<()> {
+        self.app.remove_image(&request.name)
+    }
+
+    async fn gc_images(self, request: GcImagesRequest) -> Result<GcImagesResponse> {
+        let removed = self.app.gc_images(request.dry_run)?;
+        Ok(GcImagesResponse { removed })
+    }
+
     async fn upgrade_app(self, request: UpgradeAppRequest) -> Result<Id> {
         let new_id = if !request.compose_file.is_empty() {
             // check the compose file is valid
@@ -343,12 +428,20 @@ impl VmmRpc for RpcHandler {
             .vm_info(&request.id)
             .await?
             .context("vm not found")?;
-        if !["stopped", "exited"].contains(&vm.status.as_str()) {
+        let is_running = !["stopped", "exited"].contains(&vm.status.as_str());
+        let needs_restart = request.vcpu.is_some()
+            || request.memory.is_some()
+            || request.image.is_some()
+            || request.disk_size.is_some();
+        if is_running && needs_restart {
             return Err(anyhow!(
                 "vm should be stopped before resize: {}",
                 request.id
             ));
         }
+        if request.limits.is_some() && !self.app.config.cvm.cgroup.enabled {
+            bail!("cgroup isolation is disabled");
+        }
         let work_dir = self.app.config.run_path.join(&request.id);
         let vm_work_dir = VmWorkDir::new(&work_dir);
         let mut manifest = vm_work_dir.manifest().context("failed to read manifest")?;
@@ -361,26 +454,21 @@ impl VmmRpc for RpcHandler {
         if let Some(image) = request.image {
             manifest.image = image;
         }
+        if let Some(limits) = &request.limits {
+            manifest.limits.update(limits);
+        }
         if let Some(disk_size) = request.disk_size {
             if disk_size < manifest.disk_size {
                 bail!("Cannot shrink disk size");
             }
             manifest.disk_size = disk_size;
 
-            // Run qemu-img resize to resize the disk
             info!("Resizing disk to {}GB", disk_size);
             let hda_path = vm_work_dir.hda_path();
-            let new_size_str = format!("{}G", disk_size);
-            let output = std::process::Command::new("qemu-img")
-                .args(["resize", &hda_path.display().to_string(), &new_size_str])
-                .output()
+            self.app
+                .qemu_img
+                .resize(&hda_path, &format!("{disk_size}G"))
                 .context("Failed to resize disk")?;
-            if !output.status.success() {
-                bail!(
-                    "Failed to resize disk: {}",
-                    String::from_utf8_lossy(&output.stderr)
-                );
-            }
         }
         vm_work_dir
             .put_manifest(&manifest)
@@ -389,6 +477,12 @@ impl VmmRpc for RpcHandler {
             .load_vm(work_dir, &Default::default(), false)
             .await
             .context("Failed to load VM")?;
+        if is_running && request.limits.is_some() {
+            self.app
+                .update_vm_limits(&request.id)
+                .await
+                .context("Failed to update resource limits")?;
+        }
         Ok(())
     }
 
@@ -448,6 +542,100 @@ impl VmmRpc for RpcHandler {
         })
     }
 
+    async fn list_data_disks(self, request: Id) -> Result<ListDataDisksResponse> {
+        let disks = self.app.list_data_disks(&request.id)?;
+        Ok(ListDataDisksResponse {
+            disks: disks.iter().map(|d| d.to_pb()).collect(),
+        })
+    }
+
+    async fn create_data_disk(self, request: CreateDataDiskRequest) -> Result<()> {
+        let now = now_ms();
+        self.app
+            .update_data_disks(&request.id, false, |disks, manifest| {
+                disks.create(manifest, &request.name, request.size, request.attach, now)
+            })
+            .await
+            .context("Failed to create data disk")
+    }
+
+    async fn attach_data_disk(self, request: DataDiskRequest) -> Result<()> {
+        self.app
+            .update_data_disks(&request.id, false, |disks, manifest| {
+                disks.set_attached(manifest, &request.name, true)
+            })
+            .await
+            .context("Failed to attach data disk")
+    }
+
+    async fn detach_data_disk(self, request: DataDiskRequest) -> Result<()> {
+        self.app
+            .update_data_disks(&request.id, false, |disks, manifest| {
+                disks.set_attached(manifest, &request.name, false)
+            })
+            .await
+            .context("Failed to detach data disk")
+    }
+
+    async fn remove_data_disk(self, request: DataDiskRequest) -> Result<()> {
+        self.app
+            .update_data_disks(&request.id, true, |disks, manifest| {
+                disks.remove(manifest, &request.name)
+            })
+            .await
+            .context("Failed to remove data disk")
+    }
+
+    async fn resize_data_disk(self, request: ResizeDataDiskRequest) -> Result<()> {
+        self.app
+            .update_data_disks(&request.id, true, |disks, manifest| {
+                disks.resize(manifest, &request.name, request.size)
+            })
+            .await
+            .context("Failed to resize data disk")
+    }
+
+    async fn create_data_disk_snapshot(self, request: DataDiskSnapshotRequest) -> Result<()> {
+        let kind = if request.external {
+            SnapshotKind::External
+        } else {
+            SnapshotKind::Internal
+        };
+        let now = now_ms();
+        self.app
+            .update_data_disks(&request.id, true, |disks, manifest| {
+                disks.create_snapshot(manifest, &request.name, &request.snapshot, kind, now)
+            })
+            .await
+            .context("Failed to create snapshot")
+    }
+
+    async fn delete_data_disk_snapshot(self, request: DataDiskSnapshotRequest) -> Result<()> {
+        self.app
+            .update_data_disks(&request.id, true, |disks, manifest| {
+                disks.delete_snapshot(manifest, &request.name, &request.snapshot)
+            })
+            .await
+            .context("Failed to delete snapshot")
+    }
+
+    async fn clone_data_disk(self, request: CloneDataDiskRequest) -> Result<()> {
+        let now = now_ms();
+        self.app
+            .update_data_disks(&request.id, true, |disks, manifest| {
+                disks.clone_disk(
+                    manifest,
+                    &request.source,
+                    request.snapshot.as_deref(),
+                    &request.name,
+                    request.attach,
+                    now,
+                )
+            })
+            .await
+            .context("Failed to clone data disk")
+    }
+
     async fn get_compose_hash(self, request: VmConfiguration) -> Result<RpcComposeHash> {
         validate_label(&request.name)?;
         // check the compose file is valid
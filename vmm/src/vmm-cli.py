@@ -469,6 +469,8 @@ class VmmCLI:
             "no_instance_id": args.no_instance_id,
             "secure_time": True,
         }
+        if args.public_service_control:
+            app_compose["public_service_control"] = True
         if args.prelaunch_script:
             app_compose["pre_launch_script"] = open(
                 args.prelaunch_script, 'rb').read().decode('utf-8')
@@ -863,6 +865,9 @@ def main():
         '--public-logs', action='store_true', help='Enable public logs')
     compose_parser.add_argument(
         '--public-sysinfo', action='store_true', help='Enable public sysinfo')
+    compose_parser.add_argument(
+        '--public-service-control', action='store_true',
+        help='Allow restarting and stopping services through the guest API')
     compose_parser.add_argument(
         '--env-file', help='File with environment variables to encrypt', default=None)
     compose_parser.add_argument(